@@ -5,7 +5,7 @@ import { RobotStateDisplay } from './RobotStateDisplay';
 import { RobotStateCommand } from './RobotStateCommand';
 import RobotVisualization from './RobotVisualization';
 
-import { Coord4DOF, RobotState } from '../types/RobotTypes';
+import { Coord4DOF, RobotState, TelemetryFrame } from '../types/RobotTypes';
 
 import styles from '../css/Robot.module.css'
 
@@ -14,12 +14,10 @@ export const Robot = () => {
   const [coords, setCoords] = useState<Coord4DOF | null>(null);
 
   useEffect(() => {
-    socket.on('joint state', (data: RobotState) => {
-      setRobotState(data);  // Update the joints with the received data.
-    });
-
-    socket.on('base coords', (data: Coord4DOF) => {
-      setCoords(data);  // Update the base with the received data.
+    socket.on('telemetry', (frame: TelemetryFrame) => {
+      // State and coords come from the same tick so they are always consistent.
+      setRobotState(frame.state);
+      setCoords(frame.coords);
     });
 
     socket.on('disconnect', () => {
@@ -28,8 +26,7 @@ export const Robot = () => {
 
     // Cleanup on component unmount
     return () => {
-      socket.off('joint state'); 
-      socket.off('base coords');
+      socket.off('telemetry');
     };
   }, []);
 
@@ -1,24 +1,2 @@
-export type JointState = {
-    swing_rotation_deg: number;
-    lift_elevation_mm: number;
-    elbow_rotation_deg: number;
-    wrist_rotation_deg: number;
-    gripper_open_mm: number;
-  };
-
-export type Coord4DOF = {
-    x : number;
-    y : number;
-    z : number;
-    theta: number;
-};
-
-export type Coord2D = {
-    x : number;
-    y : number;
-};
-
-export type RobotState = {
-  joint_state: JointState;
-  base_state: Coord4DOF;
-};
+// The wire types are generated from the server, see protocol.ts.
+export type { JointState, Coord4DOF, RobotState, TelemetryFrame } from './protocol';
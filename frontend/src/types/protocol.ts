@@ -0,0 +1,384 @@
+// Generated from the server's Rust types. Do not edit, run `UPDATE_PROTOCOL=1 cargo test` in `server` instead.
+
+export type JsonValue = number | string | boolean | Array<JsonValue> | { [key in string]?: JsonValue } | null;
+
+export type JointState = { 
+/**
+ * Swing rotation (degrees).
+ */
+swing_rotation_deg: number, 
+/**
+ * Lift elevation (mm).
+ */
+lift_elevation_mm: number, 
+/**
+ * Elbow rotation (degrees).
+ */
+elbow_rotation_deg: number, 
+/**
+ * Wrist rotation (degrees).
+ */
+wrist_rotation_deg: number, 
+/**
+ * Gripper opening (mm).
+ */
+gripper_open_mm: number, };
+
+export type Coord4DOF = { x: number, y: number, z: number, theta: number, };
+
+export type RobotState = { joint_state: JointState, base_state: Coord4DOF, };
+
+export type LinkCoords = { base: Coord4DOF, 
+/**
+ * Top of the lift, where the swing joint turns the elbow link.
+ */
+swing: Coord4DOF, elbow: Coord4DOF, wrist: Coord4DOF, end_effector: Coord4DOF, };
+
+export type Role = "viewer" | "operator" | "admin";
+
+export type AuthError = { "kind": "missing_token" } | { "kind": "invalid_token" };
+
+export type Command = { "command": "set_joint_state", "data": JointState } | { "command": "set_coord_state", "data": Coord4DOF } | { "command": "set_base_state", "data": Coord4DOF } | { "command": "set_gripper", "data": number } | { "command": "emergency_stop" } | { "command": "reset_fault" } | { "command": "move_to_pose", "data": string };
+
+export type CommandError = { "kind": "malformed", message: string, } | { "kind": "invalid_value", field: string, message: string, } | { "kind": "faulted", reason: string, } | { "kind": "unreachable" } | { "kind": "forbidden", required: Role, } | { "kind": "control_held", holder: string, } | { "kind": "lease_not_held" } | { "kind": "replaying" } | { "kind": "not_replaying" } | { "kind": "storage", message: string, } | { "kind": "unknown_pose", name: string, } | { "kind": "unknown_script", name: string, } | { "kind": "script_running", name: string, } | { "kind": "no_script_running" };
+
+export type CommandErrorEvent = { 
+/**
+ * The event name of the rejected command.
+ */
+command: string, } & ({ "kind": "malformed", message: string, } | { "kind": "invalid_value", field: string, message: string, } | { "kind": "faulted", reason: string, } | { "kind": "unreachable" } | { "kind": "forbidden", required: Role, } | { "kind": "control_held", holder: string, } | { "kind": "lease_not_held" } | { "kind": "replaying" } | { "kind": "not_replaying" } | { "kind": "storage", message: string, } | { "kind": "unknown_pose", name: string, } | { "kind": "unknown_script", name: string, } | { "kind": "script_running", name: string, } | { "kind": "no_script_running" });
+
+export type CommandOutcome = { 
+/**
+ * The joint and base target after limits were applied.
+ */
+target_state: RobotState, 
+/**
+ * The end effector coordinate being tracked with ik, if any.
+ */
+target_coord_state: Coord4DOF | null, 
+/**
+ * The joint solution ik found for a coordinate command.
+ */
+ik_solution: JointState | null, };
+
+export type CommandAck = { 
+/**
+ * The id supplied with the request, echoed back so the client can match the ack.
+ */
+request_id: JsonValue | null, 
+/**
+ * The name of the command.
+ */
+command: string, accepted: boolean, 
+/**
+ * Set when the command was accepted.
+ */
+outcome: CommandOutcome | null, 
+/**
+ * Set when the command was rejected.
+ */
+error: CommandError | null, };
+
+export type ControlStatus = { 
+/**
+ * Increments with every new lease. Renewing keeps the same id.
+ */
+lease_id: number, holder: string, expires_at: string, };
+
+export type ControlRequest = { take_over?: boolean, };
+
+export type LeaseGrant = { 
+/**
+ * Secret presenting the lease. Motion commands must carry it while the lease is held.
+ */
+token: string, 
+/**
+ * Increments with every new lease. Renewing keeps the same id.
+ */
+lease_id: number, holder: string, expires_at: string, };
+
+export type ControlAck = { accepted: boolean, 
+/**
+ * Set when control was granted or renewed.
+ */
+lease: LeaseGrant | null, 
+/**
+ * Set when the request was rejected.
+ */
+error: CommandError | null, };
+
+export type ReplayControl = { "action": "play" } | { "action": "pause" } | { "action": "seek", position_s: number, } | { "action": "speed", speed: number, };
+
+export type ReplayStatus = { playing: boolean, speed: number, 
+/**
+ * Seconds from the start of the recording.
+ */
+position_s: number, duration_s: number, 
+/**
+ * When the snapshot being shown was recorded.
+ */
+timestamp: string, };
+
+export type ReplayAck = { accepted: boolean, 
+/**
+ * The playback status after the control was applied.
+ */
+status: ReplayStatus | null, 
+/**
+ * Set when the control was rejected.
+ */
+error: CommandError | null, };
+
+export type HistoryFormat = "json" | "csv";
+
+export type HistoryQuery = { 
+/**
+ * Start of the range, an RFC 3339 timestamp or seconds relative to now such as `-10`. The oldest sample when not set.
+ */
+from?: string | null, 
+/**
+ * End of the range, in the same form as `from`. The newest sample when not set.
+ */
+to?: string | null, 
+/**
+ * Comma separated telemetry fields, e.g. `state.joint_state,coords.x`. A field selects every field nested under it. All fields when not set.
+ */
+fields?: string | null, format?: HistoryFormat | null, };
+
+export type HistoryAck = { samples: Array<JsonValue> | null, csv: string | null, error: string | null, };
+
+export type SavedState = { version: number, 
+/**
+ * When the state was saved.
+ */
+timestamp: string, state: RobotState, target_state: RobotState, target_coord_state: Coord4DOF | null, 
+/**
+ * The active fault, restored so a faulted robot stays faulted across a restart.
+ */
+fault: string | null, };
+
+export type SnapshotAck = { accepted: boolean, 
+/**
+ * The state that was saved or loaded.
+ */
+snapshot: SavedState | null, 
+/**
+ * Set when the operation was rejected.
+ */
+error: CommandError | null, };
+
+export type PoseTarget = { "joints": JointState } | { "coords": Coord4DOF };
+
+export type Pose = { target: PoseTarget, 
+/**
+ * The base target. The base stays where it is when not set.
+ */
+base_state?: Coord4DOF | null, };
+
+export type PoseKind = "joints" | "coords";
+
+export type SavePose = { name: string, 
+/**
+ * The pose to save. The robot's current pose, with its base, is taught when not set.
+ */
+pose?: Pose | null, 
+/**
+ * How the current pose is taught when `pose` is not set.
+ */
+kind?: PoseKind, };
+
+export type PoseName = { name: string, };
+
+export type PoseAck = { accepted: boolean, 
+/**
+ * The pose saved or deleted.
+ */
+pose: Pose | null, 
+/**
+ * Set when the request was rejected.
+ */
+error: CommandError | null, };
+
+export type ScriptLanguage = "rhai" | "rcl" | "behavior_tree";
+
+export type ScriptInfo = { name: string, language: ScriptLanguage, };
+
+export type PickAndPlace = { 
+/**
+ * Where the gripper closes on the object.
+ */
+pick: Coord4DOF, 
+/**
+ * Where the object is let go.
+ */
+place: Coord4DOF, 
+/**
+ * Height (m) above `pick` and `place` the gripper approaches from and retracts to.
+ */
+approach_height_m: number, 
+/**
+ * Gripper opening (mm) while approaching and after letting go.
+ */
+open_mm: number, 
+/**
+ * Gripper opening (mm) that holds the object.
+ */
+closed_mm: number, 
+/**
+ * End effector speed (m/sec) of the straight descents and retracts. `DEFAULT_PICK_AND_PLACE_SPEED` when not set.
+ */
+speed?: number | null, };
+
+export type NodeKind = "sequence" | "fallback" | "parallel" | "retry" | "condition" | "action";
+
+export type NodeStatus = "idle" | "running" | "success" | "failure";
+
+export type TreeNodeStatus = { 
+/**
+ * Position of the node in the tree, counted depth first from 0 at the root.
+ */
+id: number, 
+/**
+ * Not set for the root.
+ */
+parent: number | null, name: string, kind: NodeKind, status: NodeStatus, };
+
+export type ScriptSource = { name: string, 
+/**
+ * Rhai when not set.
+ */
+language?: ScriptLanguage, source: string, };
+
+export type ScriptName = { name: string, };
+
+export type ScriptControl = { "action": "pause" } | { "action": "resume" } | { "action": "abort" };
+
+export type ScriptState = "running" | "paused" | "finished" | "failed" | "aborted";
+
+export type ScriptStatus = { 
+/**
+ * Increments with every run.
+ */
+run_id: number, name: string, state: ScriptState, started_at: string, finished_at: string | null, 
+/**
+ * Why the script failed, with the line and position.
+ */
+error: string | null, };
+
+export type ScriptEvent = { "event": "output", run_id: number, line: string, } | { "event": "status" } & ScriptStatus | { "event": "tree", run_id: number, nodes: Array<TreeNodeStatus>, };
+
+export type ScriptAck = { accepted: boolean, 
+/**
+ * The status of the run started or controlled. Not set for uploads and deletes.
+ */
+status: ScriptStatus | null, 
+/**
+ * Set when the request was rejected.
+ */
+error: CommandError | null, };
+
+export type Stream = "joint state" | "base coords" | "velocity" | "links" | "fault" | "control" | "telemetry" | "script";
+
+export type StreamSubscription = { stream: Stream, 
+/**
+ * Maximum messages per second, at least `MIN_RATE_HZ`. Every broadcast tick when not set.
+ */
+rate_hz?: number | null, 
+/**
+ * Only deliver a message when it differs from the last one delivered.
+ * `telemetry` frames are compared without their `sequence` and `timestamp`.
+ */
+on_change?: boolean, };
+
+export type StreamRequest = Stream | StreamSubscription;
+
+export type SubscriptionAck = { 
+/**
+ * The streams the client is subscribed to afterwards.
+ */
+subscriptions: Array<StreamSubscription>, error: string | null, };
+
+export type TelemetryFrame = { 
+/**
+ * Layout version, see `TELEMETRY_VERSION`.
+ */
+version: number, 
+/**
+ * Increments by one every broadcast tick so clients can detect dropped frames.
+ */
+sequence: number, timestamp: string, 
+/**
+ * The current joint and base state.
+ */
+state: RobotState, 
+/**
+ * The current end effector pose.
+ */
+coords: Coord4DOF, velocity: RobotState, target_state: RobotState, target_coord_state: Coord4DOF | null, 
+/**
+ * `target_state - state`, using the shortest difference for angles.
+ */
+tracking_error: RobotState, 
+/**
+ * Whether ik used feedforward to reach `target_coord_state`. `None` without a coordinate target.
+ */
+ik_feedforward: boolean | null, 
+/**
+ * True once the robot has reached its targets and stopped moving.
+ */
+settled: boolean, fault: string | null, 
+/**
+ * The client holding the control lease, if any.
+ */
+control: ControlStatus | null, };
+
+export type Telemetry = { "type": "joint state", "data": RobotState } | { "type": "base coords", "data": Coord4DOF } | { "type": "velocity", "data": RobotState } | { "type": "links", "data": LinkCoords } | { "type": "fault", "data": string | null } | { "type": "control", "data": ControlStatus | null } | { "type": "telemetry", "data": TelemetryFrame } | { "type": "script", "data": ScriptEvent };
+
+export type TargetResponse = { target_state: RobotState, target_coord_state: Coord4DOF | null, };
+
+export type FaultResponse = { fault: string | null, };
+
+export type WsClientMessage = { "type": "command", 
+/**
+ * Optional request id echoed back in the ack.
+ */
+id?: JsonValue | null, } & ({ "command": "set_joint_state", "data": JointState } | { "command": "set_coord_state", "data": Coord4DOF } | { "command": "set_base_state", "data": Coord4DOF } | { "command": "set_gripper", "data": number } | { "command": "emergency_stop" } | { "command": "reset_fault" } | { "command": "move_to_pose", "data": string }) | { "type": "subscribe", streams: Array<StreamRequest>, } | { "type": "unsubscribe", streams: Array<Stream>, } | { "type": "request_control", take_over?: boolean, } | { "type": "renew_control" } | { "type": "release_control" } | { "type": "replay", } & ({ "action": "play" } | { "action": "pause" } | { "action": "seek", position_s: number, } | { "action": "speed", speed: number, }) | { "type": "save_snapshot" } | { "type": "load_snapshot" } | { "type": "list_poses" } | { "type": "save_pose", name: string, 
+/**
+ * The pose to save. The robot's current pose, with its base, is taught when not set.
+ */
+pose?: Pose | null, 
+/**
+ * How the current pose is taught when `pose` is not set.
+ */
+kind?: PoseKind, } | { "type": "delete_pose", name: string, } | { "type": "list_scripts" } | { "type": "upload_script", name: string, 
+/**
+ * Rhai when not set.
+ */
+language?: ScriptLanguage, source: string, } | { "type": "delete_script", name: string, } | { "type": "run_script", name: string, } | { "type": "script", } & ({ "action": "pause" } | { "action": "resume" } | { "action": "abort" }) | { "type": "pick_and_place", 
+/**
+ * Where the gripper closes on the object.
+ */
+pick: Coord4DOF, 
+/**
+ * Where the object is let go.
+ */
+place: Coord4DOF, 
+/**
+ * Height (m) above `pick` and `place` the gripper approaches from and retracts to.
+ */
+approach_height_m: number, 
+/**
+ * Gripper opening (mm) while approaching and after letting go.
+ */
+open_mm: number, 
+/**
+ * Gripper opening (mm) that holds the object.
+ */
+closed_mm: number, 
+/**
+ * End effector speed (m/sec) of the straight descents and retracts. `DEFAULT_PICK_AND_PLACE_SPEED` when not set.
+ */
+speed?: number | null, } | { "type": "script_status" };
+
+export type WsServerMessage = { "type": "ack", "data": CommandAck } | { "type": "subscriptions", "data": Array<StreamSubscription> } | { "type": "error", "data": string } | { "type": "control ack", "data": ControlAck } | { "type": "control lost", "data": ControlStatus | null } | { "type": "replay ack", "data": ReplayAck } | { "type": "snapshot ack", "data": SnapshotAck } | { "type": "poses", "data": { [key in string]?: Pose } } | { "type": "pose ack", "data": PoseAck } | { "type": "scripts", "data": Array<ScriptInfo> } | { "type": "script ack", "data": ScriptAck } | { "type": "script status", "data": ScriptStatus | null };
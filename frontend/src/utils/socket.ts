@@ -2,6 +2,7 @@
 import { io, Socket } from 'socket.io-client';
 
 // Connect to your server at localhost (or your specific server address)
-const socket: Socket = io('ws://127.0.0.1:3000'); // Adjust URL if necessary
+// Set VITE_ROBOT_TOKEN when the server has authentication enabled.
+const socket: Socket = io('ws://127.0.0.1:3000', { auth: { token: import.meta.env.VITE_ROBOT_TOKEN } }); // Adjust URL if necessary
 
 export default socket;
@@ -5,6 +5,11 @@ mod robot;
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
     
+    // Simulate a program headless and exit instead of serving.
+    if std::env::args().any(|arg| arg == robot::batch::BATCH_ARG) {
+        return robot::batch::run_from_args();
+    }
+
     let _ = robot::Robot::new().await;
 
 
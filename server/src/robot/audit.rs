@@ -0,0 +1,458 @@
+use super::auth::{Identity, Role};
+use super::command::{CommandError, CommandOutcome};
+use super::history::parse_time;
+
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use chrono::{DateTime, Utc};
+use serde_json::Value;
+use tokio::sync::mpsc;
+use tracing::{error, info, warn};
+
+/// Environment variable holding the path of the JSON audit log configuration file.
+pub const AUDIT_CONFIG_ENV: &str = "ROBOT_AUDIT_CONFIG";
+
+/// Number of entries returned by a query when no limit is given.
+pub const DEFAULT_AUDIT_QUERY_LIMIT: usize = 100;
+
+/// Configuration of the audit log. Every field is optional in the configuration file.
+#[derive(serde::Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct AuditConfig {
+    pub enabled: bool,
+    /// The file being written. Rotated files get a `.1`, `.2`, ... suffix, `.1` being the newest.
+    pub path: PathBuf,
+    /// Size at which the file is rotated (bytes).
+    pub max_bytes: u64,
+    /// Number of rotated files kept.
+    pub max_files: usize,
+}
+
+impl Default for AuditConfig {
+    fn default() -> Self {
+        AuditConfig { enabled: true, path: PathBuf::from("audit.log"), max_bytes: 10 * 1024 * 1024, max_files: 5 }
+    }
+}
+
+impl AuditConfig {
+    /// Loads the configuration from the file named by `ROBOT_AUDIT_CONFIG`, or the defaults if it is not set.
+    pub fn load() -> Result<AuditConfig, Box<dyn std::error::Error>> {
+        match std::env::var(AUDIT_CONFIG_ENV) {
+            Ok(path) => Ok(serde_json::from_str(&std::fs::read_to_string(path)?)?),
+            Err(_) => Ok(AuditConfig::default()),
+        }
+    }
+
+    /// The `index`th rotated file, 0 being the file being written.
+    fn rotated_path(&self, index: usize) -> PathBuf {
+        match index {
+            0 => self.path.clone(),
+            index => {
+                let mut path = self.path.clone().into_os_string();
+                path.push(format!(".{index}"));
+                PathBuf::from(path)
+            }
+        }
+    }
+}
+
+/// Where a command came from.
+#[derive(Clone, Debug)]
+pub struct Origin {
+    /// `socket.io`, `http`, `websocket`, `rosbridge`, `mqtt` or `modbus`.
+    pub transport: &'static str,
+    /// The Socket.IO socket id.
+    pub client_id: Option<String>,
+    pub remote_addr: Option<SocketAddr>,
+    /// The authenticated client. The MQTT and Modbus bridges are not authenticated.
+    pub identity: Option<Identity>,
+}
+
+/// One line of the audit log.
+#[derive(serde::Serialize, Debug)]
+struct AuditEntry<'a> {
+    timestamp: DateTime<Utc>,
+    transport: &'static str,
+    client_id: Option<&'a str>,
+    remote_addr: Option<SocketAddr>,
+    user: Option<&'a str>,
+    role: Option<Role>,
+    command: &'a str,
+    /// The payload as received.
+    payload: &'a Value,
+    accepted: bool,
+    error: Option<&'a CommandError>,
+    /// The effective target once the command was applied.
+    target: Option<&'a CommandOutcome>,
+}
+
+/// Filters for `AuditLog::query`.
+#[derive(serde::Deserialize, Clone, Debug, Default)]
+#[serde(default)]
+pub struct AuditQuery {
+    /// Start of the range, an RFC 3339 timestamp or seconds relative to now such as `-60`.
+    pub from: Option<String>,
+    /// End of the range, in the same form as `from`.
+    pub to: Option<String>,
+    pub user: Option<String>,
+    pub client_id: Option<String>,
+    /// Matches the remote address with or without its port.
+    pub remote_addr: Option<String>,
+    pub command: Option<String>,
+    /// The most recent entries returned, `DEFAULT_AUDIT_QUERY_LIMIT` when not set.
+    pub limit: Option<usize>,
+}
+
+/// Writes every command received, with who sent it and what it did, as JSON lines to a rotating file.
+#[derive(Debug)]
+pub struct AuditLog {
+    config: AuditConfig,
+    entries: Option<mpsc::UnboundedSender<String>>,
+}
+
+pub type SharedAudit = Arc<AuditLog>;
+
+impl AuditLog {
+    /// Opens the log and starts the writer thread. Nothing is written when the log is disabled.
+    pub fn start(config: AuditConfig) -> std::io::Result<AuditLog> {
+        if !config.enabled {
+            warn!("the audit log is disabled");
+            return Ok(AuditLog { config, entries: None });
+        }
+
+        let writer = RotatingWriter::open(config.clone())?;
+        info!("writing the audit log to {}", config.path.display());
+        let (entries, receiver) = mpsc::unbounded_channel();
+        std::thread::spawn(move || write_entries(writer, receiver));
+        Ok(AuditLog { config, entries: Some(entries) })
+    }
+
+    /// Records a command, whether or not it was accepted.
+    pub fn record(&self, origin: &Origin, command: &str, payload: &Value, result: &Result<CommandOutcome, CommandError>) {
+        let Some(entries) = &self.entries else {
+            return;
+        };
+
+        let entry = AuditEntry {
+            timestamp: Utc::now(),
+            transport: origin.transport,
+            client_id: origin.client_id.as_deref(),
+            remote_addr: origin.remote_addr,
+            user: origin.identity.as_ref().map(|identity| identity.name.as_str()),
+            role: origin.identity.as_ref().map(|identity| identity.role),
+            command,
+            payload,
+            accepted: result.is_ok(),
+            error: result.as_ref().err(),
+            target: result.as_ref().ok(),
+        };
+        if let Ok(line) = serde_json::to_string(&entry) {
+            let _ = entries.send(line);
+        }
+    }
+
+    /// Reads the entries matching `query` from the log and its rotated files, oldest first.
+    /// Blocks on file IO.
+    pub fn query(&self, query: &AuditQuery) -> Result<Vec<Value>, String> {
+        let now = Utc::now();
+        let from = query.from.as_deref().map(|from| parse_time(from, now)).transpose()?;
+        let to = query.to.as_deref().map(|to| parse_time(to, now)).transpose()?;
+        let limit = query.limit.unwrap_or(DEFAULT_AUDIT_QUERY_LIMIT);
+
+        let mut matches = std::collections::VecDeque::new();
+        for index in (0..=self.config.max_files).rev() {
+            let Ok(file) = File::open(self.config.rotated_path(index)) else {
+                continue;
+            };
+            for line in BufReader::new(file).lines() {
+                let Ok(entry) = serde_json::from_str::<Value>(&line.map_err(|err| err.to_string())?) else {
+                    continue;
+                };
+                let timestamp = entry["timestamp"].as_str().and_then(|timestamp| DateTime::parse_from_rfc3339(timestamp).ok());
+                if from.is_some_and(|from| timestamp.is_none_or(|timestamp| timestamp < from))
+                    || to.is_some_and(|to| timestamp.is_none_or(|timestamp| timestamp > to))
+                    || !matches_field(&entry, "user", &query.user)
+                    || !matches_field(&entry, "client_id", &query.client_id)
+                    || !matches_field(&entry, "command", &query.command)
+                    || !matches_remote_addr(&entry, &query.remote_addr)
+                {
+                    continue;
+                }
+
+                matches.push_back(entry);
+                if matches.len() > limit {
+                    matches.pop_front();
+                }
+            }
+        }
+        Ok(matches.into())
+    }
+}
+
+/// A payload as received, as JSON when it parses and as text otherwise.
+pub fn raw_payload(bytes: &[u8]) -> Value {
+    if bytes.is_empty() {
+        return Value::Null;
+    }
+    serde_json::from_slice(bytes).unwrap_or_else(|_| Value::String(String::from_utf8_lossy(bytes).into_owned()))
+}
+
+fn matches_field(entry: &Value, field: &str, expected: &Option<String>) -> bool {
+    expected.as_ref().is_none_or(|expected| entry[field].as_str() == Some(expected.as_str()))
+}
+
+fn matches_remote_addr(entry: &Value, expected: &Option<String>) -> bool {
+    let Some(expected) = expected else {
+        return true;
+    };
+    let remote_addr = entry["remote_addr"].as_str().unwrap_or_default();
+    remote_addr == expected || remote_addr.parse::<SocketAddr>().is_ok_and(|addr| addr.ip().to_string() == *expected)
+}
+
+/// Appends lines to the log, rotating it once it reaches `max_bytes`.
+struct RotatingWriter {
+    config: AuditConfig,
+    writer: BufWriter<File>,
+    size: u64,
+}
+
+impl RotatingWriter {
+    fn open(config: AuditConfig) -> std::io::Result<RotatingWriter> {
+        let file = OpenOptions::new().create(true).append(true).open(&config.path)?;
+        let size = file.metadata()?.len();
+        Ok(RotatingWriter { config, writer: BufWriter::new(file), size })
+    }
+
+    fn write_line(&mut self, line: &str) -> std::io::Result<()> {
+        if self.size > 0 && self.size + line.len() as u64 + 1 > self.config.max_bytes {
+            self.rotate()?;
+        }
+        self.writer.write_all(line.as_bytes())?;
+        self.writer.write_all(b"\n")?;
+        self.size += line.len() as u64 + 1;
+        Ok(())
+    }
+
+    /// Shifts every file up one suffix, dropping the oldest, and starts a new file.
+    fn rotate(&mut self) -> std::io::Result<()> {
+        self.writer.flush()?;
+        if self.config.max_files == 0 {
+            std::fs::remove_file(&self.config.path)?;
+        }
+        for index in (0..self.config.max_files).rev() {
+            let from = self.config.rotated_path(index);
+            if from.exists() {
+                std::fs::rename(from, self.config.rotated_path(index + 1))?;
+            }
+        }
+
+        let file = OpenOptions::new().create(true).append(true).open(&self.config.path)?;
+        self.writer = BufWriter::new(file);
+        self.size = 0;
+        Ok(())
+    }
+}
+
+/// Writes lines until the log is dropped, flushing whenever the queue is drained.
+fn write_entries(mut writer: RotatingWriter, mut entries: mpsc::UnboundedReceiver<String>) {
+    while let Some(line) = entries.blocking_recv() {
+        let mut result = writer.write_line(&line);
+        // Stop taking lines once a write fails so none are dropped unwritten.
+        while result.is_ok() {
+            let Ok(line) = entries.try_recv() else {
+                break;
+            };
+            result = writer.write_line(&line);
+        }
+        if let Err(err) = result.and_then(|_| writer.writer.flush()) {
+            error!("could not write the audit log, auditing stopped: {}", err);
+            return;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::path::Path;
+
+    fn config(dir: &Path, max_bytes: u64, max_files: usize) -> AuditConfig {
+        AuditConfig { enabled: true, path: dir.join("audit.log"), max_bytes, max_files }
+    }
+
+    fn read_lines(path: &Path) -> Vec<String> {
+        std::fs::read_to_string(path).unwrap().lines().map(str::to_string).collect()
+    }
+
+    /// Writes `lines` through a writer and flushes them.
+    fn write_lines(config: &AuditConfig, lines: &[&str]) {
+        let mut writer = RotatingWriter::open(config.clone()).unwrap();
+        for line in lines {
+            writer.write_line(line).unwrap();
+        }
+        writer.writer.flush().unwrap();
+    }
+
+    #[test]
+    fn the_log_is_rotated_once_it_is_full() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = config(dir.path(), 10, 5);
+        // Each line takes 6 bytes with its newline, so every file holds one line.
+        write_lines(&config, &["line1", "line2", "line3"]);
+
+        assert_eq!(read_lines(&config.rotated_path(0)), ["line3"]);
+        assert_eq!(read_lines(&config.rotated_path(1)), ["line2"]);
+        assert_eq!(read_lines(&config.rotated_path(2)), ["line1"]);
+        assert!(!config.rotated_path(3).exists());
+    }
+
+    #[test]
+    fn a_line_larger_than_the_limit_fills_a_file_alone() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = config(dir.path(), 4, 5);
+        write_lines(&config, &["a long line", "another long line"]);
+
+        assert_eq!(read_lines(&config.rotated_path(0)), ["another long line"]);
+        assert_eq!(read_lines(&config.rotated_path(1)), ["a long line"]);
+    }
+
+    #[test]
+    fn reopening_the_log_keeps_appending_to_it() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = config(dir.path(), 12, 5);
+        write_lines(&config, &["line1"]);
+        // The size on disk counts towards the limit, so the third line rotates.
+        write_lines(&config, &["line2", "line3"]);
+
+        assert_eq!(read_lines(&config.rotated_path(0)), ["line3"]);
+        assert_eq!(read_lines(&config.rotated_path(1)), ["line1", "line2"]);
+    }
+
+    #[test]
+    fn only_max_files_rotated_files_are_kept() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = config(dir.path(), 10, 2);
+        write_lines(&config, &["line1", "line2", "line3", "line4", "line5"]);
+
+        assert_eq!(read_lines(&config.rotated_path(0)), ["line5"]);
+        assert_eq!(read_lines(&config.rotated_path(1)), ["line4"]);
+        assert_eq!(read_lines(&config.rotated_path(2)), ["line3"]);
+        assert!(!config.rotated_path(3).exists());
+    }
+
+    #[test]
+    fn no_rotated_files_are_kept_when_max_files_is_zero() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = config(dir.path(), 10, 0);
+        write_lines(&config, &["line1", "line2", "line3"]);
+
+        assert_eq!(read_lines(&config.rotated_path(0)), ["line3"]);
+        assert!(!config.rotated_path(1).exists());
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 1);
+    }
+
+    fn entry(timestamp: DateTime<Utc>, user: &str, command: &str, remote_addr: &str) -> String {
+        serde_json::json!({ "timestamp": timestamp, "user": user, "command": command, "remote_addr": remote_addr, "client_id": null }).to_string()
+    }
+
+    /// A log spread over a rotated file and the current one, an entry a minute for the last five minutes.
+    fn query_log(dir: &Path) -> (AuditLog, DateTime<Utc>) {
+        let config = config(dir, 1024 * 1024, 5);
+        let now = Utc::now();
+        let minutes_ago = |minutes| now - chrono::Duration::minutes(minutes);
+        std::fs::write(
+            config.rotated_path(1),
+            [entry(minutes_ago(5), "alice", "set_joint_state", "10.0.0.1:4000"), entry(minutes_ago(4), "bob", "set_gripper", "10.0.0.2:4000"), "not json".to_string()].join("\n"),
+        )
+        .unwrap();
+        write_lines(
+            &config,
+            &[
+                &entry(minutes_ago(3), "alice", "set_gripper", "10.0.0.1:4001"),
+                &entry(minutes_ago(2), "bob", "set_joint_state", "10.0.0.2:4001"),
+                &entry(minutes_ago(1), "alice", "emergency_stop", "[::1]:4000"),
+            ],
+        );
+        (AuditLog { config, entries: None }, now)
+    }
+
+    fn commands(entries: &[Value]) -> Vec<(&str, &str)> {
+        entries.iter().map(|entry| (entry["user"].as_str().unwrap(), entry["command"].as_str().unwrap())).collect()
+    }
+
+    #[test]
+    fn queries_read_the_rotated_files_oldest_first() {
+        let dir = tempfile::tempdir().unwrap();
+        let (log, _) = query_log(dir.path());
+
+        let entries = log.query(&AuditQuery::default()).unwrap();
+        assert_eq!(
+            commands(&entries),
+            [("alice", "set_joint_state"), ("bob", "set_gripper"), ("alice", "set_gripper"), ("bob", "set_joint_state"), ("alice", "emergency_stop")]
+        );
+
+        let latest = log.query(&AuditQuery { limit: Some(2), ..Default::default() }).unwrap();
+        assert_eq!(commands(&latest), [("bob", "set_joint_state"), ("alice", "emergency_stop")]);
+    }
+
+    #[test]
+    fn queries_filter_on_every_field() {
+        let dir = tempfile::tempdir().unwrap();
+        let (log, now) = query_log(dir.path());
+        let query = |query: AuditQuery| commands(&log.query(&query).unwrap()).into_iter().map(|(user, command)| format!("{user} {command}")).collect::<Vec<_>>();
+
+        assert_eq!(query(AuditQuery { user: Some("bob".into()), ..Default::default() }), ["bob set_gripper", "bob set_joint_state"]);
+        assert_eq!(query(AuditQuery { command: Some("set_gripper".into()), ..Default::default() }), ["bob set_gripper", "alice set_gripper"]);
+        assert_eq!(
+            query(AuditQuery { user: Some("alice".into()), command: Some("set_gripper".into()), ..Default::default() }),
+            ["alice set_gripper"]
+        );
+        assert_eq!(query(AuditQuery { client_id: Some("sid".into()), ..Default::default() }), Vec::<String>::new());
+
+        assert_eq!(query(AuditQuery { remote_addr: Some("10.0.0.1".into()), ..Default::default() }), ["alice set_joint_state", "alice set_gripper"]);
+        assert_eq!(query(AuditQuery { remote_addr: Some("10.0.0.1:4001".into()), ..Default::default() }), ["alice set_gripper"]);
+        assert_eq!(query(AuditQuery { remote_addr: Some("::1".into()), ..Default::default() }), ["alice emergency_stop"]);
+
+        // Relative times are seconds from now.
+        assert_eq!(query(AuditQuery { from: Some("-150".into()), ..Default::default() }), ["bob set_joint_state", "alice emergency_stop"]);
+        let to = (now - chrono::Duration::seconds(210)).to_rfc3339();
+        assert_eq!(query(AuditQuery { from: Some("-330".into()), to: Some(to), ..Default::default() }), ["alice set_joint_state", "bob set_gripper"]);
+
+        assert!(log.query(&AuditQuery { from: Some("yesterday".into()), ..Default::default() }).is_err());
+    }
+
+    #[test]
+    fn recorded_commands_are_written_by_the_writer_thread() {
+        let dir = tempfile::tempdir().unwrap();
+        let log = AuditLog::start(config(dir.path(), 1024 * 1024, 5)).unwrap();
+        let origin = Origin {
+            transport: "websocket",
+            client_id: None,
+            remote_addr: Some("127.0.0.1:5000".parse().unwrap()),
+            identity: Some(Identity { name: "alice".to_string(), role: Role::Operator }),
+        };
+        log.record(&origin, "set_gripper", &serde_json::json!({ "gripper_open_mm": 20.0 }), &Err(CommandError::LeaseNotHeld));
+
+        // The writer thread writes asynchronously.
+        let deadline = std::time::Instant::now() + std::time::Duration::from_secs(5);
+        let entries = loop {
+            let entries = log.query(&AuditQuery::default()).unwrap();
+            if !entries.is_empty() || std::time::Instant::now() > deadline {
+                break entries;
+            }
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        };
+        assert_eq!(entries.len(), 1);
+        let entry = &entries[0];
+        assert_eq!((entry["transport"].as_str(), entry["user"].as_str(), entry["role"].as_str()), (Some("websocket"), Some("alice"), Some("operator")));
+        assert_eq!(entry["payload"]["gripper_open_mm"], 20.0);
+        assert_eq!(entry["accepted"], false);
+        assert!(entry["target"].is_null());
+        assert!(!entry["error"].is_null());
+    }
+}
@@ -0,0 +1,311 @@
+use super::command::{Command, CommandError, CommandOutcome};
+use super::control::{ControlOperation, LeaseGrant};
+use super::persistence::{self, SavedState, SnapshotOperation};
+use super::poses::{Pose, PoseOperation};
+use super::replay::{ReplayControl, ReplayStatus};
+use super::scripting::{ScriptOperation, ScriptStatus, Scripts};
+use super::RobotLock;
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use axum::{
+    extract::{Query, State},
+    http::{header, HeaderMap, Request, StatusCode, Uri},
+    middleware::Next,
+    response::{IntoResponse, Response},
+    Json,
+};
+use tracing::{error, warn};
+
+/// Environment variable holding the path of the JSON file listing the access tokens.
+pub const AUTH_CONFIG_ENV: &str = "ROBOT_AUTH_CONFIG";
+
+/// What a client is allowed to do. Each role can do everything the roles before it can.
+#[derive(serde::Serialize, serde::Deserialize, Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, schemars::JsonSchema, ts_rs::TS)]
+#[serde(rename_all = "snake_case")]
+pub enum Role {
+    /// Receives telemetry and queries state.
+    Viewer,
+    /// Sends motion commands and emergency stops.
+    Operator,
+    /// Resets faults and changes configuration and limits.
+    Admin,
+}
+
+/// A token and the identity it grants.
+#[derive(serde::Deserialize, Clone, Debug)]
+pub struct TokenConfig {
+    pub token: String,
+    pub role: Role,
+    /// Name used in the logs. Defaults to the role.
+    #[serde(default)]
+    pub name: Option<String>,
+}
+
+/// The contents of the file named by `ROBOT_AUTH_CONFIG`.
+#[derive(serde::Deserialize, Clone, Debug, Default)]
+#[serde(default)]
+pub struct AuthConfig {
+    pub tokens: Vec<TokenConfig>,
+}
+
+/// Who a client authenticated as.
+#[derive(Clone, Debug)]
+pub struct Identity {
+    pub name: String,
+    pub role: Role,
+}
+
+/// Why a client could not authenticate.
+#[derive(serde::Serialize, Clone, Debug, PartialEq, schemars::JsonSchema, ts_rs::TS)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum AuthError {
+    MissingToken,
+    InvalidToken,
+}
+
+impl std::fmt::Display for AuthError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AuthError::MissingToken => write!(f, "an access token is required"),
+            AuthError::InvalidToken => write!(f, "the access token is not valid"),
+        }
+    }
+}
+
+impl std::error::Error for AuthError {}
+
+/// Maps access tokens to identities. Authentication is disabled when no tokens are configured.
+#[derive(Debug, Default)]
+pub struct Auth {
+    tokens: Option<HashMap<String, Identity>>,
+}
+
+pub type SharedAuth = Arc<Auth>;
+
+impl Auth {
+    /// Loads the tokens from the file named by `ROBOT_AUTH_CONFIG`. Authentication is disabled if the variable is not set.
+    pub fn load() -> Result<Auth, Box<dyn std::error::Error>> {
+        match std::env::var(AUTH_CONFIG_ENV) {
+            Ok(path) => Ok(Auth::new(serde_json::from_str(&std::fs::read_to_string(path)?)?)),
+            Err(_) => {
+                error!("AUTHENTICATION IS DISABLED: {} is not set, so every client, including anonymous ones, has the admin role", AUTH_CONFIG_ENV);
+                Ok(Auth::default())
+            }
+        }
+    }
+
+    pub fn new(config: AuthConfig) -> Auth {
+        let tokens = config.tokens.into_iter().map(|token| {
+            let name = token.name.unwrap_or_else(|| format!("{:?}", token.role).to_lowercase());
+            (token.token, Identity { name, role: token.role })
+        });
+        Auth { tokens: Some(tokens.collect()) }
+    }
+
+    /// Returns the identity granted by `token`.
+    pub fn authenticate(&self, token: Option<&str>) -> Result<Identity, AuthError> {
+        let Some(tokens) = &self.tokens else {
+            return Ok(Identity { name: "anonymous".to_string(), role: Role::Admin });
+        };
+
+        let token = token.ok_or(AuthError::MissingToken)?;
+        tokens.get(token).cloned().ok_or(AuthError::InvalidToken)
+    }
+
+    /// Authenticates an HTTP request from its `Authorization: Bearer` header or, for WebSocket upgrades from browsers, a `token` query parameter.
+    /// The header is used when both are given.
+    pub fn authenticate_request(&self, headers: &HeaderMap, uri: &Uri) -> Result<Identity, AuthError> {
+        let token = bearer_token(headers).map(str::to_string).or_else(|| query_token(uri));
+        self.authenticate(token.as_deref())
+    }
+}
+
+fn bearer_token(headers: &HeaderMap) -> Option<&str> {
+    headers.get(header::AUTHORIZATION)?.to_str().ok()?.strip_prefix("Bearer ")
+}
+
+/// The percent-decoded `token` query parameter.
+fn query_token(uri: &Uri) -> Option<String> {
+    let Query(mut query) = Query::<HashMap<String, String>>::try_from_uri(uri).ok()?;
+    query.remove("token")
+}
+
+/// Rejects `action` if `identity` does not have the `required` role.
+pub fn require_role(identity: &Identity, required: Role, action: &str) -> Result<(), CommandError> {
+    if identity.role < required {
+        warn!("{} ({:?}) is not allowed to {}", identity.name, identity.role, action);
+        return Err(CommandError::Forbidden { required });
+    }
+    Ok(())
+}
+
+/// Rejects the command if `identity` does not have the role it requires.
+pub fn authorize(identity: &Identity, command: &Command) -> Result<(), CommandError> {
+    require_role(identity, command.required_role(), &format!("send '{}'", command.name()))
+}
+
+/// Executes `command` if `identity` is allowed to send it. `lease` is the control lease token the client holds, if any.
+pub async fn execute_authorized(robot_lock: &RobotLock, identity: &Identity, lease: Option<&str>, command: Command) -> Result<CommandOutcome, CommandError> {
+    authorize(identity, &command)?;
+    robot_lock.write().await.execute(command, lease)
+}
+
+/// Requests, renews or releases control of the robot on behalf of `identity`. Only operators may hold control.
+pub async fn control_authorized(robot_lock: &RobotLock, identity: &Identity, operation: ControlOperation<'_>) -> Result<Option<LeaseGrant>, CommandError> {
+    require_role(identity, Role::Operator, "hold control")?;
+    let mut robot = robot_lock.write().await;
+    match operation {
+        ControlOperation::Request { holder, take_over } => robot.request_control(holder, take_over).map(Some),
+        ControlOperation::Renew { token } => robot.renew_control(token).map(Some),
+        ControlOperation::Release { token } => robot.release_control(token).map(|_| None),
+    }
+}
+
+/// Controls playback of the recording being replayed. Only operators may change what every client sees.
+pub async fn replay_authorized(robot_lock: &RobotLock, identity: &Identity, control: ReplayControl) -> Result<ReplayStatus, CommandError> {
+    require_role(identity, Role::Operator, "control the replay")?;
+    robot_lock.write().await.control_replay(control)
+}
+
+/// Saves or restores the robot's state on behalf of `identity`. Any operator may save.
+/// Loading moves the robot straight to the saved state and may clear a fault, so it needs an admin and, while a lease is held, its token as `lease`.
+pub async fn snapshot_authorized(robot_lock: &RobotLock, identity: &Identity, lease: Option<&str>, operation: SnapshotOperation) -> Result<SavedState, CommandError> {
+    match operation {
+        SnapshotOperation::Save => {
+            require_role(identity, Role::Operator, "save the state")?;
+            persistence::save(robot_lock).await
+        }
+        SnapshotOperation::Load => {
+            require_role(identity, Role::Admin, "load the saved state")?;
+            persistence::load(robot_lock, lease).await
+        }
+    }
+}
+
+/// Saves or deletes a pose on behalf of `identity`. Only operators may change the pose library.
+pub async fn pose_authorized(robot_lock: &RobotLock, identity: &Identity, operation: PoseOperation) -> Result<Pose, CommandError> {
+    require_role(identity, Role::Operator, "change the pose library")?;
+    let mut robot = robot_lock.write().await;
+    match operation {
+        PoseOperation::Save(request) => robot.save_pose(&request),
+        PoseOperation::Delete(request) => robot.delete_pose(&request.name),
+    }
+}
+
+/// Uploads, deletes, runs or controls a script on behalf of `identity`. Only operators may change or run motion programs.
+/// A script's commands are sent as `identity` with `lease`, the control lease token the client holds when it starts the script, and audited with `remote_addr`.
+pub fn script_authorized(scripts: &Scripts, identity: &Identity, remote_addr: Option<SocketAddr>, lease: Option<&str>, operation: ScriptOperation) -> Result<Option<ScriptStatus>, CommandError> {
+    require_role(identity, Role::Operator, "change or run scripts")?;
+    match operation {
+        ScriptOperation::Upload(script) => scripts.upload(&script).map(|_| None),
+        ScriptOperation::Delete(script) => scripts.delete(&script.name).map(|_| None),
+        ScriptOperation::Run(script) => scripts.run(&script.name, identity, remote_addr, lease).map(Some),
+        ScriptOperation::Control(control) => scripts.control(control).map(Some),
+        ScriptOperation::PickAndPlace(task) => scripts.pick_and_place(task, identity, remote_addr, lease).map(Some),
+    }
+}
+
+/// Middleware rejecting requests without a valid token with 401. Handlers can extract the caller's `Identity` as an `Extension`.
+pub async fn require_identity<B>(State(auth): State<SharedAuth>, mut request: Request<B>, next: Next<B>) -> Response {
+    match auth.authenticate_request(request.headers(), request.uri()) {
+        Ok(identity) => {
+            request.extensions_mut().insert(identity);
+            next.run(request).await
+        }
+        Err(error) => (StatusCode::UNAUTHORIZED, Json(error)).into_response(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn auth() -> Auth {
+        Auth::new(AuthConfig {
+            tokens: vec![
+                TokenConfig { token: "v1".to_string(), role: Role::Viewer, name: None },
+                TokenConfig { token: "o/1+2".to_string(), role: Role::Operator, name: Some("cell 1 hmi".to_string()) },
+                TokenConfig { token: "a1".to_string(), role: Role::Admin, name: None },
+            ],
+        })
+    }
+
+    fn headers(authorization: Option<&str>) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        if let Some(authorization) = authorization {
+            headers.insert(header::AUTHORIZATION, authorization.parse().unwrap());
+        }
+        headers
+    }
+
+    fn identity(name: &str, role: Role) -> Result<(String, Role), AuthError> {
+        Ok((name.to_string(), role))
+    }
+
+    fn request(auth: &Auth, authorization: Option<&str>, uri: &str) -> Result<(String, Role), AuthError> {
+        auth.authenticate_request(&headers(authorization), &uri.parse().unwrap()).map(|identity| (identity.name, identity.role))
+    }
+
+    #[test]
+    fn roles_are_ordered_by_what_they_allow() {
+        assert!(Role::Viewer < Role::Operator && Role::Operator < Role::Admin);
+
+        let viewer = Identity { name: "viewer".to_string(), role: Role::Viewer };
+        let operator = Identity { name: "operator".to_string(), role: Role::Operator };
+        let admin = Identity { name: "admin".to_string(), role: Role::Admin };
+        assert!(matches!(authorize(&viewer, &Command::EmergencyStop), Err(CommandError::Forbidden { required: Role::Operator })));
+        assert!(authorize(&operator, &Command::EmergencyStop).is_ok());
+        assert!(matches!(authorize(&operator, &Command::ResetFault), Err(CommandError::Forbidden { required: Role::Admin })));
+        assert!(authorize(&admin, &Command::ResetFault).is_ok());
+        assert!(require_role(&admin, Role::Viewer, "watch").is_ok());
+    }
+
+    #[test]
+    fn tokens_map_to_their_identity() {
+        let auth = auth();
+        let lookup = |token| auth.authenticate(token).map(|identity| (identity.name, identity.role));
+
+        assert_eq!(lookup(Some("v1")), identity("viewer", Role::Viewer));
+        assert_eq!(lookup(Some("o/1+2")), identity("cell 1 hmi", Role::Operator));
+        assert_eq!(lookup(Some("a1")), identity("admin", Role::Admin));
+        assert_eq!(lookup(Some("A1")), Err(AuthError::InvalidToken));
+        assert_eq!(lookup(Some("")), Err(AuthError::InvalidToken));
+        assert_eq!(lookup(None), Err(AuthError::MissingToken));
+    }
+
+    #[test]
+    fn the_header_takes_precedence_over_the_query() {
+        let auth = auth();
+
+        assert_eq!(request(&auth, Some("Bearer a1"), "/ws"), identity("admin", Role::Admin));
+        assert_eq!(request(&auth, None, "/ws?token=v1"), identity("viewer", Role::Viewer));
+        assert_eq!(request(&auth, Some("Bearer a1"), "/ws?token=v1"), identity("admin", Role::Admin));
+        assert_eq!(request(&auth, Some("Bearer nope"), "/ws?token=v1"), Err(AuthError::InvalidToken));
+        // Only bearer tokens are read from the header.
+        assert_eq!(request(&auth, Some("Basic a1"), "/ws?token=v1"), identity("viewer", Role::Viewer));
+        assert_eq!(request(&auth, None, "/ws?other=1"), Err(AuthError::MissingToken));
+    }
+
+    #[test]
+    fn query_tokens_are_percent_decoded() {
+        let auth = auth();
+
+        assert_eq!(request(&auth, None, "/ws?token=o%2F1%2B2"), identity("cell 1 hmi", Role::Operator));
+        assert_eq!(request(&auth, None, "/ws?subscribe=links&token=o%2F1%2B2&x=%20"), identity("cell 1 hmi", Role::Operator));
+        // An unencoded `+` is a space in a query.
+        assert_eq!(request(&auth, None, "/ws?token=o/1+2"), Err(AuthError::InvalidToken));
+    }
+
+    #[test]
+    fn without_tokens_everyone_is_an_anonymous_admin() {
+        let auth = Auth::default();
+
+        assert_eq!(request(&auth, None, "/ws"), identity("anonymous", Role::Admin));
+        assert_eq!(request(&auth, Some("Bearer anything"), "/ws?token=x"), identity("anonymous", Role::Admin));
+        // A configuration without any tokens still requires one.
+        assert_eq!(Auth::new(AuthConfig::default()).authenticate(None).map(|identity| identity.role), Err(AuthError::MissingToken));
+    }
+}
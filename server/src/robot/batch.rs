@@ -0,0 +1,582 @@
+use super::behavior_tree::BehaviorTree;
+use super::command::{Command, CommandError, CommandOutcome};
+use super::command_language::{self, Instruction};
+use super::history::HistoryTable;
+use super::motion::{Interrupt, Motion};
+use super::persistence::{StateFile, DEFAULT_STATE_FILE};
+use super::poses::PoseLibrary;
+use super::robot_config::RobotConfig;
+use super::robot_state::{Coord4DOF, JointState, RobotState};
+use super::telemetry::RobotSnapshot;
+use super::Robot;
+
+use std::cell::RefCell;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// Command line option running a program headless in simulated time instead of serving, e.g. `--batch pick.rcl`.
+pub const BATCH_ARG: &str = "--batch";
+/// Batch option naming the JSON robot config, `ROBOT_CONFIG` or the defaults when not given.
+pub const CONFIG_ARG: &str = "--config";
+/// Batch option naming a saved state to start from, as written by `save snapshot`. The origin when not given.
+pub const INITIAL_STATE_ARG: &str = "--initial";
+/// Batch option naming the `.csv` or `.json` file the trajectory is written to.
+pub const OUTPUT_ARG: &str = "--output";
+/// Batch option setting the simulation step (ms).
+pub const STEP_ARG: &str = "--step-ms";
+/// Batch option setting how long (simulated sec) the program may run before it is stopped.
+pub const MAX_TIME_ARG: &str = "--max-time-s";
+
+/// Simulation step (ms) when `--step-ms` is not given, the same as the live controller.
+const DEFAULT_STEP_MS: f64 = super::CONTROLLER_LOOP_TIME_MS as f64;
+/// Simulated seconds a program may run when `--max-time-s` is not given.
+const DEFAULT_MAX_TIME_S: f64 = 600.0;
+/// Difference (mm or deg) between a commanded joint target and the one applied that counts as hitting a limit.
+const LIMIT_TOLERANCE: f64 = 1e-6;
+
+/// What a batch run is asked to do.
+#[derive(Clone, Debug)]
+pub struct BatchOptions {
+    /// A `.rcl` command file, or a `.json` array of commands run as waypoints.
+    pub program: PathBuf,
+    pub config: Option<PathBuf>,
+    pub initial_state: Option<PathBuf>,
+    pub output: Option<PathBuf>,
+    pub step: Duration,
+    pub max_time: Duration,
+}
+
+impl BatchOptions {
+    /// Reads the options following `--batch` on the command line.
+    pub fn from_args(args: &[String]) -> Result<BatchOptions, String> {
+        let value = |name: &str| args.iter().position(|arg| arg == name).map(|index| args.get(index + 1).ok_or(format!("{name} needs a value")));
+        let seconds = |name: &str, default: f64, scale: f64| -> Result<Duration, String> {
+            let value = match value(name) {
+                Some(value) => value?.parse::<f64>().map_err(|err| format!("{name}: {err}"))?,
+                None => default,
+            };
+            Duration::try_from_secs_f64(value * scale).ok().filter(|duration| !duration.is_zero()).ok_or(format!("{name} must be a positive number, got {value}"))
+        };
+
+        Ok(BatchOptions {
+            program: PathBuf::from(value(BATCH_ARG).ok_or(format!("{BATCH_ARG} needs a program"))??),
+            config: value(CONFIG_ARG).transpose()?.map(PathBuf::from),
+            initial_state: value(INITIAL_STATE_ARG).transpose()?.map(PathBuf::from),
+            output: value(OUTPUT_ARG).transpose()?.map(PathBuf::from),
+            step: seconds(STEP_ARG, DEFAULT_STEP_MS, 0.001)?,
+            max_time: seconds(MAX_TIME_ARG, DEFAULT_MAX_TIME_S, 1.0)?,
+        })
+    }
+}
+
+/// A program for a batch run.
+enum BatchProgram {
+    Rcl(Vec<Instruction>),
+    /// Each command is executed once the robot has settled from the one before.
+    Waypoints(Vec<Command>),
+    BehaviorTree(BehaviorTree),
+}
+
+impl BatchProgram {
+    fn load(path: &Path) -> Result<BatchProgram, String> {
+        let source = std::fs::read_to_string(path).map_err(|err| format!("{}: {}", path.display(), err))?;
+        match path.extension().and_then(|extension| extension.to_str()) {
+            Some("rcl") => command_language::parse(&source)
+                .map(BatchProgram::Rcl)
+                .map_err(|errors| errors.iter().map(|error| format!("{}: {}", path.display(), error)).collect::<Vec<_>>().join("\n")),
+            Some("json") => serde_json::from_str(&source).map(BatchProgram::Waypoints).map_err(|err| format!("{}: {}", path.display(), err)),
+            Some("yaml" | "yml") => BehaviorTree::parse(&source).map(BatchProgram::BehaviorTree).map_err(|err| format!("{}: {}", path.display(), err)),
+            _ => Err(format!("{} is not a .rcl command file, a .json list of commands or a .yaml behavior tree", path.display())),
+        }
+    }
+}
+
+/// The robot at one simulation step.
+#[derive(serde::Serialize, Clone, Debug)]
+pub struct TrajectorySample {
+    /// Simulated time since the start (sec).
+    pub time_s: f64,
+    pub state: RobotState,
+    pub velocity: RobotState,
+    /// The end effector pose.
+    pub coords: Coord4DOF,
+    pub target_state: RobotState,
+    pub tracking_error: RobotState,
+}
+
+/// Something worth reporting that happened during a batch run.
+#[derive(serde::Serialize, Clone, Debug)]
+pub struct BatchEvent {
+    pub time_s: f64,
+    /// The program line, or waypoint number, running at the time.
+    pub line: Option<usize>,
+    pub message: String,
+}
+
+impl fmt::Display for BatchEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.line {
+            Some(line) => write!(f, "{:.3} s, line {}: {}", self.time_s, line, self.message),
+            None => write!(f, "{:.3} s: {}", self.time_s, self.message),
+        }
+    }
+}
+
+/// The outcome of a batch run.
+#[derive(serde::Serialize, Clone, Debug)]
+pub struct BatchSummary {
+    pub program: PathBuf,
+    /// Why the program stopped before its end.
+    pub error: Option<String>,
+    /// Simulated time (sec) until the program stopped.
+    pub cycle_time_s: f64,
+    pub steps: u64,
+    /// The largest tracking error of each joint and the base over the run, as magnitudes.
+    pub max_tracking_error: RobotState,
+    /// Joint targets that were clamped to a joint limit, and commands rejected for being out of range.
+    pub limit_hits: Vec<BatchEvent>,
+    /// Coordinate targets ik could not reach, when commanded or while being tracked.
+    pub ik_failures: Vec<BatchEvent>,
+}
+
+impl fmt::Display for BatchSummary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let joints = self.max_tracking_error.joint_state;
+        let base = self.max_tracking_error.base_state;
+        writeln!(f, "program: {}", self.program.display())?;
+        match &self.error {
+            Some(error) => writeln!(f, "result: failed, {error}")?,
+            None => writeln!(f, "result: completed")?,
+        }
+        writeln!(f, "cycle time: {:.3} s ({} steps)", self.cycle_time_s, self.steps)?;
+        writeln!(
+            f,
+            "max tracking error: swing {:.3} deg, lift {:.3} mm, elbow {:.3} deg, wrist {:.3} deg, gripper {:.3} mm, base {:.4} m / {:.3} deg",
+            joints.swing_rotation_deg,
+            joints.lift_elevation_mm,
+            joints.elbow_rotation_deg,
+            joints.wrist_rotation_deg,
+            joints.gripper_open_mm,
+            base.x.max(base.y).max(base.z),
+            base.theta
+        )?;
+        for (name, events) in [("limit hits", &self.limit_hits), ("ik failures", &self.ik_failures)] {
+            writeln!(f, "{name}: {}", events.len())?;
+            for event in events {
+                writeln!(f, "  {event}")?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Written to a `.json` output.
+#[derive(serde::Serialize)]
+struct BatchReport<'a> {
+    summary: &'a BatchSummary,
+    samples: &'a [TrajectorySample],
+}
+
+/// Runs the batch described on the command line, printing the summary. Fails if the program did not complete.
+pub fn run_from_args() -> Result<(), Box<dyn std::error::Error>> {
+    let args: Vec<String> = std::env::args().collect();
+    let options = BatchOptions::from_args(&args)?;
+    let (summary, samples) = run(&options)?;
+    print!("{summary}");
+
+    if let Some(output) = &options.output {
+        write_output(output, &summary, &samples)?;
+        println!("trajectory: {} samples written to {}", samples.len(), output.display());
+    }
+
+    match summary.error {
+        Some(error) => Err(error.into()),
+        None => Ok(()),
+    }
+}
+
+/// Writes the trajectory to `output` as a `.csv` table, or as `.json` with the summary.
+fn write_output(output: &Path, summary: &BatchSummary, samples: &[TrajectorySample]) -> Result<(), Box<dyn std::error::Error>> {
+    let contents = match output.extension().and_then(|extension| extension.to_str()) {
+        Some("csv") => HistoryTable::from_samples(samples, "time_s").to_csv().into_bytes(),
+        Some("json") => serde_json::to_vec(&BatchReport { summary, samples })?,
+        _ => return Err(format!("{} is not a .csv or .json file", output.display()).into()),
+    };
+    std::fs::write(output, contents).map_err(|err| format!("{}: {}", output.display(), err))?;
+    Ok(())
+}
+
+/// Simulates the program as fast as possible in steps of `options.step`, returning the summary and a sample per step.
+pub fn run(options: &BatchOptions) -> Result<(BatchSummary, Vec<TrajectorySample>), Box<dyn std::error::Error>> {
+    let config = match &options.config {
+        Some(path) => RobotConfig::read(path)?,
+        None => RobotConfig::load()?,
+    };
+    simulate(options, config, PoseLibrary::load()?)
+}
+
+/// Runs the program on a robot with `config` and the saved `poses`.
+fn simulate(options: &BatchOptions, config: RobotConfig, poses: PoseLibrary) -> Result<(BatchSummary, Vec<TrajectorySample>), Box<dyn std::error::Error>> {
+    let program = BatchProgram::load(&options.program)?;
+    // Nothing is saved during a batch run.
+    let state_file = StateFile { path: PathBuf::from(DEFAULT_STATE_FILE), autosave_period: None };
+    let mut robot = Robot::simulation(config, poses, state_file, None, None);
+    if let Some(path) = &options.initial_state {
+        let saved = StateFile { path: path.clone(), autosave_period: None }.read()?;
+        robot.restore(&saved, None)?;
+    }
+
+    let run = BatchRun { robot: RefCell::new(robot), step: options.step, max_time: options.max_time, record: RefCell::new(BatchRecord::default()) };
+    let result = match program {
+        BatchProgram::Rcl(instructions) => command_language::execute(&run, &instructions),
+        BatchProgram::Waypoints(commands) => commands.iter().enumerate().try_for_each(|(index, command)| {
+            run.at_line(index + 1);
+            run.execute(command.clone()).and_then(|_| run.wait_settled(None)).map(|_| ()).map_err(|interrupt| match interrupt {
+                Interrupt::Failed(message) => Interrupt::Failed(format!("waypoint {}: {}", index + 1, message)),
+                Interrupt::Aborted => Interrupt::Aborted,
+            })
+        }),
+        BatchProgram::BehaviorTree(mut tree) => tree.run(&run, |_| {}),
+    };
+
+    let record = run.record.into_inner();
+    let summary = BatchSummary {
+        program: options.program.clone(),
+        error: result.err().map(|interrupt| match interrupt {
+            Interrupt::Failed(message) => message,
+            Interrupt::Aborted => "aborted".to_string(),
+        }),
+        cycle_time_s: record.time.as_secs_f64(),
+        steps: record.samples.len() as u64,
+        max_tracking_error: record.max_tracking_error,
+        limit_hits: record.limit_hits,
+        ik_failures: record.ik_failures,
+    };
+    Ok((summary, record.samples))
+}
+
+/// What a batch run has recorded so far.
+#[derive(Default)]
+struct BatchRecord {
+    time: Duration,
+    line: Option<usize>,
+    samples: Vec<TrajectorySample>,
+    max_tracking_error: RobotState,
+    limit_hits: Vec<BatchEvent>,
+    ik_failures: Vec<BatchEvent>,
+    /// Set while ik cannot reach the coordinate target being tracked, so each failure is reported once.
+    ik_failing: bool,
+}
+
+impl BatchRecord {
+    fn event(&self, message: String) -> BatchEvent {
+        BatchEvent { time_s: self.time.as_secs_f64(), line: self.line, message }
+    }
+}
+
+/// A robot simulated without the server. Time only passes when the program waits.
+struct BatchRun {
+    robot: RefCell<Robot>,
+    step: Duration,
+    max_time: Duration,
+    record: RefCell<BatchRecord>,
+}
+
+impl BatchRun {
+    /// Advances the simulation by one step and records the result.
+    fn tick(&self) -> Result<(), Interrupt> {
+        let mut record = self.record.borrow_mut();
+        if record.time >= self.max_time {
+            return Err(Interrupt::Failed(format!("still running after {} s of simulated time", self.max_time.as_secs_f64())));
+        }
+
+        let mut robot = self.robot.borrow_mut();
+        if !robot.is_faulted() {
+            robot.step(self.step.as_secs_f64());
+        }
+        record.time += self.step;
+
+        let tracking_ik = robot.target_coord_state.is_some() && robot.ik_feedforward.is_none();
+        if tracking_ik && !record.ik_failing {
+            let event = record.event(format!("ik cannot reach {:?} while tracking it", robot.target_coord_state.unwrap_or_default()));
+            record.ik_failures.push(event);
+        }
+        record.ik_failing = tracking_ik;
+
+        let snapshot = robot.snapshot();
+        record.max_tracking_error = max_magnitude(record.max_tracking_error, snapshot.tracking_error);
+        let time_s = record.time.as_secs_f64();
+        record.samples.push(TrajectorySample {
+            time_s,
+            state: snapshot.state,
+            velocity: snapshot.velocity,
+            coords: snapshot.coords,
+            target_state: snapshot.target_state,
+            tracking_error: snapshot.tracking_error,
+        });
+        Ok(())
+    }
+}
+
+impl Motion for BatchRun {
+    fn execute(&self, command: Command) -> Result<CommandOutcome, Interrupt> {
+        let name = command.name();
+        let result = self.robot.borrow_mut().execute(command.clone(), None);
+        let mut record = self.record.borrow_mut();
+        match result {
+            Ok(outcome) => {
+                let limited = requested_joints(&command, &outcome).map(|requested| limited_joints(requested, outcome.target_state.joint_state)).unwrap_or_default();
+                if !limited.is_empty() {
+                    let event = record.event(format!("{name} clamped {} to the joint limits", limited.join(", ")));
+                    record.limit_hits.push(event);
+                }
+                Ok(outcome)
+            }
+            Err(error) => {
+                match error {
+                    CommandError::Unreachable => {
+                        let event = record.event(format!("{name} target is out of reach"));
+                        record.ik_failures.push(event);
+                    }
+                    CommandError::InvalidValue { .. } => {
+                        let event = record.event(format!("{name}: {error}"));
+                        record.limit_hits.push(event);
+                    }
+                    _ => {}
+                }
+                Err(Interrupt::Failed(format!("{name} was rejected: {error}")))
+            }
+        }
+    }
+
+    fn snapshot(&self) -> RobotSnapshot {
+        self.robot.borrow().snapshot()
+    }
+
+    fn sleep(&self, duration: Duration) -> Result<(), Interrupt> {
+        let steps = (duration.as_secs_f64() / self.step.as_secs_f64()).ceil() as u64;
+        for _ in 0..steps {
+            self.tick()?;
+        }
+        Ok(())
+    }
+
+    fn elapsed(&self) -> Duration {
+        self.record.borrow().time
+    }
+
+    fn print(&self, line: String) {
+        println!("{line}");
+    }
+
+    fn at_line(&self, line: usize) {
+        self.record.borrow_mut().line = Some(line);
+    }
+}
+
+/// The joint target a command asked for, before it was limited.
+fn requested_joints(command: &Command, outcome: &CommandOutcome) -> Option<JointState> {
+    match command {
+        Command::SetJointState(joint_state) => Some(*joint_state),
+        Command::SetGripper(gripper_open_mm) => Some(JointState { gripper_open_mm: *gripper_open_mm, ..outcome.target_state.joint_state }),
+        _ => outcome.ik_solution,
+    }
+}
+
+/// The joints whose applied target differs from the one requested.
+fn limited_joints(requested: JointState, applied: JointState) -> Vec<&'static str> {
+    let difference = JointState::clamped_sub(requested, applied);
+    [
+        ("swing_rotation_deg", difference.swing_rotation_deg),
+        ("lift_elevation_mm", difference.lift_elevation_mm),
+        ("elbow_rotation_deg", difference.elbow_rotation_deg),
+        ("wrist_rotation_deg", difference.wrist_rotation_deg),
+        ("gripper_open_mm", difference.gripper_open_mm),
+    ]
+    .into_iter()
+    .filter(|(_, difference)| difference.abs() > LIMIT_TOLERANCE)
+    .map(|(field, _)| field)
+    .collect()
+}
+
+/// The larger magnitude of each field.
+fn max_magnitude(max: RobotState, error: RobotState) -> RobotState {
+    let (joints, base) = (error.joint_state, error.base_state);
+    RobotState {
+        joint_state: JointState {
+            swing_rotation_deg: max.joint_state.swing_rotation_deg.max(joints.swing_rotation_deg.abs()),
+            lift_elevation_mm: max.joint_state.lift_elevation_mm.max(joints.lift_elevation_mm.abs()),
+            elbow_rotation_deg: max.joint_state.elbow_rotation_deg.max(joints.elbow_rotation_deg.abs()),
+            wrist_rotation_deg: max.joint_state.wrist_rotation_deg.max(joints.wrist_rotation_deg.abs()),
+            gripper_open_mm: max.joint_state.gripper_open_mm.max(joints.gripper_open_mm.abs()),
+        },
+        base_state: Coord4DOF { x: max.base_state.x.max(base.x.abs()), y: max.base_state.y.max(base.y.abs()), z: max.base_state.z.max(base.z.abs()), theta: max.base_state.theta.max(base.theta.abs()) },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::robot::constants::LIFT_HEIGHT_MM;
+
+    fn args(args: &[&str]) -> Vec<String> {
+        std::iter::once("server").chain(args.iter().copied()).map(str::to_string).collect()
+    }
+
+    /// Writes `source` to `file` in `dir` and runs it in steps of `step_ms`.
+    fn run_program(dir: &Path, file: &str, source: &str, step_ms: u64) -> (BatchSummary, Vec<TrajectorySample>) {
+        let program = dir.join(file);
+        std::fs::write(&program, source).unwrap();
+        let options = BatchOptions { program, config: None, initial_state: None, output: None, step: Duration::from_millis(step_ms), max_time: Duration::from_secs(60) };
+        simulate(&options, RobotConfig::default(), PoseLibrary::new(dir.join("poses.json"))).unwrap()
+    }
+
+    #[test]
+    fn options_are_read_from_the_arguments() {
+        let options = BatchOptions::from_args(&args(&["--batch", "pick.rcl", "--output", "out.csv", "--step-ms", "5", "--max-time-s", "2.5"])).unwrap();
+        assert_eq!(options.program, PathBuf::from("pick.rcl"));
+        assert_eq!(options.output, Some(PathBuf::from("out.csv")));
+        assert_eq!((options.config, options.initial_state), (None, None));
+        assert_eq!(options.step, Duration::from_millis(5));
+        assert_eq!(options.max_time, Duration::from_millis(2500));
+
+        let defaults = BatchOptions::from_args(&args(&["--batch", "pick.rcl"])).unwrap();
+        assert_eq!(defaults.step, Duration::from_secs_f64(DEFAULT_STEP_MS / 1000.0));
+        assert_eq!(defaults.max_time, Duration::from_secs_f64(DEFAULT_MAX_TIME_S));
+    }
+
+    #[test]
+    fn invalid_options_are_rejected() {
+        let cases: &[(&[&str], &str)] = &[
+            (&[], "--batch needs a program"),
+            (&["--batch"], "--batch needs a value"),
+            (&["--batch", "pick.rcl", "--output"], "--output needs a value"),
+            (&["--batch", "pick.rcl", "--config"], "--config needs a value"),
+            (&["--batch", "pick.rcl", "--step-ms", "0"], "--step-ms must be a positive number, got 0"),
+            (&["--batch", "pick.rcl", "--step-ms", "-5"], "--step-ms must be a positive number, got -5"),
+            (&["--batch", "pick.rcl", "--step-ms", "fast"], "--step-ms: invalid float literal"),
+            (&["--batch", "pick.rcl", "--max-time-s", "-1"], "--max-time-s must be a positive number, got -1"),
+            (&["--batch", "pick.rcl", "--max-time-s", "inf"], "--max-time-s must be a positive number, got inf"),
+        ];
+        for (arguments, expected) in cases {
+            assert_eq!(BatchOptions::from_args(&args(arguments)).unwrap_err(), *expected, "{arguments:?}");
+        }
+    }
+
+    #[test]
+    fn the_cycle_time_is_the_simulated_time() {
+        let dir = tempfile::tempdir().unwrap();
+        let (summary, samples) = run_program(dir.path(), "wait.rcl", "WAIT 0.5", 20);
+
+        assert_eq!(summary.error, None);
+        assert_eq!(summary.steps, 25);
+        assert_eq!(samples.len(), 25);
+        assert!((summary.cycle_time_s - 0.5).abs() < 1e-9);
+        assert!((samples.last().unwrap().time_s - 0.5).abs() < 1e-9);
+    }
+
+    #[test]
+    fn a_program_runs_the_same_every_time() {
+        let dir = tempfile::tempdir().unwrap();
+        let source = "MOVEJ swing=30 lift=500\nGRIP 40\nWAIT 0.2";
+        let (first, samples) = run_program(dir.path(), "pick.rcl", source, 10);
+        let (second, _) = run_program(dir.path(), "pick.rcl", source, 10);
+
+        assert_eq!(first.error, None);
+        assert_eq!(first.steps, second.steps);
+        assert!(first.cycle_time_s > 0.2);
+        let last = samples.last().unwrap().state.joint_state;
+        assert!((last.lift_elevation_mm - 500.0).abs() < 1.0 && (last.gripper_open_mm - 40.0).abs() < 1.0, "{last:?}");
+    }
+
+    #[test]
+    fn clamped_targets_are_limit_hits() {
+        let dir = tempfile::tempdir().unwrap();
+        let source = format!("WAIT 0.1\nMOVEJ lift={}", LIFT_HEIGHT_MM + 500.0);
+        let (summary, samples) = run_program(dir.path(), "high.rcl", &source, 20);
+
+        assert_eq!(summary.error, None);
+        assert_eq!(summary.limit_hits.len(), 1);
+        let hit = &summary.limit_hits[0];
+        assert_eq!(hit.line, Some(2));
+        assert!((hit.time_s - 0.1).abs() < 1e-9);
+        assert_eq!(hit.message, "set_joint_state clamped lift_elevation_mm to the joint limits");
+        assert_eq!(samples.last().unwrap().target_state.joint_state.lift_elevation_mm, LIFT_HEIGHT_MM);
+        assert!(summary.ik_failures.is_empty());
+    }
+
+    #[test]
+    fn unreachable_points_are_ik_failures() {
+        let dir = tempfile::tempdir().unwrap();
+        let (summary, _) = run_program(dir.path(), "far.rcl", "MOVEJ lift=100\nMOVEP x=9 z=0", 20);
+
+        assert_eq!(summary.error.as_deref(), Some("line 2: set_coord_state was rejected: target is out of reach"));
+        assert_eq!(summary.ik_failures.len(), 1);
+        assert_eq!(summary.ik_failures[0].line, Some(2));
+        assert_eq!(summary.ik_failures[0].message, "set_coord_state target is out of reach");
+        assert!(summary.limit_hits.is_empty());
+    }
+
+    #[test]
+    fn json_programs_run_as_waypoints() {
+        let dir = tempfile::tempdir().unwrap();
+        let source = r#"[
+            {"command": "set_joint_state", "data": {"swing_rotation_deg": 10, "lift_elevation_mm": 100, "elbow_rotation_deg": 0, "wrist_rotation_deg": 0, "gripper_open_mm": 50}},
+            {"command": "set_gripper", "data": 1e9},
+            {"command": "set_coord_state", "data": {"x": 9, "y": 0, "z": 0, "theta": 0}}
+        ]"#;
+        let (summary, samples) = run_program(dir.path(), "waypoints.json", source, 20);
+
+        // The gripper is out of range, so the program stops on the second waypoint.
+        let error = summary.error.unwrap();
+        assert!(error.starts_with("waypoint 2: set_gripper was rejected"), "{error}");
+        assert_eq!(summary.limit_hits.len(), 1);
+        assert_eq!(summary.limit_hits[0].line, Some(2));
+        assert!(summary.ik_failures.is_empty());
+        assert!(summary.cycle_time_s > 0.0);
+        assert!((samples.last().unwrap().state.joint_state.lift_elevation_mm - 100.0).abs() < 1.0);
+    }
+
+    #[test]
+    fn a_program_still_running_at_the_time_limit_fails() {
+        let dir = tempfile::tempdir().unwrap();
+        let program = dir.path().join("long.rcl");
+        std::fs::write(&program, "WAIT 5").unwrap();
+        let options = BatchOptions { program, config: None, initial_state: None, output: None, step: Duration::from_millis(20), max_time: Duration::from_secs(1) };
+        let (summary, samples) = simulate(&options, RobotConfig::default(), PoseLibrary::new(dir.path().join("poses.json"))).unwrap();
+
+        assert_eq!(summary.error.as_deref(), Some("line 1: still running after 1 s of simulated time"));
+        assert_eq!(samples.len(), 50);
+    }
+
+    #[test]
+    fn trajectories_are_written_as_csv_or_json() {
+        let dir = tempfile::tempdir().unwrap();
+        let (summary, samples) = run_program(dir.path(), "wait.rcl", "MOVEJ lift=100", 20);
+
+        let csv_path = dir.path().join("trajectory.csv");
+        write_output(&csv_path, &summary, &samples).unwrap();
+        let csv = std::fs::read_to_string(&csv_path).unwrap();
+        let mut lines = csv.lines();
+        let header: Vec<&str> = lines.next().unwrap().split(',').collect();
+        assert_eq!(header[0], "time_s");
+        for column in ["state.joint_state.lift_elevation_mm", "velocity.joint_state.lift_elevation_mm", "coords.x", "target_state.base_state.theta", "tracking_error.joint_state.swing_rotation_deg"] {
+            assert!(header.contains(&column), "{column} is missing from {header:?}");
+        }
+        let rows: Vec<&str> = lines.collect();
+        assert_eq!(rows.len(), samples.len());
+        assert!(rows.iter().all(|row| row.split(',').count() == header.len()));
+
+        let json_path = dir.path().join("trajectory.json");
+        write_output(&json_path, &summary, &samples).unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&std::fs::read(&json_path).unwrap()).unwrap();
+        assert_eq!(json["summary"]["steps"], summary.steps);
+        assert_eq!(json["summary"]["error"], serde_json::Value::Null);
+        assert!(json["summary"]["limit_hits"].is_array() && json["summary"]["ik_failures"].is_array());
+        assert_eq!(json["samples"].as_array().unwrap().len(), samples.len());
+        assert_eq!(json["samples"][0]["state"]["joint_state"]["lift_elevation_mm"], samples[0].state.joint_state.lift_elevation_mm);
+
+        assert!(write_output(&dir.path().join("trajectory.txt"), &summary, &samples).is_err());
+    }
+}
@@ -0,0 +1,667 @@
+use super::command::Command;
+use super::motion::{distance, interpolate, overlay, Fields, Interrupt, Motion, MIN_LINEAR_SPEED};
+use super::robot_state::Coord4DOF;
+
+use std::time::Duration;
+
+/// Time between ticks of a tree.
+pub const TICK_PERIOD: Duration = Duration::from_millis(50);
+/// End effector speed (m/sec) of `move_linear` actions when the node does not give one.
+const DEFAULT_LINEAR_SPEED: f64 = 0.1;
+
+/// A node of a behavior tree as it is written in JSON or YAML, e.g.
+/// `{"type": "retry", "attempts": 3, "child": {"type": "action", "action": "move_to_pose", "pose": "home"}}`.
+#[derive(serde::Deserialize, Clone, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum NodeDefinition {
+    /// Ticks its children in order. Fails as soon as one fails and succeeds once they all have.
+    Sequence {
+        #[serde(default)]
+        name: Option<String>,
+        children: Vec<NodeDefinition>,
+    },
+    /// Ticks its children in order. Succeeds as soon as one succeeds and fails once they all have.
+    Fallback {
+        #[serde(default)]
+        name: Option<String>,
+        children: Vec<NodeDefinition>,
+    },
+    /// Ticks all its children every tick. Succeeds once `success_threshold` of them have (all when not given),
+    /// and fails once too many have failed for that to happen. Children still running are then halted.
+    Parallel {
+        #[serde(default)]
+        name: Option<String>,
+        children: Vec<NodeDefinition>,
+        #[serde(default)]
+        success_threshold: Option<usize>,
+    },
+    /// Runs its child again when it fails, up to `attempts` runs in all.
+    Retry {
+        #[serde(default)]
+        name: Option<String>,
+        attempts: u32,
+        child: Box<NodeDefinition>,
+    },
+    /// Succeeds if the condition holds and fails otherwise.
+    Condition {
+        #[serde(default)]
+        name: Option<String>,
+        #[serde(flatten)]
+        condition: Condition,
+    },
+    /// Runs until the action is done.
+    Action {
+        #[serde(default)]
+        name: Option<String>,
+        #[serde(flatten)]
+        action: Action,
+    },
+}
+
+/// What a condition node checks, read from the simulated robot as a sensor in the cell would.
+#[derive(serde::Deserialize, Clone, Debug, PartialEq)]
+#[serde(tag = "condition", rename_all = "snake_case")]
+pub enum Condition {
+    /// The robot has reached its targets.
+    Settled,
+    /// The robot is faulted.
+    Faulted,
+    /// The gripper opening (mm) is within the bounds given, e.g. narrower than open when holding a part.
+    Gripper {
+        #[serde(default)]
+        min_mm: Option<f64>,
+        #[serde(default)]
+        max_mm: Option<f64>,
+    },
+    /// The end effector is within `radius_m` of a point, as a proximity sensor there would see it.
+    Near { x: f64, y: f64, z: f64, radius_m: f64 },
+}
+
+/// What an action node does. Moves change only the fields given and succeed once the robot settles.
+#[derive(serde::Deserialize, Clone, Debug, PartialEq)]
+#[serde(tag = "action", rename_all = "snake_case")]
+pub enum Action {
+    MoveJoints { joints: Fields },
+    MoveCoords { coords: Fields },
+    /// Moves the end effector along a straight line at `speed` (m/sec).
+    MoveLinear {
+        coords: Fields,
+        #[serde(default)]
+        speed: Option<f64>,
+    },
+    MoveBase { base: Fields },
+    Grip { gripper_open_mm: f64 },
+    MoveToPose { pose: String },
+    Wait { seconds: f64 },
+    Print { text: String },
+}
+
+/// What a node is, as reported with its status.
+#[derive(serde::Serialize, Copy, Clone, Debug, PartialEq, schemars::JsonSchema, ts_rs::TS)]
+#[serde(rename_all = "snake_case")]
+pub enum NodeKind {
+    Sequence,
+    Fallback,
+    Parallel,
+    Retry,
+    Condition,
+    Action,
+}
+
+/// Where a node is. Finished nodes keep their status until they are ticked again.
+#[derive(serde::Serialize, Copy, Clone, Debug, PartialEq, schemars::JsonSchema, ts_rs::TS)]
+#[serde(rename_all = "snake_case")]
+pub enum NodeStatus {
+    /// Not ticked since the run started or its parent started over.
+    Idle,
+    Running,
+    Success,
+    Failure,
+}
+
+/// The status of a node of the tree running.
+#[derive(serde::Serialize, Clone, Debug, PartialEq, schemars::JsonSchema, ts_rs::TS)]
+pub struct TreeNodeStatus {
+    /// Position of the node in the tree, counted depth first from 0 at the root.
+    pub id: usize,
+    /// Not set for the root.
+    pub parent: Option<usize>,
+    pub name: String,
+    pub kind: NodeKind,
+    pub status: NodeStatus,
+}
+
+/// A behavior tree ready to run.
+#[derive(Debug)]
+pub struct BehaviorTree {
+    root: Node,
+}
+
+#[derive(Debug)]
+struct Node {
+    name: String,
+    status: NodeStatus,
+    behavior: Behavior,
+}
+
+#[derive(Debug)]
+enum Behavior {
+    Sequence(Vec<Node>),
+    Fallback(Vec<Node>),
+    Parallel { children: Vec<Node>, success_threshold: usize },
+    Retry { child: Box<Node>, attempts: u32, failures: u32 },
+    Condition(Condition),
+    Action { action: Action, progress: Option<Progress> },
+}
+
+/// How far an action that was started has got.
+#[derive(Debug)]
+enum Progress {
+    /// Waiting for the robot to settle.
+    Moving,
+    /// Streaming waypoints along a line, `duration` (sec) long from `started`.
+    Linear { start: Coord4DOF, end: Coord4DOF, started: Duration, duration: f64 },
+    Waiting { until: Duration },
+}
+
+impl BehaviorTree {
+    /// Parses a tree from YAML, or JSON as YAML accepts it too, and checks every node.
+    pub fn parse(source: &str) -> Result<BehaviorTree, String> {
+        let definition: NodeDefinition = serde_yaml::from_str(source).map_err(|err| err.to_string())?;
+        Ok(BehaviorTree { root: Node::new(definition)? })
+    }
+
+    /// Ticks the tree every `TICK_PERIOD` until the root succeeds or fails, calling `report` whenever a node's status changes.
+    /// Fails with the leaf that failed last when the root fails.
+    pub fn run(&mut self, motion: &impl Motion, report: impl Fn(Vec<TreeNodeStatus>)) -> Result<(), Interrupt> {
+        let mut reported = Vec::new();
+        let mut failure = None;
+        loop {
+            let status = self.root.tick(motion, &mut failure)?;
+            let statuses = self.statuses();
+            if statuses != reported {
+                report(statuses.clone());
+                reported = statuses;
+            }
+            match status {
+                NodeStatus::Success => return Ok(()),
+                NodeStatus::Failure => {
+                    let reason = failure.unwrap_or_else(|| "no leaf failed".to_string());
+                    return Err(Interrupt::Failed(format!("behavior tree '{}' failed: {}", self.root.name, reason)));
+                }
+                NodeStatus::Idle | NodeStatus::Running => motion.sleep(TICK_PERIOD)?,
+            }
+        }
+    }
+
+    /// Every node's status, depth first.
+    pub fn statuses(&self) -> Vec<TreeNodeStatus> {
+        let mut statuses = Vec::new();
+        self.root.collect(None, &mut statuses);
+        statuses
+    }
+}
+
+impl Node {
+    fn new(definition: NodeDefinition) -> Result<Node, String> {
+        let (name, behavior) = match definition {
+            NodeDefinition::Sequence { name, children } => (name.unwrap_or_else(|| "sequence".to_string()), Behavior::Sequence(Node::children(children)?)),
+            NodeDefinition::Fallback { name, children } => (name.unwrap_or_else(|| "fallback".to_string()), Behavior::Fallback(Node::children(children)?)),
+            NodeDefinition::Parallel { name, children, success_threshold } => {
+                let children = Node::children(children)?;
+                let success_threshold = success_threshold.unwrap_or(children.len());
+                if success_threshold == 0 || success_threshold > children.len() {
+                    return Err(format!("success_threshold must be between 1 and the {} children, got {}", children.len(), success_threshold));
+                }
+                (name.unwrap_or_else(|| "parallel".to_string()), Behavior::Parallel { children, success_threshold })
+            }
+            NodeDefinition::Retry { name, attempts, child } => {
+                if attempts == 0 {
+                    return Err("retry needs at least 1 attempt".to_string());
+                }
+                (name.unwrap_or_else(|| "retry".to_string()), Behavior::Retry { child: Box::new(Node::new(*child)?), attempts, failures: 0 })
+            }
+            NodeDefinition::Condition { name, condition } => {
+                condition.validate()?;
+                (name.unwrap_or_else(|| condition.name().to_string()), Behavior::Condition(condition))
+            }
+            NodeDefinition::Action { name, action } => {
+                action.validate()?;
+                (name.unwrap_or_else(|| action.name().to_string()), Behavior::Action { action, progress: None })
+            }
+        };
+        Ok(Node { name, status: NodeStatus::Idle, behavior })
+    }
+
+    fn children(definitions: Vec<NodeDefinition>) -> Result<Vec<Node>, String> {
+        if definitions.is_empty() {
+            return Err("sequence, fallback and parallel nodes need at least one child".to_string());
+        }
+        definitions.into_iter().map(Node::new).collect()
+    }
+
+    fn kind(&self) -> NodeKind {
+        match self.behavior {
+            Behavior::Sequence(_) => NodeKind::Sequence,
+            Behavior::Fallback(_) => NodeKind::Fallback,
+            Behavior::Parallel { .. } => NodeKind::Parallel,
+            Behavior::Retry { .. } => NodeKind::Retry,
+            Behavior::Condition(_) => NodeKind::Condition,
+            Behavior::Action { .. } => NodeKind::Action,
+        }
+    }
+
+    /// Ticks the node, starting it over if it had finished. `failure` is set to why a leaf failed.
+    fn tick(&mut self, motion: &impl Motion, failure: &mut Option<String>) -> Result<NodeStatus, Interrupt> {
+        if self.status != NodeStatus::Running {
+            self.reset();
+        }
+        let status = match &mut self.behavior {
+            Behavior::Sequence(children) => tick_in_order(children, motion, failure, NodeStatus::Failure)?,
+            Behavior::Fallback(children) => tick_in_order(children, motion, failure, NodeStatus::Success)?,
+            Behavior::Parallel { children, success_threshold } => {
+                let (mut successes, mut failures) = (0, 0);
+                for child in children.iter_mut() {
+                    let status = match child.status {
+                        NodeStatus::Success | NodeStatus::Failure => child.status,
+                        NodeStatus::Idle | NodeStatus::Running => child.tick(motion, failure)?,
+                    };
+                    match status {
+                        NodeStatus::Success => successes += 1,
+                        NodeStatus::Failure => failures += 1,
+                        NodeStatus::Idle | NodeStatus::Running => {}
+                    }
+                }
+                let status = if successes >= *success_threshold {
+                    NodeStatus::Success
+                } else if failures > children.len() - *success_threshold {
+                    NodeStatus::Failure
+                } else {
+                    NodeStatus::Running
+                };
+                if status != NodeStatus::Running {
+                    children.iter_mut().filter(|child| child.status == NodeStatus::Running).for_each(Node::reset);
+                }
+                status
+            }
+            Behavior::Retry { child, attempts, failures } => match child.tick(motion, failure)? {
+                NodeStatus::Failure => {
+                    *failures += 1;
+                    if *failures < *attempts {
+                        motion.print(format!("retrying '{}' ({}/{})", child.name, *failures + 1, attempts));
+                        NodeStatus::Running
+                    } else {
+                        NodeStatus::Failure
+                    }
+                }
+                status => status,
+            },
+            Behavior::Condition(condition) => {
+                if condition.holds(motion) {
+                    NodeStatus::Success
+                } else {
+                    *failure = Some(format!("condition '{}' does not hold", self.name));
+                    NodeStatus::Failure
+                }
+            }
+            Behavior::Action { action, progress } => match action.tick(motion, progress)? {
+                Ok(status) => status,
+                Err(message) => {
+                    motion.print(format!("action '{}' failed: {}", self.name, message));
+                    *failure = Some(format!("action '{}' failed: {}", self.name, message));
+                    NodeStatus::Failure
+                }
+            },
+        };
+        self.status = status;
+        Ok(status)
+    }
+
+    /// Sets the node and everything under it back to idle. Motion already commanded carries on to its target.
+    fn reset(&mut self) {
+        self.status = NodeStatus::Idle;
+        match &mut self.behavior {
+            Behavior::Sequence(children) | Behavior::Fallback(children) | Behavior::Parallel { children, .. } => children.iter_mut().for_each(Node::reset),
+            Behavior::Retry { child, failures, .. } => {
+                *failures = 0;
+                child.reset();
+            }
+            Behavior::Condition(_) => {}
+            Behavior::Action { progress, .. } => *progress = None,
+        }
+    }
+
+    fn collect(&self, parent: Option<usize>, statuses: &mut Vec<TreeNodeStatus>) {
+        let id = statuses.len();
+        statuses.push(TreeNodeStatus { id, parent, name: self.name.clone(), kind: self.kind(), status: self.status });
+        match &self.behavior {
+            Behavior::Sequence(children) | Behavior::Fallback(children) | Behavior::Parallel { children, .. } => children.iter().for_each(|child| child.collect(Some(id), statuses)),
+            Behavior::Retry { child, .. } => child.collect(Some(id), statuses),
+            Behavior::Condition(_) | Behavior::Action { .. } => {}
+        }
+    }
+}
+
+/// Ticks children one after the other, skipping those already done, until one ends with `stop` or is still running.
+fn tick_in_order(children: &mut [Node], motion: &impl Motion, failure: &mut Option<String>, stop: NodeStatus) -> Result<NodeStatus, Interrupt> {
+    let done = match stop {
+        NodeStatus::Failure => NodeStatus::Success,
+        _ => NodeStatus::Failure,
+    };
+    for child in children.iter_mut().filter(|child| child.status != done) {
+        match child.tick(motion, failure)? {
+            NodeStatus::Running => return Ok(NodeStatus::Running),
+            status if status == stop => return Ok(stop),
+            _ => {}
+        }
+    }
+    Ok(done)
+}
+
+impl Condition {
+    fn name(&self) -> &'static str {
+        match self {
+            Condition::Settled => "settled",
+            Condition::Faulted => "faulted",
+            Condition::Gripper { .. } => "gripper",
+            Condition::Near { .. } => "near",
+        }
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        match self {
+            Condition::Settled | Condition::Faulted => Ok(()),
+            Condition::Gripper { min_mm, max_mm } => match (min_mm, max_mm) {
+                (None, None) => Err("gripper conditions need min_mm, max_mm or both".to_string()),
+                (Some(min_mm), Some(max_mm)) if min_mm > max_mm => Err(format!("min_mm {min_mm} is above max_mm {max_mm}")),
+                _ => finite(&[("min_mm", min_mm.unwrap_or_default()), ("max_mm", max_mm.unwrap_or_default())]),
+            },
+            Condition::Near { x, y, z, radius_m } => {
+                finite(&[("x", *x), ("y", *y), ("z", *z), ("radius_m", *radius_m)])?;
+                if *radius_m <= 0.0 {
+                    return Err(format!("radius_m must be positive, got {radius_m}"));
+                }
+                Ok(())
+            }
+        }
+    }
+
+    fn holds(&self, motion: &impl Motion) -> bool {
+        let snapshot = motion.snapshot();
+        match self {
+            Condition::Settled => snapshot.settled,
+            Condition::Faulted => snapshot.fault.is_some(),
+            Condition::Gripper { min_mm, max_mm } => {
+                let gripper_open_mm = snapshot.state.joint_state.gripper_open_mm;
+                min_mm.is_none_or(|min_mm| gripper_open_mm >= min_mm) && max_mm.is_none_or(|max_mm| gripper_open_mm <= max_mm)
+            }
+            Condition::Near { x, y, z, radius_m } => distance(&snapshot.coords, &Coord4DOF { x: *x, y: *y, z: *z, theta: 0.0 }) <= *radius_m,
+        }
+    }
+}
+
+impl Action {
+    fn name(&self) -> &'static str {
+        match self {
+            Action::MoveJoints { .. } => "move_joints",
+            Action::MoveCoords { .. } => "move_coords",
+            Action::MoveLinear { .. } => "move_linear",
+            Action::MoveBase { .. } => "move_base",
+            Action::Grip { .. } => "grip",
+            Action::MoveToPose { .. } => "move_to_pose",
+            Action::Wait { .. } => "wait",
+            Action::Print { .. } => "print",
+        }
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        match self {
+            Action::MoveLinear { speed: Some(speed), .. } if !speed.is_finite() || *speed < MIN_LINEAR_SPEED => Err(format!("speed must be at least {MIN_LINEAR_SPEED} m/sec, got {speed}")),
+            Action::Wait { seconds } => Duration::try_from_secs_f64(*seconds).map(|_| ()).map_err(|_| format!("cannot wait {seconds} sec")),
+            Action::Grip { gripper_open_mm } => finite(&[("gripper_open_mm", *gripper_open_mm)]),
+            _ => Ok(()),
+        }
+    }
+
+    /// Starts the action on its first tick and checks on it after. Fails with why the action could not be done.
+    fn tick(&self, motion: &impl Motion, progress: &mut Option<Progress>) -> Result<Result<NodeStatus, String>, Interrupt> {
+        let started = match progress.take() {
+            Some(started) => started,
+            None => match self.start(motion) {
+                // The robot has not moved towards the new target yet, so it cannot have settled on it.
+                Ok(Some(Progress::Moving)) => {
+                    *progress = Some(Progress::Moving);
+                    return Ok(Ok(NodeStatus::Running));
+                }
+                Ok(Some(started)) => started,
+                Ok(None) => return Ok(Ok(NodeStatus::Success)),
+                Err(Interrupt::Failed(message)) => return Ok(Err(message)),
+                Err(Interrupt::Aborted) => return Err(Interrupt::Aborted),
+            },
+        };
+        let snapshot = motion.snapshot();
+        if let Some(fault) = snapshot.fault {
+            return Ok(Err(format!("robot is faulted: {fault}")));
+        }
+        let next = match started {
+            Progress::Moving if snapshot.settled => return Ok(Ok(NodeStatus::Success)),
+            Progress::Waiting { until } if motion.elapsed() >= until => return Ok(Ok(NodeStatus::Success)),
+            Progress::Linear { start, end, started, duration } => {
+                let fraction = if duration > 0.0 { ((motion.elapsed() - started).as_secs_f64() / duration).min(1.0) } else { 1.0 };
+                if let Err(interrupt) = motion.execute(Command::SetCoordState(interpolate(&start, &end, fraction))) {
+                    return match interrupt {
+                        Interrupt::Failed(message) => Ok(Err(message)),
+                        Interrupt::Aborted => Err(Interrupt::Aborted),
+                    };
+                }
+                if fraction < 1.0 {
+                    Progress::Linear { start, end, started, duration }
+                } else {
+                    Progress::Moving
+                }
+            }
+            waiting => waiting,
+        };
+        *progress = Some(next);
+        Ok(Ok(NodeStatus::Running))
+    }
+
+    /// Sends the action's first command. Returns how to follow it up, or nothing if it is already done.
+    fn start(&self, motion: &impl Motion) -> Result<Option<Progress>, Interrupt> {
+        match self {
+            Action::MoveJoints { joints } => motion.move_joints(joints.clone())?,
+            Action::MoveCoords { coords } => motion.move_coords(coords.clone())?,
+            Action::MoveBase { base } => motion.move_base(base.clone())?,
+            Action::Grip { gripper_open_mm } => motion.open_gripper(*gripper_open_mm)?,
+            Action::MoveToPose { pose } => motion.move_to_pose(pose)?,
+            Action::MoveLinear { coords, speed } => {
+                let start = motion.snapshot().coords;
+                let end: Coord4DOF = overlay(&start, coords.clone())?;
+                Command::SetCoordState(end).validate().map_err(|error| Interrupt::Failed(error.to_string()))?;
+                let duration = distance(&start, &end) / speed.unwrap_or(DEFAULT_LINEAR_SPEED);
+                return Ok(Some(Progress::Linear { start, end, started: motion.elapsed(), duration }));
+            }
+            Action::Wait { seconds } => return Ok(Some(Progress::Waiting { until: motion.elapsed() + Duration::from_secs_f64(*seconds) })),
+            Action::Print { text } => {
+                motion.print(text.clone());
+                return Ok(None);
+            }
+        }
+        Ok(Some(Progress::Moving))
+    }
+}
+
+fn finite(values: &[(&str, f64)]) -> Result<(), String> {
+    match values.iter().find(|(_, value)| !value.is_finite()) {
+        Some((field, value)) => Err(format!("{field} must be a finite number, got {value}")),
+        None => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::robot::motion::mock::MockMotion;
+
+    /// Runs the tree to the end, returning the failure message if it failed.
+    fn run(tree: &mut BehaviorTree, motion: &MockMotion) -> Result<(), String> {
+        tree.run(motion, |_| {}).map_err(|interrupt| match interrupt {
+            Interrupt::Failed(message) => message,
+            Interrupt::Aborted => "aborted".to_string(),
+        })
+    }
+
+    fn statuses(tree: &BehaviorTree) -> Vec<(String, NodeStatus)> {
+        tree.statuses().into_iter().map(|node| (node.name, node.status)).collect()
+    }
+
+    #[test]
+    fn sequence_runs_children_in_order() {
+        let mut tree = BehaviorTree::parse(
+            "type: sequence
+children:
+  - {type: action, action: print, text: first}
+  - {type: action, action: grip, gripper_open_mm: 40}
+  - {type: condition, condition: gripper, min_mm: 30, max_mm: 50}
+  - {type: action, action: print, text: last}",
+        )
+        .unwrap();
+        let motion = MockMotion::new();
+
+        assert_eq!(run(&mut tree, &motion), Ok(()));
+        assert_eq!(*motion.output.borrow(), vec!["first", "last"]);
+        assert!(statuses(&tree).iter().all(|(_, status)| *status == NodeStatus::Success));
+    }
+
+    #[test]
+    fn sequence_stops_at_the_first_failure() {
+        let mut tree = BehaviorTree::parse(
+            "type: sequence
+name: pick
+children:
+  - {type: action, action: print, text: first}
+  - {type: condition, name: holding, condition: gripper, min_mm: 30}
+  - {type: action, action: print, text: never}",
+        )
+        .unwrap();
+        let motion = MockMotion::new();
+
+        assert_eq!(run(&mut tree, &motion), Err("behavior tree 'pick' failed: condition 'holding' does not hold".to_string()));
+        assert_eq!(*motion.output.borrow(), vec!["first"]);
+        assert_eq!(statuses(&tree)[3], ("print".to_string(), NodeStatus::Idle));
+    }
+
+    #[test]
+    fn fallback_stops_at_the_first_success() {
+        let mut tree = BehaviorTree::parse(
+            "type: fallback
+children:
+  - {type: condition, condition: faulted}
+  - {type: action, action: print, text: recovered}
+  - {type: action, action: print, text: never}",
+        )
+        .unwrap();
+        let motion = MockMotion::new();
+
+        assert_eq!(run(&mut tree, &motion), Ok(()));
+        assert_eq!(*motion.output.borrow(), vec!["recovered"]);
+        assert_eq!(statuses(&tree)[1], ("faulted".to_string(), NodeStatus::Failure));
+    }
+
+    #[test]
+    fn fallback_fails_once_every_child_has() {
+        let mut tree = BehaviorTree::parse(
+            "type: fallback
+children:
+  - {type: condition, condition: faulted}
+  - {type: condition, condition: near, x: 5, y: 5, z: 5, radius_m: 0.1}",
+        )
+        .unwrap();
+
+        assert_eq!(run(&mut tree, &MockMotion::new()), Err("behavior tree 'fallback' failed: condition 'near' does not hold".to_string()));
+    }
+
+    #[test]
+    fn parallel_succeeds_at_the_threshold() {
+        let mut tree = BehaviorTree::parse(
+            "type: parallel
+success_threshold: 1
+children:
+  - {type: action, action: wait, seconds: 1}
+  - {type: condition, condition: faulted}",
+        )
+        .unwrap();
+        let motion = MockMotion::new();
+
+        assert_eq!(run(&mut tree, &motion), Ok(()));
+        assert!(motion.elapsed() >= Duration::from_secs(1));
+        assert_eq!(statuses(&tree)[1..], [("wait".to_string(), NodeStatus::Success), ("faulted".to_string(), NodeStatus::Failure)]);
+    }
+
+    #[test]
+    fn parallel_fails_and_halts_children_once_the_threshold_is_out_of_reach() {
+        let mut tree = BehaviorTree::parse(
+            "type: parallel
+children:
+  - {type: action, action: wait, seconds: 1}
+  - {type: condition, condition: faulted}",
+        )
+        .unwrap();
+        let motion = MockMotion::new();
+
+        assert!(run(&mut tree, &motion).is_err());
+        assert_eq!(motion.elapsed(), Duration::ZERO);
+        assert_eq!(statuses(&tree)[1], ("wait".to_string(), NodeStatus::Idle));
+    }
+
+    #[test]
+    fn retry_runs_the_child_again_until_it_succeeds() {
+        let mut tree = BehaviorTree::parse("{type: retry, attempts: 3, child: {type: action, action: grip, gripper_open_mm: 40}}").unwrap();
+        let motion = MockMotion::new();
+        motion.rejections.set(2);
+
+        assert_eq!(run(&mut tree, &motion), Ok(()));
+        assert_eq!(motion.snapshot().state.joint_state.gripper_open_mm, 40.0);
+        let output = motion.output.borrow();
+        assert!(output.contains(&"retrying 'grip' (2/3)".to_string()));
+        assert!(output.contains(&"retrying 'grip' (3/3)".to_string()));
+    }
+
+    #[test]
+    fn retry_fails_after_its_attempts() {
+        let mut tree = BehaviorTree::parse("{type: retry, attempts: 2, child: {type: action, action: grip, gripper_open_mm: 40}}").unwrap();
+        let motion = MockMotion::new();
+        motion.rejections.set(2);
+
+        assert_eq!(run(&mut tree, &motion), Err("behavior tree 'retry' failed: action 'grip' failed: set_gripper was rejected".to_string()));
+    }
+
+    #[test]
+    fn reports_statuses_depth_first() {
+        let tree = BehaviorTree::parse(
+            "type: sequence
+children:
+  - {type: retry, attempts: 2, child: {type: action, action: wait, seconds: 1}}
+  - {type: action, action: print, text: done}",
+        )
+        .unwrap();
+        let nodes: Vec<(usize, Option<usize>, NodeKind)> = tree.statuses().into_iter().map(|node| (node.id, node.parent, node.kind)).collect();
+        assert_eq!(nodes, vec![(0, None, NodeKind::Sequence), (1, Some(0), NodeKind::Retry), (2, Some(1), NodeKind::Action), (3, Some(0), NodeKind::Action)]);
+    }
+
+    #[test]
+    fn rejects_invalid_trees() {
+        for (source, error) in [
+            ("{type: sequence, children: []}", "need at least one child"),
+            ("{type: retry, attempts: 0, child: {type: condition, condition: settled}}", "at least 1 attempt"),
+            ("{type: parallel, success_threshold: 3, children: [{type: condition, condition: settled}]}", "success_threshold must be between 1 and the 1 children"),
+            ("{type: condition, condition: gripper}", "need min_mm, max_mm or both"),
+            ("{type: action, action: move_linear, coords: {z: 1}, speed: 0}", "speed must be at least"),
+            ("{type: action, action: wait, seconds: -1}", "cannot wait -1 sec"),
+            ("{type: jump}", "unknown variant"),
+        ] {
+            let message = BehaviorTree::parse(source).unwrap_err();
+            assert!(message.contains(error), "{source}: {message}");
+        }
+    }
+}
@@ -0,0 +1,304 @@
+use super::auth::Role;
+use super::constants::*;
+use super::robot_state::{Coord4DOF, JointState, RobotState};
+
+/// A command sent by a client to change what the robot is doing.
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug, schemars::JsonSchema, ts_rs::TS)]
+#[serde(tag = "command", content = "data", rename_all = "snake_case")]
+pub enum Command {
+    /// Move the joints to the given state.
+    SetJointState(JointState),
+    /// Move the end effector to the given coordinate using ik.
+    SetCoordState(Coord4DOF),
+    /// Move the base to the given coordinate.
+    SetBaseState(Coord4DOF),
+    /// Open the gripper to the given width (mm) without changing the other targets.
+    SetGripper(f64),
+    /// Stop all motion and fault the robot until the fault is reset.
+    EmergencyStop,
+    /// Clear an active fault so the robot accepts commands again.
+    ResetFault,
+    /// Move to the pose saved under the given name.
+    MoveToPose(String),
+}
+
+impl Command {
+    /// The name used to tag the command in JSON messages.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Command::SetJointState(_) => "set_joint_state",
+            Command::SetCoordState(_) => "set_coord_state",
+            Command::SetBaseState(_) => "set_base_state",
+            Command::SetGripper(_) => "set_gripper",
+            Command::EmergencyStop => "emergency_stop",
+            Command::ResetFault => "reset_fault",
+            Command::MoveToPose(_) => "move_to_pose",
+        }
+    }
+
+    /// The role needed to send the command.
+    pub fn required_role(&self) -> Role {
+        match self {
+            Command::ResetFault => Role::Admin,
+            _ => Role::Operator,
+        }
+    }
+
+    /// Returns true if the command moves the robot.
+    pub fn is_motion(&self) -> bool {
+        !matches!(self, Command::EmergencyStop | Command::ResetFault)
+    }
+
+    /// Ensures every value in the command is finite and within a sane range.
+    pub fn validate(&self) -> Result<(), CommandError> {
+        match self {
+            Command::SetJointState(joint_state) => {
+                check_value("swing_rotation_deg", joint_state.swing_rotation_deg, MAX_COMMAND_ANGLE_DEG)?;
+                check_value("lift_elevation_mm", joint_state.lift_elevation_mm, MAX_COMMAND_DISTANCE_M*1000.0)?;
+                check_value("elbow_rotation_deg", joint_state.elbow_rotation_deg, MAX_COMMAND_ANGLE_DEG)?;
+                check_value("wrist_rotation_deg", joint_state.wrist_rotation_deg, MAX_COMMAND_ANGLE_DEG)?;
+                check_value("gripper_open_mm", joint_state.gripper_open_mm, MAX_COMMAND_DISTANCE_M*1000.0)
+            }
+            Command::SetCoordState(coord) | Command::SetBaseState(coord) => {
+                check_value("x", coord.x, MAX_COMMAND_DISTANCE_M)?;
+                check_value("y", coord.y, MAX_COMMAND_DISTANCE_M)?;
+                check_value("z", coord.z, MAX_COMMAND_DISTANCE_M)?;
+                check_value("theta", coord.theta, MAX_COMMAND_ANGLE_DEG)
+            }
+            Command::SetGripper(gripper_open_mm) => check_value("gripper_open_mm", *gripper_open_mm, MAX_COMMAND_DISTANCE_M*1000.0),
+            // Saved poses were validated when they were saved.
+            Command::EmergencyStop | Command::ResetFault | Command::MoveToPose(_) => Ok(()),
+        }
+    }
+}
+
+/// Rejects `value` if it is not finite or its magnitude is larger than `max`.
+fn check_value(field: &'static str, value: f64, max: f64) -> Result<(), CommandError> {
+    if !value.is_finite() {
+        return Err(CommandError::InvalidValue { field, message: format!("{field} must be a finite number") });
+    }
+    if value.abs() > max {
+        return Err(CommandError::InvalidValue { field, message: format!("{field} must be within ±{max}, got {value}") });
+    }
+    Ok(())
+}
+
+/// The reason a command was rejected.
+#[derive(serde::Serialize, Clone, Debug, schemars::JsonSchema, ts_rs::TS)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum CommandError {
+    /// The payload could not be deserialized into the expected type.
+    Malformed { message: String },
+    /// A value in the payload is not finite or is out of range.
+    InvalidValue { field: &'static str, message: String },
+    /// The robot is faulted and only accepts a fault reset.
+    Faulted { reason: String },
+    /// No ik solution reaches the requested end effector coordinate.
+    Unreachable,
+    /// The client's role is not allowed to send the command.
+    Forbidden { required: Role },
+    /// Another client holds the control lease.
+    ControlHeld { holder: String },
+    /// The presented lease token is not the current lease, it expired, was released or was taken over.
+    LeaseNotHeld,
+    /// The server is replaying a recording and does not accept commands.
+    Replaying,
+    /// Replay controls were sent while no recording is being replayed.
+    NotReplaying,
+    /// The saved state, pose library or a script could not be written or read back.
+    Storage { message: String },
+    /// No pose is saved under the name.
+    UnknownPose { name: String },
+    /// No script is saved under the name.
+    UnknownScript { name: String },
+    /// Another script is already running, only one runs at a time.
+    ScriptRunning { name: String },
+    /// Script controls were sent while no script is running.
+    NoScriptRunning,
+}
+
+impl std::fmt::Display for CommandError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CommandError::Malformed { message } => write!(f, "malformed command: {message}"),
+            CommandError::InvalidValue { message, .. } => write!(f, "invalid value: {message}"),
+            CommandError::Faulted { reason } => write!(f, "robot is faulted: {reason}"),
+            CommandError::Unreachable => write!(f, "target is out of reach"),
+            CommandError::Forbidden { required } => write!(f, "permission denied, requires the {required:?} role"),
+            CommandError::ControlHeld { holder } => write!(f, "{holder} has control of the robot"),
+            CommandError::LeaseNotHeld => write!(f, "the control lease is not held"),
+            CommandError::Replaying => write!(f, "a recording is being replayed"),
+            CommandError::NotReplaying => write!(f, "no recording is being replayed"),
+            CommandError::Storage { message } => write!(f, "could not access {message}"),
+            CommandError::UnknownPose { name } => write!(f, "no pose is saved as '{name}'"),
+            CommandError::UnknownScript { name } => write!(f, "no script is saved as '{name}'"),
+            CommandError::ScriptRunning { name } => write!(f, "script '{name}' is already running"),
+            CommandError::NoScriptRunning => write!(f, "no script is running"),
+        }
+    }
+}
+
+impl std::error::Error for CommandError {}
+
+/// The error event sent back to the client that issued a rejected command.
+#[derive(serde::Serialize, Clone, Debug, schemars::JsonSchema, ts_rs::TS)]
+pub struct CommandErrorEvent {
+    /// The event name of the rejected command.
+    pub command: String,
+    #[serde(flatten)]
+    pub error: CommandError,
+}
+
+/// The result of an accepted command.
+#[derive(serde::Serialize, Copy, Clone, Debug, schemars::JsonSchema, ts_rs::TS)]
+pub struct CommandOutcome {
+    /// The joint and base target after limits were applied.
+    pub target_state: RobotState,
+    /// The end effector coordinate being tracked with ik, if any.
+    pub target_coord_state: Option<Coord4DOF>,
+    /// The joint solution ik found for a coordinate command.
+    pub ik_solution: Option<JointState>,
+}
+
+/// Acknowledges a command, reporting whether it was accepted and what the robot will do.
+#[derive(serde::Serialize, Clone, Debug, schemars::JsonSchema, ts_rs::TS)]
+pub struct CommandAck {
+    /// The id supplied with the request, echoed back so the client can match the ack.
+    pub request_id: Option<serde_json::Value>,
+    /// The name of the command.
+    pub command: String,
+    pub accepted: bool,
+    /// Set when the command was accepted.
+    pub outcome: Option<CommandOutcome>,
+    /// Set when the command was rejected.
+    pub error: Option<CommandError>,
+}
+
+impl CommandAck {
+    pub fn new(command: &str, request_id: Option<serde_json::Value>, result: Result<CommandOutcome, CommandError>) -> CommandAck {
+        let (outcome, error) = match result {
+            Ok(outcome) => (Some(outcome), None),
+            Err(error) => (None, Some(error)),
+        };
+
+        CommandAck { request_id, command: command.to_string(), accepted: outcome.is_some(), outcome, error }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::robot::robot_config::RobotConfig;
+    use crate::robot::{Robot, CONTROLLER_LOOP_TIME_S};
+
+    fn joints(field: &str, value: f64) -> Command {
+        let mut joint_state = JointState::default();
+        match field {
+            "swing_rotation_deg" => joint_state.swing_rotation_deg = value,
+            "lift_elevation_mm" => joint_state.lift_elevation_mm = value,
+            "elbow_rotation_deg" => joint_state.elbow_rotation_deg = value,
+            "wrist_rotation_deg" => joint_state.wrist_rotation_deg = value,
+            "gripper_open_mm" => joint_state.gripper_open_mm = value,
+            _ => unreachable!(),
+        }
+        Command::SetJointState(joint_state)
+    }
+
+    fn coords(field: &str, value: f64) -> Coord4DOF {
+        let mut coord = Coord4DOF::default();
+        match field {
+            "x" => coord.x = value,
+            "y" => coord.y = value,
+            "z" => coord.z = value,
+            "theta" => coord.theta = value,
+            _ => unreachable!(),
+        }
+        coord
+    }
+
+    /// Each command is paired with the field it should be rejected for, or `None` if it is valid.
+    fn cases() -> Vec<(Command, Option<&'static str>)> {
+        let max_mm = MAX_COMMAND_DISTANCE_M * 1000.0;
+        let mut cases = vec![
+            (Command::SetGripper(40.0), None),
+            (Command::SetGripper(f64::NAN), Some("gripper_open_mm")),
+            (Command::SetGripper(-max_mm - 1.0), Some("gripper_open_mm")),
+            (Command::EmergencyStop, None),
+            (Command::ResetFault, None),
+            (Command::MoveToPose("home".to_string()), None),
+        ];
+        for (field, max) in [
+            ("swing_rotation_deg", MAX_COMMAND_ANGLE_DEG),
+            ("lift_elevation_mm", max_mm),
+            ("elbow_rotation_deg", MAX_COMMAND_ANGLE_DEG),
+            ("wrist_rotation_deg", MAX_COMMAND_ANGLE_DEG),
+            ("gripper_open_mm", max_mm),
+        ] {
+            cases.push((joints(field, max), None));
+            cases.push((joints(field, -max), None));
+            cases.push((joints(field, f64::NAN), Some(field)));
+            cases.push((joints(field, f64::INFINITY), Some(field)));
+            cases.push((joints(field, f64::NEG_INFINITY), Some(field)));
+            cases.push((joints(field, max * 1.01), Some(field)));
+            cases.push((joints(field, -max * 1.01), Some(field)));
+        }
+        for (field, max) in [("x", MAX_COMMAND_DISTANCE_M), ("y", MAX_COMMAND_DISTANCE_M), ("z", MAX_COMMAND_DISTANCE_M), ("theta", MAX_COMMAND_ANGLE_DEG)] {
+            for command in [Command::SetCoordState, Command::SetBaseState] {
+                cases.push((command(coords(field, max)), None));
+                cases.push((command(coords(field, f64::NAN)), Some(field)));
+                cases.push((command(coords(field, f64::INFINITY)), Some(field)));
+                cases.push((command(coords(field, -max * 1.01)), Some(field)));
+            }
+        }
+        cases
+    }
+
+    #[test]
+    fn validate_rejects_non_finite_and_out_of_range_values() {
+        for (command, invalid_field) in cases() {
+            match (command.validate(), invalid_field) {
+                (Ok(()), None) => {}
+                (Err(CommandError::InvalidValue { field, .. }), Some(expected)) => assert_eq!(field, expected, "{command:?}"),
+                (result, expected) => panic!("{command:?} gave {result:?}, expected {expected:?} to be rejected"),
+            }
+        }
+    }
+
+    #[test]
+    fn invalid_commands_do_not_change_the_targets() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut robot = Robot::test(RobotConfig::default(), dir.path());
+        let before = robot.get_target_state();
+
+        assert!(matches!(robot.execute(joints("lift_elevation_mm", f64::NAN), None), Err(CommandError::InvalidValue { .. })));
+        assert!(matches!(robot.execute(Command::SetBaseState(coords("x", f64::INFINITY)), None), Err(CommandError::InvalidValue { .. })));
+        assert_eq!(robot.get_target_state(), before);
+        assert_eq!(robot.get_target_coord_state(), None);
+    }
+
+    #[test]
+    fn a_non_finite_state_faults_until_reset() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut robot = Robot::test(RobotConfig::default(), dir.path());
+        assert!(robot.execute(joints("lift_elevation_mm", 500.0), None).is_ok());
+        robot.step(CONTROLLER_LOOP_TIME_S);
+        let last_valid = robot.get_state();
+
+        // Poison the simulation as a diverging controller would.
+        robot.velocity.joint_state.lift_elevation_mm = f64::NAN;
+        robot.step(CONTROLLER_LOOP_TIME_S);
+
+        assert!(robot.is_faulted());
+        assert_eq!(robot.get_state(), last_valid);
+        assert_eq!(robot.get_target_state(), last_valid);
+        assert!(matches!(robot.execute(joints("lift_elevation_mm", 100.0), None), Err(CommandError::Faulted { .. })));
+        assert!(matches!(robot.execute(Command::SetGripper(10.0), None), Err(CommandError::Faulted { .. })));
+        assert!(robot.execute(Command::EmergencyStop, None).is_ok());
+
+        assert!(robot.execute(Command::ResetFault, None).is_ok());
+        assert_eq!(robot.get_fault(), None);
+        assert!(robot.execute(joints("lift_elevation_mm", 100.0), None).is_ok());
+        robot.step(CONTROLLER_LOOP_TIME_S);
+        assert!(robot.get_state().joint_state.is_finite());
+    }
+}
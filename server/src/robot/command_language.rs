@@ -0,0 +1,276 @@
+use super::motion::{Fields, Interrupt, Motion, MIN_LINEAR_SPEED};
+
+use std::fmt;
+use std::time::Duration;
+
+/// Feed rate (mm/sec) of `MOVEL` when the line does not give `F`.
+pub const DEFAULT_FEED_MM_S: f64 = 100.0;
+/// Slowest feed rate (mm/sec) `MOVEL` accepts, the slowest linear move.
+pub const MIN_FEED_MM_S: f64 = MIN_LINEAR_SPEED * 1000.0;
+
+/// The commands of the language.
+const KEYWORDS: &[&str] = &["MOVEJ", "MOVEL", "MOVEP", "BASE", "GRIP", "WAIT", "POSE", "PRINT"];
+
+/// `MOVEJ` parameters and the joint they move.
+const JOINT_PARAMETERS: &[(&str, &str)] = &[
+    ("swing", "swing_rotation_deg"),
+    ("lift", "lift_elevation_mm"),
+    ("elbow", "elbow_rotation_deg"),
+    ("wrist", "wrist_rotation_deg"),
+    ("grip", "gripper_open_mm"),
+];
+/// `MOVEP` and `BASE` parameters and the coordinate they set.
+const COORD_PARAMETERS: &[(&str, &str)] = &[("x", "x"), ("y", "y"), ("z", "z"), ("th", "theta")];
+/// `MOVEL` parameters: the coordinates and the feed rate.
+const LINEAR_PARAMETERS: &[(&str, &str)] = &[("x", "x"), ("y", "y"), ("z", "z"), ("th", "theta"), ("f", "feed")];
+
+/// What one line of a program does.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Operation {
+    /// `MOVEJ swing=30 elbow=-45` moves the joints given, leaving the others at their targets.
+    MoveJoints(Fields),
+    /// `MOVEP x=1.2 z=0.8` moves the end effector to a point using ik.
+    MovePoint(Fields),
+    /// `MOVEL x=1.2 y=0.5 th=90 F=200` moves the end effector along a straight line at a feed rate (mm/sec).
+    MoveLinear { coords: Fields, feed_mm_s: f64 },
+    /// `BASE x=1 y=0 th=90` moves the base.
+    MoveBase(Fields),
+    /// `GRIP 120` opens the gripper (mm).
+    Grip(f64),
+    /// `WAIT 0.5` waits (sec).
+    Wait(Duration),
+    /// `POSE home` moves to a saved pose.
+    Pose(String),
+    /// `PRINT text` writes the rest of the line to the script output.
+    Print(String),
+}
+
+/// A line of a program.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Instruction {
+    /// Line number in the source, from 1.
+    pub line: usize,
+    pub operation: Operation,
+}
+
+/// Why a line of a program could not be parsed.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ParseError {
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+/// Parses a program, one instruction per line. Keywords and parameter names are not case sensitive.
+/// `;` starts a comment, as does `#` at the start of a line. Every line that fails is reported.
+pub fn parse(source: &str) -> Result<Vec<Instruction>, Vec<ParseError>> {
+    let mut instructions = Vec::new();
+    let mut errors = Vec::new();
+    for (index, line) in source.lines().enumerate() {
+        let text = line.split(';').next().unwrap_or_default().trim();
+        if text.is_empty() || text.starts_with('#') {
+            continue;
+        }
+        match parse_line(text) {
+            Ok(operation) => instructions.push(Instruction { line: index + 1, operation }),
+            Err(message) => errors.push(ParseError { line: index + 1, message }),
+        }
+    }
+    if !errors.is_empty() {
+        return Err(errors);
+    }
+    Ok(instructions)
+}
+
+fn parse_line(text: &str) -> Result<Operation, String> {
+    let (keyword, arguments) = text.split_once(char::is_whitespace).map(|(keyword, arguments)| (keyword, arguments.trim())).unwrap_or((text, ""));
+    let keyword = keyword.to_ascii_uppercase();
+    if !KEYWORDS.contains(&keyword.as_str()) {
+        return Err(format!("unknown command '{keyword}', expected one of {}", KEYWORDS.join(", ")));
+    }
+    parse_operation(&keyword, arguments).map_err(|message| format!("{keyword}: {message}"))
+}
+
+fn parse_operation(keyword: &str, arguments: &str) -> Result<Operation, String> {
+    match keyword {
+        "MOVEJ" => fields(parameters(arguments, JOINT_PARAMETERS)?, JOINT_PARAMETERS).map(Operation::MoveJoints),
+        "MOVEP" => fields(parameters(arguments, COORD_PARAMETERS)?, COORD_PARAMETERS).map(Operation::MovePoint),
+        "MOVEL" => {
+            let mut parameters = parameters(arguments, LINEAR_PARAMETERS)?;
+            let feed_mm_s = match parameters.iter().position(|(field, _)| *field == "feed") {
+                Some(index) => parameters.remove(index).1,
+                None => DEFAULT_FEED_MM_S,
+            };
+            if feed_mm_s < MIN_FEED_MM_S {
+                return Err(format!("F must be a feed rate of at least {MIN_FEED_MM_S} mm/sec, got {feed_mm_s}"));
+            }
+            fields(parameters, COORD_PARAMETERS).map(|coords| Operation::MoveLinear { coords, feed_mm_s })
+        }
+        "BASE" => fields(parameters(arguments, COORD_PARAMETERS)?, COORD_PARAMETERS).map(Operation::MoveBase),
+        "GRIP" => number(single(arguments, "a width (mm)")?).map(Operation::Grip),
+        "WAIT" => number(single(arguments, "a time (sec)")?)
+            .and_then(|seconds| Duration::try_from_secs_f64(seconds).map_err(|_| format!("cannot wait {seconds} sec")))
+            .map(Operation::Wait),
+        "POSE" => single(arguments, "a pose name").map(|name| Operation::Pose(name.to_string())),
+        "PRINT" => Ok(Operation::Print(arguments.to_string())),
+        _ => unreachable!("{keyword} is not a keyword"),
+    }
+}
+
+/// Parses `key=value` arguments into the field each key sets, in the order given.
+fn parameters(arguments: &str, names: &[(&str, &'static str)]) -> Result<Vec<(&'static str, f64)>, String> {
+    let mut parameters: Vec<(&'static str, f64)> = Vec::new();
+    for argument in arguments.split_whitespace() {
+        let Some((key, value)) = argument.split_once('=') else {
+            return Err(format!("expected key=value, got '{argument}'"));
+        };
+        let Some((_, field)) = names.iter().find(|(name, _)| name.eq_ignore_ascii_case(key)) else {
+            let keys: Vec<&str> = names.iter().map(|(name, _)| *name).collect();
+            return Err(format!("unknown parameter '{key}', expected one of {keys:?}"));
+        };
+        if parameters.iter().any(|(seen, _)| seen == field) {
+            return Err(format!("'{key}' is given more than once"));
+        }
+        parameters.push((field, number(value)?));
+    }
+    Ok(parameters)
+}
+
+/// The target fields to change. At least one must be given.
+fn fields(parameters: Vec<(&'static str, f64)>, names: &[(&str, &str)]) -> Result<Fields, String> {
+    if parameters.is_empty() {
+        let keys: Vec<&str> = names.iter().map(|(name, _)| *name).collect();
+        return Err(format!("expected at least one of {keys:?}"));
+    }
+    Ok(parameters.into_iter().map(|(field, value)| (field.to_string(), value.into())).collect())
+}
+
+/// The one argument of a command.
+fn single<'a>(arguments: &'a str, expected: &str) -> Result<&'a str, String> {
+    let mut words = arguments.split_whitespace();
+    match (words.next(), words.next()) {
+        (Some(argument), None) => Ok(argument),
+        _ => Err(format!("expected {expected}, got '{arguments}'")),
+    }
+}
+
+fn number(text: &str) -> Result<f64, String> {
+    match text.parse::<f64>() {
+        Ok(value) if value.is_finite() => Ok(value),
+        _ => Err(format!("'{text}' is not a finite number")),
+    }
+}
+
+/// Runs a program one line after the other. Each move waits until the robot has settled before the next line runs.
+pub fn execute(context: &impl Motion, instructions: &[Instruction]) -> Result<(), Interrupt> {
+    for instruction in instructions {
+        context.at_line(instruction.line);
+        run(context, &instruction.operation).map_err(|interrupt| match interrupt {
+            Interrupt::Failed(message) => Interrupt::Failed(format!("line {}: {}", instruction.line, message)),
+            Interrupt::Aborted => Interrupt::Aborted,
+        })?;
+    }
+    Ok(())
+}
+
+fn run(context: &impl Motion, operation: &Operation) -> Result<(), Interrupt> {
+    match operation {
+        Operation::MoveJoints(joints) => context.move_joints(joints.clone())?,
+        Operation::MovePoint(coords) => context.move_coords(coords.clone())?,
+        Operation::MoveLinear { coords, feed_mm_s } => context.move_linear(coords.clone(), feed_mm_s / 1000.0)?,
+        Operation::MoveBase(base) => context.move_base(base.clone())?,
+        Operation::Grip(gripper_open_mm) => context.open_gripper(*gripper_open_mm)?,
+        Operation::Pose(name) => context.move_to_pose(name)?,
+        Operation::Wait(duration) => return context.sleep(*duration),
+        Operation::Print(text) => {
+            context.print(text.clone());
+            return Ok(());
+        }
+    }
+    context.wait_settled(None).map(|_| ())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fields(pairs: &[(&str, f64)]) -> Fields {
+        pairs.iter().map(|(field, value)| (field.to_string(), (*value).into())).collect()
+    }
+
+    fn operations(source: &str) -> Vec<Operation> {
+        parse(source).unwrap().into_iter().map(|instruction| instruction.operation).collect()
+    }
+
+    fn errors(source: &str) -> Vec<ParseError> {
+        parse(source).unwrap_err()
+    }
+
+    #[test]
+    fn parses_every_command() {
+        let program = "MOVEJ swing=30 elbow=-45\nMOVEP x=1.2 z=0.8\nMOVEL x=1 F=200\nBASE th=90\nGRIP 120\nWAIT 0.5\nPOSE home\nPRINT picked it up";
+        assert_eq!(
+            operations(program),
+            vec![
+                Operation::MoveJoints(fields(&[("swing_rotation_deg", 30.0), ("elbow_rotation_deg", -45.0)])),
+                Operation::MovePoint(fields(&[("x", 1.2), ("z", 0.8)])),
+                Operation::MoveLinear { coords: fields(&[("x", 1.0)]), feed_mm_s: 200.0 },
+                Operation::MoveBase(fields(&[("theta", 90.0)])),
+                Operation::Grip(120.0),
+                Operation::Wait(Duration::from_millis(500)),
+                Operation::Pose("home".to_string()),
+                Operation::Print("picked it up".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn ignores_case_comments_and_blank_lines() {
+        let program = "# pick\n\nmovel X=1 f=50 ; slowly\n   ; nothing\ngrip 40";
+        let instructions = parse(program).unwrap();
+        assert_eq!(instructions.iter().map(|instruction| instruction.line).collect::<Vec<_>>(), vec![3, 5]);
+        assert_eq!(instructions[0].operation, Operation::MoveLinear { coords: fields(&[("x", 1.0)]), feed_mm_s: 50.0 });
+    }
+
+    #[test]
+    fn movel_uses_the_default_feed() {
+        assert_eq!(operations("MOVEL z=0.3"), vec![Operation::MoveLinear { coords: fields(&[("z", 0.3)]), feed_mm_s: DEFAULT_FEED_MM_S }]);
+    }
+
+    #[test]
+    fn rejects_feeds_below_the_minimum() {
+        for feed in ["0", "-5", "1e-9", "0.5"] {
+            let errors = errors(&format!("MOVEL x=1 F={feed}"));
+            assert_eq!(errors.len(), 1);
+            assert!(errors[0].message.starts_with("MOVEL: F must be a feed rate of at least"), "{}", errors[0]);
+        }
+        assert!(parse(&format!("MOVEL x=1 F={MIN_FEED_MM_S}")).is_ok());
+    }
+
+    #[test]
+    fn reports_every_failing_line() {
+        let program = "MOVEJ swing=30\nJUMP 3\nMOVEP\nGRIP wide\nMOVEJ swing=1 swing=2\nMOVEP q=1\nWAIT -1\nPOSE\nMOVEL x=1 F=200";
+        let errors = errors(program);
+        assert_eq!(errors.iter().map(|error| error.line).collect::<Vec<_>>(), vec![2, 3, 4, 5, 6, 7, 8]);
+        assert!(errors[0].message.starts_with("unknown command 'JUMP'"));
+        assert!(errors[1].message.starts_with("MOVEP: expected at least one of"));
+        assert_eq!(errors[2].message, "GRIP: 'wide' is not a finite number");
+        assert_eq!(errors[3].message, "MOVEJ: 'swing' is given more than once");
+        assert!(errors[4].message.starts_with("MOVEP: unknown parameter 'q'"));
+        assert_eq!(errors[5].message, "WAIT: cannot wait -1 sec");
+        assert_eq!(errors[6].message, "POSE: expected a pose name, got ''");
+        assert_eq!(errors[6].to_string(), "line 8: POSE: expected a pose name, got ''");
+    }
+
+    #[test]
+    fn rejects_malformed_arguments() {
+        assert_eq!(errors("MOVEP x 1")[0].message, "MOVEP: expected key=value, got 'x'");
+        assert_eq!(errors("MOVEP x=inf")[0].message, "MOVEP: 'inf' is not a finite number");
+        assert_eq!(errors("GRIP 10 20")[0].message, "GRIP: expected a width (mm), got '10 20'");
+    }
+}
@@ -4,3 +4,8 @@ pub const GRIPPER_LENGTH_M: f64 = 0.5;
 
 pub const LIFT_HEIGHT_MM: f64 = 3000.0;
 pub const GRIPPER_WIDTH_MM: f64 = 300.0;
+
+/// Largest angle (degrees) accepted from an incoming command.
+pub const MAX_COMMAND_ANGLE_DEG: f64 = 3600.0;
+/// Largest distance (m) from the origin accepted from an incoming command.
+pub const MAX_COMMAND_DISTANCE_M: f64 = 1000.0;
@@ -0,0 +1,244 @@
+use super::command::CommandError;
+
+use chrono::{DateTime, Duration, Utc};
+use tracing::info;
+
+/// How long a control lease lasts before it must be renewed (ms).
+pub const CONTROL_LEASE_DURATION_MS: i64 = 5000;
+
+/// Who holds control of the robot. Published in telemetry so every client can see it.
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug, PartialEq, schemars::JsonSchema, ts_rs::TS)]
+pub struct ControlStatus {
+    /// Increments with every new lease. Renewing keeps the same id.
+    #[ts(type = "number")]
+    pub lease_id: u64,
+    pub holder: String,
+    pub expires_at: DateTime<Utc>,
+}
+
+/// A request for control. Set `take_over` to take control from the current holder, who is notified.
+#[derive(serde::Deserialize, Clone, Debug, Default, schemars::JsonSchema, ts_rs::TS)]
+pub struct ControlRequest {
+    #[serde(default)]
+    #[ts(as = "Option<bool>", optional)]
+    pub take_over: bool,
+}
+
+/// Returned to the client that was granted or renewed the lease.
+#[derive(serde::Serialize, Clone, Debug, schemars::JsonSchema, ts_rs::TS)]
+pub struct LeaseGrant {
+    #[serde(flatten)]
+    pub status: ControlStatus,
+    /// Secret presenting the lease. Motion commands must carry it while the lease is held.
+    pub token: String,
+}
+
+/// Reply to a request, renewal or release of control.
+#[derive(serde::Serialize, Clone, Debug, schemars::JsonSchema, ts_rs::TS)]
+pub struct ControlAck {
+    pub accepted: bool,
+    /// Set when control was granted or renewed.
+    pub lease: Option<LeaseGrant>,
+    /// Set when the request was rejected.
+    pub error: Option<CommandError>,
+}
+
+impl ControlAck {
+    pub fn new(result: Result<Option<LeaseGrant>, CommandError>) -> Self {
+        match result {
+            Ok(lease) => ControlAck { accepted: true, lease, error: None },
+            Err(error) => ControlAck { accepted: false, lease: None, error: Some(error) },
+        }
+    }
+}
+
+/// An operation on the control lease.
+#[derive(Clone, Copy, Debug)]
+pub enum ControlOperation<'a> {
+    Request { holder: &'a str, take_over: bool },
+    Renew { token: &'a str },
+    Release { token: &'a str },
+}
+
+#[derive(Clone, Debug)]
+struct Lease {
+    status: ControlStatus,
+    token: String,
+}
+
+impl Lease {
+    fn is_expired(&self) -> bool {
+        Utc::now() >= self.status.expires_at
+    }
+
+    fn grant(&self) -> LeaseGrant {
+        LeaseGrant { status: self.status.clone(), token: self.token.clone() }
+    }
+}
+
+/// The exclusive control lease. While a lease is held only commands presenting its token may move the robot.
+#[derive(Debug, Default)]
+pub struct Control {
+    lease: Option<Lease>,
+    last_lease_id: u64,
+}
+
+impl Control {
+    /// The current holder, if the lease has not expired.
+    pub fn status(&self) -> Option<ControlStatus> {
+        self.current().map(|lease| lease.status.clone())
+    }
+
+    fn current(&self) -> Option<&Lease> {
+        self.lease.as_ref().filter(|lease| !lease.is_expired())
+    }
+
+    fn is_holder(&self, token: &str) -> bool {
+        self.current().is_some_and(|lease| lease.token == token)
+    }
+
+    /// Grants `holder` a new lease. Fails if someone else holds control unless `take_over` is set.
+    pub fn request(&mut self, holder: &str, take_over: bool) -> Result<LeaseGrant, CommandError> {
+        if let Some(current) = self.current() {
+            if !take_over {
+                return Err(CommandError::ControlHeld { holder: current.status.holder.clone() });
+            }
+            info!("{} took over control from {}", holder, current.status.holder);
+        }
+
+        self.last_lease_id += 1;
+        let lease = Lease {
+            status: ControlStatus { lease_id: self.last_lease_id, holder: holder.to_string(), expires_at: Utc::now() + Duration::milliseconds(CONTROL_LEASE_DURATION_MS) },
+            token: uuid::Uuid::new_v4().to_string(),
+        };
+        let grant = lease.grant();
+        self.lease = Some(lease);
+        Ok(grant)
+    }
+
+    /// Extends the lease presented by `token`.
+    pub fn renew(&mut self, token: &str) -> Result<LeaseGrant, CommandError> {
+        if !self.is_holder(token) {
+            return Err(CommandError::LeaseNotHeld);
+        }
+
+        let lease = self.lease.as_mut().expect("holder has a lease");
+        lease.status.expires_at = Utc::now() + Duration::milliseconds(CONTROL_LEASE_DURATION_MS);
+        Ok(lease.grant())
+    }
+
+    /// Gives up the lease presented by `token`.
+    pub fn release(&mut self, token: &str) -> Result<(), CommandError> {
+        if !self.is_holder(token) {
+            return Err(CommandError::LeaseNotHeld);
+        }
+
+        self.lease = None;
+        Ok(())
+    }
+
+    /// Rejects motion from anyone but the holder while a lease is held.
+    pub fn check(&self, token: Option<&str>) -> Result<(), CommandError> {
+        match self.current() {
+            Some(lease) if Some(lease.token.as_str()) != token => Err(CommandError::ControlHeld { holder: lease.status.holder.clone() }),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::robot::command::Command;
+    use crate::robot::robot_config::RobotConfig;
+    use crate::robot::robot_state::JointState;
+    use crate::robot::Robot;
+
+    /// Moves the lease's expiry into the past, as if it had not been renewed in time.
+    fn expire(control: &mut Control) {
+        let lease = control.lease.as_mut().expect("a lease is held");
+        lease.status.expires_at = Utc::now() - Duration::milliseconds(1);
+    }
+
+    #[test]
+    fn only_the_holder_may_move() {
+        let mut control = Control::default();
+        assert!(control.check(None).is_ok());
+
+        let grant = control.request("alice", false).unwrap();
+        assert!(control.check(Some(&grant.token)).is_ok());
+        assert!(matches!(control.check(None), Err(CommandError::ControlHeld { holder }) if holder == "alice"));
+        assert!(matches!(control.request("bob", false), Err(CommandError::ControlHeld { .. })));
+    }
+
+    #[test]
+    fn take_over_replaces_the_lease() {
+        let mut control = Control::default();
+        let alice = control.request("alice", false).unwrap();
+        let bob = control.request("bob", true).unwrap();
+
+        assert_eq!(bob.status.lease_id, alice.status.lease_id + 1);
+        assert!(matches!(control.renew(&alice.token), Err(CommandError::LeaseNotHeld)));
+        assert!(control.check(Some(&alice.token)).is_err());
+        assert!(control.check(Some(&bob.token)).is_ok());
+    }
+
+    #[test]
+    fn an_expired_lease_frees_control() {
+        let mut control = Control::default();
+        let grant = control.request("alice", false).unwrap();
+        expire(&mut control);
+
+        assert_eq!(control.status(), None);
+        assert!(control.check(None).is_ok());
+        assert!(matches!(control.renew(&grant.token), Err(CommandError::LeaseNotHeld)));
+        assert!(matches!(control.release(&grant.token), Err(CommandError::LeaseNotHeld)));
+        assert_eq!(control.request("bob", false).unwrap().status.holder, "bob");
+    }
+
+    #[test]
+    fn renewing_extends_the_same_lease() {
+        let mut control = Control::default();
+        let grant = control.request("alice", false).unwrap();
+        control.lease.as_mut().unwrap().status.expires_at = Utc::now() + Duration::milliseconds(10);
+
+        let renewed = control.renew(&grant.token).unwrap();
+        assert_eq!(renewed.status.lease_id, grant.status.lease_id);
+        assert_eq!(renewed.token, grant.token);
+        assert!(renewed.status.expires_at > Utc::now() + Duration::milliseconds(CONTROL_LEASE_DURATION_MS / 2));
+        assert!(matches!(control.renew("not the token"), Err(CommandError::LeaseNotHeld)));
+    }
+
+    #[test]
+    fn releasing_frees_control() {
+        let mut control = Control::default();
+        let grant = control.request("alice", false).unwrap();
+        assert!(matches!(control.release("not the token"), Err(CommandError::LeaseNotHeld)));
+
+        control.release(&grant.token).unwrap();
+        assert_eq!(control.status(), None);
+        assert!(control.request("bob", false).is_ok());
+    }
+
+    #[test]
+    fn a_held_lease_locks_out_the_bridges() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut robot = Robot::test(RobotConfig::default(), dir.path());
+        let grant = robot.request_control("alice (websocket)", false).unwrap();
+
+        // The MQTT, Modbus and rosbridge bridges execute without a lease token.
+        let target = JointState { lift_elevation_mm: 100.0, ..Default::default() };
+        let result = robot.execute(Command::SetJointState(target), None);
+        assert!(matches!(result, Err(CommandError::ControlHeld { holder }) if holder == "alice (websocket)"));
+        assert!(matches!(robot.execute(Command::SetGripper(10.0), None), Err(CommandError::ControlHeld { .. })));
+        assert_eq!(robot.get_target_state().joint_state, JointState::default());
+
+        // Stopping and resetting do not need control.
+        assert!(robot.execute(Command::EmergencyStop, None).is_ok());
+        assert!(robot.execute(Command::ResetFault, None).is_ok());
+
+        assert!(robot.execute(Command::SetJointState(target), Some(&grant.token)).is_ok());
+        robot.release_control(&grant.token).unwrap();
+        assert!(robot.execute(Command::SetGripper(10.0), None).is_ok());
+    }
+}
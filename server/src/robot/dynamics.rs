@@ -0,0 +1,339 @@
+use super::constants::{ELBOW_LENGTH_M, GRIPPER_LENGTH_M, WRIST_LENGTH_M};
+use super::robot_state::JointState;
+
+/// Longest step (sec) the arm is simulated in. Explicit integration of the stiffer joints diverges over longer steps.
+pub const MAX_STEP_S: f64 = 0.005;
+/// Speed (rad/sec or m/sec) below which a joint is held by static friction.
+const STICTION_VELOCITY: f64 = 1e-4;
+/// Step (rad) of the finite differences the Coriolis and centrifugal effort is found with.
+const DIFFERENTIAL_STEP_RAD: f64 = 1e-6;
+/// Lengths (m) of the upper arm, forearm and gripper, swung by the swing, elbow and wrist.
+const LINK_LENGTHS_M: [f64; 3] = [ELBOW_LENGTH_M, WRIST_LENGTH_M, GRIPPER_LENGTH_M];
+
+/// A motor and the friction of its joint. Efforts are in N m for rotary joints and N for linear ones,
+/// against angles in rad and distances in m.
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug, PartialEq, schemars::JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct JointDynamics {
+    /// Effort per unit of position error.
+    pub p: f64,
+    /// Effort per unit of velocity, opposing it.
+    pub d: f64,
+    /// Most effort the motor can apply.
+    pub max_effort: f64,
+    /// Friction per unit of velocity.
+    pub viscous_friction: f64,
+    /// Friction that opposes motion whatever the speed, and holds the joint until the effort on it exceeds it.
+    pub coulomb_friction: f64,
+}
+
+/// Masses, motors and friction of the arm. With these the controller's PD output is the effort of each motor,
+/// and the joints accelerate with the arm's inertia, gravity and friction.
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug, PartialEq, schemars::JsonSchema)]
+#[serde(default, deny_unknown_fields)]
+pub struct DynamicsConfig {
+    /// Gravitational acceleration (m/sec^2), acting on the lift.
+    pub gravity_m_s2: f64,
+    /// Mass (kg) of the carriage the lift moves the arm with.
+    pub carriage_mass_kg: f64,
+    /// Masses (kg) of the links, each a uniform bar along its length.
+    pub upper_arm_mass_kg: f64,
+    pub forearm_mass_kg: f64,
+    pub gripper_mass_kg: f64,
+    /// Mass (kg) of the gripper fingers, at the end effector and moved by the gripper motor.
+    pub finger_mass_kg: f64,
+    /// Mass (kg) held at the end effector.
+    pub payload_kg: f64,
+    /// Adds the weight of everything the lift carries to the lift's effort, as a real controller's feedforward would.
+    pub lift_gravity_compensation: bool,
+    pub swing: JointDynamics,
+    pub lift: JointDynamics,
+    pub elbow: JointDynamics,
+    pub wrist: JointDynamics,
+    pub gripper: JointDynamics,
+}
+
+impl Default for DynamicsConfig {
+    fn default() -> Self {
+        DynamicsConfig {
+            gravity_m_s2: 9.81,
+            carriage_mass_kg: 20.0,
+            upper_arm_mass_kg: 15.0,
+            forearm_mass_kg: 8.0,
+            gripper_mass_kg: 4.0,
+            finger_mass_kg: 0.5,
+            payload_kg: 0.0,
+            lift_gravity_compensation: true,
+            swing: JointDynamics { p: 450.0, d: 450.0, max_effort: 200.0, viscous_friction: 5.0, coulomb_friction: 1.0 },
+            lift: JointDynamics { p: 5000.0, d: 950.0, max_effort: 1500.0, viscous_friction: 20.0, coulomb_friction: 2.0 },
+            elbow: JointDynamics { p: 40.0, d: 40.0, max_effort: 60.0, viscous_friction: 1.0, coulomb_friction: 0.2 },
+            wrist: JointDynamics { p: 2.0, d: 1.3, max_effort: 10.0, viscous_friction: 0.05, coulomb_friction: 0.01 },
+            gripper: JointDynamics { p: 200.0, d: 20.0, max_effort: 100.0, viscous_friction: 1.0, coulomb_friction: 0.1 },
+        }
+    }
+}
+
+/// A mass on one of the links of the arm.
+struct Body {
+    /// The upper arm, forearm or gripper.
+    link: usize,
+    mass_kg: f64,
+    /// Distance (m) from the link's joint to the centre of mass.
+    center_m: f64,
+    /// Moment of inertia (kg m^2) about the centre of mass.
+    inertia: f64,
+}
+
+impl DynamicsConfig {
+    /// The arm cannot be simulated without mass on every link and the gripper.
+    pub fn validate(&self) -> Result<(), String> {
+        let masses = [("upper_arm_mass_kg", self.upper_arm_mass_kg), ("forearm_mass_kg", self.forearm_mass_kg), ("gripper_mass_kg", self.gripper_mass_kg), ("finger_mass_kg", self.finger_mass_kg)];
+        match masses.iter().find(|(_, mass)| *mass <= 0.0) {
+            Some((field, mass)) => Err(format!("dynamics.{field} must be above 0, got {mass}")),
+            None => Ok(()),
+        }
+    }
+
+    /// The joint velocities `dt` after the motors apply the PD effort for `error` to the arm at `state` moving at `velocity`.
+    /// The base's motion does not act on the arm.
+    pub fn step(&self, state: JointState, error: JointState, velocity: JointState, dt: f64) -> JointState {
+        let mut next = velocity;
+
+        // Swing, elbow and wrist turn a chain of links in the horizontal plane, each carrying the ones after it.
+        let angles = [state.swing_rotation_deg, state.elbow_rotation_deg, state.wrist_rotation_deg].map(f64::to_radians);
+        let rates = [velocity.swing_rotation_deg, velocity.elbow_rotation_deg, velocity.wrist_rotation_deg].map(f64::to_radians);
+        let errors = [error.swing_rotation_deg, error.elbow_rotation_deg, error.wrist_rotation_deg].map(f64::to_radians);
+        let motors = [&self.swing, &self.elbow, &self.wrist];
+        let coriolis = self.coriolis(angles, rates);
+        let mut efforts = [0.0; 3];
+        let mut stuck = [false; 3];
+        for joint in 0..3 {
+            let applied = motors[joint].effort(errors[joint], rates[joint]) - coriolis[joint];
+            let friction = motors[joint].friction(rates[joint], applied);
+            stuck[joint] = friction.is_none();
+            efforts[joint] = applied + friction.unwrap_or(-applied);
+        }
+        let accelerations = solve(self.mass_matrix(angles), efforts);
+        let rates = [0, 1, 2].map(|joint| if stuck[joint] { 0.0 } else { rates[joint] + accelerations[joint] * dt });
+        [next.swing_rotation_deg, next.elbow_rotation_deg, next.wrist_rotation_deg] = rates.map(f64::to_degrees);
+
+        // The lift raises everything above it against gravity.
+        let lifted_kg = self.carriage_mass_kg + self.upper_arm_mass_kg + self.forearm_mass_kg + self.gripper_mass_kg + self.finger_mass_kg + self.payload_kg;
+        let weight = lifted_kg * self.gravity_m_s2;
+        let compensation = if self.lift_gravity_compensation { weight } else { 0.0 };
+        next.lift_elevation_mm = linear_step(&self.lift, lifted_kg, error.lift_elevation_mm, velocity.lift_elevation_mm, compensation, -weight, dt);
+
+        next.gripper_open_mm = linear_step(&self.gripper, self.finger_mass_kg, error.gripper_open_mm, velocity.gripper_open_mm, 0.0, 0.0, dt);
+        next
+    }
+
+    fn bodies(&self) -> [Body; 4] {
+        let bar = |link: usize, mass_kg: f64| Body { link, mass_kg, center_m: LINK_LENGTHS_M[link] / 2.0, inertia: mass_kg * LINK_LENGTHS_M[link].powi(2) / 12.0 };
+        [
+            bar(0, self.upper_arm_mass_kg),
+            bar(1, self.forearm_mass_kg),
+            bar(2, self.gripper_mass_kg),
+            Body { link: 2, mass_kg: self.finger_mass_kg + self.payload_kg, center_m: GRIPPER_LENGTH_M, inertia: 0.0 },
+        ]
+    }
+
+    /// Inertia of the swing, elbow and wrist as seen by their motors at `angles` (rad).
+    fn mass_matrix(&self, angles: [f64; 3]) -> [[f64; 3]; 3] {
+        let headings = [angles[0], angles[0] + angles[1], angles[0] + angles[1] + angles[2]];
+        let mut matrix = [[0.0; 3]; 3];
+        for body in self.bodies() {
+            // How the body's centre moves as each joint before it turns.
+            let mut jacobian = [[0.0; 2]; 3];
+            for (joint, column) in jacobian.iter_mut().enumerate().take(body.link + 1) {
+                for link in joint..=body.link {
+                    let length = if link == body.link { body.center_m } else { LINK_LENGTHS_M[link] };
+                    column[0] -= length * headings[link].sin();
+                    column[1] += length * headings[link].cos();
+                }
+            }
+            for a in 0..=body.link {
+                for b in 0..=body.link {
+                    matrix[a][b] += body.mass_kg * (jacobian[a][0] * jacobian[b][0] + jacobian[a][1] * jacobian[b][1]) + body.inertia;
+                }
+            }
+        }
+        matrix
+    }
+
+    /// Effort on each joint from the links swinging each other around, from the change of the mass matrix.
+    fn coriolis(&self, angles: [f64; 3], rates: [f64; 3]) -> [f64; 3] {
+        let derivatives = [0, 1, 2].map(|joint| {
+            let (mut above, mut below) = (angles, angles);
+            above[joint] += DIFFERENTIAL_STEP_RAD;
+            below[joint] -= DIFFERENTIAL_STEP_RAD;
+            let (above, below) = (self.mass_matrix(above), self.mass_matrix(below));
+            [0, 1, 2].map(|a| [0, 1, 2].map(|b| (above[a][b] - below[a][b]) / (2.0 * DIFFERENTIAL_STEP_RAD)))
+        });
+        [0, 1, 2].map(|k| {
+            let mut effort = 0.0;
+            for i in 0..3 {
+                for j in 0..3 {
+                    effort += (derivatives[i][k][j] - 0.5 * derivatives[k][i][j]) * rates[i] * rates[j];
+                }
+            }
+            effort
+        })
+    }
+}
+
+impl JointDynamics {
+    /// The PD effort, limited to what the motor can apply.
+    fn effort(&self, error: f64, velocity: f64) -> f64 {
+        (self.p * error - self.d * velocity).clamp(-self.max_effort, self.max_effort)
+    }
+
+    /// Friction against the joint moving at `velocity` with `applied` effort on it, or nothing if static friction holds it still.
+    fn friction(&self, velocity: f64, applied: f64) -> Option<f64> {
+        if velocity.abs() > STICTION_VELOCITY {
+            return Some(-self.viscous_friction * velocity - self.coulomb_friction * velocity.signum());
+        }
+        if applied.abs() <= self.coulomb_friction {
+            return None;
+        }
+        Some(-self.coulomb_friction * applied.signum())
+    }
+}
+
+/// `velocity` with the lift and gripper stopped if `state` drives them into their end stops.
+pub fn stop_at_end_stops(state: JointState, mut velocity: JointState) -> JointState {
+    let mut limited = state;
+    limited.check_limits();
+    if limited.lift_elevation_mm != state.lift_elevation_mm {
+        velocity.lift_elevation_mm = 0.0;
+    }
+    if limited.gripper_open_mm != state.gripper_open_mm {
+        velocity.gripper_open_mm = 0.0;
+    }
+    velocity
+}
+
+/// The velocity (mm/sec) of a linear joint moving `mass_kg` after `dt`, from its error (mm) and velocity (mm/sec).
+/// `feedforward` (N) is added to the motor's effort and `load` (N) acts on the joint besides it.
+fn linear_step(joint: &JointDynamics, mass_kg: f64, error_mm: f64, velocity_mm: f64, feedforward: f64, load: f64, dt: f64) -> f64 {
+    let velocity = velocity_mm / 1000.0;
+    let motor = (joint.p * error_mm / 1000.0 - joint.d * velocity + feedforward).clamp(-joint.max_effort, joint.max_effort);
+    let applied = motor + load;
+    match joint.friction(velocity, applied) {
+        Some(friction) => (velocity + (applied + friction) / mass_kg * dt) * 1000.0,
+        None => 0.0,
+    }
+}
+
+/// Solves `matrix * x = vector` by Cramer's rule. The mass matrix is positive definite so it always has a solution.
+fn solve(matrix: [[f64; 3]; 3], vector: [f64; 3]) -> [f64; 3] {
+    let determinant = |m: [[f64; 3]; 3]| m[0][0] * (m[1][1] * m[2][2] - m[1][2] * m[2][1]) - m[0][1] * (m[1][0] * m[2][2] - m[1][2] * m[2][0]) + m[0][2] * (m[1][0] * m[2][1] - m[1][1] * m[2][0]);
+    let whole = determinant(matrix);
+    [0, 1, 2].map(|column| {
+        let mut replaced = matrix;
+        for row in 0..3 {
+            replaced[row][column] = vector[row];
+        }
+        determinant(replaced) / whole
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::robot::command::Command;
+    use crate::robot::constants::{GRIPPER_WIDTH_MM, LIFT_HEIGHT_MM};
+    use crate::robot::robot_config::RobotConfig;
+    use crate::robot::Robot;
+
+    /// The joint state after `seconds` of the motors driving the arm from `state` towards `target`.
+    fn simulate(config: &DynamicsConfig, mut state: JointState, target: JointState, seconds: f64) -> JointState {
+        let mut velocity = JointState::default();
+        for _ in 0..(seconds / MAX_STEP_S).round() as usize {
+            velocity = config.step(state, JointState::clamped_sub(target, state), velocity, MAX_STEP_S);
+            state = state + velocity.val_mul(MAX_STEP_S);
+            velocity = stop_at_end_stops(state, velocity);
+            state.check_limits();
+        }
+        state
+    }
+
+    fn lift_at(lift_elevation_mm: f64) -> JointState {
+        JointState { lift_elevation_mm, ..Default::default() }
+    }
+
+    #[test]
+    fn gravity_compensation_holds_the_lift() {
+        let config = DynamicsConfig::default();
+        assert_eq!(config.step(lift_at(1000.0), JointState::default(), JointState::default(), MAX_STEP_S), JointState::default());
+
+        let held = simulate(&config, lift_at(1000.0), lift_at(1000.0), 2.0);
+        assert!((held.lift_elevation_mm - 1000.0).abs() < 0.1, "lift moved to {}", held.lift_elevation_mm);
+    }
+
+    #[test]
+    fn the_lift_sags_without_gravity_compensation() {
+        let config = DynamicsConfig { lift_gravity_compensation: false, ..Default::default() };
+        assert!(config.step(lift_at(1000.0), JointState::default(), JointState::default(), MAX_STEP_S).lift_elevation_mm < 0.0);
+
+        // The motor holds the weight only once the error is large enough for its P effort to match it.
+        let lifted_kg = config.carriage_mass_kg + config.upper_arm_mass_kg + config.forearm_mass_kg + config.gripper_mass_kg + config.finger_mass_kg;
+        let sag_mm = lifted_kg * config.gravity_m_s2 / config.lift.p * 1000.0;
+        let held = simulate(&config, lift_at(1000.0), lift_at(1000.0), 5.0);
+        assert!((1000.0 - held.lift_elevation_mm - sag_mm).abs() < 1.0, "lift sagged to {} instead of by {sag_mm} mm", held.lift_elevation_mm);
+    }
+
+    #[test]
+    fn static_friction_holds_small_errors() {
+        let config = DynamicsConfig::default();
+        // The wrist's P effort for this error is below its Coulomb friction.
+        let small = JointState { wrist_rotation_deg: (config.wrist.coulomb_friction / config.wrist.p / 2.0).to_degrees(), ..Default::default() };
+        assert_eq!(config.step(JointState::default(), small, JointState::default(), MAX_STEP_S).wrist_rotation_deg, 0.0);
+
+        let large = JointState { wrist_rotation_deg: 10.0, ..Default::default() };
+        assert!(config.step(JointState::default(), large, JointState::default(), MAX_STEP_S).wrist_rotation_deg > 0.0);
+    }
+
+    #[test]
+    fn joints_reach_their_targets() {
+        let config = DynamicsConfig::default();
+        let target = JointState { swing_rotation_deg: 30.0, lift_elevation_mm: 500.0, elbow_rotation_deg: -45.0, wrist_rotation_deg: 20.0, gripper_open_mm: 80.0 };
+        let reached = simulate(&config, JointState::default(), target, 10.0);
+        assert!(JointState::clamped_sub(target, reached).within(1.0, 0.5), "stopped at {reached:?}");
+    }
+
+    #[test]
+    fn end_stops_stop_the_lift_and_gripper() {
+        let velocity = JointState { lift_elevation_mm: -100.0, gripper_open_mm: 50.0, swing_rotation_deg: 10.0, ..Default::default() };
+        let beyond = JointState { lift_elevation_mm: -1.0, gripper_open_mm: GRIPPER_WIDTH_MM + 1.0, ..Default::default() };
+        assert_eq!(stop_at_end_stops(beyond, velocity), JointState { swing_rotation_deg: 10.0, ..Default::default() });
+
+        let within = JointState { lift_elevation_mm: LIFT_HEIGHT_MM / 2.0, gripper_open_mm: GRIPPER_WIDTH_MM / 2.0, ..Default::default() };
+        assert_eq!(stop_at_end_stops(within, velocity), velocity);
+
+        // Driving down into the bottom of the lift leaves it resting there.
+        let rested = simulate(&DynamicsConfig::default(), lift_at(100.0), lift_at(-500.0), 3.0);
+        assert_eq!(rested.lift_elevation_mm, 0.0);
+    }
+
+    #[test]
+    fn long_controller_ticks_stay_stable() {
+        let config = RobotConfig { dynamics: Some(DynamicsConfig::default()), ..Default::default() };
+        let dir = tempfile::tempdir().unwrap();
+        let mut robot = Robot::test(config, dir.path());
+        let target = JointState { swing_rotation_deg: 30.0, lift_elevation_mm: 500.0, elbow_rotation_deg: -45.0, ..Default::default() };
+        assert!(robot.execute(Command::SetJointState(target), None).is_ok());
+
+        for _ in 0..100 {
+            robot.step(0.1);
+        }
+        assert_eq!(robot.get_fault(), None);
+        assert!(JointState::clamped_sub(target, robot.get_state().joint_state).within(1.0, 0.5), "stopped at {:?}", robot.get_state());
+    }
+
+    #[test]
+    fn rejects_massless_links() {
+        assert!(DynamicsConfig::default().validate().is_ok());
+        let config = DynamicsConfig { forearm_mass_kg: 0.0, ..Default::default() };
+        assert_eq!(config.validate(), Err("dynamics.forearm_mass_kg must be above 0, got 0".to_string()));
+    }
+}
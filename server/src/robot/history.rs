@@ -0,0 +1,333 @@
+use super::telemetry::TelemetryFrame;
+
+use std::collections::{HashSet, VecDeque};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+
+use chrono::{DateTime, Duration, Utc};
+use serde::Serialize;
+use serde_json::{Map, Value};
+
+/// Environment variable holding how many seconds of telemetry the history keeps.
+pub const HISTORY_DURATION_ENV: &str = "ROBOT_HISTORY_S";
+/// Seconds of telemetry kept when `ROBOT_HISTORY_S` is not set.
+pub const DEFAULT_HISTORY_DURATION_S: f64 = 60.0;
+/// Most seconds of telemetry `ROBOT_HISTORY_S` may keep, a frame every broadcast tick for an hour.
+pub const MAX_HISTORY_DURATION_S: f64 = 3600.0;
+
+/// How a history query is returned.
+#[derive(serde::Deserialize, Copy, Clone, Debug, Default, PartialEq, schemars::JsonSchema, ts_rs::TS)]
+#[serde(rename_all = "snake_case")]
+pub enum HistoryFormat {
+    /// An array of objects keyed by field.
+    #[default]
+    Json,
+    /// A header row of field names followed by a row per sample.
+    Csv,
+}
+
+/// A time range and the fields to return from the history.
+#[derive(serde::Deserialize, Clone, Debug, Default, schemars::JsonSchema, ts_rs::TS)]
+#[serde(default)]
+pub struct HistoryQuery {
+    /// Start of the range, an RFC 3339 timestamp or seconds relative to now such as `-10`. The oldest sample when not set.
+    #[ts(optional = nullable)]
+    pub from: Option<String>,
+    /// End of the range, in the same form as `from`. The newest sample when not set.
+    #[ts(optional = nullable)]
+    pub to: Option<String>,
+    /// Comma separated telemetry fields, e.g. `state.joint_state,coords.x`. A field selects every field nested under it. All fields when not set.
+    #[ts(optional = nullable)]
+    pub fields: Option<String>,
+    #[ts(optional = nullable)]
+    pub format: Option<HistoryFormat>,
+}
+
+impl HistoryQuery {
+    /// Resolves `from` and `to` against `now`, so a bad time is rejected before the history is locked.
+    pub fn range(&self, now: DateTime<Utc>) -> Result<HistoryRange, String> {
+        Ok(HistoryRange {
+            from: self.from.as_deref().map(|from| parse_time(from, now)).transpose()?,
+            to: self.to.as_deref().map(|to| parse_time(to, now)).transpose()?,
+        })
+    }
+}
+
+/// The absolute time range of a `HistoryQuery`. Open ended on a side that is not set.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct HistoryRange {
+    pub from: Option<DateTime<Utc>>,
+    pub to: Option<DateTime<Utc>>,
+}
+
+/// Reply to a Socket.IO `history` request. `samples` is set for JSON queries and `csv` for CSV queries.
+#[derive(serde::Serialize, Clone, Debug, schemars::JsonSchema, ts_rs::TS)]
+pub struct HistoryAck {
+    pub samples: Option<Vec<Value>>,
+    pub csv: Option<String>,
+    pub error: Option<String>,
+}
+
+impl HistoryAck {
+    pub fn new(result: Result<HistoryTable, String>, format: HistoryFormat) -> Self {
+        match (result, format) {
+            (Ok(table), HistoryFormat::Json) => HistoryAck { samples: Some(table.to_json()), csv: None, error: None },
+            (Ok(table), HistoryFormat::Csv) => HistoryAck { samples: None, csv: Some(table.to_csv()), error: None },
+            (Err(error), _) => HistoryAck { samples: None, csv: None, error: Some(error) },
+        }
+    }
+}
+
+/// The selected fields of the samples in a time range, or of a batch run. The first column is always the sample's time.
+#[derive(Clone, Debug, Default)]
+pub struct HistoryTable {
+    pub fields: Vec<String>,
+    pub rows: Vec<Vec<Value>>,
+}
+
+impl HistoryTable {
+    /// A row per sample with its value of each field, null when it does not have the field.
+    fn new(fields: Vec<String>, samples: Vec<Vec<(String, Value)>>) -> HistoryTable {
+        let rows: Vec<Vec<Value>> = samples
+            .into_iter()
+            .map(|sample| {
+                let values: Map<String, Value> = sample.into_iter().collect();
+                fields.iter().map(|field| values.get(field).cloned().unwrap_or(Value::Null)).collect()
+            })
+            .collect();
+        HistoryTable { fields, rows }
+    }
+
+    /// A table of any serializable samples, with a column per nested field. `first` is put first.
+    pub fn from_samples<T: Serialize>(samples: &[T], first: &str) -> HistoryTable {
+        let samples: Vec<Vec<(String, Value)>> = samples.iter().map(flatten_sample).collect();
+        let fields = columns(&samples, first);
+        HistoryTable::new(fields, samples)
+    }
+
+    pub fn to_json(&self) -> Vec<Value> {
+        self.rows.iter().map(|row| Value::Object(self.fields.iter().cloned().zip(row.iter().cloned()).collect())).collect()
+    }
+
+    pub fn to_csv(&self) -> String {
+        let mut csv = self.fields.iter().map(|field| csv_cell(&Value::String(field.clone()))).collect::<Vec<_>>().join(",");
+        csv.push('\n');
+        for row in &self.rows {
+            csv.push_str(&row.iter().map(csv_cell).collect::<Vec<_>>().join(","));
+            csv.push('\n');
+        }
+        csv
+    }
+}
+
+fn csv_cell(value: &Value) -> String {
+    match value {
+        Value::Null => String::new(),
+        Value::String(text) if text.contains([',', '"', '\n', '\r']) => format!("\"{}\"", text.replace('"', "\"\"")),
+        Value::String(text) => text.clone(),
+        other => other.to_string(),
+    }
+}
+
+/// A ring buffer of the telemetry frames from the last `duration`, filled every broadcast tick.
+#[derive(Debug)]
+pub struct History {
+    frames: VecDeque<TelemetryFrame>,
+    duration: Duration,
+}
+
+pub type SharedHistory = Arc<Mutex<History>>;
+
+/// Locks the history. A panic while it was locked leaves at worst a frame missing, so a poisoned lock is still used.
+pub fn lock_history(history: &SharedHistory) -> MutexGuard<'_, History> {
+    history.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+impl History {
+    pub fn new(duration_s: f64) -> History {
+        History { frames: VecDeque::new(), duration: Duration::microseconds((duration_s * 1e6) as i64) }
+    }
+
+    /// Keeps the duration from `ROBOT_HISTORY_S`, or `DEFAULT_HISTORY_DURATION_S` when it is not set.
+    pub fn from_env() -> Result<History, String> {
+        let duration_s = match std::env::var(HISTORY_DURATION_ENV) {
+            Ok(duration) => duration.parse::<f64>().map_err(|err| format!("{HISTORY_DURATION_ENV}: {err}"))?,
+            Err(_) => DEFAULT_HISTORY_DURATION_S,
+        };
+        if !duration_s.is_finite() || !(0.0..=MAX_HISTORY_DURATION_S).contains(&duration_s) {
+            return Err(format!("{HISTORY_DURATION_ENV} must be between 0 and {MAX_HISTORY_DURATION_S} seconds, got {duration_s}"));
+        }
+        Ok(History::new(duration_s))
+    }
+
+    /// Adds the newest frame, dropping frames older than the history's duration.
+    pub fn push(&mut self, frame: TelemetryFrame) {
+        // Time went backwards, e.g. a replay seeked back, so the history starts again.
+        if self.frames.back().is_some_and(|newest| frame.timestamp < newest.timestamp) {
+            self.frames.clear();
+        }
+        let oldest = frame.timestamp.checked_sub_signed(self.duration).unwrap_or(DateTime::<Utc>::MIN_UTC);
+        self.frames.push_back(frame);
+        while self.frames.front().is_some_and(|frame| frame.timestamp < oldest) {
+            self.frames.pop_front();
+        }
+    }
+
+    /// Selects the requested fields of every frame in `range`, resolved from the query with `HistoryQuery::range`.
+    pub fn query(&self, query: &HistoryQuery, range: HistoryRange) -> Result<HistoryTable, String> {
+        let HistoryRange { from, to } = range;
+        let requested: Option<Vec<&str>> = query.fields.as_deref().map(|fields| fields.split(',').map(str::trim).filter(|field| !field.is_empty()).collect());
+
+        let start = from.map_or(0, |from| self.frames.partition_point(|frame| frame.timestamp < from));
+        let end = to.map_or(self.frames.len(), |to| self.frames.partition_point(|frame| frame.timestamp <= to));
+
+        // Optional fields are flattened to different columns depending on whether they are set, so take every column seen in the range.
+        let samples: Vec<Vec<(String, Value)>> = self.frames.range(start..end.max(start)).map(flatten_sample).collect();
+        let columns = columns(&samples, "timestamp");
+        if let Some(requested) = &requested {
+            if let Some(unknown) = requested.iter().find(|prefix| !samples.is_empty() && !columns.iter().any(|column| is_nested(column, prefix))) {
+                return Err(format!("unknown field '{}', available fields are {}", unknown, columns.join(", ")));
+            }
+        }
+        let fields: Vec<String> = columns
+            .into_iter()
+            .filter(|column| column == "timestamp" || requested.as_ref().is_none_or(|requested| requested.iter().any(|prefix| is_nested(column, prefix))))
+            .collect();
+
+        Ok(HistoryTable::new(fields, samples))
+    }
+}
+
+/// Parses an RFC 3339 timestamp, or seconds relative to `now`.
+pub(super) fn parse_time(time: &str, now: DateTime<Utc>) -> Result<DateTime<Utc>, String> {
+    if let Ok(seconds) = time.parse::<f64>() {
+        if !seconds.is_finite() {
+            return Err(format!("invalid time '{time}'"));
+        }
+        return now.checked_add_signed(Duration::microseconds((seconds * 1e6) as i64)).ok_or_else(|| format!("time out of range '{time}'"));
+    }
+    DateTime::parse_from_rfc3339(time).map(|time| time.with_timezone(&Utc)).map_err(|err| format!("invalid time '{time}': {err}"))
+}
+
+/// True if `field` is `prefix` or nested under it.
+fn is_nested(field: &str, prefix: &str) -> bool {
+    field.strip_prefix(prefix).is_some_and(|rest| rest.is_empty() || rest.starts_with('.'))
+}
+
+/// The sample's fields as dotted paths, e.g. `state.joint_state.swing_rotation_deg`.
+fn flatten_sample<T: Serialize>(sample: &T) -> Vec<(String, Value)> {
+    let mut fields = Vec::new();
+    if let Ok(Value::Object(object)) = serde_json::to_value(sample) {
+        flatten("", object, &mut fields);
+    }
+    fields
+}
+
+fn flatten(prefix: &str, object: Map<String, Value>, fields: &mut Vec<(String, Value)>) {
+    for (key, value) in object {
+        let path = if prefix.is_empty() { key } else { format!("{prefix}.{key}") };
+        match value {
+            Value::Object(object) => flatten(&path, object, fields),
+            value => fields.push((path, value)),
+        }
+    }
+}
+
+/// Every field of the samples once, in the order first seen after `first`.
+fn columns(samples: &[Vec<(String, Value)>], first: &str) -> Vec<String> {
+    let mut seen = HashSet::from([first]);
+    let mut columns = vec![first.to_string()];
+    for (field, _) in samples.iter().flatten() {
+        if seen.insert(field.as_str()) {
+            columns.push(field.clone());
+        }
+    }
+    columns
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::robot::telemetry::RobotSnapshot;
+
+    fn now() -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339("2024-05-01T12:00:00Z").unwrap().with_timezone(&Utc)
+    }
+
+    /// A history with a frame every second for the `seconds` before `now()`, the newest at `now()`.
+    fn history(seconds: i64) -> History {
+        let mut history = History::new(seconds as f64);
+        for (sequence, ago) in (0..=seconds).rev().enumerate() {
+            let snapshot = RobotSnapshot { timestamp: now() - Duration::seconds(ago), settled: ago == 0, ..Default::default() };
+            history.push(TelemetryFrame::new(&snapshot, sequence as u64));
+        }
+        history
+    }
+
+    fn query(from: Option<&str>, to: Option<&str>, fields: Option<&str>) -> HistoryQuery {
+        HistoryQuery { from: from.map(str::to_string), to: to.map(str::to_string), fields: fields.map(str::to_string), format: None }
+    }
+
+    fn run(history: &History, query: &HistoryQuery) -> Result<HistoryTable, String> {
+        history.query(query, query.range(now())?)
+    }
+
+    #[test]
+    fn parses_relative_and_absolute_times() {
+        assert_eq!(parse_time("-10", now()), Ok(now() - Duration::seconds(10)));
+        assert_eq!(parse_time("0.5", now()), Ok(now() + Duration::milliseconds(500)));
+        assert_eq!(parse_time("2024-05-01T11:59:00Z", now()), Ok(now() - Duration::minutes(1)));
+        assert!(parse_time("yesterday", now()).is_err());
+        assert!(parse_time("NaN", now()).is_err());
+        assert!(parse_time("inf", now()).is_err());
+    }
+
+    #[test]
+    fn rejects_times_out_of_range() {
+        for time in ["-1e13", "1e13", "-1e300"] {
+            assert!(parse_time(time, now()).is_err_and(|error| error.contains("out of range")), "accepted {time}");
+        }
+    }
+
+    #[test]
+    fn drops_frames_older_than_the_duration() {
+        let history = history(10);
+        assert_eq!(history.frames.len(), 11);
+        assert_eq!(history.frames.front().unwrap().timestamp, now() - Duration::seconds(10));
+
+        let mut history = History::new(2.0);
+        for ago in [5, 4, 3, 2, 1, 0] {
+            history.push(TelemetryFrame::new(&RobotSnapshot { timestamp: now() - Duration::seconds(ago), ..Default::default() }, 0));
+        }
+        assert_eq!(history.frames.len(), 3);
+    }
+
+    #[test]
+    fn queries_the_time_range() {
+        let history = history(10);
+        let table = run(&history, &query(Some("-3"), Some("-1"), None)).unwrap();
+        assert_eq!(table.rows.len(), 3);
+        assert_eq!(table.fields[0], "timestamp");
+
+        assert_eq!(run(&history, &query(None, None, None)).unwrap().rows.len(), 11);
+        assert_eq!(run(&history, &query(Some("-1"), Some("-3"), None)).unwrap().rows.len(), 0);
+        assert!(run(&history, &query(Some("-1e13"), None, None)).is_err());
+    }
+
+    #[test]
+    fn selects_nested_fields() {
+        let history = history(2);
+        let table = run(&history, &query(None, None, Some("settled, state.joint_state"))).unwrap();
+        assert_eq!(table.fields.len(), 7);
+        assert!(table.fields.iter().skip(1).all(|field| field == "settled" || field.starts_with("state.joint_state.")));
+        assert_eq!(table.to_json().last().unwrap()["settled"], Value::Bool(true));
+
+        let error = run(&history, &query(None, None, Some("state.joint"))).unwrap_err();
+        assert!(error.starts_with("unknown field 'state.joint'"));
+    }
+
+    #[test]
+    fn quotes_csv_cells() {
+        let table = HistoryTable { fields: vec!["timestamp".to_string(), "fault".to_string()], rows: vec![vec![Value::from(1), Value::from("jammed, \"badly\"")], vec![Value::from(2), Value::Null]] };
+        assert_eq!(table.to_csv(), "timestamp,fault\n1,\"jammed, \"\"badly\"\"\"\n2,\n");
+    }
+}
@@ -0,0 +1,215 @@
+use super::recording::{CommandRecord, RecordEntry, RecordWriter, RecordingError};
+use super::robot_state::{Coord4DOF, RobotState};
+use super::telemetry::RobotSnapshot;
+
+use std::collections::BTreeMap;
+use std::io::{Seek, Write};
+
+use chrono::{DateTime, Utc};
+use mcap::records::MessageHeader;
+use tokio::time::{Duration, Instant};
+use tracing::warn;
+
+/// The complete `RobotSnapshot`. Replays are loaded from this channel.
+pub const SNAPSHOT_TOPIC: &str = "/robot/snapshot";
+/// The current `RobotState`.
+pub const JOINT_STATE_TOPIC: &str = "/robot/joint_state";
+/// The current end effector pose.
+pub const COORDS_TOPIC: &str = "/robot/coords";
+/// Every command received, as a `CommandRecord`.
+pub const COMMAND_TOPIC: &str = "/robot/command";
+/// An `AlarmRecord` whenever a fault is raised or cleared.
+pub const ALARM_TOPIC: &str = "/robot/alarm";
+
+/// How often the chunk being written is completed so an interrupted recording loses little.
+const MCAP_FLUSH_PERIOD: Duration = Duration::from_secs(1);
+
+/// Sent on `ALARM_TOPIC` when a fault is raised or cleared.
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug, schemars::JsonSchema)]
+pub struct AlarmRecord {
+    pub timestamp: DateTime<Utc>,
+    /// The active fault, `None` once cleared.
+    pub fault: Option<String>,
+}
+
+/// The channel ids of each topic.
+struct Channels {
+    snapshot: u16,
+    joint_state: u16,
+    coords: u16,
+    command: u16,
+    alarm: u16,
+}
+
+/// Writes a recording as MCAP with one JSON encoded channel per stream, each with a JSON Schema, for Foxglove and ROS tooling.
+pub struct McapWriter<W: Write + Seek> {
+    writer: mcap::Writer<W>,
+    channels: Channels,
+    sequence: u32,
+    last_fault: Option<String>,
+    last_flush: Instant,
+}
+
+impl<W: Write + Seek + Send> McapWriter<W> {
+    pub fn new(writer: W) -> Result<Self, RecordingError> {
+        let mut writer = mcap::Writer::new(writer)?;
+        let channels = Channels {
+            snapshot: add_channel::<RobotSnapshot, W>(&mut writer, SNAPSHOT_TOPIC)?,
+            joint_state: add_channel::<RobotState, W>(&mut writer, JOINT_STATE_TOPIC)?,
+            coords: add_channel::<Coord4DOF, W>(&mut writer, COORDS_TOPIC)?,
+            command: add_channel::<CommandRecord, W>(&mut writer, COMMAND_TOPIC)?,
+            alarm: add_channel::<AlarmRecord, W>(&mut writer, ALARM_TOPIC)?,
+        };
+        Ok(McapWriter { writer, channels, sequence: 0, last_fault: None, last_flush: Instant::now() })
+    }
+
+    fn write_message<T: serde::Serialize>(&mut self, channel_id: u16, timestamp: DateTime<Utc>, message: &T) -> Result<(), RecordingError> {
+        let time = timestamp.timestamp_nanos_opt().unwrap_or_default().max(0) as u64;
+        let header = MessageHeader { channel_id, sequence: self.sequence, log_time: time, publish_time: time };
+        self.sequence = self.sequence.wrapping_add(1);
+        self.writer.write_to_known_channel(&header, &serde_json::to_vec(message)?)?;
+        Ok(())
+    }
+}
+
+/// Registers the JSON Schema of `T` and a JSON channel using it on `topic`.
+fn add_channel<T: schemars::JsonSchema, W: Write + Seek>(writer: &mut mcap::Writer<W>, topic: &str) -> Result<u16, RecordingError> {
+    let schema = serde_json::to_vec(&schemars::schema_for!(T))?;
+    let schema_id = writer.add_schema(&T::schema_name(), "jsonschema", &schema)?;
+    Ok(writer.add_channel(schema_id, topic, "json", &BTreeMap::new())?)
+}
+
+impl<W: Write + Seek + Send> RecordWriter for McapWriter<W> {
+    fn write(&mut self, entry: &RecordEntry) -> Result<(), RecordingError> {
+        match entry {
+            RecordEntry::Snapshot(snapshot) => {
+                self.write_message(self.channels.snapshot, snapshot.timestamp, snapshot)?;
+                self.write_message(self.channels.joint_state, snapshot.timestamp, &snapshot.state)?;
+                self.write_message(self.channels.coords, snapshot.timestamp, &snapshot.coords)?;
+                if snapshot.fault != self.last_fault {
+                    self.last_fault = snapshot.fault.clone();
+                    let alarm = AlarmRecord { timestamp: snapshot.timestamp, fault: snapshot.fault.clone() };
+                    self.write_message(self.channels.alarm, snapshot.timestamp, &alarm)?;
+                }
+            }
+            RecordEntry::Command(command) => self.write_message(self.channels.command, command.timestamp, command)?,
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<(), RecordingError> {
+        // Completing a chunk on every flush would leave the file in tiny chunks.
+        if self.last_flush.elapsed() >= MCAP_FLUSH_PERIOD {
+            self.writer.flush()?;
+            self.last_flush = Instant::now();
+        }
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Result<(), RecordingError> {
+        self.writer.finish()?;
+        Ok(())
+    }
+}
+
+/// Reads the snapshots from `SNAPSHOT_TOPIC`. Files cut short, e.g. when the server was killed while recording, are read up to the last complete chunk.
+pub fn read_snapshots(mcap: &[u8]) -> Result<Vec<RobotSnapshot>, RecordingError> {
+    let mut snapshots = Vec::new();
+    for message in mcap::MessageStream::new_with_options(mcap, mcap::read::Options::IgnoreEndMagic.into())? {
+        let message = match message {
+            Ok(message) => message,
+            // An interrupted recording ends part way through a chunk.
+            Err(err) if !snapshots.is_empty() => {
+                warn!("recording is cut short, replaying the {} snapshots before it: {}", snapshots.len(), err);
+                break;
+            }
+            Err(err) => return Err(err.into()),
+        };
+        if message.channel.topic == SNAPSHOT_TOPIC {
+            snapshots.push(serde_json::from_slice(&message.data)?);
+        }
+    }
+    Ok(snapshots)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::robot::command::Command;
+
+    use serde_json::Value;
+
+    /// Snapshots one second apart, the second raising a fault the third clears.
+    fn snapshots() -> Vec<RobotSnapshot> {
+        let start = Utc::now();
+        (0..3)
+            .map(|second| {
+                let mut snapshot = RobotSnapshot { timestamp: start + chrono::Duration::seconds(second), ..Default::default() };
+                snapshot.state.joint_state.lift_elevation_mm = 100.0 * second as f64;
+                snapshot.coords.x = second as f64;
+                snapshot.fault = (second == 1).then(|| "lift_elevation_mm is not finite".to_string());
+                snapshot
+            })
+            .collect()
+    }
+
+    /// Writes the first snapshot, a command and then the other snapshots, returning the file.
+    fn write(snapshots: &[RobotSnapshot], command: &CommandRecord) -> Vec<u8> {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("recording.mcap");
+        let mut writer = McapWriter::new(std::fs::File::create(&path).unwrap()).unwrap();
+        writer.write(&RecordEntry::Snapshot(Box::new(snapshots[0].clone()))).unwrap();
+        writer.write(&RecordEntry::Command(command.clone())).unwrap();
+        for snapshot in &snapshots[1..] {
+            writer.write(&RecordEntry::Snapshot(Box::new(snapshot.clone()))).unwrap();
+        }
+        writer.finish().unwrap();
+        drop(writer);
+        std::fs::read(path).unwrap()
+    }
+
+    #[test]
+    fn every_stream_is_written_to_its_channel() {
+        let snapshots = snapshots();
+        let command = CommandRecord { timestamp: snapshots[0].timestamp, command: Command::SetGripper(20.0), accepted: true, error: None };
+        let mcap = write(&snapshots, &command);
+
+        let mut messages: BTreeMap<String, Vec<Value>> = BTreeMap::new();
+        for message in mcap::MessageStream::new(&mcap).unwrap() {
+            let message = message.unwrap();
+            assert_eq!(message.channel.message_encoding, "json");
+            let schema = message.channel.schema.as_ref().expect("every channel has a schema");
+            assert_eq!(schema.encoding, "jsonschema");
+            messages.entry(message.channel.topic.clone()).or_default().push(serde_json::from_slice(&message.data).unwrap());
+        }
+
+        let topics: Vec<_> = messages.keys().map(String::as_str).collect();
+        assert_eq!(topics, [ALARM_TOPIC, COMMAND_TOPIC, COORDS_TOPIC, JOINT_STATE_TOPIC, SNAPSHOT_TOPIC]);
+        for topic in [SNAPSHOT_TOPIC, JOINT_STATE_TOPIC, COORDS_TOPIC] {
+            assert_eq!(messages[topic].len(), snapshots.len(), "{topic}");
+        }
+
+        let lifts: Vec<_> = messages[JOINT_STATE_TOPIC].iter().map(|state| state["joint_state"]["lift_elevation_mm"].as_f64().unwrap()).collect();
+        assert_eq!(lifts, [0.0, 100.0, 200.0]);
+        let xs: Vec<_> = messages[COORDS_TOPIC].iter().map(|coords| coords["x"].as_f64().unwrap()).collect();
+        assert_eq!(xs, [0.0, 1.0, 2.0]);
+
+        let commands = &messages[COMMAND_TOPIC];
+        assert_eq!(commands.len(), 1);
+        assert_eq!(commands[0]["command"]["command"], "set_gripper");
+        assert_eq!(commands[0]["accepted"], true);
+
+        // Only the changes of the fault are alarms: raised by the second snapshot and cleared by the third.
+        let alarms: Vec<_> = messages[ALARM_TOPIC].iter().map(|alarm| alarm["fault"].clone()).collect();
+        assert_eq!(alarms, [Value::from("lift_elevation_mm is not finite"), Value::Null]);
+    }
+
+    #[test]
+    fn snapshots_are_read_back_from_their_channel() {
+        let snapshots = snapshots();
+        let command = CommandRecord { timestamp: snapshots[0].timestamp, command: Command::EmergencyStop, accepted: true, error: None };
+        let mcap = write(&snapshots, &command);
+
+        assert_eq!(read_snapshots(&mcap).unwrap(), snapshots);
+    }
+}
@@ -1,54 +1,72 @@
+pub mod audit;
+pub mod auth;
+pub mod batch;
+pub mod behavior_tree;
+pub mod robot_config;
 pub mod robot_state;
 pub mod constants;
-
-use robot_state::{limit_angle, shortest_angle_diff, Coord4DOF, JointState, RobotState};
+pub mod command;
+pub mod command_language;
+pub mod control;
+pub mod dynamics;
+pub mod history;
+pub mod rest;
+#[cfg(feature = "mqtt")]
+pub mod mqtt;
+#[cfg(feature = "modbus")]
+pub mod modbus;
+pub mod mcap_log;
+pub mod motion;
+pub mod persistence;
+pub mod poses;
+pub mod protocol;
+pub mod recording;
+pub mod replay;
+pub mod rosbridge;
+pub mod scripting;
+pub mod tasks;
+pub mod telemetry;
+pub mod ws;
+
+use audit::{AuditConfig, AuditLog, Origin, SharedAudit};
+use auth::{control_authorized, execute_authorized, pose_authorized, replay_authorized, script_authorized, snapshot_authorized, Auth, Identity, Role, SharedAuth};
+use command::{Command, CommandAck, CommandError, CommandErrorEvent, CommandOutcome};
+use history::{lock_history, History, HistoryAck, HistoryQuery, SharedHistory};
+use control::{Control, ControlAck, ControlOperation, ControlRequest, ControlStatus, LeaseGrant};
+use persistence::{SavedState, SnapshotAck, SnapshotOperation, StateFile, RESUME_ARG};
+use poses::{Pose, PoseAck, PoseKind, PoseLibrary, PoseName, PoseOperation, PoseTarget, SavePose};
+use recording::{Recorder, REPLAY_ENV};
+use replay::{Replay, ReplayAck, ReplayControl, ReplayStatus};
+use scripting::{ScriptAck, ScriptControl, ScriptName, ScriptOperation, ScriptSource, Scripts, SharedScripts, PROGRAM_ARG};
+use tasks::PickAndPlace;
+use telemetry::{
+    RobotSnapshot, SnapshotReceiver, SnapshotSender, Stream, StreamRequest, StreamSubscription, Subscriptions, Telemetry, TelemetryFrame, TelemetrySender,
+    TELEMETRY_CHANNEL_CAPACITY,
+};
+use robot_config::RobotConfig;
+use robot_state::{limit_angle, shortest_angle_diff, Coord4DOF, JointState, LinkCoords, RobotState};
 use constants::*;
-use std::{f64::consts::PI, sync::Arc};
+use std::{f64::consts::PI, net::SocketAddr, sync::Arc};
 use tokio::time::{sleep, Instant, Duration};
 
-use axum::{routing::get, Router};
+use axum::{extract::ConnectInfo, routing::get, Router};
+use serde::de::DeserializeOwned;
+use serde_json::Value;
 use socketioxide::{
-    extract::{Data, SocketRef, State},
+    extract::{AckSender, SocketRef, State, TryData},
     SocketIo,
 };
-use tokio::sync::RwLock;
+use tokio::sync::{broadcast::error::RecvError, RwLock};
+use tokio::task::JoinHandle;
 use tower::ServiceBuilder;
 use tower_http::cors::CorsLayer;
-use tracing::info;
+use tracing::{error, info, warn};
 use tracing_subscriber::FmtSubscriber;
 
 const BROADCAST_PERIOD_MS: u64 = 20;
 const CONTROLLER_LOOP_TIME_MS: u64 = 5;
 const CONTROLLER_LOOP_TIME_S: f64 = CONTROLLER_LOOP_TIME_MS as f64/1000.0;
 
-/// 
-const MAX_BASE_LINEAR_VEL: f64 = 0.06;
-const BASE_LINEAR_P: f64 = 1.0;
-const BASE_LINEAR_D: f64 = 0.5;
-
-const MAX_BASE_ANGLE_VEL: f64 = 3.0;
-const BASE_ANGLE_P: f64 = 0.5;
-const BASE_ANGLE_D: f64 = 0.1;
-
-/// Max Angular velcoity (deg/sec)
-const MAX_ANGULAR_VELOCITY: f64 = 18.0;
-/// Max Angular acceleration (deg/sec^2)
-const MAX_ANGULAR_ACCELERATION: f64 = 9.0;
-
-const ANGLE_P: f64 = 0.7;
-const ANGLE_D: f64 = 1.5;
-
-const FEEDFORWARD_FACTOR: f64 = 2.22;
-
-/// Max linear acceleration (mm/sec^2)
-const MAX_LINEAR_ACCELERATION: f64 = 40.0;
-/// Max linear velocity (mm/sec)
-const MAX_LINEAR_VELOCITY: f64 = 80.0;
-
-const LINEAR_P: f64 = 2.5;
-const LINEAR_D: f64 = 4.0;
-
-
 fn degrees_to_radians(degrees: f64) -> f64 {
     degrees * PI / 180.0
 }
@@ -57,44 +75,468 @@ fn radians_to_degrees(degrees: f64) -> f64 {
     degrees * 180.0 / PI
 }
 
-async fn on_connect(socket: SocketRef) {
-    info!("socket connected: {}", socket.id);
+async fn on_connect(socket: SocketRef, TryData(credentials): TryData<SocketCredentials>, auth: State<SharedAuth>, snapshots: State<SnapshotReceiver>) {
+    // Clients pass their token as `io(url, { auth: { token } })`.
+    let token = credentials.ok().and_then(|credentials| credentials.token);
+    let identity = match auth.authenticate(token.as_deref()) {
+        Ok(identity) => identity,
+        Err(error) => {
+            warn!("refused socket {}: {}", socket.id, error);
+            let _ = socket.emit("auth error", error);
+            let _ = socket.disconnect();
+            return;
+        }
+    };
+    info!("socket connected: {} as {} ({:?})", socket.id, identity.name, identity.role);
+    socket.extensions.insert(identity);
+
+    // Clients receive the default telemetry until they subscribe to their own streams.
+    let _ = socket.join(DEFAULT_TELEMETRY_ROOM);
+    let subscriptions = SocketSubscriptions::default();
+    let unsubscriptions = subscriptions.clone();
+    let disconnect_subscriptions = subscriptions.clone();
+
+    // Let the client know if the robot is already faulted.
+    let fault = snapshots.borrow().fault.clone();
+    if fault.is_some() {
+        let _ = socket.emit("fault", fault);
+    }
 
     socket.on(
         "set joint state",
-        |Data::<JointState>(data), robot_lock: State<RobotLock>| async move {
-            
-            {
-                robot_lock.write().await.set_joint_target_state(data, true);
-            }
+        |socket: SocketRef, TryData::<Value>(data), robot_lock: State<RobotLock>, audit: State<SharedAudit>, ack: AckSender| async move {
+            let (payload, command) = parse_payload(data, Command::SetJointState);
+            handle_command(&socket, ack, &robot_lock, &audit, "set joint state", payload, command).await;
         },
     );
 
     socket.on(
         "set coord state",
-        |Data::<Coord4DOF>(data), robot_lock: State<RobotLock>| async move {
-            
-            {
-                robot_lock.write().await.set_target_coord_state(data);
-            }
+        |socket: SocketRef, TryData::<Value>(data), robot_lock: State<RobotLock>, audit: State<SharedAudit>, ack: AckSender| async move {
+            let (payload, command) = parse_payload(data, Command::SetCoordState);
+            handle_command(&socket, ack, &robot_lock, &audit, "set coord state", payload, command).await;
         },
     );
 
     socket.on(
         "set base state",
-        |Data::<Coord4DOF>(data), robot_lock: State<RobotLock>| async move {
-            {
-                robot_lock.write().await.set_target_base_state(data);
+        |socket: SocketRef, TryData::<Value>(data), robot_lock: State<RobotLock>, audit: State<SharedAudit>, ack: AckSender| async move {
+            let (payload, command) = parse_payload(data, Command::SetBaseState);
+            handle_command(&socket, ack, &robot_lock, &audit, "set base state", payload, command).await;
+        },
+    );
+
+    socket.on(
+        "set gripper",
+        |socket: SocketRef, TryData::<Value>(data), robot_lock: State<RobotLock>, audit: State<SharedAudit>, ack: AckSender| async move {
+            let (payload, command) = parse_payload(data, Command::SetGripper);
+            handle_command(&socket, ack, &robot_lock, &audit, "set gripper", payload, command).await;
+        },
+    );
+
+    socket.on(
+        "emergency stop",
+        |socket: SocketRef, robot_lock: State<RobotLock>, audit: State<SharedAudit>, ack: AckSender| async move {
+            handle_command(&socket, ack, &robot_lock, &audit, "emergency stop", Value::Null, Ok(Command::EmergencyStop)).await;
+        },
+    );
+
+    socket.on(
+        "reset fault",
+        |socket: SocketRef, robot_lock: State<RobotLock>, audit: State<SharedAudit>, ack: AckSender| async move {
+            handle_command(&socket, ack, &robot_lock, &audit, "reset fault", Value::Null, Ok(Command::ResetFault)).await;
+        },
+    );
+
+    socket.on(
+        "move to pose",
+        |socket: SocketRef, TryData::<Value>(data), robot_lock: State<RobotLock>, audit: State<SharedAudit>, ack: AckSender| async move {
+            let (payload, command) = parse_payload(data, Command::MoveToPose);
+            handle_command(&socket, ack, &robot_lock, &audit, "move to pose", payload, command).await;
+        },
+    );
+
+    socket.on(
+        "poses",
+        |robot_lock: State<RobotLock>, ack: AckSender| async move {
+            let _ = ack.send(robot_lock.read().await.poses().list());
+        },
+    );
+
+    socket.on(
+        "save pose",
+        |socket: SocketRef, TryData::<SavePose>(data), robot_lock: State<RobotLock>, ack: AckSender| async move {
+            handle_pose(&socket, ack, &robot_lock, data.map(PoseOperation::Save)).await;
+        },
+    );
+
+    socket.on(
+        "delete pose",
+        |socket: SocketRef, TryData::<PoseName>(data), robot_lock: State<RobotLock>, ack: AckSender| async move {
+            handle_pose(&socket, ack, &robot_lock, data.map(PoseOperation::Delete)).await;
+        },
+    );
+
+    socket.on(
+        "request control",
+        |socket: SocketRef, TryData::<ControlRequest>(data), robot_lock: State<RobotLock>, ack: AckSender| async move {
+            // The request is optional, sending no data requests control without taking it over.
+            let take_over = data.unwrap_or_default().take_over;
+            let holder = format!("{} (socket {})", identity_of(&socket).name, socket.id);
+            handle_control(&socket, ack, &robot_lock, ControlOperation::Request { holder: &holder, take_over }).await;
+        },
+    );
+
+    socket.on(
+        "renew control",
+        |socket: SocketRef, robot_lock: State<RobotLock>, ack: AckSender| async move {
+            let token = lease_token(&socket).unwrap_or_default();
+            handle_control(&socket, ack, &robot_lock, ControlOperation::Renew { token: &token }).await;
+        },
+    );
+
+    socket.on(
+        "release control",
+        |socket: SocketRef, robot_lock: State<RobotLock>, ack: AckSender| async move {
+            let token = lease_token(&socket).unwrap_or_default();
+            handle_control(&socket, ack, &robot_lock, ControlOperation::Release { token: &token }).await;
+        },
+    );
+
+
+    socket.on(
+        "replay",
+        |socket: SocketRef, TryData::<ReplayControl>(data), robot_lock: State<RobotLock>, ack: AckSender| async move {
+            let result = match data {
+                Ok(control) => replay_authorized(&robot_lock, &identity_of(&socket), control).await,
+                Err(err) => Err(CommandError::Malformed { message: err.to_string() }),
+            };
+            if let Err(error) = &result {
+                warn!("rejected replay control from {}: {}", socket.id, error);
             }
+            let _ = ack.send(ReplayAck::new(result));
+        },
+    );
+
+    socket.on(
+        "save snapshot",
+        |socket: SocketRef, robot_lock: State<RobotLock>, audit: State<SharedAudit>, ack: AckSender| async move {
+            handle_snapshot(&socket, ack, &robot_lock, &audit, SnapshotOperation::Save).await;
+        },
+    );
+
+    socket.on(
+        "load snapshot",
+        |socket: SocketRef, robot_lock: State<RobotLock>, audit: State<SharedAudit>, ack: AckSender| async move {
+            handle_snapshot(&socket, ack, &robot_lock, &audit, SnapshotOperation::Load).await;
+        },
+    );
+
+    socket.on(
+        "scripts",
+        |scripts: State<SharedScripts>, ack: AckSender| async move {
+            let list = scripts.list().unwrap_or_else(|err| {
+                error!("could not list the scripts: {}", err);
+                Vec::new()
+            });
+            let _ = ack.send(list);
+        },
+    );
+
+    socket.on(
+        "upload script",
+        |socket: SocketRef, TryData::<ScriptSource>(data), scripts: State<SharedScripts>, ack: AckSender| async move {
+            handle_script(&socket, ack, &scripts, data.map(ScriptOperation::Upload));
+        },
+    );
+
+    socket.on(
+        "delete script",
+        |socket: SocketRef, TryData::<ScriptName>(data), scripts: State<SharedScripts>, ack: AckSender| async move {
+            handle_script(&socket, ack, &scripts, data.map(ScriptOperation::Delete));
+        },
+    );
+
+    socket.on(
+        "run script",
+        |socket: SocketRef, TryData::<ScriptName>(data), scripts: State<SharedScripts>, ack: AckSender| async move {
+            handle_script(&socket, ack, &scripts, data.map(ScriptOperation::Run));
+        },
+    );
+
+    socket.on(
+        "script",
+        |socket: SocketRef, TryData::<ScriptControl>(data), scripts: State<SharedScripts>, ack: AckSender| async move {
+            handle_script(&socket, ack, &scripts, data.map(ScriptOperation::Control));
         },
     );
 
+    socket.on(
+        "pick and place",
+        |socket: SocketRef, TryData::<PickAndPlace>(data), scripts: State<SharedScripts>, ack: AckSender| async move {
+            handle_script(&socket, ack, &scripts, data.map(ScriptOperation::PickAndPlace));
+        },
+    );
+
+    socket.on(
+        "script status",
+        |scripts: State<SharedScripts>, ack: AckSender| async move {
+            let _ = ack.send(scripts.status());
+        },
+    );
+
+    socket.on(
+        "history",
+        |TryData::<HistoryQuery>(data), history: State<SharedHistory>, ack: AckSender| async move {
+            let query = data.unwrap_or_default();
+            let result = query.range(chrono::Utc::now()).and_then(|range| lock_history(&history).query(&query, range));
+            let _ = ack.send(HistoryAck::new(result, query.format.unwrap_or_default()));
+        },
+    );
 
-    socket.on_disconnect(|| async move {
+    socket.on(
+        "subscribe",
+        |socket: SocketRef, TryData::<Vec<StreamRequest>>(data), telemetry: State<TelemetrySender>, ack: AckSender| async move {
+            let result = match data {
+                Ok(requests) => subscriptions.subscribe(socket, &telemetry, requests),
+                Err(err) => Err(err.to_string()),
+            };
+            let _ = ack.send(SubscriptionAck::from(result));
+        },
+    );
+
+    socket.on(
+        "unsubscribe",
+        |TryData::<Vec<Stream>>(data), ack: AckSender| async move {
+            let result = data.map(|streams| unsubscriptions.unsubscribe(&streams)).map_err(|err| err.to_string());
+            let _ = ack.send(SubscriptionAck::from(result));
+        },
+    );
+
+    socket.on_disconnect(|socket: SocketRef, robot_lock: State<RobotLock>| async move {
+        disconnect_subscriptions.stop();
+        // Hand control back straight away rather than leaving it held until the lease expires.
+        if let Some(token) = lease_token(&socket) {
+            let _ = robot_lock.write().await.release_control(&token);
+        }
         info!("Client disconnected");
     });
 }
 
+/// Executes a command received on `event`, answering the client's ack callback if it supplied one.
+/// Rejections are also reported back to the sending socket as a `command error` event. Every command is audited with its `payload` as received.
+async fn handle_command(socket: &SocketRef, ack: AckSender, robot_lock: &RobotLock, audit: &AuditLog, event: &str, payload: Value, command: Result<Command, serde_json::Error>) {
+    let identity = identity_of(socket);
+    let name = command.as_ref().map_or(event, |command| command.name());
+    let result = match command {
+        Ok(command) => execute_authorized(robot_lock, &identity, lease_token(socket).as_deref(), command).await,
+        Err(err) => Err(CommandError::Malformed { message: err.to_string() }),
+    };
+    audit.record(&origin_of(socket), name, &payload, &result);
+
+    if let Err(error) = &result {
+        warn!("rejected '{}' from {}: {}", event, socket.id, error);
+        let _ = socket.emit("command error", CommandErrorEvent { command: event.to_string(), error: error.clone() });
+    }
+
+    let _ = ack.send(CommandAck::new(event, None, result));
+}
+
+/// Requests, renews or releases control for a socket, answering its ack callback.
+/// A socket that is granted control joins the lease's room so it is told when it loses control.
+async fn handle_control(socket: &SocketRef, ack: AckSender, robot_lock: &RobotLock, operation: ControlOperation<'_>) {
+    let result = control_authorized(robot_lock, &identity_of(socket), operation).await;
+
+    match &result {
+        Ok(grant) => {
+            if let Some(previous) = socket.extensions.remove::<LeaseGrant>() {
+                let _ = socket.leave(lease_room(previous.status.lease_id));
+            }
+            if let Some(grant) = grant {
+                let _ = socket.join(lease_room(grant.status.lease_id));
+                socket.extensions.insert(grant.clone());
+            }
+        }
+        Err(error) => warn!("rejected control request from {}: {}", socket.id, error),
+    }
+
+    let _ = ack.send(ControlAck::new(result));
+}
+
+/// Saves or restores the robot's state for a socket, answering its ack callback.
+async fn handle_snapshot(socket: &SocketRef, ack: AckSender, robot_lock: &RobotLock, audit: &AuditLog, operation: SnapshotOperation) {
+    let result = snapshot_authorized(robot_lock, &identity_of(socket), lease_token(socket).as_deref(), operation).await;
+    audit.record(&origin_of(socket), operation.name(), &Value::Null, &result.as_ref().map(SavedState::outcome).map_err(Clone::clone));
+    if let Err(error) = &result {
+        warn!("rejected {} from {}: {}", operation.name(), socket.id, error);
+    }
+    let _ = ack.send(SnapshotAck::new(result));
+}
+
+/// Saves or deletes a pose for a socket, answering its ack callback.
+async fn handle_pose(socket: &SocketRef, ack: AckSender, robot_lock: &RobotLock, operation: Result<PoseOperation, serde_json::Error>) {
+    let result = match operation {
+        Ok(operation) => pose_authorized(robot_lock, &identity_of(socket), operation).await,
+        Err(err) => Err(CommandError::Malformed { message: err.to_string() }),
+    };
+    if let Err(error) = &result {
+        warn!("rejected pose change from {}: {}", socket.id, error);
+    }
+    let _ = ack.send(PoseAck::new(result));
+}
+
+/// Uploads, deletes, runs or controls a script for a socket, answering its ack callback.
+/// A script started by the socket sends its commands with the socket's identity and control lease.
+fn handle_script(socket: &SocketRef, ack: AckSender, scripts: &Scripts, operation: Result<ScriptOperation, serde_json::Error>) {
+    let result = match operation {
+        Ok(operation) => script_authorized(scripts, &identity_of(socket), origin_of(socket).remote_addr, lease_token(socket).as_deref(), operation),
+        Err(err) => Err(CommandError::Malformed { message: err.to_string() }),
+    };
+    if let Err(error) = &result {
+        warn!("rejected script request from {}: {}", socket.id, error);
+    }
+    let _ = ack.send(ScriptAck::new(result));
+}
+
+/// Decodes a command from an event's payload, keeping the payload as received for the audit log.
+fn parse_payload<T: DeserializeOwned>(data: Result<Value, serde_json::Error>, command: fn(T) -> Command) -> (Value, Result<Command, serde_json::Error>) {
+    match data {
+        Ok(payload) => {
+            let command = T::deserialize(&payload).map(command);
+            (payload, command)
+        }
+        Err(err) => (Value::Null, Err(err)),
+    }
+}
+
+/// Who sent a socket's commands, for the audit log.
+fn origin_of(socket: &SocketRef) -> Origin {
+    Origin {
+        transport: "socket.io",
+        client_id: Some(socket.id.to_string()),
+        remote_addr: socket.req_parts().extensions.get::<ConnectInfo<SocketAddr>>().map(|ConnectInfo(addr)| *addr),
+        identity: Some(identity_of(socket)),
+    }
+}
+
+fn identity_of(socket: &SocketRef) -> Identity {
+    // Sockets are only given handlers once they have authenticated.
+    socket.extensions.get::<Identity>().map(|identity| identity.clone()).expect("socket has no identity")
+}
+
+/// The token of the control lease last granted to the socket.
+fn lease_token(socket: &SocketRef) -> Option<String> {
+    socket.extensions.get::<LeaseGrant>().map(|grant| grant.token.clone())
+}
+
+/// The Socket.IO room of the client holding lease `lease_id`.
+fn lease_room(lease_id: u64) -> String {
+    format!("lease {}", lease_id)
+}
+
+/// The `auth` payload of a Socket.IO connection.
+#[derive(serde::Deserialize, Clone, Debug, Default)]
+struct SocketCredentials {
+    #[serde(default)]
+    token: Option<String>,
+}
+
+/// The Socket.IO room sent the default telemetry by the broadcast loop.
+const DEFAULT_TELEMETRY_ROOM: &str = "default telemetry";
+
+/// Reply to a Socket.IO `subscribe` or `unsubscribe`.
+#[derive(serde::Serialize, Clone, Debug, schemars::JsonSchema, ts_rs::TS)]
+struct SubscriptionAck {
+    /// The streams the client is subscribed to afterwards.
+    subscriptions: Vec<StreamSubscription>,
+    error: Option<String>,
+}
+
+impl From<Result<Vec<StreamSubscription>, String>> for SubscriptionAck {
+    fn from(result: Result<Vec<StreamSubscription>, String>) -> Self {
+        match result {
+            Ok(subscriptions) => SubscriptionAck { subscriptions, error: None },
+            Err(error) => SubscriptionAck { subscriptions: Vec::new(), error: Some(error) },
+        }
+    }
+}
+
+/// The telemetry streams a Socket.IO client chose. A task forwarding the chosen streams is started on the first subscribe.
+#[derive(Clone, Default)]
+struct SocketSubscriptions {
+    subscriptions: Arc<std::sync::Mutex<Subscriptions>>,
+    forwarder: Arc<std::sync::Mutex<Option<JoinHandle<()>>>>,
+}
+
+impl SocketSubscriptions {
+    fn subscribe(&self, socket: SocketRef, telemetry: &TelemetrySender, requests: Vec<StreamRequest>) -> Result<Vec<StreamSubscription>, String> {
+        let mut subscriptions = self.subscriptions.lock().unwrap();
+        subscriptions.subscribe(requests)?;
+
+        let mut forwarder = self.forwarder.lock().unwrap();
+        if forwarder.is_none() {
+            // The client now picks its own streams so stop sending it the default telemetry.
+            let _ = socket.leave(DEFAULT_TELEMETRY_ROOM);
+            *forwarder = Some(tokio::spawn(forward_telemetry(socket, telemetry.subscribe(), self.subscriptions.clone())));
+        }
+
+        Ok(subscriptions.list())
+    }
+
+    fn unsubscribe(&self, streams: &[Stream]) -> Vec<StreamSubscription> {
+        let mut subscriptions = self.subscriptions.lock().unwrap();
+        subscriptions.unsubscribe(streams);
+        subscriptions.list()
+    }
+
+    fn stop(&self) {
+        if let Some(forwarder) = self.forwarder.lock().unwrap().take() {
+            forwarder.abort();
+        }
+    }
+}
+
+/// Emits the telemetry a Socket.IO client is subscribed to, each stream as its own event.
+async fn forward_telemetry(socket: SocketRef, mut telemetry: tokio::sync::broadcast::Receiver<Telemetry>, subscriptions: Arc<std::sync::Mutex<Subscriptions>>) {
+    loop {
+        let message = match telemetry.recv().await {
+            Ok(message) => message,
+            Err(RecvError::Lagged(_)) => continue,
+            Err(RecvError::Closed) => return,
+        };
+
+        if !subscriptions.lock().unwrap().should_send(&message) {
+            continue;
+        }
+
+        let _ = match message {
+            Telemetry::JointState(state) => socket.emit("joint state", state),
+            Telemetry::BaseCoords(coords) => socket.emit("base coords", coords),
+            Telemetry::Velocity(velocity) => socket.emit("velocity", velocity),
+            Telemetry::Links(links) => socket.emit("links", links),
+            Telemetry::Fault(fault) => socket.emit("fault", fault),
+            Telemetry::Control(control) => socket.emit("control", control),
+            Telemetry::Frame(frame) => socket.emit("telemetry", frame),
+            Telemetry::Script(event) => socket.emit("script", event),
+        };
+    }
+}
+
+/// Emits script output and status changes as `script` events to the Socket.IO clients receiving the default telemetry.
+fn forward_script_events(io: SocketIo, mut telemetry: tokio::sync::broadcast::Receiver<Telemetry>) {
+    tokio::spawn(async move {
+        loop {
+            match telemetry.recv().await {
+                Ok(Telemetry::Script(event)) => {
+                    let _ = io.to(DEFAULT_TELEMETRY_ROOM).emit("script", event);
+                }
+                Ok(_) | Err(RecvError::Lagged(_)) => continue,
+                Err(RecvError::Closed) => return,
+            }
+        }
+    });
+}
+
 pub type RobotLock = Arc<RwLock<Robot>>;
 pub struct Robot {
     /// The current state of the robot.
@@ -105,25 +547,80 @@ pub struct Robot {
     target_coord_state: Option<Coord4DOF>,
     /// The robot's velocity.
     velocity: RobotState,
+    /// Whether the last ik solution used feedforward. `None` when no coordinate target was solved.
+    ik_feedforward: Option<bool>,
+    /// Set when the simulation becomes non-finite. While faulted the robot holds still and rejects motion commands.
+    fault: Option<String>,
+    /// Which client, if any, has exclusive control of motion.
+    control: Control,
+    /// Publishes the state so readers never need to take the lock.
+    snapshots: SnapshotSender,
+    /// Records the state and commands to disk when enabled.
+    recorder: Option<Recorder>,
+    /// Set when a recording is played back instead of simulating.
+    replay: Option<Replay>,
+    /// Where the state is saved to survive a restart.
+    state_file: StateFile,
+    /// Poses saved by name for `MoveToPose`.
+    poses: PoseLibrary,
+    /// The controller's limits and gains.
+    config: RobotConfig,
 }
 
 impl Robot {
     pub async fn new() -> Arc<RwLock<Self>> {
-        let robot_lock: RobotLock = Arc::new(RwLock::new(Self { state: RobotState::default(), target_state: RobotState::default(), target_coord_state: None, velocity: RobotState::default()}));
-        
         // Enables logging
         tracing::subscriber::set_global_default(FmtSubscriber::default()).expect("Unable to enable logging");
+
+        // Replay a recording instead of simulating when one is given. A replay is not recorded again.
+        let replay = std::env::var(REPLAY_ENV).ok().map(|path| Replay::load(&path).expect("Could not load the recording to replay"));
+        let recorder = match replay {
+            Some(_) => None,
+            None => Recorder::from_env(Duration::from_millis(BROADCAST_PERIOD_MS)).expect("Could not start recording"),
+        };
+
+        // The saved state is not touched while replaying.
+        let replaying = replay.is_some();
+        let state_file = StateFile::from_env().expect("Invalid state file settings");
+        let poses = PoseLibrary::load().expect("Could not load the pose library");
+        let config = RobotConfig::load().expect("Could not load the robot config");
+
+        let robot_lock: RobotLock = Arc::new(RwLock::new(Self::simulation(config, poses, state_file.clone(), recorder, replay)));
+        let snapshots = robot_lock.read().await.subscribe_snapshots();
+
+        // Carry on from the last save rather than starting at the origin.
+        if std::env::args().skip(1).any(|arg| arg == RESUME_ARG) {
+            // Refuse to start rather than quietly start from the origin when the save cannot be used.
+            persistence::resume(&mut *robot_lock.write().await).expect("Could not resume from the saved state");
+        }
+
+        // Refuse to start rather than run without the configured authentication.
+        let auth: SharedAuth = Arc::new(Auth::load().expect("Could not load the auth config"));
         
+        // Refuse to start rather than run without the configured audit log.
+        let audit: SharedAudit = Arc::new(AuditLog::start(AuditConfig::load().expect("Could not load the audit config")).expect("Could not open the audit log"));
+
+        // Keep the recent telemetry so late clients can draw what they missed.
+        let history: SharedHistory = Arc::new(std::sync::Mutex::new(History::from_env().expect("Invalid history duration")));
+
+        // Telemetry for clients that choose their own streams.
+        let (telemetry, _): (TelemetrySender, _) = tokio::sync::broadcast::channel(TELEMETRY_CHANNEL_CAPACITY);
+
+        // Motion programs run on their own threads and publish their output as telemetry.
+        let scripts: SharedScripts = Arc::new(Scripts::from_env(robot_lock.clone(), snapshots.clone(), telemetry.clone(), audit.clone()));
+
         // Create websocket.
-        let (layer, io) = SocketIo::builder().with_state(robot_lock.clone()).build_layer();
+        let (layer, io) = SocketIo::builder().with_state(robot_lock.clone()).with_state(telemetry.clone()).with_state(snapshots.clone()).with_state(auth.clone()).with_state(history.clone()).with_state(audit.clone()).with_state(scripts.clone()).build_layer();
 
         io.ns("/", on_connect);
-
-        let io_handler: Arc<RwLock<SocketIo>> = Arc::new(RwLock::new(io));
+        forward_script_events(io.clone(), telemetry.subscribe());
 
         let app: Router = axum::Router::new()
             .route("/", get(|| async { "Robot Server" }))
-            .with_state(io_handler.clone())
+            .with_state(io.clone())
+            .merge(rest::router(robot_lock.clone(), snapshots.clone(), history.clone(), audit.clone(), scripts.clone(), auth.clone()))
+            .merge(protocol::router())
+            .merge(ws::router(robot_lock.clone(), telemetry.clone(), audit.clone(), scripts.clone(), auth.clone()))
             .layer(
                 ServiceBuilder::new()
                     .layer(CorsLayer::permissive())
@@ -133,35 +630,125 @@ impl Robot {
             // Start the controller and broadcasting state messages to client's.
             Self::controller(robot_lock.clone());
 
-            Self::broadcast(robot_lock.clone(), io_handler.clone());
+            if !replaying {
+                persistence::autosave(snapshots.clone(), state_file.clone());
+            }
+
+            Self::broadcast(snapshots, io, telemetry.clone(), history);
+
+            // Run the program given on the command line with full rights. The server keeps running once it stops.
+            if let Some(program) = std::env::args().skip_while(|arg| arg != PROGRAM_ARG).nth(1) {
+                let identity = Identity { name: "cli".to_string(), role: Role::Admin };
+                if let Err(error) = scripts.run_file(std::path::Path::new(&program), &identity) {
+                    error!("Could not run {}: {}", program, error);
+                    std::process::exit(1);
+                }
+            }
+
+            #[cfg(feature = "mqtt")]
+            match mqtt::MqttConfig::load() {
+                Ok(config) => mqtt::start(config, robot_lock.clone(), telemetry.clone(), audit.clone()),
+                Err(err) => error!("Could not load MQTT config, MQTT bridge disabled: {}", err),
+            }
+
+            #[cfg(feature = "modbus")]
+            match modbus::ModbusConfig::load() {
+                Ok(config) => modbus::start(config, robot_lock.clone(), audit.clone()),
+                Err(err) => error!("Could not load modbus config, modbus server disabled: {}", err),
+            }
+
+            // Serve rosbridge on its own port so ROS clients can use their default address.
+            let rosbridge_app = rosbridge::router(robot_lock.clone(), telemetry.clone(), audit.clone(), auth.clone());
+            tokio::spawn(async move {
+                axum::Server::bind(&rosbridge::ROSBRIDGE_ADDR.parse().unwrap())
+                    .serve(rosbridge_app.into_make_service_with_connect_info::<SocketAddr>())
+                    .await.expect("Could not start rosbridge websocket");
+            });
+
+            let server = axum::Server::bind(&"127.0.0.1:3000".parse().unwrap()).serve(app.into_make_service_with_connect_info::<SocketAddr>());
+            tokio::select! {
+                result = server => result.expect("Could not start websocket"),
+                _ = tokio::signal::ctrl_c() => info!("shutting down"),
+            }
+
+            // Save the final state so `--resume` carries on from exactly where the server stopped.
+            if !replaying && state_file.autosave_period.is_some() {
+                if let Err(err) = persistence::save(&robot_lock).await {
+                    error!("could not save the state on shutdown: {}", err);
+                }
+            }
 
-            axum::Server::bind(&"127.0.0.1:3000".parse().unwrap())
-                .serve(app.into_make_service())
-                .await.expect("Could not start websocket");
+            // Complete the recording so it can be read back.
+            let recorder = robot_lock.write().await.recorder.take();
+            if let Some(recorder) = recorder {
+                let _ = tokio::task::spawn_blocking(move || recorder.finish()).await;
+            }
 
             robot_lock
         }
 
+    /// A robot at the origin with no targets, simulated with `config`.
+    fn simulation(config: RobotConfig, poses: PoseLibrary, state_file: StateFile, recorder: Option<Recorder>, replay: Option<Replay>) -> Self {
+        Self { state: RobotState::default(), target_state: RobotState::default(), target_coord_state: None, velocity: RobotState::default(), ik_feedforward: None, fault: None, control: Control::default(), snapshots: SnapshotSender::new(RobotSnapshot::default()), recorder, replay, state_file, poses, config }
+    }
+
+    /// A simulated robot for tests with an empty pose library. Poses and saved state are written into `dir`.
+    #[cfg(test)]
+    fn test(config: RobotConfig, dir: &std::path::Path) -> Self {
+        let state_file = StateFile { path: dir.join("robot_state.json"), autosave_period: None };
+        Self::simulation(config, PoseLibrary::new(dir.join("poses.json")), state_file, None, None)
+    }
+
     /// Starts a thread that works to broadcast the state of the robot to client's.
-    fn broadcast(robot_lock: RobotLock, io_handler: Arc<RwLock<SocketIo>>) {
+    fn broadcast(snapshots: SnapshotReceiver, io: SocketIo, telemetry: TelemetrySender, history: SharedHistory) {
         tokio::spawn(async move {
+            let mut last_fault = None;
+            let mut last_control: Option<ControlStatus> = None;
+            let mut sequence: u64 = 0;
             loop {
                 let start = Instant::now();
                 
-                let state;
-                let coords;
-                {
-                    let robot = robot_lock.read().await;
-                    state = robot.get_state();
-                    coords = robot.get_coord_state();
+                // Sample the latest snapshot published by the controller. Borrowing it never waits on the robot lock.
+                let (frame, links) = {
+                    let snapshot = snapshots.borrow();
+                    (TelemetryFrame::new(&snapshot, sequence), snapshot.links)
+                };
+                sequence += 1;
+
+                let _ = io.to(DEFAULT_TELEMETRY_ROOM).emit("telemetry", frame.clone());
+                let _ = io.to(DEFAULT_TELEMETRY_ROOM).emit("joint state", frame.state);
+                let _ = io.to(DEFAULT_TELEMETRY_ROOM).emit("base coords", frame.coords);
+
+                // Only notify clients of faults when they are raised or cleared.
+                if frame.fault != last_fault {
+                    let _ = io.to(DEFAULT_TELEMETRY_ROOM).emit("fault", frame.fault.clone());
                 }
 
-                // This is bad. Fix this.
-                {
-                    let socket = io_handler.read().await;
-                    let _ = socket.emit("joint state", state);
-                    let _ = socket.emit("base coords", coords);
+                // Tell the previous holder when its lease expires or is taken over. Renewals keep the lease id.
+                let control_changed = frame.control != last_control;
+                let lease_id = |control: &Option<ControlStatus>| control.as_ref().map(|control| control.lease_id);
+                if let Some(previous) = lease_id(&last_control).filter(|previous| lease_id(&frame.control) != Some(*previous)) {
+                    let _ = io.to(lease_room(previous)).emit("control lost", frame.control.clone());
                 }
+                if control_changed {
+                    let _ = io.to(DEFAULT_TELEMETRY_ROOM).emit("control", frame.control.clone());
+                }
+
+                // Publish every stream to clients that chose their own. Sending fails only when there are no subscribers.
+                let _ = telemetry.send(Telemetry::JointState(frame.state));
+                let _ = telemetry.send(Telemetry::BaseCoords(frame.coords));
+                let _ = telemetry.send(Telemetry::Velocity(frame.velocity));
+                let _ = telemetry.send(Telemetry::Links(links));
+                if frame.fault != last_fault {
+                    let _ = telemetry.send(Telemetry::Fault(frame.fault.clone()));
+                    last_fault = frame.fault.clone();
+                }
+                if control_changed {
+                    let _ = telemetry.send(Telemetry::Control(frame.control.clone()));
+                    last_control = frame.control.clone();
+                }
+                lock_history(&history).push(frame.clone());
+                let _ = telemetry.send(Telemetry::Frame(Box::new(frame)));
 
                 // Sleep to keep the loop operating at the specified frequency.
                 let loop_duration = Instant::now().duration_since(start);
@@ -178,92 +765,18 @@ impl Robot {
         tokio::spawn(async move {
             loop {
                 let start = Instant::now();
-                
-                // If a target coordinate state exists perform ik to calculate the required joint target.
-                {
-                    let target_coord_state = robot_lock.read().await.target_coord_state;
-                    if let Some(coord_state) = target_coord_state {
-                        let found_state = robot_lock.write().await.ik(coord_state, true).is_some();
-                        // If feedforwad ik cannot find a solution try without as it may cause it to command an out of reach position.
-                        if !found_state {robot_lock.write().await.ik(coord_state, false);}
-                    }
-                }
 
-                // Collect values from the robot after ik.
-                let joint_state;
-                let joint_target;
-                let base_state;
-                let base_target;
-                let veloctiy;
-                {
-                    let robot = robot_lock.read().await;
-                    joint_state = robot.state.joint_state;
-                    joint_target = robot.target_state.joint_state;
-
-                    base_state = robot.state.base_state;
-                    base_target = robot.target_state.base_state;
-                    veloctiy = robot.velocity;
-                }
-                
-                // Perform controller calcualtions for base motion. Find the error and feed it into the PD controller for velocity.
-                let mut base_state_error = Coord4DOF::default();
-                base_state_error.x = base_target.x - base_state.x;
-                base_state_error.y = base_target.y - base_state.y;
-                base_state_error.z = base_target.z - base_state.z;
-                base_state_error.theta = shortest_angle_diff(base_target.theta, base_state.theta);
-                
-                let mut base_velocity = base_state_error.apply_control(BASE_LINEAR_P, BASE_ANGLE_P);
-                base_velocity = base_velocity - base_velocity.apply_control(BASE_LINEAR_D, BASE_ANGLE_D);
-                
-                base_velocity.clamp(MAX_BASE_LINEAR_VEL, MAX_BASE_ANGLE_VEL);
-                
-                // Update base state with velocity.
-                let new_base_state = base_state + base_velocity.val_mul(CONTROLLER_LOOP_TIME_S);
-                
-                
-                // Perform controller calcualtions for joint motion. Find the error and feed it into the PD controller for acceleration.
-                let joint_state_error = JointState::clamped_sub(joint_target, joint_state);
-                
-                let mut joint_state_velocity: JointState = veloctiy.joint_state;
-                let mut joint_state_acceleration = JointState::default();
-
-                // Calculate P.
-                joint_state_acceleration.swing_rotation_deg = joint_state_error.swing_rotation_deg*ANGLE_P;
-                joint_state_acceleration.lift_elevation_mm = joint_state_error.lift_elevation_mm*LINEAR_P;
-                joint_state_acceleration.elbow_rotation_deg = joint_state_error.elbow_rotation_deg*ANGLE_P;
-                joint_state_acceleration.wrist_rotation_deg = joint_state_error.wrist_rotation_deg*ANGLE_P;
-                joint_state_acceleration.gripper_open_mm = joint_state_error.gripper_open_mm*LINEAR_P;
-
-                // Caculate D.
-                joint_state_acceleration.swing_rotation_deg += -joint_state_velocity.swing_rotation_deg*ANGLE_D;
-                joint_state_acceleration.lift_elevation_mm += -joint_state_velocity.lift_elevation_mm*LINEAR_D;
-                joint_state_acceleration.elbow_rotation_deg += -joint_state_velocity.elbow_rotation_deg*ANGLE_D;
-                joint_state_acceleration.wrist_rotation_deg += -joint_state_velocity.wrist_rotation_deg*ANGLE_D;
-                joint_state_acceleration.gripper_open_mm += -joint_state_velocity.gripper_open_mm*LINEAR_D;
-
-                // Clamp acceleration within the max. The max acceleration is inversely scaled by the length of the arms to allow the end effector to be moved equally by all joints.
-                joint_state_acceleration.swing_rotation_deg = joint_state_acceleration.swing_rotation_deg.clamp(-MAX_ANGULAR_ACCELERATION/ELBOW_LENGTH_M, MAX_ANGULAR_ACCELERATION/ELBOW_LENGTH_M);
-                joint_state_acceleration.lift_elevation_mm = joint_state_acceleration.lift_elevation_mm.clamp(-MAX_LINEAR_ACCELERATION, MAX_LINEAR_ACCELERATION);
-                joint_state_acceleration.elbow_rotation_deg = joint_state_acceleration.elbow_rotation_deg.clamp(-MAX_ANGULAR_ACCELERATION, MAX_ANGULAR_ACCELERATION);
-                joint_state_acceleration.wrist_rotation_deg = joint_state_acceleration.wrist_rotation_deg.clamp(-MAX_ANGULAR_ACCELERATION/GRIPPER_LENGTH_M, MAX_ANGULAR_ACCELERATION/GRIPPER_LENGTH_M);
-                joint_state_acceleration.gripper_open_mm = joint_state_acceleration.gripper_open_mm.clamp(-MAX_LINEAR_ACCELERATION, MAX_LINEAR_ACCELERATION);
-
-                // Apply acceleration to update the velocity.
-                joint_state_velocity = joint_state_velocity + joint_state_acceleration.val_mul(CONTROLLER_LOOP_TIME_S);
-
-                // Clamp velocity within the max. The max acceleration is inversely scaled by the length of the arms to allow the end effector to be moved equally by all joints.
-                joint_state_velocity.swing_rotation_deg = joint_state_velocity.swing_rotation_deg.clamp(-MAX_ANGULAR_VELOCITY/ELBOW_LENGTH_M, MAX_ANGULAR_VELOCITY/ELBOW_LENGTH_M);
-                joint_state_velocity.lift_elevation_mm = joint_state_velocity.lift_elevation_mm.clamp(-MAX_LINEAR_VELOCITY, MAX_LINEAR_VELOCITY);
-                joint_state_velocity.elbow_rotation_deg = joint_state_velocity.elbow_rotation_deg.clamp(-MAX_ANGULAR_VELOCITY, MAX_ANGULAR_VELOCITY);
-                joint_state_velocity.wrist_rotation_deg = joint_state_velocity.wrist_rotation_deg.clamp(-MAX_ANGULAR_VELOCITY/GRIPPER_LENGTH_M, MAX_ANGULAR_VELOCITY/GRIPPER_LENGTH_M);
-                joint_state_velocity.gripper_open_mm = joint_state_velocity.gripper_open_mm.clamp(-MAX_LINEAR_VELOCITY, MAX_LINEAR_VELOCITY);
-
-                // Update by applying velocity to the current state and storing the velocity of the joints and base.
+                // Take the lock once per tick and publish the result so readers never wait on the controller.
                 {
                     let mut robot = robot_lock.write().await;
-                    robot.set_state(joint_state+joint_state_velocity.val_mul(CONTROLLER_LOOP_TIME_S), new_base_state);
-                    robot.velocity.joint_state = joint_state_velocity;
-                    robot.velocity.base_state = base_velocity;
+
+                    // Play back the recording in place of the simulation, otherwise hold still while faulted.
+                    if let Some(replay) = &mut robot.replay {
+                        replay.advance();
+                    } else if !robot.is_faulted() {
+                        robot.step(CONTROLLER_LOOP_TIME_S);
+                    }
+                    robot.publish_snapshot();
                 }
 
                 // Sleep to keep the loop operating at the specified frequency.
@@ -276,6 +789,286 @@ impl Robot {
 
     }
 
+    /// Advances the simulation by one controller tick of `dt` seconds.
+    /// With dynamics the tick is split into steps of at most `dynamics::MAX_STEP_S`, as the arm's response diverges over longer steps.
+    fn step(&mut self, dt: f64) {
+        let substeps = match self.config.dynamics {
+            Some(_) => (dt / dynamics::MAX_STEP_S).ceil().max(1.0),
+            None => 1.0,
+        };
+        for _ in 0..substeps as usize {
+            self.integrate(dt / substeps);
+        }
+    }
+
+    /// Runs the controller once and moves the robot on by `dt` seconds.
+    #[allow(clippy::field_reassign_with_default)]
+    fn integrate(&mut self, dt: f64) {
+        // If a target coordinate state exists perform ik to calculate the required joint target.
+        if let Some(coord_state) = self.target_coord_state {
+            self.solve_ik(coord_state);
+        }
+
+        // Collect values from the robot after ik.
+        let joint_state = self.state.joint_state;
+        let joint_target = self.target_state.joint_state;
+        let base_state = self.state.base_state;
+        let base_target = self.target_state.base_state;
+        let veloctiy = self.velocity;
+        let config = &self.config;
+        
+        // Perform controller calcualtions for base motion. Find the error and feed it into the PD controller for velocity.
+        let mut base_state_error = Coord4DOF::default();
+        base_state_error.x = base_target.x - base_state.x;
+        base_state_error.y = base_target.y - base_state.y;
+        base_state_error.z = base_target.z - base_state.z;
+        base_state_error.theta = shortest_angle_diff(base_target.theta, base_state.theta);
+        
+        let mut base_velocity = base_state_error.apply_control(config.base_linear_p, config.base_angle_p);
+        base_velocity = base_velocity - base_velocity.apply_control(config.base_linear_d, config.base_angle_d);
+        
+        base_velocity.clamp(config.max_base_linear_vel, config.max_base_angle_vel);
+        
+        // Update base state with velocity.
+        let new_base_state = base_state + base_velocity.val_mul(dt);
+        
+        
+        // Perform controller calcualtions for joint motion. Find the error and feed it into the PD controller for acceleration.
+        let joint_state_error = JointState::clamped_sub(joint_target, joint_state);
+        
+        let mut joint_state_velocity: JointState = veloctiy.joint_state;
+        if let Some(dynamics) = &config.dynamics {
+            // The PD output is the motors' effort and the joints respond to it with the arm's inertia, gravity and friction.
+            joint_state_velocity = dynamics.step(joint_state, joint_state_error, joint_state_velocity, dt);
+        } else {
+            let mut joint_state_acceleration = JointState::default();
+
+            // Calculate P.
+            joint_state_acceleration.swing_rotation_deg = joint_state_error.swing_rotation_deg*config.angle_p;
+            joint_state_acceleration.lift_elevation_mm = joint_state_error.lift_elevation_mm*config.linear_p;
+            joint_state_acceleration.elbow_rotation_deg = joint_state_error.elbow_rotation_deg*config.angle_p;
+            joint_state_acceleration.wrist_rotation_deg = joint_state_error.wrist_rotation_deg*config.angle_p;
+            joint_state_acceleration.gripper_open_mm = joint_state_error.gripper_open_mm*config.linear_p;
+
+            // Caculate D.
+            joint_state_acceleration.swing_rotation_deg += -joint_state_velocity.swing_rotation_deg*config.angle_d;
+            joint_state_acceleration.lift_elevation_mm += -joint_state_velocity.lift_elevation_mm*config.linear_d;
+            joint_state_acceleration.elbow_rotation_deg += -joint_state_velocity.elbow_rotation_deg*config.angle_d;
+            joint_state_acceleration.wrist_rotation_deg += -joint_state_velocity.wrist_rotation_deg*config.angle_d;
+            joint_state_acceleration.gripper_open_mm += -joint_state_velocity.gripper_open_mm*config.linear_d;
+
+            // Clamp acceleration within the max. The max acceleration is inversely scaled by the length of the arms to allow the end effector to be moved equally by all joints.
+            joint_state_acceleration.swing_rotation_deg = joint_state_acceleration.swing_rotation_deg.clamp(-config.max_angular_acceleration/ELBOW_LENGTH_M, config.max_angular_acceleration/ELBOW_LENGTH_M);
+            joint_state_acceleration.lift_elevation_mm = joint_state_acceleration.lift_elevation_mm.clamp(-config.max_linear_acceleration, config.max_linear_acceleration);
+            joint_state_acceleration.elbow_rotation_deg = joint_state_acceleration.elbow_rotation_deg.clamp(-config.max_angular_acceleration, config.max_angular_acceleration);
+            joint_state_acceleration.wrist_rotation_deg = joint_state_acceleration.wrist_rotation_deg.clamp(-config.max_angular_acceleration/GRIPPER_LENGTH_M, config.max_angular_acceleration/GRIPPER_LENGTH_M);
+            joint_state_acceleration.gripper_open_mm = joint_state_acceleration.gripper_open_mm.clamp(-config.max_linear_acceleration, config.max_linear_acceleration);
+
+            // Apply acceleration to update the velocity.
+            joint_state_velocity = joint_state_velocity + joint_state_acceleration.val_mul(dt);
+        }
+
+        // Clamp velocity within the max. The max acceleration is inversely scaled by the length of the arms to allow the end effector to be moved equally by all joints.
+        joint_state_velocity.swing_rotation_deg = joint_state_velocity.swing_rotation_deg.clamp(-config.max_angular_velocity/ELBOW_LENGTH_M, config.max_angular_velocity/ELBOW_LENGTH_M);
+        joint_state_velocity.lift_elevation_mm = joint_state_velocity.lift_elevation_mm.clamp(-config.max_linear_velocity, config.max_linear_velocity);
+        joint_state_velocity.elbow_rotation_deg = joint_state_velocity.elbow_rotation_deg.clamp(-config.max_angular_velocity, config.max_angular_velocity);
+        joint_state_velocity.wrist_rotation_deg = joint_state_velocity.wrist_rotation_deg.clamp(-config.max_angular_velocity/GRIPPER_LENGTH_M, config.max_angular_velocity/GRIPPER_LENGTH_M);
+        joint_state_velocity.gripper_open_mm = joint_state_velocity.gripper_open_mm.clamp(-config.max_linear_velocity, config.max_linear_velocity);
+
+        // Update by applying velocity to the current state and storing the velocity of the joints and base.
+        let new_joint_state = joint_state+joint_state_velocity.val_mul(dt);
+        if config.dynamics.is_some() {
+            joint_state_velocity = dynamics::stop_at_end_stops(new_joint_state, joint_state_velocity);
+        }
+
+        // Fail safe rather than letting a non-finite value poison the simulation.
+        if !new_joint_state.is_finite() || !new_base_state.is_finite() || !joint_state_velocity.is_finite() || !base_velocity.is_finite() {
+            self.enter_fault("controller produced a non-finite state".to_string());
+        } else {
+            self.set_state(new_joint_state, new_base_state);
+            self.velocity.joint_state = joint_state_velocity;
+            self.velocity.base_state = base_velocity;
+        }
+    }
+
+    /// Validates and applies a command received from a client.
+    /// Returns the effective targets once the command has been applied.
+    /// `lease` is the control lease token presented by the client. Motion commands must present it while a lease is held.
+    pub fn execute(&mut self, command: Command, lease: Option<&str>) -> Result<CommandOutcome, CommandError> {
+        let result = self.apply(&command, lease);
+        if let Some(recorder) = &self.recorder {
+            recorder.record_command(command, &result);
+        }
+        result
+    }
+
+    fn apply(&mut self, command: &Command, lease: Option<&str>) -> Result<CommandOutcome, CommandError> {
+        if self.replay.is_some() {
+            return Err(CommandError::Replaying);
+        }
+        command.validate()?;
+
+        if command.is_motion() {
+            if let Some(reason) = &self.fault {
+                return Err(CommandError::Faulted { reason: reason.clone() });
+            }
+            self.control.check(lease)?;
+        }
+
+        let mut ik_solution = None;
+        match *command {
+            Command::SetJointState(joint_state) => self.set_joint_target_state(joint_state, true),
+            Command::SetCoordState(coord_state) => {
+                // Solve now so an unreachable target is rejected instead of silently ignored by the controller.
+                ik_solution = Some(self.solve_ik(coord_state).ok_or(CommandError::Unreachable)?);
+                self.set_target_coord_state(coord_state);
+            }
+            Command::SetBaseState(coord_state) => self.set_target_base_state(coord_state),
+            Command::SetGripper(gripper_open_mm) => self.set_gripper_target(gripper_open_mm),
+            Command::EmergencyStop => self.enter_fault("emergency stop".to_string()),
+            Command::ResetFault => self.reset_fault(),
+            Command::MoveToPose(ref name) => {
+                let pose = self.poses.get(name)?;
+                match pose.target {
+                    PoseTarget::Joints(joint_state) => self.set_joint_target_state(joint_state, true),
+                    PoseTarget::Coords(coord_state) => {
+                        ik_solution = Some(self.solve_ik(coord_state).ok_or(CommandError::Unreachable)?);
+                        self.set_target_coord_state(coord_state);
+                    }
+                }
+                if let Some(base_state) = pose.base_state {
+                    self.set_target_base_state(base_state);
+                }
+            }
+        }
+
+        // Let readers see the new targets without waiting for the next controller tick.
+        self.publish_snapshot();
+
+        Ok(CommandOutcome { target_state: self.target_state, target_coord_state: self.target_coord_state, ik_solution })
+    }
+
+    /// Grants `holder` exclusive control of motion. Fails while someone else holds control unless `take_over` is set.
+    pub fn request_control(&mut self, holder: &str, take_over: bool) -> Result<LeaseGrant, CommandError> {
+        let grant = self.control.request(holder, take_over)?;
+        info!("{} has control (lease {})", holder, grant.status.lease_id);
+        self.publish_snapshot();
+        Ok(grant)
+    }
+
+    /// Extends the control lease presented by `token`.
+    pub fn renew_control(&mut self, token: &str) -> Result<LeaseGrant, CommandError> {
+        let grant = self.control.renew(token)?;
+        self.publish_snapshot();
+        Ok(grant)
+    }
+
+    /// Gives up the control lease presented by `token`.
+    pub fn release_control(&mut self, token: &str) -> Result<(), CommandError> {
+        self.control.release(token)?;
+        info!("control released");
+        self.publish_snapshot();
+        Ok(())
+    }
+
+    /// Moves the robot straight to a saved state and its targets without simulating the motion in between.
+    /// Returns the state as applied. `lease` is the control lease token presented by the client.
+    pub fn restore(&mut self, saved: &SavedState, lease: Option<&str>) -> Result<SavedState, CommandError> {
+        if self.replay.is_some() {
+            return Err(CommandError::Replaying);
+        }
+        for state in [saved.state, saved.target_state] {
+            Command::SetJointState(state.joint_state).validate()?;
+            Command::SetBaseState(state.base_state).validate()?;
+        }
+        if let Some(coord_state) = saved.target_coord_state {
+            Command::SetCoordState(coord_state).validate()?;
+        }
+        self.control.check(lease)?;
+
+        self.set_state(saved.state.joint_state, saved.state.base_state);
+        self.velocity = RobotState::default();
+        self.set_joint_target_state(saved.target_state.joint_state, true);
+        self.set_target_base_state(saved.target_state.base_state);
+        self.target_coord_state = saved.target_coord_state;
+        self.ik_feedforward = None;
+        self.fault = saved.fault.clone();
+        info!("restored the state saved at {}", saved.timestamp);
+
+        self.publish_snapshot();
+        Ok(SavedState { timestamp: saved.timestamp, ..SavedState::from(&self.snapshot()) })
+    }
+
+    /// The file the state is saved to.
+    pub fn state_file(&self) -> &StateFile {
+        &self.state_file
+    }
+
+    /// Saves the pose in `request` or, when it has none, teaches the robot's current pose.
+    pub fn save_pose(&mut self, request: &SavePose) -> Result<Pose, CommandError> {
+        let pose = match request.pose {
+            Some(pose) => pose,
+            // The simulated state is not what is being shown while replaying.
+            None if self.replay.is_some() => return Err(CommandError::Replaying),
+            None => {
+                let target = match request.kind {
+                    PoseKind::Joints => PoseTarget::Joints(self.state.joint_state),
+                    PoseKind::Coords => PoseTarget::Coords(self.get_coord_state()),
+                };
+                Pose { target, base_state: Some(self.state.base_state) }
+            }
+        };
+        self.poses.insert(&request.name, pose)
+    }
+
+    pub fn delete_pose(&mut self, name: &str) -> Result<Pose, CommandError> {
+        self.poses.remove(name)
+    }
+
+    /// The pose library.
+    pub fn poses(&self) -> &PoseLibrary {
+        &self.poses
+    }
+
+    /// Plays, pauses, seeks or changes the speed of the recording being replayed.
+    pub fn control_replay(&mut self, control: ReplayControl) -> Result<ReplayStatus, CommandError> {
+        let status = self.replay.as_mut().ok_or(CommandError::NotReplaying)?.control(control)?;
+        info!("replay {:?}", control);
+        self.publish_snapshot();
+        Ok(status)
+    }
+
+    /// The playback status, `None` unless a recording is being replayed.
+    pub fn replay_status(&self) -> Option<ReplayStatus> {
+        self.replay.as_ref().map(Replay::status)
+    }
+
+    /// Stops all motion and rejects motion commands until the fault is reset.
+    fn enter_fault(&mut self, reason: String) {
+        error!("robot faulted: {}", reason);
+
+        self.velocity = RobotState::default();
+        self.target_state = self.state;
+        self.target_coord_state = None;
+        self.fault = Some(reason);
+    }
+
+    /// Clears the active fault. The robot stays at its last valid state.
+    pub fn reset_fault(&mut self) {
+        if self.fault.take().is_some() {
+            info!("robot fault reset");
+        }
+    }
+
+    /// Returns the reason for the active fault, if any.
+    pub fn get_fault(&self) -> Option<String> {
+        self.fault.clone()
+    }
+
+    pub fn is_faulted(&self) -> bool {
+        self.fault.is_some()
+    }
+
     // Set the state of the robot's joints and base.
     fn set_state(&mut self, mut new_joint_state: JointState, new_base_state: Coord4DOF) {
         new_joint_state.check_limits();
@@ -285,10 +1078,21 @@ impl Robot {
     }
 
     // Retyurns the state of the robot.
+    #[allow(clippy::needless_return)]
     pub fn get_state(&self) -> RobotState {
         return self.state;
     }
 
+    /// Returns the state the controller is working to reach.
+    pub fn get_target_state(&self) -> RobotState {
+        self.target_state
+    }
+
+    /// Returns the end effector coordinate being tracked with ik, if any.
+    pub fn get_target_coord_state(&self) -> Option<Coord4DOF> {
+        self.target_coord_state
+    }
+
     /// Sets the target state for all the joints of the robot. If `erase_coord_target` is true the current `target_coord_state` is erased to stop ik calcualtions.
     pub fn set_joint_target_state(&mut self, mut target_state: JointState, erase_coord_target: bool) {
         target_state.check_limits();
@@ -300,19 +1104,93 @@ impl Robot {
         }
     }
 
+    /// Sets the gripper target, leaving the other joint targets and any coordinate target in place.
+    pub fn set_gripper_target(&mut self, gripper_open_mm: f64) {
+        let mut target_state = self.target_state.joint_state;
+        target_state.gripper_open_mm = gripper_open_mm;
+        target_state.check_limits();
+        self.target_state.joint_state = target_state;
+    }
+
+    /// Returns true once the robot has reached its targets and stopped moving.
+    pub fn is_settled(&self) -> bool {
+        let error = self.tracking_error();
+
+        error.joint_state.within(self.config.settled_linear_tolerance_mm, self.config.settled_angle_tolerance_deg)
+            && self.velocity.joint_state.within(self.config.settled_linear_tolerance_mm, self.config.settled_angle_tolerance_deg)
+            && error.base_state.within(self.config.settled_base_tolerance_m, self.config.settled_angle_tolerance_deg)
+            && self.velocity.base_state.within(self.config.settled_base_tolerance_m, self.config.settled_angle_tolerance_deg)
+    }
+
+    /// Returns the difference between the target and current state, using the shortest difference for angles.
+    pub fn tracking_error(&self) -> RobotState {
+        let mut base_error = self.target_state.base_state - self.state.base_state;
+        base_error.theta = shortest_angle_diff(self.target_state.base_state.theta, self.state.base_state.theta);
+
+        RobotState { joint_state: JointState::clamped_sub(self.target_state.joint_state, self.state.joint_state), base_state: base_error }
+    }
+
+    /// Updates the joint target to reach `coord_state`, returning the solution if one exists.
+    fn solve_ik(&mut self, coord_state: Coord4DOF) -> Option<JointState> {
+        // If feedforwad ik cannot find a solution try without as it may cause it to command an out of reach position.
+        if let Some(solution) = self.ik(coord_state, true) {
+            self.ik_feedforward = Some(true);
+            return Some(solution);
+        }
+
+        let solution = self.ik(coord_state, false);
+        self.ik_feedforward = solution.map(|_| false);
+        solution
+    }
+
+    /// Captures everything clients need to know about the robot at this instant.
+    pub fn snapshot(&self) -> RobotSnapshot {
+        let links = self.get_link_coords();
+        RobotSnapshot {
+            timestamp: chrono::Utc::now(),
+            state: self.get_state(),
+            coords: links.end_effector,
+            links,
+            velocity: self.velocity,
+            target_state: self.get_target_state(),
+            target_coord_state: self.get_target_coord_state(),
+            tracking_error: self.tracking_error(),
+            ik_feedforward: self.target_coord_state.and(self.ik_feedforward),
+            settled: self.is_settled(),
+            fault: self.get_fault(),
+            control: self.control.status(),
+        }
+    }
+
+    /// Publishes the current state, or the replayed snapshot, to every `SnapshotReceiver` and the recorder.
+    fn publish_snapshot(&mut self) {
+        let snapshot = match &self.replay {
+            Some(replay) => replay.current().clone(),
+            None => self.snapshot(),
+        };
+        if let Some(recorder) = &mut self.recorder {
+            recorder.record_snapshot(&snapshot);
+        }
+        self.snapshots.send_replace(snapshot);
+    }
+
+    /// Returns a receiver that always holds the latest published snapshot.
+    pub fn subscribe_snapshots(&self) -> SnapshotReceiver {
+        self.snapshots.subscribe()
+    }
+
     /// Performs inverse kinematics using the current base position and target end effector state to return a joint state that will reach the target.
     /// Applys a feedforward approach to the position of the joints to counter the motion of the base if `apply_feedforward` is true.
+    #[allow(clippy::needless_return, clippy::needless_late_init)]
     fn ik(&mut self, coord_state: Coord4DOF, apply_feedforward: bool) -> Option<JointState> {
 
-        let mut target_state: JointState = JointState::default();
-
         // Get the radian andle of the end effector. Apply the angular velocity of base to counter its rotation.
-        let end_effector_rad = degrees_to_radians(limit_angle(coord_state.theta - self.velocity.base_state.theta*FEEDFORWARD_FACTOR));
+        let end_effector_rad = degrees_to_radians(limit_angle(coord_state.theta - self.velocity.base_state.theta*self.config.feedforward_factor));
 
         let end_effector_to_base;
         if apply_feedforward {
             // Get the radian andle of the end effector. Apply the angular velocity of base to counter its rotation.
-            let end_effector_rad = degrees_to_radians(limit_angle(coord_state.theta - self.velocity.base_state.theta*FEEDFORWARD_FACTOR));
+            let end_effector_rad = degrees_to_radians(limit_angle(coord_state.theta - self.velocity.base_state.theta*self.config.feedforward_factor));
 
             // Calculate the velocity applied to end effector due to the rotation of the base and its linear motion.
             let current_state = self.get_coord_state();
@@ -322,40 +1200,18 @@ impl Robot {
             // Get the position of the end effectors base that the wrist and elbow must be positioned to meet the end effector.
             // Apply the feedforward of the bases velocity in the xyz to counter the base's motion.
             end_effector_to_base =  Coord4DOF{
-                x: coord_state.x - self.state.base_state.x - FEEDFORWARD_FACTOR*base_applied_x_vel - GRIPPER_LENGTH_M*(end_effector_rad.cos()),
-                y: coord_state.y - self.state.base_state.y - FEEDFORWARD_FACTOR*base_applied_y_vel - GRIPPER_LENGTH_M*(end_effector_rad.sin()),
-                z: coord_state.z - self.state.base_state.z - FEEDFORWARD_FACTOR*self.velocity.base_state.z,
+                x: coord_state.x - self.state.base_state.x - self.config.feedforward_factor*base_applied_x_vel - GRIPPER_LENGTH_M*(end_effector_rad.cos()),
+                y: coord_state.y - self.state.base_state.y - self.config.feedforward_factor*base_applied_y_vel - GRIPPER_LENGTH_M*(end_effector_rad.sin()),
+                z: coord_state.z - self.state.base_state.z - self.config.feedforward_factor*self.velocity.base_state.z,
                 theta: coord_state.theta
             };
         } else {
-            end_effector_to_base =  Coord4DOF{
-                x: coord_state.x - self.state.base_state.x  - GRIPPER_LENGTH_M*(end_effector_rad.cos()),
-                y: coord_state.y - self.state.base_state.y  - GRIPPER_LENGTH_M*(end_effector_rad.sin()),
-                z: coord_state.z - self.state.base_state.z,
-                theta: coord_state.theta
-            };
+            end_effector_to_base = gripper_base(coord_state, self.state.base_state, end_effector_rad);
         }
 
-        // Using cosine law to calculate the angles required by the swing and elbow to meet the end effector.
-        let base_angle = (end_effector_to_base.y).atan2(end_effector_to_base.x);
-
-        let c = (end_effector_to_base.x.powf(2.0) + end_effector_to_base.y.powf(2.0)).sqrt();
-        if c > WRIST_LENGTH_M+ELBOW_LENGTH_M {return None;}
-
-        let elbow_angle: f64 = -(PI - ((c.powf(2.0) - ELBOW_LENGTH_M.powf(2.0) - WRIST_LENGTH_M.powf(2.0))/(-2.0*ELBOW_LENGTH_M*WRIST_LENGTH_M)).acos());
-        
-        let swing_angle_local = ((WRIST_LENGTH_M.powf(2.0) - ELBOW_LENGTH_M.powf(2.0) - c.powf(2.0))/(-2.0*ELBOW_LENGTH_M*c)).acos();
-        
-        // If no solution is found return None;
-        if elbow_angle.is_nan() || swing_angle_local.is_nan() {return None;}
-
-        let swing_angle = base_angle + swing_angle_local;
-        
-        // Apply the calculated states.
-        target_state.swing_rotation_deg = radians_to_degrees(swing_angle) - self.state.base_state.theta;
-        target_state.elbow_rotation_deg = radians_to_degrees(elbow_angle);
-        target_state.wrist_rotation_deg = radians_to_degrees(end_effector_rad - elbow_angle - swing_angle);
-        target_state.lift_elevation_mm = end_effector_to_base.z * 1000.0;
+        let mut target_state = arm_ik(end_effector_to_base, end_effector_rad, self.state.base_state.theta)?;
+        // The gripper is not part of the ik solution so keep its current target.
+        target_state.gripper_open_mm = self.target_state.joint_state.gripper_open_mm;
 
         self.set_joint_target_state(target_state, false);
         
@@ -372,32 +1228,82 @@ impl Robot {
 
     /// Get the end effectors current position in space.
     pub fn get_coord_state(&self) -> Coord4DOF {
+        self.get_link_coords().end_effector
+    }
+
+    /// Get the position of every joint in space using forward kinematics.
+    pub fn get_link_coords(&self) -> LinkCoords {
         let joint_state = self.state.joint_state;
         let base_state = self.state.base_state;
-        
-        let mut coords = Coord4DOF::default();
-        coords.x = base_state.x;
-        coords.y = base_state.y;
-        coords.z = joint_state.lift_elevation_mm/1000.0 + base_state.z;
 
         let elbow_angle_rad = degrees_to_radians(base_state.theta+ joint_state.swing_rotation_deg);
         let wrist_angle_rad = elbow_angle_rad+degrees_to_radians(joint_state.elbow_rotation_deg);
         let gripper_angle_rad = wrist_angle_rad + degrees_to_radians(joint_state.wrist_rotation_deg);
 
+        let mut swing = base_state;
+        swing.z += joint_state.lift_elevation_mm/1000.0;
+        swing.theta = radians_to_degrees(elbow_angle_rad);
+
         // Calculate elbow coordinates
-        coords.x += ELBOW_LENGTH_M * elbow_angle_rad.cos();
-        coords.y += ELBOW_LENGTH_M * elbow_angle_rad.sin();
+        let mut elbow = swing;
+        elbow.x += ELBOW_LENGTH_M * elbow_angle_rad.cos();
+        elbow.y += ELBOW_LENGTH_M * elbow_angle_rad.sin();
+        elbow.theta = radians_to_degrees(wrist_angle_rad);
 
         // Calculate wrist coordinates relative to the elbow
-        coords.x += WRIST_LENGTH_M * wrist_angle_rad.cos();
-        coords.y += WRIST_LENGTH_M * wrist_angle_rad.sin();
+        let mut wrist = elbow;
+        wrist.x += WRIST_LENGTH_M * wrist_angle_rad.cos();
+        wrist.y += WRIST_LENGTH_M * wrist_angle_rad.sin();
+        wrist.theta = radians_to_degrees(gripper_angle_rad);
 
         // Calculate gripper coordinates relative to the wrist
-        coords.x += GRIPPER_LENGTH_M * gripper_angle_rad.cos();
-        coords.y += GRIPPER_LENGTH_M * gripper_angle_rad.sin();
+        let mut end_effector = wrist;
+        end_effector.x += GRIPPER_LENGTH_M * gripper_angle_rad.cos();
+        end_effector.y += GRIPPER_LENGTH_M * gripper_angle_rad.sin();
 
-        coords.theta = radians_to_degrees(gripper_angle_rad);
+        LinkCoords { base: base_state, swing, elbow, wrist, end_effector }
+    }
+}
 
-        return coords;
+/// Returns true if ik can reach `coord_state` with the base stopped at `base_state`. Nothing is commanded.
+pub fn is_reachable(base_state: Coord4DOF, coord_state: Coord4DOF) -> bool {
+    let end_effector_rad = degrees_to_radians(limit_angle(coord_state.theta));
+    arm_ik(gripper_base(coord_state, base_state, end_effector_rad), end_effector_rad, base_state.theta).is_some()
+}
+
+/// The position of the end effector's base, where the wrist and elbow must put the gripper, relative to the base.
+fn gripper_base(coord_state: Coord4DOF, base_state: Coord4DOF, end_effector_rad: f64) -> Coord4DOF {
+    Coord4DOF{
+        x: coord_state.x - base_state.x  - GRIPPER_LENGTH_M*(end_effector_rad.cos()),
+        y: coord_state.y - base_state.y  - GRIPPER_LENGTH_M*(end_effector_rad.sin()),
+        z: coord_state.z - base_state.z,
+        theta: coord_state.theta
     }
-}
\ No newline at end of file
+}
+
+/// Solves the swing, lift, elbow and wrist to put the end effector's base at `end_effector_to_base` facing `end_effector_rad`.
+/// Returns None if the arm cannot reach it. The gripper is left closed.
+fn arm_ik(end_effector_to_base: Coord4DOF, end_effector_rad: f64, base_theta: f64) -> Option<JointState> {
+    // Using cosine law to calculate the angles required by the swing and elbow to meet the end effector.
+    let base_angle = (end_effector_to_base.y).atan2(end_effector_to_base.x);
+
+    let c = (end_effector_to_base.x.powf(2.0) + end_effector_to_base.y.powf(2.0)).sqrt();
+    if c > WRIST_LENGTH_M+ELBOW_LENGTH_M {return None;}
+
+    let elbow_angle: f64 = -(PI - ((c.powf(2.0) - ELBOW_LENGTH_M.powf(2.0) - WRIST_LENGTH_M.powf(2.0))/(-2.0*ELBOW_LENGTH_M*WRIST_LENGTH_M)).acos());
+    
+    let swing_angle_local = ((WRIST_LENGTH_M.powf(2.0) - ELBOW_LENGTH_M.powf(2.0) - c.powf(2.0))/(-2.0*ELBOW_LENGTH_M*c)).acos();
+    
+    // If no solution is found return None;
+    if elbow_angle.is_nan() || swing_angle_local.is_nan() {return None;}
+
+    let swing_angle = base_angle + swing_angle_local;
+    
+    Some(JointState {
+        swing_rotation_deg: radians_to_degrees(swing_angle) - base_theta,
+        lift_elevation_mm: end_effector_to_base.z * 1000.0,
+        elbow_rotation_deg: radians_to_degrees(elbow_angle),
+        wrist_rotation_deg: radians_to_degrees(end_effector_rad - elbow_angle - swing_angle),
+        gripper_open_mm: 0.0,
+    })
+}
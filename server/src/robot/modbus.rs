@@ -0,0 +1,438 @@
+use super::audit::{Origin, SharedAudit};
+use super::command::{Command, CommandError};
+use super::robot_state::{Coord4DOF, JointState};
+use super::{Robot, RobotLock};
+
+use std::future::Future;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::Arc;
+
+use tokio::net::TcpListener;
+use tokio_modbus::prelude::{ExceptionCode, Request, Response};
+use tokio_modbus::server::tcp::{accept_tcp_connection, Server};
+use tokio_modbus::server::Service;
+use tracing::{error, info, warn};
+
+/// Environment variable holding the path of the JSON Modbus configuration file.
+pub const MODBUS_CONFIG_ENV: &str = "ROBOT_MODBUS_CONFIG";
+
+// Register map. Every value is a signed 32 bit integer spread over two registers, high word first.
+// Offsets are relative to the configured base address of each table.
+
+/// Holding registers 0-9: joint target (swing, lift, elbow, wrist, gripper).
+pub const HOLDING_JOINT_TARGET: u16 = 0;
+/// Holding registers 10-17: end effector target (x, y, z, theta). Reads the current pose when no target is set.
+pub const HOLDING_COORD_TARGET: u16 = 10;
+/// Holding registers 18-25: base target (x, y, z, theta).
+pub const HOLDING_BASE_TARGET: u16 = 18;
+const HOLDING_REGISTER_COUNT: u16 = 26;
+
+/// Input registers 0-9: joint state (swing, lift, elbow, wrist, gripper).
+pub const INPUT_JOINT_STATE: u16 = 0;
+/// Input registers 10-17: base state (x, y, z, theta).
+pub const INPUT_BASE_STATE: u16 = 10;
+/// Input registers 18-25: end effector pose (x, y, z, theta).
+pub const INPUT_END_EFFECTOR: u16 = 18;
+const INPUT_REGISTER_COUNT: u16 = 26;
+
+/// Coil 0: emergency stop. Reads 1 while faulted. Write 1 to stop, 0 to reset the fault.
+pub const COIL_EMERGENCY_STOP: u16 = 0;
+/// Coil 1: gripper open. Reads 1 while the gripper target is open. Write 1 to fully open, 0 to close.
+pub const COIL_GRIPPER_OPEN: u16 = 1;
+/// Coil 2: motion complete (read only).
+pub const COIL_MOTION_COMPLETE: u16 = 2;
+const COIL_COUNT: u16 = 3;
+
+/// Configuration of the Modbus TCP server. Every field is optional in the configuration file.
+#[derive(serde::Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct ModbusConfig {
+    /// Address to listen on.
+    pub addr: String,
+    /// Address of the first holding register.
+    pub holding_register_base: u16,
+    /// Address of the first input register.
+    pub input_register_base: u16,
+    /// Address of the first coil.
+    pub coil_base: u16,
+    /// Register counts per degree.
+    pub angle_scale: f64,
+    /// Register counts per mm of lift or gripper travel.
+    pub linear_mm_scale: f64,
+    /// Register counts per meter of base or end effector position.
+    pub distance_m_scale: f64,
+}
+
+impl Default for ModbusConfig {
+    fn default() -> Self {
+        ModbusConfig {
+            addr: "127.0.0.1:5020".to_string(),
+            holding_register_base: 0,
+            input_register_base: 0,
+            coil_base: 0,
+            angle_scale: 1000.0,
+            linear_mm_scale: 1000.0,
+            distance_m_scale: 10000.0,
+        }
+    }
+}
+
+impl ModbusConfig {
+    /// Loads the configuration from the file named by `ROBOT_MODBUS_CONFIG`, falling back to the defaults.
+    pub fn load() -> Result<ModbusConfig, Box<dyn std::error::Error>> {
+        match std::env::var(MODBUS_CONFIG_ENV) {
+            Ok(path) => Ok(serde_json::from_str(&std::fs::read_to_string(path)?)?),
+            Err(_) => Ok(ModbusConfig::default()),
+        }
+    }
+
+    fn encode_joints(&self, registers: &mut [u16], joint_state: JointState) {
+        write_value(registers, 0, joint_state.swing_rotation_deg * self.angle_scale);
+        write_value(registers, 2, joint_state.lift_elevation_mm * self.linear_mm_scale);
+        write_value(registers, 4, joint_state.elbow_rotation_deg * self.angle_scale);
+        write_value(registers, 6, joint_state.wrist_rotation_deg * self.angle_scale);
+        write_value(registers, 8, joint_state.gripper_open_mm * self.linear_mm_scale);
+    }
+
+    fn decode_joints(&self, registers: &[u16]) -> JointState {
+        JointState {
+            swing_rotation_deg: read_value(registers, 0) / self.angle_scale,
+            lift_elevation_mm: read_value(registers, 2) / self.linear_mm_scale,
+            elbow_rotation_deg: read_value(registers, 4) / self.angle_scale,
+            wrist_rotation_deg: read_value(registers, 6) / self.angle_scale,
+            gripper_open_mm: read_value(registers, 8) / self.linear_mm_scale,
+        }
+    }
+
+    fn encode_coord(&self, registers: &mut [u16], coord: Coord4DOF) {
+        write_value(registers, 0, coord.x * self.distance_m_scale);
+        write_value(registers, 2, coord.y * self.distance_m_scale);
+        write_value(registers, 4, coord.z * self.distance_m_scale);
+        write_value(registers, 6, coord.theta * self.angle_scale);
+    }
+
+    fn decode_coord(&self, registers: &[u16]) -> Coord4DOF {
+        Coord4DOF {
+            x: read_value(registers, 0) / self.distance_m_scale,
+            y: read_value(registers, 2) / self.distance_m_scale,
+            z: read_value(registers, 4) / self.distance_m_scale,
+            theta: read_value(registers, 6) / self.angle_scale,
+        }
+    }
+
+    fn holding_registers(&self, robot: &Robot) -> Vec<u16> {
+        let mut registers = vec![0; HOLDING_REGISTER_COUNT as usize];
+        let target_state = robot.get_target_state();
+        let coord_target = robot.get_target_coord_state().unwrap_or_else(|| robot.get_coord_state());
+
+        self.encode_joints(&mut registers[HOLDING_JOINT_TARGET as usize..], target_state.joint_state);
+        self.encode_coord(&mut registers[HOLDING_COORD_TARGET as usize..], coord_target);
+        self.encode_coord(&mut registers[HOLDING_BASE_TARGET as usize..], target_state.base_state);
+        registers
+    }
+
+    fn input_registers(&self, robot: &Robot) -> Vec<u16> {
+        let mut registers = vec![0; INPUT_REGISTER_COUNT as usize];
+        let state = robot.get_state();
+
+        self.encode_joints(&mut registers[INPUT_JOINT_STATE as usize..], state.joint_state);
+        self.encode_coord(&mut registers[INPUT_BASE_STATE as usize..], state.base_state);
+        self.encode_coord(&mut registers[INPUT_END_EFFECTOR as usize..], robot.get_coord_state());
+        registers
+    }
+
+    fn coils(&self, robot: &Robot) -> Vec<bool> {
+        let mut coils = vec![false; COIL_COUNT as usize];
+        coils[COIL_EMERGENCY_STOP as usize] = robot.is_faulted();
+        coils[COIL_GRIPPER_OPEN as usize] = robot.get_target_state().joint_state.gripper_open_mm > 0.0;
+        coils[COIL_MOTION_COMPLETE as usize] = robot.is_settled();
+        coils
+    }
+}
+
+/// Writes `value` rounded to an i32 into the two registers at `offset`.
+fn write_value(registers: &mut [u16], offset: usize, value: f64) {
+    let value = value.round() as i32 as u32;
+    registers[offset] = (value >> 16) as u16;
+    registers[offset + 1] = value as u16;
+}
+
+/// Reads the i32 held in the two registers at `offset`.
+fn read_value(registers: &[u16], offset: usize) -> f64 {
+    (((registers[offset] as u32) << 16) | registers[offset + 1] as u32) as i32 as f64
+}
+
+/// Returns the range within a table of `count` values addressed by `address` and `quantity`, relative to `base`.
+fn table_range(base: u16, count: u16, address: u16, quantity: usize) -> Result<std::ops::Range<usize>, ExceptionCode> {
+    let start = address.checked_sub(base).ok_or(ExceptionCode::IllegalDataAddress)? as usize;
+    let end = start + quantity;
+    if quantity == 0 || end > count as usize {
+        return Err(ExceptionCode::IllegalDataAddress);
+    }
+    Ok(start..end)
+}
+
+fn command_exception(error: CommandError) -> ExceptionCode {
+    warn!("rejected modbus command: {}", error);
+    match error {
+        CommandError::Malformed { .. } | CommandError::InvalidValue { .. } | CommandError::Unreachable | CommandError::UnknownPose { .. } => ExceptionCode::IllegalDataValue,
+        CommandError::Faulted { .. } | CommandError::Storage { .. } => ExceptionCode::ServerDeviceFailure,
+        CommandError::Forbidden { .. } => ExceptionCode::IllegalFunction,
+        // Modbus clients cannot hold the control lease, so writes that move the robot are busy until the lease is released.
+        CommandError::ControlHeld { .. } | CommandError::LeaseNotHeld => ExceptionCode::ServerDeviceBusy,
+        CommandError::Replaying | CommandError::NotReplaying => ExceptionCode::ServerDeviceBusy,
+        // Scripts are not controlled over modbus.
+        CommandError::UnknownScript { .. } | CommandError::ScriptRunning { .. } | CommandError::NoScriptRunning => ExceptionCode::IllegalFunction,
+    }
+}
+
+/// Serves the register map of a single robot.
+#[derive(Clone)]
+struct RobotService {
+    robot_lock: RobotLock,
+    config: Arc<ModbusConfig>,
+    audit: SharedAudit,
+    /// The connected client, set once the connection is accepted.
+    peer: Option<SocketAddr>,
+}
+
+impl Service for RobotService {
+    type Request = Request<'static>;
+    type Response = Response;
+    type Exception = ExceptionCode;
+    type Future = Pin<Box<dyn Future<Output = Result<Response, ExceptionCode>> + Send>>;
+
+    fn call(&self, request: Self::Request) -> Self::Future {
+        let service = self.clone();
+        Box::pin(async move { service.handle(request).await })
+    }
+}
+
+impl RobotService {
+    /// Executes a command decoded from a write, auditing it with the `address` and `values` written.
+    fn execute<T: serde::Serialize>(&self, robot: &mut Robot, command: Command, address: u16, values: &[T]) -> Result<(), ExceptionCode> {
+        let name = command.name();
+        let result = robot.execute(command, None);
+        let origin = Origin { transport: "modbus", client_id: None, remote_addr: self.peer, identity: None };
+        let payload = serde_json::json!({ "address": address, "values": values });
+        self.audit.record(&origin, name, &payload, &result);
+        result.map(|_| ()).map_err(command_exception)
+    }
+
+    async fn handle(&self, request: Request<'static>) -> Result<Response, ExceptionCode> {
+        let config = &self.config;
+        match request {
+            Request::ReadHoldingRegisters(address, quantity) => {
+                let range = table_range(config.holding_register_base, HOLDING_REGISTER_COUNT, address, quantity as usize)?;
+                let registers = config.holding_registers(&*self.robot_lock.read().await);
+                Ok(Response::ReadHoldingRegisters(registers[range].to_vec()))
+            }
+            Request::ReadInputRegisters(address, quantity) => {
+                let range = table_range(config.input_register_base, INPUT_REGISTER_COUNT, address, quantity as usize)?;
+                let registers = config.input_registers(&*self.robot_lock.read().await);
+                Ok(Response::ReadInputRegisters(registers[range].to_vec()))
+            }
+            Request::ReadCoils(address, quantity) => {
+                let range = table_range(config.coil_base, COIL_COUNT, address, quantity as usize)?;
+                let coils = config.coils(&*self.robot_lock.read().await);
+                Ok(Response::ReadCoils(coils[range].to_vec()))
+            }
+            Request::WriteSingleRegister(address, value) => {
+                self.write_registers(address, &[value]).await?;
+                Ok(Response::WriteSingleRegister(address, value))
+            }
+            Request::WriteMultipleRegisters(address, values) => {
+                self.write_registers(address, &values).await?;
+                Ok(Response::WriteMultipleRegisters(address, values.len() as u16))
+            }
+            Request::WriteSingleCoil(address, value) => {
+                self.write_coils(address, &[value]).await?;
+                Ok(Response::WriteSingleCoil(address, value))
+            }
+            Request::WriteMultipleCoils(address, values) => {
+                self.write_coils(address, &values).await?;
+                Ok(Response::WriteMultipleCoils(address, values.len() as u16))
+            }
+            _ => Err(ExceptionCode::IllegalFunction),
+        }
+    }
+
+    /// Writes holding registers and commands every target block the write touched.
+    async fn write_registers(&self, address: u16, values: &[u16]) -> Result<(), ExceptionCode> {
+        let config = &self.config;
+        let range = table_range(config.holding_register_base, HOLDING_REGISTER_COUNT, address, values.len())?;
+
+        let mut robot = self.robot_lock.write().await;
+        let mut registers = config.holding_registers(&robot);
+        registers[range.clone()].copy_from_slice(values);
+
+        let blocks = [
+            (HOLDING_JOINT_TARGET, HOLDING_COORD_TARGET),
+            (HOLDING_COORD_TARGET, HOLDING_BASE_TARGET),
+            (HOLDING_BASE_TARGET, HOLDING_REGISTER_COUNT),
+        ];
+        for (start, end) in blocks {
+            let (start, end) = (start as usize, end as usize);
+            if range.start >= end || range.end <= start {
+                continue;
+            }
+
+            let block = &registers[start..end];
+            let command = match start as u16 {
+                HOLDING_JOINT_TARGET => Command::SetJointState(config.decode_joints(block)),
+                HOLDING_COORD_TARGET => Command::SetCoordState(config.decode_coord(block)),
+                _ => Command::SetBaseState(config.decode_coord(block)),
+            };
+            self.execute(&mut robot, command, address, values)?;
+        }
+
+        Ok(())
+    }
+
+    /// Writes coils, commanding the robot for each one written.
+    async fn write_coils(&self, address: u16, values: &[bool]) -> Result<(), ExceptionCode> {
+        let range = table_range(self.config.coil_base, COIL_COUNT, address, values.len())?;
+
+        let mut robot = self.robot_lock.write().await;
+        for (coil, value) in range.zip(values) {
+            let command = match (coil as u16, value) {
+                (COIL_EMERGENCY_STOP, true) => Command::EmergencyStop,
+                (COIL_EMERGENCY_STOP, false) => Command::ResetFault,
+                (COIL_GRIPPER_OPEN, true) => Command::SetGripper(super::constants::GRIPPER_WIDTH_MM),
+                (COIL_GRIPPER_OPEN, false) => Command::SetGripper(0.0),
+                _ => return Err(ExceptionCode::IllegalDataAddress),
+            };
+            self.execute(&mut robot, command, address, values)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Starts the Modbus TCP server.
+pub fn start(config: ModbusConfig, robot_lock: RobotLock, audit: SharedAudit) {
+    tokio::spawn(async move {
+        let listener = match TcpListener::bind(&config.addr).await {
+            Ok(listener) => listener,
+            Err(err) => {
+                error!("Could not start modbus server on {}: {}", config.addr, err);
+                return;
+            }
+        };
+        info!("modbus server listening on {}", config.addr);
+
+        let service = RobotService { robot_lock, config: Arc::new(config), audit, peer: None };
+        let on_connected = |stream, socket_addr: SocketAddr| {
+            let service = RobotService { peer: Some(socket_addr), ..service.clone() };
+            async move {
+                info!("modbus client connected: {}", socket_addr);
+                accept_tcp_connection(stream, socket_addr, |_| Ok(Some(service.clone())))
+            }
+        };
+        let on_process_error = |err| warn!("modbus connection error: {}", err);
+
+        if let Err(err) = Server::new(listener).serve(&on_connected, on_process_error).await {
+            error!("modbus server stopped: {}", err);
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::robot::audit::{AuditConfig, AuditLog};
+    use crate::robot::robot_config::RobotConfig;
+
+    use tokio::sync::RwLock;
+
+    fn service(dir: &std::path::Path) -> RobotService {
+        let robot_lock = Arc::new(RwLock::new(Robot::test(RobotConfig::default(), dir)));
+        let audit = Arc::new(AuditLog::start(AuditConfig { enabled: false, ..Default::default() }).unwrap());
+        RobotService { robot_lock, config: Arc::new(ModbusConfig::default()), audit, peer: None }
+    }
+
+    #[test]
+    fn values_keep_their_sign() {
+        let mut registers = [0; 2];
+        for value in [0, 1, -1, 65535, 65536, -65536, 123_456_789, -123_456_789, i32::MAX, i32::MIN] {
+            write_value(&mut registers, 0, value as f64);
+            assert_eq!(read_value(&registers, 0), value as f64);
+        }
+
+        write_value(&mut registers, 0, -1.0);
+        assert_eq!(registers, [0xFFFF, 0xFFFF]);
+        write_value(&mut registers, 0, 65536.0);
+        assert_eq!(registers, [1, 0]);
+        write_value(&mut registers, 0, -65536.0);
+        assert_eq!(registers, [0xFFFF, 0]);
+
+        // Values are rounded, and saturate rather than wrap outside an i32.
+        write_value(&mut registers, 0, -2.5);
+        assert_eq!(read_value(&registers, 0), -3.0);
+        write_value(&mut registers, 0, 1e12);
+        assert_eq!(read_value(&registers, 0), i32::MAX as f64);
+        write_value(&mut registers, 0, -1e12);
+        assert_eq!(read_value(&registers, 0), i32::MIN as f64);
+    }
+
+    #[test]
+    fn table_range_is_bounded_by_the_table() {
+        assert_eq!(table_range(0, 26, 0, 26), Ok(0..26));
+        assert_eq!(table_range(0, 26, 24, 2), Ok(24..26));
+        assert_eq!(table_range(100, 26, 110, 8), Ok(10..18));
+
+        assert_eq!(table_range(0, 26, 25, 2), Err(ExceptionCode::IllegalDataAddress));
+        assert_eq!(table_range(0, 26, 26, 1), Err(ExceptionCode::IllegalDataAddress));
+        assert_eq!(table_range(0, 26, 0, 0), Err(ExceptionCode::IllegalDataAddress));
+        assert_eq!(table_range(100, 26, 99, 1), Err(ExceptionCode::IllegalDataAddress));
+        assert_eq!(table_range(0, 3, u16::MAX, 1), Err(ExceptionCode::IllegalDataAddress));
+    }
+
+    #[tokio::test]
+    async fn writes_command_every_block_they_touch() {
+        let dir = tempfile::tempdir().unwrap();
+        let service = service(dir.path());
+        let config = ModbusConfig::default();
+
+        // A single register write keeps the rest of the block at its current target.
+        let mut lift = [0; 2];
+        write_value(&mut lift, 0, 250.0 * config.linear_mm_scale);
+        service.write_registers(HOLDING_JOINT_TARGET + 2, &lift).await.unwrap();
+        let target = service.robot_lock.read().await.get_target_state();
+        assert_eq!(target.joint_state, JointState { lift_elevation_mm: 250.0, ..Default::default() });
+
+        // A write across the joint and end effector blocks commands both.
+        let current = service.robot_lock.read().await.get_coord_state();
+        let mut values = [0; 4];
+        write_value(&mut values, 0, -30.0 * config.linear_mm_scale);
+        write_value(&mut values, 2, (current.x - 0.1) * config.distance_m_scale);
+        service.write_registers(HOLDING_JOINT_TARGET + 8, &values).await.unwrap();
+        let robot = service.robot_lock.read().await;
+        let coord_target = robot.get_target_coord_state().expect("an end effector target is set");
+        assert!((coord_target.x - (current.x - 0.1)).abs() < 1e-4);
+        assert!((coord_target.y - current.y).abs() < 1e-4);
+        // The gripper was written before the end effector target, which keeps it.
+        assert_eq!(robot.get_target_state().joint_state.gripper_open_mm, 0.0);
+        drop(robot);
+
+        let mut base = [0; 8];
+        config.encode_coord(&mut base, Coord4DOF { x: -1.5, y: 0.25, z: 0.0, theta: -90.0 });
+        service.write_registers(HOLDING_BASE_TARGET, &base).await.unwrap();
+        assert_eq!(service.robot_lock.read().await.get_target_state().base_state, Coord4DOF { x: -1.5, y: 0.25, z: 0.0, theta: -90.0 });
+
+        assert_eq!(service.write_registers(HOLDING_REGISTER_COUNT - 1, &[0, 0]).await, Err(ExceptionCode::IllegalDataAddress));
+    }
+
+    #[tokio::test]
+    async fn writes_are_busy_while_a_lease_is_held() {
+        let dir = tempfile::tempdir().unwrap();
+        let service = service(dir.path());
+        service.robot_lock.write().await.request_control("alice", false).unwrap();
+
+        assert_eq!(service.write_registers(HOLDING_JOINT_TARGET, &[0, 1]).await, Err(ExceptionCode::ServerDeviceBusy));
+        assert_eq!(service.write_coils(COIL_GRIPPER_OPEN, &[true]).await, Err(ExceptionCode::ServerDeviceBusy));
+        assert_eq!(service.write_coils(COIL_EMERGENCY_STOP, &[true]).await, Ok(()));
+        assert!(service.robot_lock.read().await.is_faulted());
+    }
+}
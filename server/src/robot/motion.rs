@@ -0,0 +1,199 @@
+use super::command::{Command, CommandOutcome};
+use super::robot_state::{shortest_angle_diff, Coord4DOF};
+use super::telemetry::RobotSnapshot;
+
+use std::time::Duration;
+
+use serde::{de::DeserializeOwned, Serialize};
+
+/// Time between the waypoints sent by `move_linear`.
+const LINEAR_WAYPOINT_PERIOD: Duration = Duration::from_millis(50);
+/// Slowest `move_linear` speed (m/sec). Slower moves would stream an unbounded number of waypoints.
+pub(super) const MIN_LINEAR_SPEED: f64 = 0.001;
+/// How often waits check on the robot and whether the program was paused or aborted.
+pub(super) const POLL_PERIOD: Duration = Duration::from_millis(10);
+
+/// Why a program stopped before its end.
+pub enum Interrupt {
+    Aborted,
+    Failed(String),
+}
+
+/// The fields of a target a program changes, by name.
+pub type Fields = serde_json::Map<String, serde_json::Value>;
+
+/// The robot as programs see it. Moves are built on these few operations so a program moves the robot
+/// the same whether it runs live against the server or in simulated time.
+pub trait Motion {
+    /// Executes a command, stopping the program if it is rejected.
+    fn execute(&self, command: Command) -> Result<CommandOutcome, Interrupt>;
+
+    /// The robot as it is now.
+    fn snapshot(&self) -> RobotSnapshot;
+
+    /// Lets `duration` pass, or longer if the program is paused meanwhile.
+    fn sleep(&self, duration: Duration) -> Result<(), Interrupt>;
+
+    /// Time since the program started.
+    fn elapsed(&self) -> Duration;
+
+    /// Writes a line to the program's output.
+    fn print(&self, line: String);
+
+    /// Called before each line of a command language program runs.
+    fn at_line(&self, _line: usize) {}
+
+    fn move_joints(&self, joints: Fields) -> Result<(), Interrupt> {
+        let target = self.snapshot().target_state.joint_state;
+        self.execute(Command::SetJointState(overlay(&target, joints)?)).map(|_| ())
+    }
+
+    fn move_coords(&self, coords: Fields) -> Result<(), Interrupt> {
+        let snapshot = self.snapshot();
+        let target = snapshot.target_coord_state.unwrap_or(snapshot.coords);
+        self.execute(Command::SetCoordState(overlay(&target, coords)?)).map(|_| ())
+    }
+
+    /// Moves the end effector along a straight line from where it is by streaming coordinate targets at `speed` (m/sec).
+    fn move_linear(&self, coords: Fields, speed: f64) -> Result<(), Interrupt> {
+        if !speed.is_finite() || speed < MIN_LINEAR_SPEED {
+            return Err(Interrupt::Failed(format!("linear moves need a speed of at least {MIN_LINEAR_SPEED} m/sec, got {speed} m/sec")));
+        }
+        let start = self.snapshot().coords;
+        let end: Coord4DOF = overlay(&start, coords)?;
+        Command::SetCoordState(end).validate().map_err(|error| Interrupt::Failed(error.to_string()))?;
+
+        let steps = (distance(&start, &end) / speed / LINEAR_WAYPOINT_PERIOD.as_secs_f64()).ceil().max(1.0) as usize;
+        for step in 1..=steps {
+            self.execute(Command::SetCoordState(interpolate(&start, &end, step as f64 / steps as f64)))?;
+            if step < steps {
+                self.sleep(LINEAR_WAYPOINT_PERIOD)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn move_base(&self, base: Fields) -> Result<(), Interrupt> {
+        let target = self.snapshot().target_state.base_state;
+        self.execute(Command::SetBaseState(overlay(&target, base)?)).map(|_| ())
+    }
+
+    fn open_gripper(&self, gripper_open_mm: f64) -> Result<(), Interrupt> {
+        self.execute(Command::SetGripper(gripper_open_mm)).map(|_| ())
+    }
+
+    fn move_to_pose(&self, name: &str) -> Result<(), Interrupt> {
+        self.execute(Command::MoveToPose(name.to_string())).map(|_| ())
+    }
+
+    /// Waits until the robot has reached its targets, returning false if `timeout` passes first. Fails if the robot faults.
+    fn wait_settled(&self, timeout: Option<Duration>) -> Result<bool, Interrupt> {
+        let deadline = timeout.map(|timeout| self.elapsed() + timeout);
+        loop {
+            let snapshot = self.snapshot();
+            if let Some(fault) = snapshot.fault {
+                return Err(Interrupt::Failed(format!("robot is faulted: {fault}")));
+            }
+            if snapshot.settled {
+                return Ok(true);
+            }
+            if deadline.is_some_and(|deadline| self.elapsed() >= deadline) {
+                return Ok(false);
+            }
+            self.sleep(POLL_PERIOD)?;
+        }
+    }
+}
+
+/// Straight line distance (m) between two end effector poses.
+pub(super) fn distance(start: &Coord4DOF, end: &Coord4DOF) -> f64 {
+    ((end.x - start.x).powi(2) + (end.y - start.y).powi(2) + (end.z - start.z).powi(2)).sqrt()
+}
+
+/// The pose `fraction` of the way along the straight line from `start` to `end`, turning the shortest way.
+pub(super) fn interpolate(start: &Coord4DOF, end: &Coord4DOF, fraction: f64) -> Coord4DOF {
+    Coord4DOF {
+        x: start.x + (end.x - start.x) * fraction,
+        y: start.y + (end.y - start.y) * fraction,
+        z: start.z + (end.z - start.z) * fraction,
+        theta: start.theta + shortest_angle_diff(end.theta, start.theta) * fraction,
+    }
+}
+
+/// `base` with the fields named in `changes` replaced, so programs only give the values they change.
+pub(super) fn overlay<T: Serialize + DeserializeOwned>(base: &T, changes: Fields) -> Result<T, Interrupt> {
+    let mut value = serde_json::to_value(base).map_err(|err| Interrupt::Failed(err.to_string()))?;
+    for (field, change) in changes {
+        let fields: Vec<String> = value.as_object().map(|object| object.keys().cloned().collect()).unwrap_or_default();
+        let Some(target) = value.get_mut(field.as_str()) else {
+            return Err(Interrupt::Failed(format!("unknown field '{field}', expected one of {fields:?}")));
+        };
+        *target = change;
+    }
+    serde_json::from_value(value).map_err(|err| Interrupt::Failed(err.to_string()))
+}
+
+/// A stand-in robot for testing programs without simulating.
+#[cfg(test)]
+pub(super) mod mock {
+    use super::*;
+
+    use std::cell::{Cell, RefCell};
+
+    /// A robot that reaches every target at once, rejecting the next `rejections` commands.
+    #[derive(Default)]
+    pub struct MockMotion {
+        pub snapshot: RefCell<RobotSnapshot>,
+        pub elapsed: Cell<Duration>,
+        pub rejections: Cell<u32>,
+        pub output: RefCell<Vec<String>>,
+        /// Every command accepted, in order.
+        pub commands: RefCell<Vec<Command>>,
+    }
+
+    impl MockMotion {
+        pub fn new() -> MockMotion {
+            let motion = MockMotion::default();
+            motion.snapshot.borrow_mut().settled = true;
+            motion
+        }
+    }
+
+    impl Motion for MockMotion {
+        fn execute(&self, command: Command) -> Result<CommandOutcome, Interrupt> {
+            if self.rejections.get() > 0 {
+                self.rejections.set(self.rejections.get() - 1);
+                return Err(Interrupt::Failed(format!("{} was rejected", command.name())));
+            }
+            self.commands.borrow_mut().push(command.clone());
+            let mut snapshot = self.snapshot.borrow_mut();
+            match command {
+                Command::SetJointState(joint_state) => snapshot.state.joint_state = joint_state,
+                Command::SetGripper(gripper_open_mm) => snapshot.state.joint_state.gripper_open_mm = gripper_open_mm,
+                Command::SetCoordState(coords) => snapshot.coords = coords,
+                Command::SetBaseState(base_state) => snapshot.state.base_state = base_state,
+                _ => {}
+            }
+            snapshot.target_state = snapshot.state;
+            Ok(CommandOutcome { target_state: snapshot.state, target_coord_state: None, ik_solution: None })
+        }
+
+        fn snapshot(&self) -> RobotSnapshot {
+            self.snapshot.borrow().clone()
+        }
+
+        fn sleep(&self, duration: Duration) -> Result<(), Interrupt> {
+            self.elapsed.set(self.elapsed.get() + duration);
+            assert!(self.elapsed.get() < Duration::from_secs(600), "the program never finished");
+            Ok(())
+        }
+
+        fn elapsed(&self) -> Duration {
+            self.elapsed.get()
+        }
+
+        fn print(&self, line: String) {
+            self.output.borrow_mut().push(line);
+        }
+    }
+}
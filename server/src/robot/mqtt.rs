@@ -0,0 +1,360 @@
+use super::audit::{raw_payload, Origin, SharedAudit};
+use super::command::{Command, CommandAck, CommandError};
+use super::telemetry::{Stream, Telemetry, TelemetrySender};
+use super::RobotLock;
+
+use std::collections::HashMap;
+use std::path::Path;
+
+use rumqttc::{AsyncClient, Event, LastWill, MqttOptions, Packet, Publish, QoS};
+use tokio::sync::broadcast::error::RecvError;
+use tokio::time::{sleep, Duration, Instant};
+use tracing::{error, info, warn};
+
+/// Environment variable holding the path of the JSON MQTT configuration file.
+pub const MQTT_CONFIG_ENV: &str = "ROBOT_MQTT_CONFIG";
+
+/// Configuration of the MQTT bridge. Every field is optional in the configuration file.
+#[derive(serde::Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct MqttConfig {
+    pub host: String,
+    pub port: u16,
+    pub client_id: String,
+    /// QoS (0, 1 or 2) used for every publish and subscription.
+    pub qos: u8,
+    /// Retain state messages so new subscribers immediately get the last state.
+    pub retain_state: bool,
+    /// Minimum time between state publishes (ms).
+    pub publish_period_ms: u64,
+    /// Topic `RobotState` is published to.
+    pub joint_state_topic: String,
+    /// Topic the end effector pose is published to.
+    pub coords_topic: String,
+    /// Topic the fault status is published to.
+    pub fault_topic: String,
+    /// Topic the complete `TelemetryFrame` is published to.
+    pub telemetry_topic: String,
+    /// Topic `online`/`offline` is published to. `offline` is set as the last will.
+    pub status_topic: String,
+    /// Topic accepting `JointState` targets.
+    pub joint_command_topic: String,
+    /// Topic accepting end effector `Coord4DOF` targets.
+    pub coord_command_topic: String,
+    /// Topic accepting base `Coord4DOF` targets.
+    pub base_command_topic: String,
+    /// Topic accepting fault resets. The payload is ignored.
+    pub reset_fault_topic: String,
+    /// Topic accepting the name of a saved pose to move to, as a JSON string.
+    pub pose_command_topic: String,
+    /// Topic every command is acknowledged on.
+    pub command_ack_topic: String,
+}
+
+impl Default for MqttConfig {
+    fn default() -> Self {
+        MqttConfig {
+            host: "localhost".to_string(),
+            port: 1883,
+            client_id: "robot-visualizer".to_string(),
+            qos: 1,
+            retain_state: true,
+            publish_period_ms: 100,
+            joint_state_topic: "robot/state/joints".to_string(),
+            coords_topic: "robot/state/coords".to_string(),
+            fault_topic: "robot/state/fault".to_string(),
+            telemetry_topic: "robot/state/telemetry".to_string(),
+            status_topic: "robot/status".to_string(),
+            joint_command_topic: "robot/command/joints".to_string(),
+            coord_command_topic: "robot/command/coords".to_string(),
+            base_command_topic: "robot/command/base".to_string(),
+            reset_fault_topic: "robot/command/reset_fault".to_string(),
+            pose_command_topic: "robot/command/pose".to_string(),
+            command_ack_topic: "robot/command/ack".to_string(),
+        }
+    }
+}
+
+impl MqttConfig {
+    /// Loads the configuration from the file named by `ROBOT_MQTT_CONFIG`, falling back to the defaults.
+    pub fn load() -> Result<MqttConfig, Box<dyn std::error::Error>> {
+        match std::env::var(MQTT_CONFIG_ENV) {
+            Ok(path) => MqttConfig::read(Path::new(&path)),
+            Err(_) => Ok(MqttConfig::default()),
+        }
+    }
+
+    pub fn read(path: &Path) -> Result<MqttConfig, Box<dyn std::error::Error>> {
+        let config: MqttConfig = serde_json::from_str(&std::fs::read_to_string(path)?)?;
+        config.validate().map_err(|err| format!("{}: {}", path.display(), err))?;
+        Ok(config)
+    }
+
+    /// Rejects values the bridge cannot connect with.
+    fn validate(&self) -> Result<(), String> {
+        if self.qos > 2 {
+            return Err(format!("qos must be 0, 1 or 2, got {}", self.qos));
+        }
+        Ok(())
+    }
+
+    fn command_topics(&self) -> [&String; 5] {
+        [&self.joint_command_topic, &self.coord_command_topic, &self.base_command_topic, &self.reset_fault_topic, &self.pose_command_topic]
+    }
+}
+
+/// Connects to the broker and starts publishing telemetry and executing commands received on the command topics.
+pub fn start(config: MqttConfig, robot_lock: RobotLock, telemetry: TelemetrySender, audit: SharedAudit) {
+    let qos = match rumqttc::qos(config.qos) {
+        Ok(qos) => qos,
+        Err(_) => {
+            error!("invalid MQTT qos {}, MQTT bridge disabled", config.qos);
+            return;
+        }
+    };
+
+    let mut options = MqttOptions::new(config.client_id.clone(), config.host.clone(), config.port);
+    options.set_keep_alive(Duration::from_secs(5));
+    options.set_last_will(LastWill::new(config.status_topic.clone(), "offline", qos, true));
+
+    let (client, eventloop) = AsyncClient::new(options, 64);
+
+    info!("MQTT bridge connecting to {}:{}", config.host, config.port);
+    tokio::spawn(publish_telemetry(config.clone(), qos, client.clone(), telemetry.subscribe()));
+    tokio::spawn(run_eventloop(config, qos, client, eventloop, robot_lock, audit));
+}
+
+/// Publishes telemetry to the state topics, limited to one message per stream every `publish_period_ms`.
+async fn publish_telemetry(config: MqttConfig, qos: QoS, client: AsyncClient, mut telemetry: tokio::sync::broadcast::Receiver<Telemetry>) {
+    let period = Duration::from_millis(config.publish_period_ms);
+    let mut last_published: HashMap<Stream, Instant> = HashMap::new();
+
+    loop {
+        let message = match telemetry.recv().await {
+            Ok(message) => message,
+            Err(RecvError::Lagged(_)) => continue,
+            Err(RecvError::Closed) => return,
+        };
+
+        // Faults are always published so a raise or clear is never dropped.
+        let stream = message.stream();
+        let now = Instant::now();
+        if stream != Stream::Fault && last_published.get(&stream).is_some_and(|last| now.duration_since(*last) < period) {
+            continue;
+        }
+        last_published.insert(stream, now);
+
+        let (topic, payload) = match &message {
+            Telemetry::JointState(state) => (&config.joint_state_topic, serde_json::to_vec(state)),
+            Telemetry::BaseCoords(coords) => (&config.coords_topic, serde_json::to_vec(coords)),
+            Telemetry::Fault(fault) => (&config.fault_topic, serde_json::to_vec(fault)),
+            Telemetry::Frame(frame) => (&config.telemetry_topic, serde_json::to_vec(frame)),
+            // These are part of the telemetry frame.
+            Telemetry::Velocity(_) | Telemetry::Links(_) | Telemetry::Control(_) => continue,
+            Telemetry::Script(_) => continue,
+        };
+
+        if let Ok(payload) = payload {
+            if let Err(err) = client.publish(topic.clone(), qos, config.retain_state, payload).await {
+                warn!("MQTT publish to {} failed: {}", topic, err);
+            }
+        }
+    }
+}
+
+/// Drives the MQTT connection, (re)subscribing to the command topics on every connect.
+async fn run_eventloop(config: MqttConfig, qos: QoS, client: AsyncClient, mut eventloop: rumqttc::EventLoop, robot_lock: RobotLock, audit: SharedAudit) {
+    loop {
+        match eventloop.poll().await {
+            Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                info!("MQTT bridge connected");
+                for topic in config.command_topics() {
+                    if let Err(err) = client.try_subscribe(topic.clone(), qos) {
+                        warn!("MQTT subscribe to {} failed: {}", topic, err);
+                    }
+                }
+                let _ = client.try_publish(config.status_topic.clone(), qos, true, "online");
+            }
+            Ok(Event::Incoming(Packet::Publish(publish))) => {
+                let Some(ack) = handle_command(&config, &robot_lock, &audit, publish).await else {
+                    continue;
+                };
+                if let Ok(payload) = serde_json::to_vec(&ack) {
+                    let _ = client.try_publish(config.command_ack_topic.clone(), qos, false, payload);
+                }
+            }
+            Ok(_) => {}
+            Err(err) => {
+                // The event loop reconnects on the next poll.
+                warn!("MQTT connection error: {}", err);
+                sleep(Duration::from_secs(1)).await;
+            }
+        }
+    }
+}
+
+/// Executes a command received on one of the command topics, returning the ack to publish on `command_ack_topic`.
+/// Returns `None` for messages that are not commands to run.
+/// A `request_id` field in the JSON payload is echoed back in the ack. Commands are audited without a user, the bridge is not authenticated.
+/// The bridge cannot hold the control lease, so motion commands are rejected with `control_held` while another client holds it.
+async fn handle_command(config: &MqttConfig, robot_lock: &RobotLock, audit: &SharedAudit, publish: Publish) -> Option<CommandAck> {
+    // Retained commands are stale and must not move the robot when the bridge (re)connects.
+    if publish.retain {
+        return None;
+    }
+
+    let topic = &publish.topic;
+    let command = if *topic == config.joint_command_topic {
+        serde_json::from_slice(&publish.payload).map(Command::SetJointState)
+    } else if *topic == config.coord_command_topic {
+        serde_json::from_slice(&publish.payload).map(Command::SetCoordState)
+    } else if *topic == config.base_command_topic {
+        serde_json::from_slice(&publish.payload).map(Command::SetBaseState)
+    } else if *topic == config.reset_fault_topic {
+        Ok(Command::ResetFault)
+    } else if *topic == config.pose_command_topic {
+        serde_json::from_slice(&publish.payload).map(Command::MoveToPose)
+    } else {
+        return None;
+    };
+
+    let request_id = serde_json::from_slice::<serde_json::Value>(&publish.payload)
+        .ok()
+        .and_then(|payload| payload.get("request_id").cloned());
+
+    let name = command.as_ref().map_or(topic.as_str(), |command| command.name());
+    let result = match command {
+        Ok(command) => robot_lock.write().await.execute(command, None),
+        Err(err) => Err(CommandError::Malformed { message: err.to_string() }),
+    };
+    let origin = Origin { transport: "mqtt", client_id: None, remote_addr: None, identity: None };
+    audit.record(&origin, name, &raw_payload(&publish.payload), &result);
+
+    if let Err(error) = &result {
+        warn!("rejected MQTT command on {}: {}", topic, error);
+    }
+
+    Some(CommandAck::new(topic, request_id, result))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::robot::audit::{AuditConfig, AuditLog};
+    use crate::robot::robot_config::RobotConfig;
+    use crate::robot::robot_state::{Coord4DOF, JointState};
+    use crate::robot::Robot;
+
+    use std::sync::Arc;
+
+    use tokio::sync::RwLock;
+
+    struct Bridge {
+        config: MqttConfig,
+        robot_lock: RobotLock,
+        audit: SharedAudit,
+        _dir: tempfile::TempDir,
+    }
+
+    impl Bridge {
+        fn new() -> Bridge {
+            let dir = tempfile::tempdir().unwrap();
+            let robot_lock = Arc::new(RwLock::new(Robot::test(RobotConfig::default(), dir.path())));
+            let audit = Arc::new(AuditLog::start(AuditConfig { enabled: false, ..Default::default() }).unwrap());
+            Bridge { config: MqttConfig::default(), robot_lock, audit, _dir: dir }
+        }
+
+        async fn send(&self, topic: &str, payload: &str, retain: bool) -> Option<CommandAck> {
+            let mut publish = Publish::new(topic, QoS::AtLeastOnce, payload);
+            publish.retain = retain;
+            handle_command(&self.config, &self.robot_lock, &self.audit, publish).await
+        }
+    }
+
+    fn json<T: serde::Serialize>(value: T) -> String {
+        serde_json::to_string(&value).unwrap()
+    }
+
+    #[tokio::test]
+    async fn command_topics_map_to_commands() {
+        let bridge = Bridge::new();
+        let config = &bridge.config;
+
+        let joints = JointState { lift_elevation_mm: 250.0, ..Default::default() };
+        let ack = bridge.send(&config.joint_command_topic, &json(joints), false).await.unwrap();
+        assert!(ack.accepted, "{:?}", ack.error);
+        assert_eq!(ack.command, config.joint_command_topic);
+        assert_eq!(bridge.robot_lock.read().await.get_target_state().joint_state, joints);
+
+        let base = Coord4DOF { x: 1.0, ..Default::default() };
+        assert!(bridge.send(&config.base_command_topic, &json(base), false).await.unwrap().accepted);
+        assert_eq!(bridge.robot_lock.read().await.get_target_state().base_state, base);
+
+        let coords = Coord4DOF { x: 1.5, y: 0.2, z: 0.3, theta: 0.0 };
+        let ack = bridge.send(&config.coord_command_topic, &json(coords), false).await.unwrap();
+        assert!(ack.outcome.unwrap().ik_solution.is_some());
+        assert_eq!(bridge.robot_lock.read().await.get_target_coord_state(), Some(coords));
+
+        let ack = bridge.send(&config.pose_command_topic, "\"home\"", false).await.unwrap();
+        assert!(matches!(ack.error, Some(CommandError::UnknownPose { name }) if name == "home"));
+
+        bridge.robot_lock.write().await.execute(Command::EmergencyStop, None).unwrap();
+        assert!(bridge.send(&config.reset_fault_topic, "", false).await.unwrap().accepted);
+        assert_eq!(bridge.robot_lock.read().await.get_fault(), None);
+
+        assert!(bridge.send(&config.joint_state_topic, &json(joints), false).await.is_none());
+        assert!(bridge.send("robot/command/unknown", &json(joints), false).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn retained_commands_are_skipped() {
+        let bridge = Bridge::new();
+        let joints = JointState { lift_elevation_mm: 250.0, ..Default::default() };
+
+        assert!(bridge.send(&bridge.config.joint_command_topic, &json(joints), true).await.is_none());
+        assert_eq!(bridge.robot_lock.read().await.get_target_state().joint_state, JointState::default());
+    }
+
+    #[tokio::test]
+    async fn acks_echo_the_request_id() {
+        let bridge = Bridge::new();
+        let config = &bridge.config;
+
+        let mut payload = serde_json::to_value(JointState::default()).unwrap();
+        payload["request_id"] = serde_json::json!("move-1");
+        let ack = bridge.send(&config.joint_command_topic, &payload.to_string(), false).await.unwrap();
+        assert_eq!(ack.request_id, Some(serde_json::json!("move-1")));
+        assert!(ack.accepted);
+
+        // Rejected commands are matched to their request too.
+        let ack = bridge.send(&config.base_command_topic, r#"{"request_id": 7, "x": "far"}"#, false).await.unwrap();
+        assert_eq!(ack.request_id, Some(serde_json::json!(7)));
+        assert!(matches!(ack.error, Some(CommandError::Malformed { .. })));
+
+        let ack = bridge.send(&config.reset_fault_topic, "", false).await.unwrap();
+        assert_eq!(ack.request_id, None);
+    }
+
+    #[tokio::test]
+    async fn motion_is_rejected_while_a_lease_is_held() {
+        let bridge = Bridge::new();
+        bridge.robot_lock.write().await.request_control("alice", false).unwrap();
+
+        let ack = bridge.send(&bridge.config.joint_command_topic, &json(JointState::default()), false).await.unwrap();
+        assert!(matches!(ack.error, Some(CommandError::ControlHeld { holder }) if holder == "alice"));
+        assert!(bridge.send(&bridge.config.reset_fault_topic, "", false).await.unwrap().accepted);
+    }
+
+    #[test]
+    fn rejects_an_invalid_qos() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("mqtt.json");
+
+        std::fs::write(&path, r#"{"qos": 2}"#).unwrap();
+        assert_eq!(MqttConfig::read(&path).unwrap().qos, 2);
+
+        std::fs::write(&path, r#"{"qos": 3}"#).unwrap();
+        let error = MqttConfig::read(&path).unwrap_err().to_string();
+        assert!(error.ends_with("qos must be 0, 1 or 2, got 3"), "{error}");
+    }
+}
@@ -0,0 +1,331 @@
+use super::command::{CommandError, CommandOutcome};
+use super::robot_state::{Coord4DOF, RobotState};
+use super::telemetry::{RobotSnapshot, SnapshotReceiver};
+use super::{Robot, RobotLock};
+
+use std::path::PathBuf;
+
+use chrono::{DateTime, Utc};
+use tokio::time::{interval, Duration, MissedTickBehavior};
+use tracing::{error, info, warn};
+
+/// Environment variable holding the path the robot's state is saved to.
+pub const STATE_FILE_ENV: &str = "ROBOT_STATE_FILE";
+/// Path the state is saved to when `ROBOT_STATE_FILE` is not set.
+pub const DEFAULT_STATE_FILE: &str = "robot_state.json";
+/// Environment variable holding how often (seconds) the state is saved, 0 disables autosave.
+pub const AUTOSAVE_PERIOD_ENV: &str = "ROBOT_AUTOSAVE_S";
+/// Seconds between autosaves when `ROBOT_AUTOSAVE_S` is not set.
+pub const DEFAULT_AUTOSAVE_PERIOD_S: f64 = 10.0;
+/// Command line option restoring the saved state on startup.
+pub const RESUME_ARG: &str = "--resume";
+
+/// Bumped whenever a field of `SavedState` changes or is removed.
+pub const SAVED_STATE_VERSION: u32 = 1;
+
+/// Held while the state file is written, so an autosave and a save on demand cannot both write the temporary file at once.
+static SAVING: std::sync::Mutex<()> = std::sync::Mutex::new(());
+
+/// The part of the robot that survives a restart. The robot config is not saved, it is read from `ROBOT_CONFIG` on every start.
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug, schemars::JsonSchema, ts_rs::TS)]
+pub struct SavedState {
+    pub version: u32,
+    /// When the state was saved.
+    pub timestamp: DateTime<Utc>,
+    pub state: RobotState,
+    pub target_state: RobotState,
+    pub target_coord_state: Option<Coord4DOF>,
+    /// The active fault, restored so a faulted robot stays faulted across a restart.
+    pub fault: Option<String>,
+}
+
+impl SavedState {
+    /// The targets as a command outcome, for the audit log.
+    pub fn outcome(&self) -> CommandOutcome {
+        CommandOutcome { target_state: self.target_state, target_coord_state: self.target_coord_state, ik_solution: None }
+    }
+}
+
+impl From<&RobotSnapshot> for SavedState {
+    fn from(snapshot: &RobotSnapshot) -> Self {
+        SavedState {
+            version: SAVED_STATE_VERSION,
+            timestamp: snapshot.timestamp,
+            state: snapshot.state,
+            target_state: snapshot.target_state,
+            target_coord_state: snapshot.target_coord_state,
+            fault: snapshot.fault.clone(),
+        }
+    }
+}
+
+/// Saving or restoring the state file.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum SnapshotOperation {
+    Save,
+    /// Moves the robot straight to the saved state and targets.
+    Load,
+}
+
+impl SnapshotOperation {
+    /// The name the operation is audited under.
+    pub fn name(&self) -> &'static str {
+        match self {
+            SnapshotOperation::Save => "save_snapshot",
+            SnapshotOperation::Load => "load_snapshot",
+        }
+    }
+}
+
+/// Reply to a `save snapshot` or `load snapshot`.
+#[derive(serde::Serialize, Clone, Debug, schemars::JsonSchema, ts_rs::TS)]
+pub struct SnapshotAck {
+    pub accepted: bool,
+    /// The state that was saved or loaded.
+    pub snapshot: Option<SavedState>,
+    /// Set when the operation was rejected.
+    pub error: Option<CommandError>,
+}
+
+impl SnapshotAck {
+    pub fn new(result: Result<SavedState, CommandError>) -> Self {
+        match result {
+            Ok(snapshot) => SnapshotAck { accepted: true, snapshot: Some(snapshot), error: None },
+            Err(error) => SnapshotAck { accepted: false, snapshot: None, error: Some(error) },
+        }
+    }
+}
+
+/// The file the state is saved to and how often it is autosaved.
+#[derive(Clone, Debug)]
+pub struct StateFile {
+    pub path: PathBuf,
+    /// `None` when autosave is disabled.
+    pub autosave_period: Option<Duration>,
+}
+
+impl StateFile {
+    /// Reads the path from `ROBOT_STATE_FILE` and the autosave period from `ROBOT_AUTOSAVE_S`, using the defaults for either when not set.
+    pub fn from_env() -> Result<StateFile, String> {
+        let path = std::env::var(STATE_FILE_ENV).unwrap_or_else(|_| DEFAULT_STATE_FILE.to_string());
+        let period_s = match std::env::var(AUTOSAVE_PERIOD_ENV) {
+            Ok(period) => period.parse::<f64>().map_err(|err| format!("{AUTOSAVE_PERIOD_ENV}: {err}"))?,
+            Err(_) => DEFAULT_AUTOSAVE_PERIOD_S,
+        };
+        if !period_s.is_finite() || period_s < 0.0 {
+            return Err(format!("{AUTOSAVE_PERIOD_ENV} must be a positive number of seconds, got {period_s}"));
+        }
+        let autosave_period = (period_s > 0.0).then(|| Duration::from_secs_f64(period_s));
+        Ok(StateFile { path: PathBuf::from(path), autosave_period })
+    }
+
+    pub fn exists(&self) -> bool {
+        self.path.exists()
+    }
+
+    /// Writes the state to a temporary file and renames it over the last save, so a crash part way through never leaves a truncated file.
+    /// Blocks while another save is being written.
+    pub fn write(&self, saved: &SavedState) -> Result<(), CommandError> {
+        let _saving = SAVING.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        let mut temporary = self.path.clone().into_os_string();
+        temporary.push(".tmp");
+        let json = serde_json::to_vec_pretty(saved).map_err(|err| self.error(err))?;
+        std::fs::write(&temporary, json).map_err(|err| self.error(err))?;
+        std::fs::rename(&temporary, &self.path).map_err(|err| self.error(err))
+    }
+
+    pub fn read(&self) -> Result<SavedState, CommandError> {
+        let json = std::fs::read(&self.path).map_err(|err| self.error(err))?;
+        let saved: SavedState = serde_json::from_slice(&json).map_err(|err| self.error(err))?;
+        if saved.version != SAVED_STATE_VERSION {
+            return Err(self.error(format!("saved with version {}, expected {}", saved.version, SAVED_STATE_VERSION)));
+        }
+        Ok(saved)
+    }
+
+    fn error(&self, err: impl std::fmt::Display) -> CommandError {
+        CommandError::Storage { message: format!("{}: {}", self.path.display(), err) }
+    }
+}
+
+/// Saves the robot's current state to its state file. Nothing is saved while a recording is replayed.
+pub async fn save(robot_lock: &RobotLock) -> Result<SavedState, CommandError> {
+    let (saved, file) = {
+        let robot = robot_lock.read().await;
+        if robot.replay_status().is_some() {
+            return Err(CommandError::Replaying);
+        }
+        (SavedState::from(&robot.snapshot()), robot.state_file().clone())
+    };
+
+    let result = tokio::task::spawn_blocking(move || file.write(&saved).map(|_| saved)).await;
+    let saved = result.map_err(|err| CommandError::Storage { message: err.to_string() })??;
+    info!("state saved");
+    Ok(saved)
+}
+
+/// Restores the state saved in the robot's state file, returning it as applied. `lease` is the control lease token the client holds, if any.
+pub async fn load(robot_lock: &RobotLock, lease: Option<&str>) -> Result<SavedState, CommandError> {
+    let file = robot_lock.read().await.state_file().clone();
+    let saved = tokio::task::spawn_blocking(move || file.read()).await.map_err(|err| CommandError::Storage { message: err.to_string() })??;
+    robot_lock.write().await.restore(&saved, lease)
+}
+
+/// Restores the saved state on startup for `--resume`. Starts from the origin when nothing has been saved, and ignores the save while replaying.
+pub fn resume(robot: &mut Robot) -> Result<(), CommandError> {
+    let file = robot.state_file().clone();
+    if robot.replay_status().is_some() {
+        warn!("{} is ignored while replaying a recording", RESUME_ARG);
+        return Ok(());
+    }
+    if !file.exists() {
+        warn!("no state has been saved to {}, starting from the origin", file.path.display());
+        return Ok(());
+    }
+    robot.restore(&file.read()?, None).map(|_| ())
+}
+
+/// Starts a task saving the latest published state every autosave period, if autosave is enabled.
+pub fn autosave(snapshots: SnapshotReceiver, file: StateFile) {
+    let Some(period) = file.autosave_period else {
+        return;
+    };
+    info!("saving the state to {} every {:?}", file.path.display(), period);
+
+    tokio::spawn(async move {
+        let mut ticks = interval(period);
+        ticks.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        // The first tick completes straight away, before anything has changed.
+        ticks.tick().await;
+        loop {
+            ticks.tick().await;
+            let saved = SavedState::from(&*snapshots.borrow());
+            let file = file.clone();
+            match tokio::task::spawn_blocking(move || file.write(&saved)).await {
+                Ok(Ok(())) => {}
+                Ok(Err(err)) => error!("autosave failed: {}", err),
+                Err(err) => error!("autosave failed: {}", err),
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::robot::command::Command;
+    use crate::robot::robot_config::RobotConfig;
+    use crate::robot::robot_state::JointState;
+
+    use std::sync::Arc;
+    use tokio::sync::RwLock;
+
+    fn saved(lift_elevation_mm: f64) -> SavedState {
+        let mut state = RobotState::default();
+        state.joint_state.lift_elevation_mm = lift_elevation_mm;
+        SavedState { version: SAVED_STATE_VERSION, timestamp: Utc::now(), state, target_state: state, target_coord_state: None, fault: Some("stopped".to_string()) }
+    }
+
+    fn state_file(dir: &std::path::Path) -> StateFile {
+        StateFile { path: dir.join("robot_state.json"), autosave_period: None }
+    }
+
+    #[test]
+    fn a_saved_state_reads_back_unchanged() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = state_file(dir.path());
+        let saved = saved(250.0);
+        file.write(&saved).unwrap();
+
+        let read = file.read().unwrap();
+        assert_eq!((read.timestamp, read.state, read.target_state, read.fault), (saved.timestamp, saved.state, saved.target_state, saved.fault));
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 1, "the temporary file is renamed away");
+    }
+
+    #[test]
+    fn a_save_from_another_version_is_refused() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = state_file(dir.path());
+        file.write(&SavedState { version: SAVED_STATE_VERSION + 1, ..saved(250.0) }).unwrap();
+
+        let Err(CommandError::Storage { message }) = file.read() else {
+            panic!("a save from another version was read");
+        };
+        assert!(message.contains(&format!("saved with version {}, expected {}", SAVED_STATE_VERSION + 1, SAVED_STATE_VERSION)), "{message}");
+    }
+
+    #[test]
+    fn concurrent_saves_do_not_tear_the_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = state_file(dir.path());
+        let writers: Vec<_> = (0..8)
+            .map(|writer| {
+                let file = file.clone();
+                std::thread::spawn(move || {
+                    for _ in 0..25 {
+                        file.write(&saved(writer as f64)).unwrap();
+                    }
+                })
+            })
+            .collect();
+        for writer in writers {
+            writer.join().unwrap();
+        }
+
+        assert!(file.read().is_ok());
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 1);
+    }
+
+    #[tokio::test]
+    async fn loading_restores_what_was_saved() {
+        let dir = tempfile::tempdir().unwrap();
+        let robot_lock: RobotLock = Arc::new(RwLock::new(Robot::test(RobotConfig::default(), dir.path())));
+        let target = JointState { lift_elevation_mm: 300.0, ..Default::default() };
+        robot_lock.write().await.execute(Command::SetJointState(target), None).unwrap();
+        let saved = save(&robot_lock).await.unwrap();
+
+        robot_lock.write().await.execute(Command::SetJointState(JointState::default()), None).unwrap();
+        let loaded = load(&robot_lock, None).await.unwrap();
+        assert_eq!(loaded.timestamp, saved.timestamp);
+        assert_eq!(loaded.target_state, saved.target_state);
+        assert_eq!(robot_lock.read().await.get_target_state().joint_state.lift_elevation_mm, 300.0);
+    }
+
+    #[test]
+    fn resuming_restores_the_saved_state() {
+        let dir = tempfile::tempdir().unwrap();
+        state_file(dir.path()).write(&saved(250.0)).unwrap();
+
+        let mut robot = Robot::test(RobotConfig::default(), dir.path());
+        resume(&mut robot).unwrap();
+        let snapshot = robot.snapshot();
+        assert_eq!(snapshot.state.joint_state.lift_elevation_mm, 250.0);
+        assert_eq!(snapshot.target_state.joint_state.lift_elevation_mm, 250.0);
+        assert_eq!(snapshot.fault.as_deref(), Some("stopped"), "a faulted robot stays faulted");
+    }
+
+    #[test]
+    fn resuming_without_a_save_starts_at_the_origin() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut robot = Robot::test(RobotConfig::default(), dir.path());
+        resume(&mut robot).unwrap();
+        assert_eq!(robot.snapshot().state, RobotState::default());
+    }
+
+    #[test]
+    fn resuming_from_an_unusable_save_fails() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = state_file(dir.path());
+        let mut robot = Robot::test(RobotConfig::default(), dir.path());
+
+        std::fs::write(&file.path, "{").unwrap();
+        assert!(matches!(resume(&mut robot), Err(CommandError::Storage { .. })));
+
+        file.write(&SavedState { version: 0, ..saved(250.0) }).unwrap();
+        assert!(matches!(resume(&mut robot), Err(CommandError::Storage { .. })));
+
+        file.write(&saved(1e9)).unwrap();
+        assert!(matches!(resume(&mut robot), Err(CommandError::InvalidValue { .. })));
+        assert_eq!(robot.snapshot().state, RobotState::default());
+    }
+}
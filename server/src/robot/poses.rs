@@ -0,0 +1,190 @@
+use super::command::{Command, CommandError};
+use super::robot_state::{Coord4DOF, JointState};
+
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+
+use tracing::info;
+
+/// Environment variable holding the path the pose library is kept in.
+pub const POSES_FILE_ENV: &str = "ROBOT_POSES_FILE";
+/// Path the pose library is kept in when `ROBOT_POSES_FILE` is not set.
+pub const DEFAULT_POSES_FILE: &str = "poses.json";
+/// Longest pose name accepted.
+pub const MAX_POSE_NAME_LEN: usize = 64;
+
+/// What a pose moves the arm to.
+#[derive(serde::Serialize, serde::Deserialize, Copy, Clone, Debug, PartialEq, schemars::JsonSchema, ts_rs::TS)]
+#[serde(rename_all = "snake_case")]
+pub enum PoseTarget {
+    /// A joint state, `{"joints": {...}}`.
+    Joints(JointState),
+    /// An end effector coordinate reached with ik, `{"coords": {...}}`.
+    Coords(Coord4DOF),
+}
+
+/// A named target the robot can be sent back to.
+#[derive(serde::Serialize, serde::Deserialize, Copy, Clone, Debug, PartialEq, schemars::JsonSchema, ts_rs::TS)]
+pub struct Pose {
+    pub target: PoseTarget,
+    /// The base target. The base stays where it is when not set.
+    #[serde(default)]
+    #[ts(optional = nullable)]
+    pub base_state: Option<Coord4DOF>,
+}
+
+impl Pose {
+    /// Ensures every value in the pose is finite and within a sane range.
+    pub fn validate(&self) -> Result<(), CommandError> {
+        match self.target {
+            PoseTarget::Joints(joint_state) => Command::SetJointState(joint_state).validate()?,
+            PoseTarget::Coords(coord_state) => Command::SetCoordState(coord_state).validate()?,
+        }
+        match self.base_state {
+            Some(base_state) => Command::SetBaseState(base_state).validate(),
+            None => Ok(()),
+        }
+    }
+}
+
+/// How the robot's current pose is taught.
+#[derive(serde::Deserialize, Copy, Clone, Debug, Default, PartialEq, schemars::JsonSchema, ts_rs::TS)]
+#[serde(rename_all = "snake_case")]
+pub enum PoseKind {
+    /// The current joint state.
+    #[default]
+    Joints,
+    /// The current end effector coordinate.
+    Coords,
+}
+
+/// Saves a pose under `name`, replacing any pose already saved under it.
+#[derive(serde::Deserialize, Clone, Debug, schemars::JsonSchema, ts_rs::TS)]
+pub struct SavePose {
+    pub name: String,
+    /// The pose to save. The robot's current pose, with its base, is taught when not set.
+    #[serde(default)]
+    #[ts(optional = nullable)]
+    pub pose: Option<Pose>,
+    /// How the current pose is taught when `pose` is not set.
+    #[serde(default)]
+    #[ts(as = "Option<PoseKind>", optional)]
+    pub kind: PoseKind,
+}
+
+/// Names a pose to delete.
+#[derive(serde::Deserialize, Clone, Debug, schemars::JsonSchema, ts_rs::TS)]
+pub struct PoseName {
+    pub name: String,
+}
+
+/// A change to the pose library.
+#[derive(Clone, Debug)]
+pub enum PoseOperation {
+    Save(SavePose),
+    Delete(PoseName),
+}
+
+/// Reply to saving or deleting a pose.
+#[derive(serde::Serialize, Clone, Debug, schemars::JsonSchema, ts_rs::TS)]
+pub struct PoseAck {
+    pub accepted: bool,
+    /// The pose saved or deleted.
+    pub pose: Option<Pose>,
+    /// Set when the request was rejected.
+    pub error: Option<CommandError>,
+}
+
+impl PoseAck {
+    pub fn new(result: Result<Pose, CommandError>) -> Self {
+        match result {
+            Ok(pose) => PoseAck { accepted: true, pose: Some(pose), error: None },
+            Err(error) => PoseAck { accepted: false, pose: None, error: Some(error) },
+        }
+    }
+}
+
+/// Poses saved by name, kept in a JSON file so they survive a restart.
+#[derive(Debug)]
+pub struct PoseLibrary {
+    path: PathBuf,
+    poses: BTreeMap<String, Pose>,
+}
+
+impl PoseLibrary {
+    /// Loads the library from the file named by `ROBOT_POSES_FILE`, or `DEFAULT_POSES_FILE`. The library starts empty if the file does not exist.
+    pub fn load() -> Result<PoseLibrary, Box<dyn std::error::Error>> {
+        let path = PathBuf::from(std::env::var(POSES_FILE_ENV).unwrap_or_else(|_| DEFAULT_POSES_FILE.to_string()));
+        let poses = match std::fs::read_to_string(&path) {
+            Ok(json) => serde_json::from_str(&json).map_err(|err| format!("{}: {}", path.display(), err))?,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => BTreeMap::new(),
+            Err(err) => return Err(format!("{}: {}", path.display(), err).into()),
+        };
+        info!("loaded {} poses from {}", poses.len(), path.display());
+        Ok(PoseLibrary { path, poses })
+    }
+
+    /// An empty library that is written to `path` when it is edited.
+    #[cfg(test)]
+    pub(crate) fn new(path: PathBuf) -> PoseLibrary {
+        PoseLibrary { path, poses: BTreeMap::new() }
+    }
+
+    pub fn get(&self, name: &str) -> Result<Pose, CommandError> {
+        self.poses.get(name).copied().ok_or_else(|| CommandError::UnknownPose { name: name.to_string() })
+    }
+
+    pub fn list(&self) -> &BTreeMap<String, Pose> {
+        &self.poses
+    }
+
+    /// Saves `pose` under `name` and writes the library.
+    pub fn insert(&mut self, name: &str, pose: Pose) -> Result<Pose, CommandError> {
+        validate_name(name, "pose")?;
+        pose.validate()?;
+        let previous = self.poses.insert(name.to_string(), pose);
+        if let Err(error) = self.write() {
+            // Keep the library matching the file.
+            match previous {
+                Some(previous) => self.poses.insert(name.to_string(), previous),
+                None => self.poses.remove(name),
+            };
+            return Err(error);
+        }
+        info!("saved pose '{}'", name);
+        Ok(pose)
+    }
+
+    /// Deletes the pose saved under `name` and writes the library.
+    pub fn remove(&mut self, name: &str) -> Result<Pose, CommandError> {
+        let pose = self.poses.remove(name).ok_or_else(|| CommandError::UnknownPose { name: name.to_string() })?;
+        if let Err(error) = self.write() {
+            self.poses.insert(name.to_string(), pose);
+            return Err(error);
+        }
+        info!("deleted pose '{}'", name);
+        Ok(pose)
+    }
+
+    /// Writes the library to a temporary file and renames it over the last write. Pose edits are rare and the file small, so it is written in place.
+    fn write(&self) -> Result<(), CommandError> {
+        let error = |err: &dyn std::fmt::Display| CommandError::Storage { message: format!("{}: {}", self.path.display(), err) };
+        let mut temporary = self.path.clone().into_os_string();
+        temporary.push(".tmp");
+        let json = serde_json::to_vec_pretty(&self.poses).map_err(|err| error(&err))?;
+        std::fs::write(&temporary, json).map_err(|err| error(&err))?;
+        std::fs::rename(&temporary, &self.path).map_err(|err| error(&err))
+    }
+}
+
+/// Names are kept to letters, digits, `_`, `-` and `.` so they can be used in URLs, topics and file names. `kind` names what is being named in the error.
+pub(super) fn validate_name(name: &str, kind: &str) -> Result<(), CommandError> {
+    let valid = !name.is_empty() && name.len() <= MAX_POSE_NAME_LEN && name.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '.'));
+    if !valid {
+        return Err(CommandError::InvalidValue {
+            field: "name",
+            message: format!("{kind} names must be 1 to {MAX_POSE_NAME_LEN} letters, digits, '_', '-' or '.', got '{name}'"),
+        });
+    }
+    Ok(())
+}
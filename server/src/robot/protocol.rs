@@ -0,0 +1,132 @@
+use super::auth::{AuthError, Role};
+use super::command::{Command, CommandAck, CommandError, CommandErrorEvent, CommandOutcome};
+use super::control::{ControlAck, ControlRequest, ControlStatus, LeaseGrant};
+use super::history::{HistoryAck, HistoryFormat, HistoryQuery};
+use super::persistence::{SavedState, SnapshotAck};
+use super::poses::{Pose, PoseAck, PoseKind, PoseName, PoseTarget, SavePose};
+use super::replay::{ReplayAck, ReplayControl, ReplayStatus};
+use super::rest::{FaultResponse, TargetResponse};
+use super::scripting::{ScriptAck, ScriptControl, ScriptEvent, ScriptInfo, ScriptLanguage, ScriptName, ScriptSource, ScriptState, ScriptStatus};
+use super::behavior_tree::{NodeKind, NodeStatus, TreeNodeStatus};
+use super::tasks::PickAndPlace;
+use super::robot_state::{Coord4DOF, JointState, LinkCoords, RobotState};
+use super::telemetry::{Stream, StreamRequest, StreamSubscription, Telemetry, TelemetryFrame};
+use super::ws::{ClientMessage, ServerMessage};
+use super::SubscriptionAck;
+
+use axum::{http::header, routing::get, Json, Router};
+use schemars::generate::SchemaSettings;
+use ts_rs::TS;
+
+/// Lists every type sent or received by a client. Generates the TypeScript definitions and JSON Schema from them.
+macro_rules! protocol_types {
+    ($($ty:ty),* $(,)?) => {
+        /// TypeScript definitions of every message type.
+        pub fn typescript() -> String {
+            let mut output = String::from("// Generated from the server's Rust types. Do not edit, run `UPDATE_PROTOCOL=1 cargo test` in `server` instead.\n");
+            $(
+                output.push_str(&format!("\nexport {}\n", <$ty as TS>::decl()));
+            )*
+            output
+        }
+
+        /// A JSON Schema document defining every message type under `$defs`.
+        pub fn json_schema() -> serde_json::Value {
+            let mut generator = SchemaSettings::draft2020_12().into_generator();
+            $(
+                generator.subschema_for::<$ty>();
+            )*
+            serde_json::json!({
+                "$schema": "https://json-schema.org/draft/2020-12/schema",
+                "title": "robot-visualizer protocol",
+                "$defs": generator.definitions(),
+            })
+        }
+    };
+}
+
+protocol_types!(
+    serde_json::Value,
+    JointState,
+    Coord4DOF,
+    RobotState,
+    LinkCoords,
+    Role,
+    AuthError,
+    Command,
+    CommandError,
+    CommandErrorEvent,
+    CommandOutcome,
+    CommandAck,
+    ControlStatus,
+    ControlRequest,
+    LeaseGrant,
+    ControlAck,
+    ReplayControl,
+    ReplayStatus,
+    ReplayAck,
+    HistoryFormat,
+    HistoryQuery,
+    HistoryAck,
+    SavedState,
+    SnapshotAck,
+    PoseTarget,
+    Pose,
+    PoseKind,
+    SavePose,
+    PoseName,
+    PoseAck,
+    ScriptLanguage,
+    ScriptInfo,
+    PickAndPlace,
+    NodeKind,
+    NodeStatus,
+    TreeNodeStatus,
+    ScriptSource,
+    ScriptName,
+    ScriptControl,
+    ScriptState,
+    ScriptStatus,
+    ScriptEvent,
+    ScriptAck,
+    Stream,
+    StreamSubscription,
+    StreamRequest,
+    SubscriptionAck,
+    TelemetryFrame,
+    Telemetry,
+    TargetResponse,
+    FaultResponse,
+    ClientMessage,
+    ServerMessage,
+);
+
+/// Serves the JSON Schema and TypeScript definitions of the protocol.
+pub fn router() -> Router {
+    Router::new()
+        .route("/schema", get(|| async { Json(json_schema()) }))
+        .route("/schema/protocol.ts", get(|| async { ([(header::CONTENT_TYPE, "text/plain; charset=utf-8")], typescript()) }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The TypeScript definitions checked in for the frontend, relative to the server crate.
+    const TYPESCRIPT_PATH: &str = "../frontend/src/types/protocol.ts";
+
+    /// Fails when the checked in TypeScript definitions no longer match the Rust types.
+    #[test]
+    fn typescript_is_up_to_date() {
+        let path = std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join(TYPESCRIPT_PATH);
+        let generated = typescript();
+
+        if std::env::var_os("UPDATE_PROTOCOL").is_some() {
+            std::fs::write(&path, generated).expect("Could not write the TypeScript definitions");
+            return;
+        }
+
+        let checked_in = std::fs::read_to_string(&path).unwrap_or_default();
+        assert!(checked_in == generated, "{} is out of date, run `UPDATE_PROTOCOL=1 cargo test` in `server` to regenerate it", path.display());
+    }
+}
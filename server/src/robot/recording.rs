@@ -0,0 +1,314 @@
+use super::command::{Command, CommandError, CommandOutcome};
+use super::mcap_log::McapWriter;
+use super::telemetry::RobotSnapshot;
+
+use std::io::{BufWriter, Write};
+
+use chrono::{DateTime, Utc};
+use tokio::sync::mpsc;
+use tokio::time::{Duration, Instant};
+use tracing::{error, info};
+
+/// Environment variable holding the path to record to. Nothing is recorded when it is not set.
+/// Paths ending in `.mcap` are written as MCAP, anything else as JSON lines.
+pub const RECORD_ENV: &str = "ROBOT_RECORD";
+/// Environment variable choosing how often the state is recorded, `tick` or `broadcast` (the default).
+pub const RECORD_EVERY_ENV: &str = "ROBOT_RECORD_EVERY";
+/// Environment variable holding the path of a recording to replay instead of simulating.
+pub const REPLAY_ENV: &str = "ROBOT_REPLAY";
+
+pub type RecordingError = Box<dyn std::error::Error + Send + Sync>;
+
+/// How often the recorder writes the robot's state.
+#[derive(serde::Deserialize, Copy, Clone, Debug, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum RecordEvery {
+    /// Every controller tick and after every command.
+    Tick,
+    /// At most once per broadcast period.
+    #[default]
+    Broadcast,
+}
+
+/// The file format of a recording.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum RecordingFormat {
+    JsonLines,
+    Mcap,
+}
+
+impl RecordingFormat {
+    /// Chooses the format from the file extension.
+    pub fn of(path: &str) -> RecordingFormat {
+        match std::path::Path::new(path).extension() {
+            Some(extension) if extension.eq_ignore_ascii_case("mcap") => RecordingFormat::Mcap,
+            _ => RecordingFormat::JsonLines,
+        }
+    }
+}
+
+/// A command received by the robot and whether it was accepted.
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug, schemars::JsonSchema)]
+pub struct CommandRecord {
+    pub timestamp: DateTime<Utc>,
+    pub command: Command,
+    pub accepted: bool,
+    /// Why the command was rejected.
+    pub error: Option<String>,
+}
+
+/// One line of a recording.
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug)]
+#[serde(tag = "type", content = "data", rename_all = "snake_case")]
+pub enum RecordEntry {
+    Snapshot(Box<RobotSnapshot>),
+    Command(CommandRecord),
+}
+
+/// A line of a JSON lines recording as read back for a replay.
+/// Commands are skipped unread, a rejected command can hold values such as NaN that are written as `null`.
+#[derive(serde::Deserialize)]
+#[serde(tag = "type", content = "data", rename_all = "snake_case")]
+enum ReplayedEntry {
+    Snapshot(Box<RobotSnapshot>),
+    Command(serde::de::IgnoredAny),
+}
+
+/// Writes entries in one recording format.
+pub trait RecordWriter: Send {
+    fn write(&mut self, entry: &RecordEntry) -> Result<(), RecordingError>;
+    fn flush(&mut self) -> Result<(), RecordingError>;
+    /// Completes the file. Nothing is written afterwards.
+    fn finish(&mut self) -> Result<(), RecordingError>;
+}
+
+/// Writes each entry as a line of JSON.
+struct JsonLinesWriter {
+    writer: BufWriter<std::fs::File>,
+}
+
+impl RecordWriter for JsonLinesWriter {
+    fn write(&mut self, entry: &RecordEntry) -> Result<(), RecordingError> {
+        serde_json::to_writer(&mut self.writer, entry)?;
+        self.writer.write_all(b"\n")?;
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<(), RecordingError> {
+        Ok(self.writer.flush()?)
+    }
+
+    fn finish(&mut self) -> Result<(), RecordingError> {
+        self.flush()
+    }
+}
+
+/// Writes the robot's state and the commands it receives to a file.
+/// Entries are handed to a writer thread so recording never blocks the controller.
+#[derive(Debug)]
+pub struct Recorder {
+    entries: mpsc::UnboundedSender<RecordEntry>,
+    writer: std::thread::JoinHandle<()>,
+    every: RecordEvery,
+    period: Duration,
+    last_snapshot: Option<Instant>,
+}
+
+impl Recorder {
+    /// Starts recording to the file named by `ROBOT_RECORD`, if it is set. `period` is the broadcast period.
+    pub fn from_env(period: Duration) -> Result<Option<Recorder>, RecordingError> {
+        let Ok(path) = std::env::var(RECORD_ENV) else {
+            return Ok(None);
+        };
+        let every = match std::env::var(RECORD_EVERY_ENV) {
+            Ok(every) => serde_json::from_value(serde_json::Value::String(every))?,
+            Err(_) => RecordEvery::default(),
+        };
+        Ok(Some(Recorder::start(&path, every, period)?))
+    }
+
+    /// Creates or truncates `path` and starts the writer thread.
+    pub fn start(path: &str, every: RecordEvery, period: Duration) -> Result<Recorder, RecordingError> {
+        let file = std::fs::File::create(path)?;
+        let format = RecordingFormat::of(path);
+        let writer: Box<dyn RecordWriter> = match format {
+            RecordingFormat::JsonLines => Box::new(JsonLinesWriter { writer: BufWriter::new(file) }),
+            RecordingFormat::Mcap => Box::new(McapWriter::new(BufWriter::new(file))?),
+        };
+        info!("recording every {:?} to {} as {:?}", every, path, format);
+
+        let (entries, receiver) = mpsc::unbounded_channel();
+        let writer = std::thread::spawn(move || write_entries(writer, receiver));
+        Ok(Recorder { entries, writer, every, period, last_snapshot: None })
+    }
+
+    /// Records `snapshot`, skipping it if the last one was recorded less than a broadcast period ago when recording every broadcast.
+    pub fn record_snapshot(&mut self, snapshot: &RobotSnapshot) {
+        let now = Instant::now();
+        if self.every == RecordEvery::Broadcast && self.last_snapshot.is_some_and(|last| now.duration_since(last) < self.period) {
+            return;
+        }
+        self.last_snapshot = Some(now);
+        let _ = self.entries.send(RecordEntry::Snapshot(Box::new(snapshot.clone())));
+    }
+
+    pub fn record_command(&self, command: Command, result: &Result<CommandOutcome, CommandError>) {
+        let record = CommandRecord {
+            timestamp: Utc::now(),
+            command,
+            accepted: result.is_ok(),
+            error: result.as_ref().err().map(|error| error.to_string()),
+        };
+        let _ = self.entries.send(RecordEntry::Command(record));
+    }
+
+    /// Writes the remaining entries and completes the file. Blocks until it is written.
+    pub fn finish(self) {
+        let Recorder { entries, writer, .. } = self;
+        drop(entries);
+        let _ = writer.join();
+    }
+}
+
+/// Writes entries until the recorder is finished, flushing whenever the queue is drained.
+fn write_entries(mut writer: Box<dyn RecordWriter>, mut entries: mpsc::UnboundedReceiver<RecordEntry>) {
+    while let Some(entry) = entries.blocking_recv() {
+        let mut result = writer.write(&entry);
+        // Stop taking entries once a write fails so none are dropped unwritten.
+        while result.is_ok() {
+            let Ok(entry) = entries.try_recv() else {
+                break;
+            };
+            result = writer.write(&entry);
+        }
+        if let Err(err) = result.and_then(|_| writer.flush()) {
+            error!("could not write recording, recording stopped: {}", err);
+            return;
+        }
+    }
+
+    match writer.finish() {
+        Ok(()) => info!("recording finished"),
+        Err(err) => error!("could not finish recording: {}", err),
+    }
+}
+
+/// Reads the snapshots of a recording in either format. Recorded commands are skipped.
+pub fn read_snapshots(path: &str) -> Result<Vec<RobotSnapshot>, RecordingError> {
+    if RecordingFormat::of(path) == RecordingFormat::Mcap {
+        return super::mcap_log::read_snapshots(&std::fs::read(path)?);
+    }
+
+    let mut snapshots = Vec::new();
+    for (number, line) in std::fs::read_to_string(path)?.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        match serde_json::from_str(line).map_err(|err| format!("{}:{}: {}", path, number + 1, err))? {
+            ReplayedEntry::Snapshot(snapshot) => snapshots.push(*snapshot),
+            ReplayedEntry::Command(_) => {}
+        }
+    }
+    Ok(snapshots)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::robot::persistence::StateFile;
+    use crate::robot::poses::PoseLibrary;
+    use crate::robot::replay::{Replay, ReplayControl};
+    use crate::robot::robot_config::RobotConfig;
+    use crate::robot::robot_state::{Coord4DOF, JointState, RobotState};
+    use crate::robot::{Robot, CONTROLLER_LOOP_TIME_S};
+
+    fn robot(dir: &std::path::Path, recorder: Option<Recorder>, replay: Option<Replay>) -> Robot {
+        let state_file = StateFile { path: dir.join("robot_state.json"), autosave_period: None };
+        Robot::simulation(RobotConfig::default(), PoseLibrary::new(dir.join("poses.json")), state_file, recorder, replay)
+    }
+
+    fn assert_same_state(replayed: &RobotState, recorded: &RobotState) {
+        let error = JointState::clamped_sub(replayed.joint_state, recorded.joint_state);
+        assert!(error.within(1e-9, 1e-9), "replayed {replayed:?}, recorded {recorded:?}");
+        assert!((replayed.base_state - recorded.base_state).within(1e-9, 1e-9), "replayed {replayed:?}, recorded {recorded:?}");
+    }
+
+    /// Records a few commands and the ticks after each to `file`, returning the snapshots published.
+    fn record(dir: &std::path::Path, file: &str) -> Vec<RobotSnapshot> {
+        let path = dir.join(file);
+        let recorder = Recorder::start(path.to_str().unwrap(), RecordEvery::Tick, Duration::from_millis(20)).unwrap();
+        let mut robot = robot(dir, Some(recorder), None);
+
+        let commands = [
+            Command::SetJointState(JointState { swing_rotation_deg: 45.0, lift_elevation_mm: 300.0, ..Default::default() }),
+            Command::SetBaseState(Coord4DOF { x: 0.5, ..Default::default() }),
+            Command::SetGripper(f64::NAN),
+            Command::SetGripper(20.0),
+        ];
+        let mut published = Vec::new();
+        for command in commands {
+            let _ = robot.execute(command, None);
+            for _ in 0..5 {
+                robot.step(CONTROLLER_LOOP_TIME_S);
+                robot.publish_snapshot();
+                published.push(robot.subscribe_snapshots().borrow().clone());
+                std::thread::sleep(std::time::Duration::from_millis(1));
+            }
+        }
+        robot.recorder.take().unwrap().finish();
+        published
+    }
+
+    #[test]
+    fn recorded_commands_are_kept_in_order() {
+        let dir = tempfile::tempdir().unwrap();
+        record(dir.path(), "recording.jsonl");
+
+        let commands: Vec<serde_json::Value> = std::fs::read_to_string(dir.path().join("recording.jsonl"))
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str::<serde_json::Value>(line).unwrap())
+            .filter(|entry| entry["type"] == "command")
+            .map(|entry| entry["data"].clone())
+            .collect();
+        let names: Vec<_> = commands.iter().map(|record| (record["command"]["command"].as_str().unwrap(), record["accepted"].as_bool().unwrap())).collect();
+        assert_eq!(names, [("set_joint_state", true), ("set_base_state", true), ("set_gripper", false), ("set_gripper", true)]);
+        assert!(commands[2]["error"].as_str().unwrap().contains("gripper_open_mm must be a finite number"));
+        // The rejected value cannot be written as JSON.
+        assert_eq!(commands[2]["command"]["data"], serde_json::Value::Null);
+    }
+
+    #[test]
+    fn recordings_replay_what_was_recorded() {
+        for file in ["recording.jsonl", "recording.mcap"] {
+            let dir = tempfile::tempdir().unwrap();
+            let published = record(dir.path(), file);
+
+            let path = dir.path().join(file);
+            let recorded = read_snapshots(path.to_str().unwrap()).unwrap();
+            // Executing a command publishes a snapshot too, so the ticks are the last five after each command.
+            let ticks: Vec<_> = recorded.iter().filter(|snapshot| published.iter().any(|tick| tick.timestamp == snapshot.timestamp)).collect();
+            assert_eq!(ticks.len(), published.len(), "{file}");
+            for (replayed, recorded) in ticks.iter().zip(&published) {
+                assert_same_state(&replayed.state, &recorded.state);
+                assert_eq!(replayed.target_state.joint_state.gripper_open_mm, recorded.target_state.joint_state.gripper_open_mm);
+            }
+
+            let replay = Replay::load(path.to_str().unwrap()).unwrap();
+            let mut robot = robot(dir.path(), None, Some(replay));
+            assert!(matches!(robot.execute(Command::SetGripper(10.0), None), Err(CommandError::Replaying)));
+
+            robot.publish_snapshot();
+            assert_eq!(robot.subscribe_snapshots().borrow().timestamp, recorded[0].timestamp);
+
+            let duration_s = robot.replay_status().unwrap().duration_s;
+            robot.control_replay(ReplayControl::Seek { position_s: duration_s }).unwrap();
+            robot.publish_snapshot();
+            let last = recorded.last().unwrap();
+            let shown = robot.subscribe_snapshots().borrow().clone();
+            assert_eq!(shown.timestamp, last.timestamp);
+            assert_same_state(&shown.state, &published.last().unwrap().state);
+            assert_eq!(shown.target_state.joint_state.gripper_open_mm, 20.0);
+        }
+    }
+}
@@ -0,0 +1,247 @@
+use super::command::CommandError;
+use super::recording::read_snapshots;
+use super::telemetry::RobotSnapshot;
+
+use chrono::{DateTime, Utc};
+use tokio::time::Instant;
+use tracing::info;
+
+/// Fastest playback speed accepted.
+pub const MAX_REPLAY_SPEED: f64 = 100.0;
+
+/// Changes how a recording is played back.
+#[derive(serde::Deserialize, Copy, Clone, Debug, schemars::JsonSchema, ts_rs::TS)]
+#[serde(tag = "action", rename_all = "snake_case")]
+pub enum ReplayControl {
+    Play,
+    Pause,
+    /// Jumps to `position_s` seconds from the start of the recording.
+    Seek { position_s: f64 },
+    /// Sets the playback speed, 1 is real time.
+    Speed { speed: f64 },
+}
+
+/// Where playback is in the recording.
+#[derive(serde::Serialize, Clone, Debug, PartialEq, schemars::JsonSchema, ts_rs::TS)]
+pub struct ReplayStatus {
+    pub playing: bool,
+    pub speed: f64,
+    /// Seconds from the start of the recording.
+    pub position_s: f64,
+    pub duration_s: f64,
+    /// When the snapshot being shown was recorded.
+    pub timestamp: DateTime<Utc>,
+}
+
+/// Reply to a replay control.
+#[derive(serde::Serialize, Clone, Debug, schemars::JsonSchema, ts_rs::TS)]
+pub struct ReplayAck {
+    pub accepted: bool,
+    /// The playback status after the control was applied.
+    pub status: Option<ReplayStatus>,
+    /// Set when the control was rejected.
+    pub error: Option<CommandError>,
+}
+
+impl ReplayAck {
+    pub fn new(result: Result<ReplayStatus, CommandError>) -> Self {
+        match result {
+            Ok(status) => ReplayAck { accepted: true, status: Some(status), error: None },
+            Err(error) => ReplayAck { accepted: false, status: None, error: Some(error) },
+        }
+    }
+}
+
+/// Plays back the snapshots of a recording in place of the simulation.
+#[derive(Debug)]
+pub struct Replay {
+    snapshots: Vec<RobotSnapshot>,
+    /// Seconds from the start of the recording.
+    position_s: f64,
+    playing: bool,
+    speed: f64,
+    /// When the playhead was last advanced.
+    last_advanced: Option<Instant>,
+}
+
+impl Replay {
+    /// Loads the snapshots from the recording at `path`, a JSON lines or MCAP file.
+    pub fn load(path: &str) -> Result<Replay, Box<dyn std::error::Error>> {
+        let snapshots = read_snapshots(path).map_err(|err| format!("{}: {}", path, err))?;
+
+        if snapshots.is_empty() {
+            return Err(format!("{} contains no snapshots", path).into());
+        }
+        info!("replaying {} snapshots from {}", snapshots.len(), path);
+        Ok(Replay { snapshots, position_s: 0.0, playing: true, speed: 1.0, last_advanced: None })
+    }
+
+    fn offset_s(&self, snapshot: &RobotSnapshot) -> f64 {
+        (snapshot.timestamp - self.snapshots[0].timestamp).num_microseconds().unwrap_or(i64::MAX) as f64 / 1e6
+    }
+
+    fn duration_s(&self) -> f64 {
+        self.offset_s(self.snapshots.last().expect("a replay has snapshots"))
+    }
+
+    /// Moves the playhead forward by the time since it was last advanced, pausing at the end of the recording.
+    pub fn advance(&mut self) {
+        let now = Instant::now();
+        if let (true, Some(last)) = (self.playing, self.last_advanced) {
+            self.position_s += now.duration_since(last).as_secs_f64() * self.speed;
+            if self.position_s >= self.duration_s() {
+                self.position_s = self.duration_s();
+                self.playing = false;
+                info!("replay finished");
+            }
+        }
+        self.last_advanced = Some(now);
+    }
+
+    /// The last snapshot recorded at or before the playhead.
+    pub fn current(&self) -> &RobotSnapshot {
+        let index = self.snapshots.partition_point(|snapshot| self.offset_s(snapshot) <= self.position_s);
+        &self.snapshots[index.saturating_sub(1)]
+    }
+
+    pub fn status(&self) -> ReplayStatus {
+        ReplayStatus {
+            playing: self.playing,
+            speed: self.speed,
+            position_s: self.position_s,
+            duration_s: self.duration_s(),
+            timestamp: self.current().timestamp,
+        }
+    }
+
+    pub fn control(&mut self, control: ReplayControl) -> Result<ReplayStatus, CommandError> {
+        match control {
+            ReplayControl::Play => {
+                // Playing at the end starts again from the beginning.
+                if self.position_s >= self.duration_s() {
+                    self.position_s = 0.0;
+                }
+                self.playing = true;
+            }
+            ReplayControl::Pause => self.playing = false,
+            ReplayControl::Seek { position_s } => {
+                if !position_s.is_finite() {
+                    return Err(CommandError::InvalidValue { field: "position_s", message: format!("position_s must be finite, got {position_s}") });
+                }
+                self.position_s = position_s.clamp(0.0, self.duration_s());
+            }
+            ReplayControl::Speed { speed } => {
+                if !speed.is_finite() || speed <= 0.0 || speed > MAX_REPLAY_SPEED {
+                    return Err(CommandError::InvalidValue { field: "speed", message: format!("speed must be in (0, {MAX_REPLAY_SPEED}], got {speed}") });
+                }
+                self.speed = speed;
+            }
+        }
+        Ok(self.status())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use tokio::time::Duration;
+
+    /// A replay of one snapshot a second for `seconds` seconds, each lifted by its offset in mm.
+    fn replay(seconds: u32) -> Replay {
+        let start = Utc::now();
+        let snapshots = (0..=seconds)
+            .map(|second| {
+                let mut snapshot = RobotSnapshot { timestamp: start + chrono::Duration::seconds(second.into()), ..Default::default() };
+                snapshot.state.joint_state.lift_elevation_mm = second.into();
+                snapshot
+            })
+            .collect();
+        let mut replay = Replay { snapshots, position_s: 0.0, playing: true, speed: 1.0, last_advanced: None };
+        replay.advance();
+        replay
+    }
+
+    async fn advance_by(replay: &mut Replay, seconds: f64) {
+        tokio::time::advance(Duration::from_secs_f64(seconds)).await;
+        replay.advance();
+    }
+
+    fn shown(replay: &Replay) -> f64 {
+        replay.current().state.joint_state.lift_elevation_mm
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn playback_follows_the_clock_at_the_set_speed() {
+        let mut replay = replay(10);
+        advance_by(&mut replay, 1.5).await;
+        assert_eq!(replay.status().position_s, 1.5);
+        assert_eq!(shown(&replay), 1.0);
+
+        replay.control(ReplayControl::Speed { speed: 2.0 }).unwrap();
+        advance_by(&mut replay, 1.0).await;
+        assert_eq!(replay.status().position_s, 3.5);
+        assert_eq!(shown(&replay), 3.0);
+        assert_eq!(replay.status().timestamp, replay.snapshots[3].timestamp);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn pausing_holds_the_playhead() {
+        let mut replay = replay(10);
+        advance_by(&mut replay, 2.0).await;
+
+        let status = replay.control(ReplayControl::Pause).unwrap();
+        assert!(!status.playing);
+        advance_by(&mut replay, 5.0).await;
+        assert_eq!(replay.status().position_s, 2.0);
+
+        replay.control(ReplayControl::Play).unwrap();
+        advance_by(&mut replay, 1.0).await;
+        assert_eq!(replay.status().position_s, 3.0);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn playback_pauses_at_the_end_and_play_restarts_it() {
+        let mut replay = replay(3);
+        advance_by(&mut replay, 10.0).await;
+        let status = replay.status();
+        assert!(!status.playing);
+        assert_eq!((status.position_s, status.duration_s), (3.0, 3.0));
+        assert_eq!(shown(&replay), 3.0);
+
+        let status = replay.control(ReplayControl::Play).unwrap();
+        assert!(status.playing);
+        assert_eq!(status.position_s, 0.0);
+        assert_eq!(shown(&replay), 0.0);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn seeking_is_clamped_to_the_recording() {
+        let mut replay = replay(10);
+        replay.control(ReplayControl::Pause).unwrap();
+
+        assert_eq!(replay.control(ReplayControl::Seek { position_s: 4.2 }).unwrap().position_s, 4.2);
+        assert_eq!(shown(&replay), 4.0);
+        assert_eq!(replay.control(ReplayControl::Seek { position_s: -3.0 }).unwrap().position_s, 0.0);
+        assert_eq!(replay.control(ReplayControl::Seek { position_s: 60.0 }).unwrap().position_s, 10.0);
+        assert_eq!(shown(&replay), 10.0);
+
+        for position_s in [f64::NAN, f64::INFINITY, f64::NEG_INFINITY] {
+            let result = replay.control(ReplayControl::Seek { position_s });
+            assert!(matches!(result, Err(CommandError::InvalidValue { field: "position_s", .. })), "{position_s}");
+        }
+        assert_eq!(replay.status().position_s, 10.0);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn speed_must_be_positive_and_bounded() {
+        let mut replay = replay(10);
+        for speed in [0.0, -1.0, MAX_REPLAY_SPEED + 1.0, f64::NAN, f64::INFINITY] {
+            let result = replay.control(ReplayControl::Speed { speed });
+            assert!(matches!(result, Err(CommandError::InvalidValue { field: "speed", .. })), "{speed}");
+        }
+        assert_eq!(replay.status().speed, 1.0);
+        assert_eq!(replay.control(ReplayControl::Speed { speed: MAX_REPLAY_SPEED }).unwrap().speed, MAX_REPLAY_SPEED);
+        assert_eq!(replay.control(ReplayControl::Speed { speed: 0.25 }).unwrap().speed, 0.25);
+    }
+}
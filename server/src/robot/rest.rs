@@ -0,0 +1,418 @@
+use super::audit::{raw_payload, AuditQuery, Origin, SharedAudit};
+use super::auth::{control_authorized, execute_authorized, pose_authorized, replay_authorized, require_identity, require_role, script_authorized, snapshot_authorized, Identity, Role, SharedAuth};
+use super::command::{Command, CommandAck, CommandError};
+use super::control::{ControlAck, ControlOperation, ControlRequest, ControlStatus};
+use super::history::{lock_history, HistoryFormat, HistoryQuery, SharedHistory};
+use super::persistence::{SavedState, SnapshotAck, SnapshotOperation};
+use super::poses::{Pose, PoseAck, PoseKind, PoseName, PoseOperation, SavePose};
+use super::replay::{ReplayAck, ReplayControl, ReplayStatus};
+use super::robot_state::{Coord4DOF, RobotState};
+use super::scripting::{ScriptAck, ScriptControl, ScriptLanguage, ScriptName, ScriptOperation, ScriptSource, ScriptStatus, SharedScripts};
+use super::tasks::PickAndPlace;
+use super::telemetry::SnapshotReceiver;
+use super::RobotLock;
+
+use std::net::SocketAddr;
+
+use axum::{
+    body::Bytes,
+    extract::{rejection::{JsonRejection, QueryRejection}, ConnectInfo, Path, Query, State},
+    middleware,
+    http::{header, HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+    routing::{get, post, put},
+    Extension, Json, Router,
+};
+use std::collections::BTreeMap;
+
+use tracing::warn;
+
+/// Header carrying the control lease token on commands and lease renewals.
+const LEASE_TOKEN_HEADER: &str = "x-lease-token";
+
+/// The targets the controller is currently working towards.
+#[derive(serde::Serialize, Clone, Debug, schemars::JsonSchema, ts_rs::TS)]
+pub struct TargetResponse {
+    pub target_state: RobotState,
+    pub target_coord_state: Option<Coord4DOF>,
+}
+
+/// The robot's fault status.
+#[derive(serde::Serialize, Clone, Debug, schemars::JsonSchema, ts_rs::TS)]
+pub struct FaultResponse {
+    pub fault: Option<String>,
+}
+
+/// Shared state for the HTTP API. Queries read the latest snapshot, commands lock the robot.
+#[derive(Clone)]
+struct RestState {
+    robot_lock: RobotLock,
+    snapshots: SnapshotReceiver,
+    history: SharedHistory,
+    audit: SharedAudit,
+    scripts: SharedScripts,
+}
+
+/// Builds the HTTP JSON API used to query and command the robot. Every route requires a valid token.
+pub fn router(robot_lock: RobotLock, snapshots: SnapshotReceiver, history: SharedHistory, audit: SharedAudit, scripts: SharedScripts, auth: SharedAuth) -> Router {
+    Router::new()
+        .route("/state", get(get_state))
+        .route("/state/coords", get(get_coords))
+        .route("/state/settled", get(get_settled))
+        .route("/target", get(get_target))
+        .route("/target/joints", put(put_target_joints))
+        .route("/target/coords", put(put_target_coords))
+        .route("/target/base", put(put_target_base))
+        .route("/target/gripper", put(put_target_gripper))
+        .route("/target/pose", put(put_target_pose))
+        .route("/estop", post(post_estop))
+        .route("/fault", get(get_fault))
+        .route("/fault/reset", post(post_fault_reset))
+        .route("/control", get(get_control).post(post_control).delete(delete_control))
+        .route("/control/renew", post(post_control_renew))
+        .route("/replay", get(get_replay).post(post_replay))
+        .route("/history", get(get_history))
+        .route("/poses", get(get_poses))
+        .route("/poses/:name", get(get_pose).put(put_pose).delete(delete_pose))
+        .route("/poses/:name/teach", post(post_pose_teach))
+        .route("/scripts", get(get_scripts))
+        .route("/scripts/:name", get(get_script).put(put_script).delete(delete_script))
+        .route("/scripts/:name/run", post(post_script_run))
+        .route("/script", get(get_script_status).post(post_script_control))
+        .route("/tasks/pick_and_place", post(post_pick_and_place))
+        .route("/snapshot/save", post(post_snapshot_save))
+        .route("/snapshot/load", post(post_snapshot_load))
+        .route("/audit", get(get_audit))
+        .route_layer(middleware::from_fn_with_state(auth, require_identity))
+        .with_state(RestState { robot_lock, snapshots, history, audit, scripts })
+}
+
+async fn get_state(State(state): State<RestState>) -> Json<RobotState> {
+    Json(state.snapshots.borrow().state)
+}
+
+async fn get_coords(State(state): State<RestState>) -> Json<Coord4DOF> {
+    Json(state.snapshots.borrow().coords)
+}
+
+async fn get_settled(State(state): State<RestState>) -> Json<bool> {
+    Json(state.snapshots.borrow().settled)
+}
+
+async fn get_target(State(state): State<RestState>) -> Json<TargetResponse> {
+    let snapshot = state.snapshots.borrow();
+    Json(TargetResponse { target_state: snapshot.target_state, target_coord_state: snapshot.target_coord_state })
+}
+
+async fn get_fault(State(state): State<RestState>) -> Json<FaultResponse> {
+    Json(FaultResponse { fault: state.snapshots.borrow().fault.clone() })
+}
+
+async fn get_control(State(state): State<RestState>) -> Json<Option<ControlStatus>> {
+    Json(state.snapshots.borrow().control.clone())
+}
+
+/// Requests the control lease. The body may be omitted to request control without taking it over.
+async fn post_control(State(state): State<RestState>, Extension(identity): Extension<Identity>, request: Option<Json<ControlRequest>>) -> Response {
+    let Json(request) = request.unwrap_or_default();
+    let holder = format!("{} (http)", identity.name);
+    control(&state.robot_lock, &identity, ControlOperation::Request { holder: &holder, take_over: request.take_over }).await
+}
+
+async fn post_control_renew(State(state): State<RestState>, Extension(identity): Extension<Identity>, headers: HeaderMap) -> Response {
+    let token = lease_token(&headers).unwrap_or_default();
+    control(&state.robot_lock, &identity, ControlOperation::Renew { token }).await
+}
+
+async fn delete_control(State(state): State<RestState>, Extension(identity): Extension<Identity>, headers: HeaderMap) -> Response {
+    let token = lease_token(&headers).unwrap_or_default();
+    control(&state.robot_lock, &identity, ControlOperation::Release { token }).await
+}
+
+/// The recent telemetry in the query's time range as JSON or CSV.
+async fn get_history(State(state): State<RestState>, query: Result<Query<HistoryQuery>, QueryRejection>) -> Response {
+    let result = match query {
+        Ok(Query(query)) => query
+            .range(chrono::Utc::now())
+            .and_then(|range| lock_history(&state.history).query(&query, range))
+            .map(|table| (table, query.format.unwrap_or_default())),
+        Err(rejection) => Err(rejection.body_text()),
+    };
+
+    match result {
+        Ok((table, HistoryFormat::Json)) => Json(table.to_json()).into_response(),
+        Ok((table, HistoryFormat::Csv)) => ([(header::CONTENT_TYPE, "text/csv")], table.to_csv()).into_response(),
+        Err(error) => (StatusCode::BAD_REQUEST, Json(serde_json::json!({ "error": error }))).into_response(),
+    }
+}
+
+/// The audited commands matching the query, oldest first. Only admins may read the audit log.
+async fn get_audit(State(state): State<RestState>, Extension(identity): Extension<Identity>, query: Result<Query<AuditQuery>, QueryRejection>) -> Response {
+    if let Err(error) = require_role(&identity, Role::Admin, "read the audit log") {
+        return (status_code(&error), Json(serde_json::json!({ "error": error }))).into_response();
+    }
+    let result = match query {
+        Ok(Query(query)) => {
+            let audit = state.audit.clone();
+            tokio::task::spawn_blocking(move || audit.query(&query)).await.unwrap_or_else(|err| Err(err.to_string()))
+        }
+        Err(rejection) => Err(rejection.body_text()),
+    };
+
+    match result {
+        Ok(entries) => Json(entries).into_response(),
+        Err(error) => (StatusCode::BAD_REQUEST, Json(serde_json::json!({ "error": error }))).into_response(),
+    }
+}
+
+async fn get_replay(State(state): State<RestState>) -> Json<Option<ReplayStatus>> {
+    Json(state.robot_lock.read().await.replay_status())
+}
+
+async fn post_replay(State(state): State<RestState>, Extension(identity): Extension<Identity>, control: Result<Json<ReplayControl>, JsonRejection>) -> Response {
+    let result = match control {
+        Ok(Json(control)) => replay_authorized(&state.robot_lock, &identity, control).await,
+        Err(rejection) => Err(CommandError::Malformed { message: rejection.body_text() }),
+    };
+    let status = match &result {
+        Ok(_) => StatusCode::OK,
+        Err(error) => {
+            warn!("rejected http replay control from {}: {}", identity.name, error);
+            status_code(error)
+        }
+    };
+    (status, Json(ReplayAck::new(result))).into_response()
+}
+
+async fn get_poses(State(state): State<RestState>) -> Json<BTreeMap<String, Pose>> {
+    Json(state.robot_lock.read().await.poses().list().clone())
+}
+
+async fn get_pose(State(state): State<RestState>, Path(name): Path<String>) -> Response {
+    match state.robot_lock.read().await.poses().get(&name) {
+        Ok(pose) => Json(pose).into_response(),
+        Err(error) => (status_code(&error), Json(serde_json::json!({ "error": error }))).into_response(),
+    }
+}
+
+/// Saves the pose in the body under `name`.
+async fn put_pose(State(state): State<RestState>, Extension(identity): Extension<Identity>, Path(name): Path<String>, pose: Result<Json<Pose>, JsonRejection>) -> Response {
+    let operation = pose.map(|Json(pose)| PoseOperation::Save(SavePose { name, pose: Some(pose), kind: PoseKind::default() }));
+    pose_change(&state, &identity, operation).await
+}
+
+/// Teaches the robot's current pose under `name`. The body may be omitted to teach the joints, or be `{"kind": "coords"}` to teach the end effector coordinate.
+async fn post_pose_teach(State(state): State<RestState>, Extension(identity): Extension<Identity>, Path(name): Path<String>, request: Option<Json<TeachPose>>) -> Response {
+    let Json(request) = request.unwrap_or_default();
+    pose_change(&state, &identity, Ok(PoseOperation::Save(SavePose { name, pose: None, kind: request.kind }))).await
+}
+
+async fn delete_pose(State(state): State<RestState>, Extension(identity): Extension<Identity>, Path(name): Path<String>) -> Response {
+    pose_change(&state, &identity, Ok(PoseOperation::Delete(PoseName { name }))).await
+}
+
+/// The body of `POST /poses/:name/teach`.
+#[derive(serde::Deserialize, Clone, Debug, Default)]
+#[serde(default)]
+struct TeachPose {
+    kind: PoseKind,
+}
+
+/// Saves or deletes a pose and responds with its ack.
+async fn pose_change(state: &RestState, identity: &Identity, operation: Result<PoseOperation, JsonRejection>) -> Response {
+    let result = match operation {
+        Ok(operation) => pose_authorized(&state.robot_lock, identity, operation).await,
+        Err(rejection) => Err(CommandError::Malformed { message: rejection.body_text() }),
+    };
+    let status = match &result {
+        Ok(_) => StatusCode::OK,
+        Err(error) => {
+            warn!("rejected http pose change from {}: {}", identity.name, error);
+            status_code(error)
+        }
+    };
+    (status, Json(PoseAck::new(result))).into_response()
+}
+
+async fn get_scripts(State(state): State<RestState>) -> Response {
+    match state.scripts.list() {
+        Ok(list) => Json(list).into_response(),
+        Err(error) => (status_code(&error), Json(serde_json::json!({ "error": error }))).into_response(),
+    }
+}
+
+/// The script's source as text.
+async fn get_script(State(state): State<RestState>, Path(name): Path<String>) -> Response {
+    match state.scripts.source(&name) {
+        Ok(script) => script.source.into_response(),
+        Err(error) => (status_code(&error), Json(serde_json::json!({ "error": error }))).into_response(),
+    }
+}
+
+/// The language of a script uploaded as text.
+#[derive(serde::Deserialize, Default)]
+#[serde(default)]
+struct ScriptQuery {
+    language: ScriptLanguage,
+}
+
+/// Saves the source in the body under `name`, as Rhai unless `?language=rcl` is given.
+async fn put_script(State(state): State<RestState>, Extension(identity): Extension<Identity>, Path(name): Path<String>, query: Result<Query<ScriptQuery>, QueryRejection>, source: String) -> Response {
+    match query {
+        Ok(Query(query)) => script(&state, &identity, None, None, ScriptOperation::Upload(ScriptSource { name, language: query.language, source })),
+        Err(rejection) => (StatusCode::BAD_REQUEST, Json(ScriptAck::new(Err(CommandError::Malformed { message: rejection.body_text() })))).into_response(),
+    }
+}
+
+async fn delete_script(State(state): State<RestState>, Extension(identity): Extension<Identity>, Path(name): Path<String>) -> Response {
+    script(&state, &identity, None, None, ScriptOperation::Delete(ScriptName { name }))
+}
+
+/// Starts the script saved as `name`. Its commands present the `x-lease-token` header sent here while a lease is held.
+async fn post_script_run(State(state): State<RestState>, Extension(identity): Extension<Identity>, ConnectInfo(remote_addr): ConnectInfo<SocketAddr>, headers: HeaderMap, Path(name): Path<String>) -> Response {
+    script(&state, &identity, Some(remote_addr), lease_token(&headers), ScriptOperation::Run(ScriptName { name }))
+}
+
+async fn get_script_status(State(state): State<RestState>) -> Json<Option<ScriptStatus>> {
+    Json(state.scripts.status())
+}
+
+async fn post_script_control(State(state): State<RestState>, Extension(identity): Extension<Identity>, control: Result<Json<ScriptControl>, JsonRejection>) -> Response {
+    match control {
+        Ok(Json(control)) => script(&state, &identity, None, None, ScriptOperation::Control(control)),
+        Err(rejection) => (StatusCode::BAD_REQUEST, Json(ScriptAck::new(Err(CommandError::Malformed { message: rejection.body_text() })))).into_response(),
+    }
+}
+
+/// Starts a pick and place. Its commands present the `x-lease-token` header sent here while a lease is held.
+async fn post_pick_and_place(State(state): State<RestState>, Extension(identity): Extension<Identity>, ConnectInfo(remote_addr): ConnectInfo<SocketAddr>, headers: HeaderMap, task: Result<Json<PickAndPlace>, JsonRejection>) -> Response {
+    match task {
+        Ok(Json(task)) => script(&state, &identity, Some(remote_addr), lease_token(&headers), ScriptOperation::PickAndPlace(task)),
+        Err(rejection) => (StatusCode::BAD_REQUEST, Json(ScriptAck::new(Err(CommandError::Malformed { message: rejection.body_text() })))).into_response(),
+    }
+}
+
+/// Uploads, deletes, runs or controls a script and responds with its ack.
+fn script(state: &RestState, identity: &Identity, remote_addr: Option<SocketAddr>, lease: Option<&str>, operation: ScriptOperation) -> Response {
+    let result = script_authorized(&state.scripts, identity, remote_addr, lease, operation);
+    let status = match &result {
+        Ok(_) => StatusCode::OK,
+        Err(error) => {
+            warn!("rejected http script request from {}: {}", identity.name, error);
+            status_code(error)
+        }
+    };
+    (status, Json(ScriptAck::new(result))).into_response()
+}
+
+async fn post_snapshot_save(State(state): State<RestState>, Extension(identity): Extension<Identity>, ConnectInfo(remote_addr): ConnectInfo<SocketAddr>, headers: HeaderMap) -> Response {
+    snapshot(&state, &identity, remote_addr, &headers, SnapshotOperation::Save).await
+}
+
+/// Moves the robot straight to the saved state. Presents the `x-lease-token` header while a lease is held.
+async fn post_snapshot_load(State(state): State<RestState>, Extension(identity): Extension<Identity>, ConnectInfo(remote_addr): ConnectInfo<SocketAddr>, headers: HeaderMap) -> Response {
+    snapshot(&state, &identity, remote_addr, &headers, SnapshotOperation::Load).await
+}
+
+/// Saves or restores the robot's state and responds with its ack.
+async fn snapshot(state: &RestState, identity: &Identity, remote_addr: SocketAddr, headers: &HeaderMap, operation: SnapshotOperation) -> Response {
+    let result = snapshot_authorized(&state.robot_lock, identity, lease_token(headers), operation).await;
+    let origin = Origin { transport: "http", client_id: None, remote_addr: Some(remote_addr), identity: Some(identity.clone()) };
+    state.audit.record(&origin, operation.name(), &serde_json::Value::Null, &result.as_ref().map(SavedState::outcome).map_err(Clone::clone));
+
+    let status = match &result {
+        Ok(_) => StatusCode::OK,
+        Err(error) => {
+            warn!("rejected http {} from {}: {}", operation.name(), identity.name, error);
+            status_code(error)
+        }
+    };
+    (status, Json(SnapshotAck::new(result))).into_response()
+}
+
+/// Performs an operation on the control lease and responds with its ack.
+async fn control(robot_lock: &RobotLock, identity: &Identity, operation: ControlOperation<'_>) -> Response {
+    let result = control_authorized(robot_lock, identity, operation).await;
+    let status = match &result {
+        Ok(_) => StatusCode::OK,
+        Err(error) => {
+            warn!("rejected http control request from {}: {}", identity.name, error);
+            status_code(error)
+        }
+    };
+    (status, Json(ControlAck::new(result))).into_response()
+}
+
+fn lease_token(headers: &HeaderMap) -> Option<&str> {
+    headers.get(LEASE_TOKEN_HEADER)?.to_str().ok()
+}
+
+async fn put_target_joints(State(state): State<RestState>, Extension(identity): Extension<Identity>, ConnectInfo(remote_addr): ConnectInfo<SocketAddr>, headers: HeaderMap, body: Bytes) -> Response {
+    execute(&state, &identity, remote_addr, &headers, "set_joint_state", &body, serde_json::from_slice(&body).map(Command::SetJointState)).await
+}
+
+async fn put_target_coords(State(state): State<RestState>, Extension(identity): Extension<Identity>, ConnectInfo(remote_addr): ConnectInfo<SocketAddr>, headers: HeaderMap, body: Bytes) -> Response {
+    execute(&state, &identity, remote_addr, &headers, "set_coord_state", &body, serde_json::from_slice(&body).map(Command::SetCoordState)).await
+}
+
+async fn put_target_base(State(state): State<RestState>, Extension(identity): Extension<Identity>, ConnectInfo(remote_addr): ConnectInfo<SocketAddr>, headers: HeaderMap, body: Bytes) -> Response {
+    execute(&state, &identity, remote_addr, &headers, "set_base_state", &body, serde_json::from_slice(&body).map(Command::SetBaseState)).await
+}
+
+async fn put_target_gripper(State(state): State<RestState>, Extension(identity): Extension<Identity>, ConnectInfo(remote_addr): ConnectInfo<SocketAddr>, headers: HeaderMap, body: Bytes) -> Response {
+    execute(&state, &identity, remote_addr, &headers, "set_gripper", &body, serde_json::from_slice(&body).map(Command::SetGripper)).await
+}
+
+async fn put_target_pose(State(state): State<RestState>, Extension(identity): Extension<Identity>, ConnectInfo(remote_addr): ConnectInfo<SocketAddr>, headers: HeaderMap, body: Bytes) -> Response {
+    execute(&state, &identity, remote_addr, &headers, "move_to_pose", &body, serde_json::from_slice(&body).map(Command::MoveToPose)).await
+}
+
+async fn post_estop(State(state): State<RestState>, Extension(identity): Extension<Identity>, ConnectInfo(remote_addr): ConnectInfo<SocketAddr>, headers: HeaderMap, body: Bytes) -> Response {
+    execute(&state, &identity, remote_addr, &headers, "emergency_stop", &body, Ok(Command::EmergencyStop)).await
+}
+
+async fn post_fault_reset(State(state): State<RestState>, Extension(identity): Extension<Identity>, ConnectInfo(remote_addr): ConnectInfo<SocketAddr>, headers: HeaderMap, body: Bytes) -> Response {
+    execute(&state, &identity, remote_addr, &headers, "reset_fault", &body, Ok(Command::ResetFault)).await
+}
+
+/// Executes a command decoded from a request body and responds with its ack.
+/// The `x-request-id` header, if present, is echoed back in the ack. Motion commands present the `x-lease-token` header while a lease is held.
+/// Every command is audited with its `body` as received.
+async fn execute(state: &RestState, identity: &Identity, remote_addr: SocketAddr, headers: &HeaderMap, name: &str, body: &[u8], command: Result<Command, serde_json::Error>) -> Response {
+    let result = match command {
+        Ok(command) => execute_authorized(&state.robot_lock, identity, lease_token(headers), command).await,
+        Err(err) => Err(CommandError::Malformed { message: err.to_string() }),
+    };
+    let origin = Origin { transport: "http", client_id: None, remote_addr: Some(remote_addr), identity: Some(identity.clone()) };
+    state.audit.record(&origin, name, &raw_payload(body), &result);
+
+    let status = match &result {
+        Ok(_) => StatusCode::OK,
+        Err(error) => {
+            warn!("rejected http command '{}': {}", name, error);
+            status_code(error)
+        }
+    };
+
+    let request_id = headers
+        .get("x-request-id")
+        .and_then(|value| value.to_str().ok())
+        .map(|value| serde_json::Value::String(value.to_string()));
+
+    (status, Json(CommandAck::new(name, request_id, result))).into_response()
+}
+
+/// The HTTP status used to report a rejected command.
+fn status_code(error: &CommandError) -> StatusCode {
+    match error {
+        CommandError::Malformed { .. } => StatusCode::BAD_REQUEST,
+        CommandError::InvalidValue { .. } | CommandError::Unreachable => StatusCode::UNPROCESSABLE_ENTITY,
+        CommandError::Faulted { .. } => StatusCode::CONFLICT,
+        CommandError::Forbidden { .. } => StatusCode::FORBIDDEN,
+        CommandError::ControlHeld { .. } | CommandError::LeaseNotHeld => StatusCode::CONFLICT,
+        CommandError::Replaying | CommandError::NotReplaying => StatusCode::CONFLICT,
+        CommandError::Storage { .. } => StatusCode::INTERNAL_SERVER_ERROR,
+        CommandError::UnknownPose { .. } | CommandError::UnknownScript { .. } => StatusCode::NOT_FOUND,
+        CommandError::ScriptRunning { .. } | CommandError::NoScriptRunning => StatusCode::CONFLICT,
+    }
+}
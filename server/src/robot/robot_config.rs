@@ -0,0 +1,108 @@
+use super::dynamics::DynamicsConfig;
+
+use std::path::Path;
+
+/// Environment variable holding the path of the JSON robot configuration file.
+pub const ROBOT_CONFIG_ENV: &str = "ROBOT_CONFIG";
+
+/// The controller's limits and gains. Every field is optional in the configuration file.
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug, PartialEq, schemars::JsonSchema)]
+#[serde(default, deny_unknown_fields)]
+pub struct RobotConfig {
+    /// Max base linear velocity (m/sec).
+    pub max_base_linear_vel: f64,
+    pub base_linear_p: f64,
+    pub base_linear_d: f64,
+    /// Max base angular velocity (deg/sec).
+    pub max_base_angle_vel: f64,
+    pub base_angle_p: f64,
+    pub base_angle_d: f64,
+    /// Max angular velocity (deg/sec).
+    pub max_angular_velocity: f64,
+    /// Max angular acceleration (deg/sec^2).
+    pub max_angular_acceleration: f64,
+    pub angle_p: f64,
+    pub angle_d: f64,
+    /// How far ahead (sec) ik leads a moving base.
+    pub feedforward_factor: f64,
+    /// Max linear acceleration (mm/sec^2).
+    pub max_linear_acceleration: f64,
+    /// Max linear velocity (mm/sec).
+    pub max_linear_velocity: f64,
+    pub linear_p: f64,
+    pub linear_d: f64,
+    /// Joint angle error (deg) and angular velocity (deg/sec) below which motion is complete.
+    pub settled_angle_tolerance_deg: f64,
+    /// Joint linear error (mm) and linear velocity (mm/sec) below which motion is complete.
+    pub settled_linear_tolerance_mm: f64,
+    /// Base position error (m) and linear velocity (m/sec) below which motion is complete.
+    pub settled_base_tolerance_m: f64,
+    /// Simulates the arm's masses, motors and friction when set. The gains and acceleration limits above then no
+    /// longer move the joints, but the velocity limits still apply as the motors' top speeds.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub dynamics: Option<DynamicsConfig>,
+}
+
+impl Default for RobotConfig {
+    fn default() -> Self {
+        RobotConfig {
+            max_base_linear_vel: 0.06,
+            base_linear_p: 1.0,
+            base_linear_d: 0.5,
+            max_base_angle_vel: 3.0,
+            base_angle_p: 0.5,
+            base_angle_d: 0.1,
+            max_angular_velocity: 18.0,
+            max_angular_acceleration: 9.0,
+            angle_p: 0.7,
+            angle_d: 1.5,
+            feedforward_factor: 2.22,
+            max_linear_acceleration: 40.0,
+            max_linear_velocity: 80.0,
+            linear_p: 2.5,
+            linear_d: 4.0,
+            settled_angle_tolerance_deg: 0.5,
+            settled_linear_tolerance_mm: 1.0,
+            settled_base_tolerance_m: 0.005,
+            dynamics: None,
+        }
+    }
+}
+
+impl RobotConfig {
+    /// Loads the configuration from the file named by `ROBOT_CONFIG`, or the defaults if it is not set.
+    pub fn load() -> Result<RobotConfig, Box<dyn std::error::Error>> {
+        match std::env::var(ROBOT_CONFIG_ENV) {
+            Ok(path) => RobotConfig::read(Path::new(&path)),
+            Err(_) => Ok(RobotConfig::default()),
+        }
+    }
+
+    pub fn read(path: &Path) -> Result<RobotConfig, Box<dyn std::error::Error>> {
+        let config: RobotConfig = serde_json::from_str(&std::fs::read_to_string(path)?)?;
+        config.validate().map_err(|err| format!("{}: {}", path.display(), err))?;
+        Ok(config)
+    }
+
+    /// Rejects values the controller cannot run with. Every value must be finite and none negative.
+    fn validate(&self) -> Result<(), String> {
+        let value = serde_json::to_value(self).map_err(|err| err.to_string())?;
+        check_values("", &value)?;
+        match &self.dynamics {
+            Some(dynamics) => dynamics.validate(),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Checks every number, naming nested fields by their path, e.g. `dynamics.swing.p`.
+fn check_values(path: &str, value: &serde_json::Value) -> Result<(), String> {
+    match value {
+        serde_json::Value::Object(fields) => fields.iter().try_for_each(|(field, value)| check_values(&if path.is_empty() { field.clone() } else { format!("{path}.{field}") }, value)),
+        serde_json::Value::Bool(_) => Ok(()),
+        _ => match value.as_f64() {
+            Some(number) if number.is_finite() && number >= 0.0 => Ok(()),
+            _ => Err(format!("{path} must be a positive number, got {value}")),
+        },
+    }
+}
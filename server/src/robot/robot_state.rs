@@ -2,6 +2,7 @@ use std::ops::{Add, Mul, Sub};
 use super::constants::*;
 
 /// Converts a degree angle into a value in the range (-180, 180)
+#[allow(clippy::needless_return)]
 pub fn limit_angle(angle: f64) -> f64{
     return ((angle - 180.0 ).rem_euclid(360.0)).abs() -180.0 ;
 }
@@ -15,6 +16,7 @@ pub fn clamp(val: f64, clamp_val: f64) -> f64{
 }
 
 /// Returns the shortest difference between 2 angles.
+#[allow(clippy::needless_return)]
 pub fn shortest_angle_diff(angle1: f64, angle2: f64) -> f64 {
     let angle = angle1 - angle2;
     return match angle {
@@ -28,7 +30,7 @@ pub fn shortest_angle_diff(angle1: f64, angle2: f64) -> f64 {
 }
 
 /// Holds the state of each of the joints the make up the robot.
-#[derive(serde::Serialize, serde::Deserialize, Copy, Clone, Debug, Default)]
+#[derive(serde::Serialize, serde::Deserialize, Copy, Clone, Debug, Default, PartialEq, schemars::JsonSchema, ts_rs::TS)]
 pub struct JointState {
     /// Swing rotation (degrees).
     pub swing_rotation_deg: f64,
@@ -64,6 +66,7 @@ impl JointState {
     }
 
     /// Multiple each joint value by `mul`.
+    #[allow(clippy::needless_return, clippy::field_reassign_with_default)]
     pub fn val_mul(&mut self, mul: f64) -> JointState{
         let mut output = JointState::default();
         
@@ -77,6 +80,7 @@ impl JointState {
     }
 
     /// Finds the difference between 2 `JointState`s.
+    #[allow(clippy::needless_return)]
     pub fn clamped_sub(lhs: JointState, rhs: JointState) -> JointState{
         
         let mut output = lhs - rhs;
@@ -87,6 +91,24 @@ impl JointState {
 
         return output
     }
+
+    /// Returns true if every linear value is within `linear_tol` and every angle is within `angle_tol` of zero.
+    pub fn within(&self, linear_tol: f64, angle_tol: f64) -> bool {
+        self.swing_rotation_deg.abs() <= angle_tol
+            && self.lift_elevation_mm.abs() <= linear_tol
+            && self.elbow_rotation_deg.abs() <= angle_tol
+            && self.wrist_rotation_deg.abs() <= angle_tol
+            && self.gripper_open_mm.abs() <= linear_tol
+    }
+
+    /// Returns true if every joint value is a finite number.
+    pub fn is_finite(&self) -> bool {
+        self.swing_rotation_deg.is_finite()
+            && self.lift_elevation_mm.is_finite()
+            && self.elbow_rotation_deg.is_finite()
+            && self.wrist_rotation_deg.is_finite()
+            && self.gripper_open_mm.is_finite()
+    }
 }
 
 impl Add for JointState{
@@ -132,7 +154,7 @@ impl Mul for JointState {
 }
 
 /// A 4DOF corrdinate that contains an x, y, and z value as well as an angle.
-#[derive(serde::Serialize, serde::Deserialize, Copy, Clone, Debug, Default)]
+#[derive(serde::Serialize, serde::Deserialize, Copy, Clone, Debug, Default, PartialEq, schemars::JsonSchema, ts_rs::TS)]
 pub struct Coord4DOF {
     pub x: f64,
     pub y: f64,
@@ -141,6 +163,7 @@ pub struct Coord4DOF {
 }
 
 impl Coord4DOF {
+    #[allow(clippy::needless_return, clippy::field_reassign_with_default)]
     pub fn val_mul(&self, val: f64) -> Coord4DOF {
         let mut output = Coord4DOF::default();
         
@@ -153,6 +176,7 @@ impl Coord4DOF {
     }
 
     /// Applies a linear and angular control value to ease in applying controller operations.
+    #[allow(clippy::needless_return, clippy::field_reassign_with_default)]
     pub fn apply_control(&self, linear: f64, angular: f64) -> Coord4DOF {
         let mut output = Coord4DOF::default();
         
@@ -172,6 +196,16 @@ impl Coord4DOF {
         self.z = clamp(self.z, clamp_pos);
         self.theta = clamp(self.theta, clamp_ang);
     }
+
+    /// Returns true if the position is within `linear_tol` and the angle is within `angle_tol` of zero.
+    pub fn within(&self, linear_tol: f64, angle_tol: f64) -> bool {
+        self.x.abs() <= linear_tol && self.y.abs() <= linear_tol && self.z.abs() <= linear_tol && self.theta.abs() <= angle_tol
+    }
+
+    /// Returns true if the position and angle are finite numbers.
+    pub fn is_finite(&self) -> bool {
+        self.x.is_finite() && self.y.is_finite() && self.z.is_finite() && self.theta.is_finite()
+    }
 }
 
 impl Add for Coord4DOF {
@@ -245,8 +279,19 @@ impl Sub for Coord4DOF {
 // }
 
 /// Holds both the joint and base state of the robot.
-#[derive(serde::Serialize, serde::Deserialize, Copy, Clone, Debug, Default)]
+#[derive(serde::Serialize, serde::Deserialize, Copy, Clone, Debug, Default, PartialEq, schemars::JsonSchema, ts_rs::TS)]
 pub struct RobotState {
     pub joint_state: JointState,
     pub base_state: Coord4DOF
 }
+
+/// The pose of each joint in space, from the base out to the end effector. `theta` is the heading of the link leaving the joint.
+#[derive(serde::Serialize, serde::Deserialize, Copy, Clone, Debug, Default, PartialEq, schemars::JsonSchema, ts_rs::TS)]
+pub struct LinkCoords {
+    pub base: Coord4DOF,
+    /// Top of the lift, where the swing joint turns the elbow link.
+    pub swing: Coord4DOF,
+    pub elbow: Coord4DOF,
+    pub wrist: Coord4DOF,
+    pub end_effector: Coord4DOF,
+}
@@ -0,0 +1,426 @@
+use super::audit::{Origin, SharedAudit};
+use super::auth::{authorize, require_identity, Identity, SharedAuth};
+use super::command::{Command, CommandError};
+use super::robot_state::{Coord4DOF, JointState, RobotState};
+use super::telemetry::{Telemetry, TelemetrySender};
+use super::{degrees_to_radians, radians_to_degrees, RobotLock};
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+
+use axum::{
+    extract::{
+        ws::{Message, WebSocket, WebSocketUpgrade},
+        ConnectInfo, State,
+    },
+    middleware,
+    response::Response,
+    routing::get,
+    Extension, Router,
+};
+use tokio::sync::broadcast::error::RecvError;
+use tokio::task::JoinHandle;
+use tokio::time::{sleep_until, Duration, Instant};
+use tracing::{info, warn};
+
+/// Address the rosbridge endpoint listens on. Port 9090 is the rosbridge default so roslibjs/roslibpy connect without extra configuration.
+pub const ROSBRIDGE_ADDR: &str = "127.0.0.1:9090";
+
+/// Topic the robot's joints are published on as `sensor_msgs/JointState`.
+pub const JOINT_STATES_TOPIC: &str = "/joint_states";
+/// Topic the end effector pose is published on as `geometry_msgs/PoseStamped`.
+pub const END_EFFECTOR_POSE_TOPIC: &str = "/end_effector_pose";
+/// Topic that accepts `trajectory_msgs/JointTrajectory` commands.
+pub const JOINT_TRAJECTORY_TOPIC: &str = "/joint_trajectory";
+
+const JOINT_STATE_TYPE: &str = "sensor_msgs/JointState";
+const POSE_STAMPED_TYPE: &str = "geometry_msgs/PoseStamped";
+const JOINT_TRAJECTORY_TYPE: &str = "trajectory_msgs/JointTrajectory";
+
+/// Joint names used in `sensor_msgs/JointState` and `trajectory_msgs/JointTrajectory`.
+const JOINT_NAMES: [&str; 5] = ["swing", "lift", "elbow", "wrist", "gripper"];
+/// Frame the end effector pose is expressed in.
+const WORLD_FRAME: &str = "world";
+
+/// Shared state for rosbridge connections.
+#[derive(Clone)]
+struct RosbridgeState {
+    robot_lock: RobotLock,
+    telemetry: TelemetrySender,
+    audit: SharedAudit,
+}
+
+/// A rosbridge v2 operation sent by a client. Fields not used by the simulator are ignored.
+#[derive(serde::Deserialize, Clone, Debug)]
+#[serde(tag = "op", rename_all = "snake_case")]
+enum Operation {
+    Advertise {
+        topic: String,
+        #[serde(rename = "type")]
+        msg_type: String,
+        id: Option<String>,
+    },
+    Unadvertise,
+    Publish {
+        topic: String,
+        msg: serde_json::Value,
+        id: Option<String>,
+    },
+    Subscribe {
+        topic: String,
+        #[serde(rename = "type")]
+        msg_type: Option<String>,
+        /// Minimum time between messages (ms).
+        #[serde(default)]
+        throttle_rate: u64,
+        id: Option<String>,
+    },
+    Unsubscribe {
+        topic: String,
+    },
+    CallService {
+        service: String,
+        id: Option<String>,
+    },
+}
+
+/// A topic the client is subscribed to.
+struct Subscription {
+    throttle: Duration,
+    last_sent: Option<Instant>,
+    /// `seq` of the next message published on the topic.
+    seq: u32,
+}
+
+/// ROS1 `time`/`duration`. ROS2 `sec`/`nanosec` field names are accepted when reading.
+#[derive(serde::Serialize, serde::Deserialize, Copy, Clone, Debug, Default)]
+struct RosTime {
+    #[serde(alias = "sec", default)]
+    secs: i64,
+    #[serde(alias = "nanosec", default)]
+    nsecs: u32,
+}
+
+impl RosTime {
+    fn now() -> RosTime {
+        let now = chrono::Utc::now();
+        RosTime { secs: now.timestamp(), nsecs: now.timestamp_subsec_nanos() }
+    }
+
+    fn to_duration(self) -> Duration {
+        Duration::from_secs(self.secs.max(0) as u64) + Duration::from_nanos(self.nsecs as u64)
+    }
+}
+
+#[derive(serde::Serialize, Clone, Debug)]
+struct Header {
+    seq: u32,
+    stamp: RosTime,
+    frame_id: String,
+}
+
+/// `sensor_msgs/JointState`
+#[derive(serde::Serialize, Clone, Debug)]
+struct RosJointState {
+    header: Header,
+    name: Vec<String>,
+    position: Vec<f64>,
+    velocity: Vec<f64>,
+    effort: Vec<f64>,
+}
+
+#[derive(serde::Serialize, Clone, Debug)]
+struct Point {
+    x: f64,
+    y: f64,
+    z: f64,
+}
+
+#[derive(serde::Serialize, Clone, Debug)]
+struct Quaternion {
+    x: f64,
+    y: f64,
+    z: f64,
+    w: f64,
+}
+
+#[derive(serde::Serialize, Clone, Debug)]
+struct Pose {
+    position: Point,
+    orientation: Quaternion,
+}
+
+/// `geometry_msgs/PoseStamped`
+#[derive(serde::Serialize, Clone, Debug)]
+struct PoseStamped {
+    header: Header,
+    pose: Pose,
+}
+
+/// `trajectory_msgs/JointTrajectory`
+#[derive(serde::Deserialize, Clone, Debug)]
+struct JointTrajectory {
+    joint_names: Vec<String>,
+    points: Vec<JointTrajectoryPoint>,
+}
+
+/// `trajectory_msgs/JointTrajectoryPoint`
+#[derive(serde::Deserialize, Clone, Debug)]
+struct JointTrajectoryPoint {
+    positions: Vec<f64>,
+    #[serde(default)]
+    time_from_start: RosTime,
+}
+
+/// Builds the rosbridge v2 WebSocket endpoint. The upgrade requires a valid token.
+pub fn router(robot_lock: RobotLock, telemetry: TelemetrySender, audit: SharedAudit, auth: SharedAuth) -> Router {
+    Router::new()
+        .route("/", get(ws_handler))
+        .route_layer(middleware::from_fn_with_state(auth, require_identity))
+        .with_state(RosbridgeState { robot_lock, telemetry, audit })
+}
+
+async fn ws_handler(ws: WebSocketUpgrade, State(state): State<RosbridgeState>, Extension(identity): Extension<Identity>, ConnectInfo(remote_addr): ConnectInfo<SocketAddr>) -> Response {
+    ws.on_upgrade(move |socket| handle_socket(socket, state, identity, remote_addr))
+}
+
+/// Serves a single rosbridge client until it disconnects.
+async fn handle_socket(mut socket: WebSocket, state: RosbridgeState, identity: Identity, remote_addr: SocketAddr) {
+    info!("rosbridge client connected from {} as {} ({:?})", remote_addr, identity.name, identity.role);
+    let origin = Origin { transport: "rosbridge", client_id: None, remote_addr: Some(remote_addr), identity: Some(identity.clone()) };
+
+    let mut telemetry = state.telemetry.subscribe();
+    let mut subscriptions: HashMap<String, Subscription> = HashMap::new();
+    // The trajectory being executed. A new trajectory replaces the previous one.
+    let mut trajectory: Option<JoinHandle<()>> = None;
+
+    loop {
+        let reply = tokio::select! {
+            message = socket.recv() => match message {
+                Some(Ok(Message::Text(text))) => handle_operation(&text, &state, &origin, &identity, &mut subscriptions, &mut trajectory).await,
+                Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                Some(Ok(_)) => None,
+            },
+            message = telemetry.recv() => match message {
+                Ok(message) => publish_telemetry(message, &mut subscriptions),
+                Err(RecvError::Lagged(_)) => None,
+                Err(RecvError::Closed) => break,
+            },
+        };
+
+        if let Some(reply) = reply {
+            if socket.send(Message::Text(reply.to_string())).await.is_err() {
+                break;
+            }
+        }
+    }
+
+    if let Some(trajectory) = trajectory {
+        trajectory.abort();
+    }
+
+    info!("rosbridge client disconnected");
+}
+
+/// Converts a telemetry message into a rosbridge `publish` if the client is subscribed to its topic.
+fn publish_telemetry(message: Telemetry, subscriptions: &mut HashMap<String, Subscription>) -> Option<serde_json::Value> {
+    let topic = match message {
+        Telemetry::JointState(_) => JOINT_STATES_TOPIC,
+        Telemetry::BaseCoords(_) => END_EFFECTOR_POSE_TOPIC,
+        Telemetry::Velocity(_) | Telemetry::Links(_) | Telemetry::Fault(_) | Telemetry::Control(_) | Telemetry::Frame(_) | Telemetry::Script(_) => return None,
+    };
+
+    // Respect the throttle rate requested by the subscriber.
+    let subscription = subscriptions.get_mut(topic)?;
+    let now = Instant::now();
+    if let Some(last_sent) = subscription.last_sent {
+        if now.duration_since(last_sent) < subscription.throttle {
+            return None;
+        }
+    }
+    subscription.last_sent = Some(now);
+    let seq = subscription.seq;
+    subscription.seq = seq.wrapping_add(1);
+
+    let msg = match message {
+        Telemetry::JointState(state) => serde_json::to_value(joint_state_msg(state, seq)).ok()?,
+        Telemetry::BaseCoords(coords) => serde_json::to_value(pose_stamped_msg(coords, seq)).ok()?,
+        Telemetry::Velocity(_) | Telemetry::Links(_) | Telemetry::Fault(_) | Telemetry::Control(_) | Telemetry::Frame(_) | Telemetry::Script(_) => return None,
+    };
+
+    Some(serde_json::json!({"op": "publish", "topic": topic, "msg": msg}))
+}
+
+/// Handles a single rosbridge operation, returning the reply to send if there is one.
+async fn handle_operation(
+    text: &str,
+    state: &RosbridgeState,
+    origin: &Origin,
+    identity: &Identity,
+    subscriptions: &mut HashMap<String, Subscription>,
+    trajectory: &mut Option<JoinHandle<()>>,
+) -> Option<serde_json::Value> {
+    let operation: Operation = match serde_json::from_str(text) {
+        Ok(operation) => operation,
+        Err(err) => return Some(status("error", &format!("unsupported or malformed operation: {err}"), None)),
+    };
+
+    match operation {
+        Operation::Advertise { topic, msg_type, id } => {
+            if topic != JOINT_TRAJECTORY_TOPIC || msg_type != JOINT_TRAJECTORY_TYPE {
+                return Some(status("error", &format!("can only advertise {JOINT_TRAJECTORY_TOPIC} as {JOINT_TRAJECTORY_TYPE}"), id));
+            }
+            None
+        }
+        Operation::Unadvertise => None,
+        Operation::Publish { topic, msg, id } => {
+            if topic != JOINT_TRAJECTORY_TOPIC {
+                return Some(status("error", &format!("cannot publish to {topic}"), id));
+            }
+            let joint_trajectory: JointTrajectory = match serde_json::from_value(msg.clone()) {
+                Ok(joint_trajectory) => joint_trajectory,
+                Err(err) => {
+                    let message = format!("invalid {JOINT_TRAJECTORY_TYPE}: {err}");
+                    state.audit.record(origin, "set_joint_state", &msg, &Err(CommandError::Malformed { message: message.clone() }));
+                    return Some(status("error", &message, id));
+                }
+            };
+
+            match start_trajectory(joint_trajectory, &msg, state, origin, identity).await {
+                Ok(handle) => {
+                    if let Some(previous) = trajectory.replace(handle) {
+                        previous.abort();
+                    }
+                    None
+                }
+                Err(message) => {
+                    warn!("rejected rosbridge trajectory: {}", message);
+                    Some(status("error", &message, id))
+                }
+            }
+        }
+        Operation::Subscribe { topic, msg_type, throttle_rate, id } => {
+            let expected_type = match topic.as_str() {
+                JOINT_STATES_TOPIC => JOINT_STATE_TYPE,
+                END_EFFECTOR_POSE_TOPIC => POSE_STAMPED_TYPE,
+                _ => return Some(status("error", &format!("unknown topic {topic}"), id)),
+            };
+            if msg_type.is_some_and(|msg_type| msg_type != expected_type) {
+                return Some(status("error", &format!("{topic} is of type {expected_type}"), id));
+            }
+
+            subscriptions.insert(topic, Subscription { throttle: Duration::from_millis(throttle_rate), last_sent: None, seq: 0 });
+            None
+        }
+        Operation::Unsubscribe { topic } => {
+            subscriptions.remove(&topic);
+            None
+        }
+        Operation::CallService { service, id } => Some(serde_json::json!({
+            "op": "service_response",
+            "service": service,
+            "id": id,
+            "result": false,
+            "values": {"message": "services are not supported"},
+        })),
+    }
+}
+
+/// Validates a trajectory and spawns a task that commands each point at its `time_from_start`.
+/// A rejected trajectory is audited with the whole `msg`, an executed point with the point from `msg`.
+/// rosbridge clients cannot hold the control lease, so the trajectory stops at the first point sent while another client holds it.
+async fn start_trajectory(joint_trajectory: JointTrajectory, msg: &serde_json::Value, state: &RosbridgeState, origin: &Origin, identity: &Identity) -> Result<JoinHandle<()>, String> {
+    let reject = |error: CommandError| {
+        state.audit.record(origin, "set_joint_state", msg, &Err(error.clone()));
+        match error {
+            CommandError::Malformed { message } => message,
+            error => error.to_string(),
+        }
+    };
+
+    for name in &joint_trajectory.joint_names {
+        if !JOINT_NAMES.contains(&name.as_str()) {
+            return Err(reject(CommandError::Malformed { message: format!("unknown joint '{name}', expected one of {JOINT_NAMES:?}") }));
+        }
+    }
+
+    // Joints missing from the trajectory hold their current target.
+    let mut target = state.robot_lock.read().await.get_target_state().joint_state;
+    let mut commands = Vec::with_capacity(joint_trajectory.points.len());
+    for (index, point) in joint_trajectory.points.iter().enumerate() {
+        if point.positions.len() != joint_trajectory.joint_names.len() {
+            let message = format!("point {index} has {} positions for {} joints", point.positions.len(), joint_trajectory.joint_names.len());
+            return Err(reject(CommandError::Malformed { message }));
+        }
+        for (name, position) in joint_trajectory.joint_names.iter().zip(&point.positions) {
+            set_joint_position(&mut target, name, *position);
+        }
+
+        let command = Command::SetJointState(target);
+        authorize(identity, &command).map_err(reject)?;
+        command.validate().map_err(|err| format!("point {index}: {}", reject(err)))?;
+        commands.push((point.time_from_start.to_duration(), command, msg["points"][index].clone()));
+    }
+
+    let (robot_lock, audit, origin) = (state.robot_lock.clone(), state.audit.clone(), origin.clone());
+    let start = Instant::now();
+    Ok(tokio::spawn(async move {
+        for (time_from_start, command, point) in commands {
+            sleep_until(start + time_from_start).await;
+            let name = command.name();
+            let result = robot_lock.write().await.execute(command, None);
+            audit.record(&origin, name, &point, &result);
+            if let Err(err) = result {
+                warn!("rosbridge trajectory stopped: {}", err);
+                return;
+            }
+        }
+    }))
+}
+
+/// Sets a joint from its ROS position (radians or meters).
+fn set_joint_position(joint_state: &mut JointState, name: &str, position: f64) {
+    match name {
+        "swing" => joint_state.swing_rotation_deg = radians_to_degrees(position),
+        "lift" => joint_state.lift_elevation_mm = position * 1000.0,
+        "elbow" => joint_state.elbow_rotation_deg = radians_to_degrees(position),
+        "wrist" => joint_state.wrist_rotation_deg = radians_to_degrees(position),
+        "gripper" => joint_state.gripper_open_mm = position * 1000.0,
+        _ => {}
+    }
+}
+
+/// Builds a `sensor_msgs/JointState` with positions in radians and meters.
+fn joint_state_msg(state: RobotState, seq: u32) -> RosJointState {
+    let joint_state = state.joint_state;
+    RosJointState {
+        header: Header { seq, stamp: RosTime::now(), frame_id: String::new() },
+        name: JOINT_NAMES.iter().map(|name| name.to_string()).collect(),
+        position: vec![
+            degrees_to_radians(joint_state.swing_rotation_deg),
+            joint_state.lift_elevation_mm / 1000.0,
+            degrees_to_radians(joint_state.elbow_rotation_deg),
+            degrees_to_radians(joint_state.wrist_rotation_deg),
+            joint_state.gripper_open_mm / 1000.0,
+        ],
+        velocity: vec![],
+        effort: vec![],
+    }
+}
+
+/// Builds a `geometry_msgs/PoseStamped` for the end effector. The angle is a rotation about z.
+fn pose_stamped_msg(coords: Coord4DOF, seq: u32) -> PoseStamped {
+    let half_angle = degrees_to_radians(coords.theta) / 2.0;
+    PoseStamped {
+        header: Header { seq, stamp: RosTime::now(), frame_id: WORLD_FRAME.to_string() },
+        pose: Pose {
+            position: Point { x: coords.x, y: coords.y, z: coords.z },
+            orientation: Quaternion { x: 0.0, y: 0.0, z: half_angle.sin(), w: half_angle.cos() },
+        },
+    }
+}
+
+/// Builds a rosbridge `status` message.
+fn status(level: &str, msg: &str, id: Option<String>) -> serde_json::Value {
+    serde_json::json!({"op": "status", "level": level, "msg": msg, "id": id})
+}
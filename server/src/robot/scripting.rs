@@ -0,0 +1,835 @@
+use super::audit::{Origin, SharedAudit};
+use super::behavior_tree::{BehaviorTree, TreeNodeStatus};
+use super::auth::{execute_authorized, Identity};
+use super::command::{Command, CommandError, CommandOutcome};
+use super::command_language::{self, Instruction};
+use super::motion::{Fields, Interrupt, Motion, POLL_PERIOD};
+use super::poses::validate_name;
+use super::tasks::PickAndPlace;
+use super::telemetry::{RobotSnapshot, SnapshotReceiver, Telemetry, TelemetrySender};
+use super::RobotLock;
+
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+use chrono::{DateTime, Utc};
+use rhai::{module_resolvers::DummyModuleResolver, Dynamic, Engine, EvalAltResult, Map, Position, AST, FLOAT, INT};
+use tokio::runtime::Handle;
+use tracing::{error, info};
+
+/// Environment variable holding the directory scripts are kept in.
+pub const SCRIPTS_DIR_ENV: &str = "ROBOT_SCRIPTS_DIR";
+/// Directory scripts are kept in when `ROBOT_SCRIPTS_DIR` is not set.
+pub const DEFAULT_SCRIPTS_DIR: &str = "scripts";
+/// Command line option naming a program file to run on startup.
+pub const PROGRAM_ARG: &str = "--program";
+/// Largest script accepted (bytes).
+pub const MAX_SCRIPT_BYTES: usize = 64 * 1024;
+/// Name the status of a pick and place run is reported under.
+pub const PICK_AND_PLACE_RUN: &str = "pick_and_place";
+
+/// End effector speed (m/sec) of `move_linear` when the script does not give one.
+const DEFAULT_LINEAR_SPEED: f64 = 0.1;
+/// Operations a Rhai script may run before it is stopped, so a runaway loop does not hold a thread forever.
+/// Time spent waiting on the robot or sleeping does not count.
+const MAX_SCRIPT_OPERATIONS: u64 = 10_000_000;
+
+/// The language a script is written in. Saved scripts are told apart by their extension.
+#[derive(serde::Serialize, serde::Deserialize, Copy, Clone, Debug, Default, PartialEq, Eq, schemars::JsonSchema, ts_rs::TS)]
+#[serde(rename_all = "snake_case")]
+pub enum ScriptLanguage {
+    /// A Rhai script, kept as `<name>.rhai`.
+    #[default]
+    Rhai,
+    /// A program in the line based robot command language, kept as `<name>.rcl`.
+    Rcl,
+    /// A behavior tree written in YAML or JSON, kept as `<name>.yaml`.
+    BehaviorTree,
+}
+
+impl ScriptLanguage {
+    pub const ALL: [ScriptLanguage; 3] = [ScriptLanguage::Rhai, ScriptLanguage::Rcl, ScriptLanguage::BehaviorTree];
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            ScriptLanguage::Rhai => "rhai",
+            ScriptLanguage::Rcl => "rcl",
+            ScriptLanguage::BehaviorTree => "yaml",
+        }
+    }
+
+    /// The language of a script file, from its extension. `.yml` and `.json` files are behavior trees.
+    pub fn from_path(path: &Path) -> Option<ScriptLanguage> {
+        let extension = path.extension()?.to_str()?;
+        if ["yml", "json"].iter().any(|tree| tree.eq_ignore_ascii_case(extension)) {
+            return Some(ScriptLanguage::BehaviorTree);
+        }
+        ScriptLanguage::ALL.into_iter().find(|language| language.extension().eq_ignore_ascii_case(extension))
+    }
+}
+
+/// A saved script.
+#[derive(serde::Serialize, Clone, Debug, PartialEq, schemars::JsonSchema, ts_rs::TS)]
+pub struct ScriptInfo {
+    pub name: String,
+    pub language: ScriptLanguage,
+}
+
+/// Where a script run is.
+#[derive(serde::Serialize, Copy, Clone, Debug, PartialEq, schemars::JsonSchema, ts_rs::TS)]
+#[serde(rename_all = "snake_case")]
+pub enum ScriptState {
+    Running,
+    Paused,
+    /// The script ran to the end.
+    Finished,
+    /// The script stopped on an error.
+    Failed,
+    /// The script was aborted by a client.
+    Aborted,
+}
+
+impl ScriptState {
+    /// Returns true until the run has stopped.
+    pub fn is_active(&self) -> bool {
+        matches!(self, ScriptState::Running | ScriptState::Paused)
+    }
+}
+
+/// The status of the script running, or of the last one run.
+#[derive(serde::Serialize, Clone, Debug, PartialEq, schemars::JsonSchema, ts_rs::TS)]
+pub struct ScriptStatus {
+    /// Increments with every run.
+    #[ts(type = "number")]
+    pub run_id: u64,
+    pub name: String,
+    pub state: ScriptState,
+    pub started_at: DateTime<Utc>,
+    pub finished_at: Option<DateTime<Utc>>,
+    /// Why the script failed, with the line and position.
+    pub error: Option<String>,
+}
+
+/// Published on the `script` stream while a script runs.
+#[derive(serde::Serialize, Clone, Debug, PartialEq, schemars::JsonSchema, ts_rs::TS)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum ScriptEvent {
+    /// A line written by the script with `print` or `debug`.
+    Output {
+        #[ts(type = "number")]
+        run_id: u64,
+        line: String,
+    },
+    /// Sent when a run starts, is paused, resumed or aborted, and when it stops.
+    Status(ScriptStatus),
+    /// The status of every node of a behavior tree, sent whenever one changes.
+    Tree {
+        #[ts(type = "number")]
+        run_id: u64,
+        nodes: Vec<TreeNodeStatus>,
+    },
+}
+
+/// Changes the script running.
+#[derive(serde::Deserialize, Copy, Clone, Debug, schemars::JsonSchema, ts_rs::TS)]
+#[serde(tag = "action", rename_all = "snake_case")]
+pub enum ScriptControl {
+    /// Stops the script at its next statement. Motion already commanded carries on to its target.
+    Pause,
+    Resume,
+    /// Stops the script and holds the robot where it is.
+    Abort,
+}
+
+/// A script to save under `name`, replacing any script already saved under it.
+#[derive(serde::Deserialize, Clone, Debug, schemars::JsonSchema, ts_rs::TS)]
+pub struct ScriptSource {
+    pub name: String,
+    /// Rhai when not set.
+    #[serde(default)]
+    #[ts(as = "Option<ScriptLanguage>", optional)]
+    pub language: ScriptLanguage,
+    pub source: String,
+}
+
+/// Names a script to run or delete.
+#[derive(serde::Deserialize, Clone, Debug, schemars::JsonSchema, ts_rs::TS)]
+pub struct ScriptName {
+    pub name: String,
+}
+
+/// A change to the saved scripts or the script running.
+#[derive(Clone, Debug)]
+pub enum ScriptOperation {
+    Upload(ScriptSource),
+    Delete(ScriptName),
+    Run(ScriptName),
+    Control(ScriptControl),
+    PickAndPlace(PickAndPlace),
+}
+
+/// Reply to uploading, deleting, running or controlling a script.
+#[derive(serde::Serialize, Clone, Debug, schemars::JsonSchema, ts_rs::TS)]
+pub struct ScriptAck {
+    pub accepted: bool,
+    /// The status of the run started or controlled. Not set for uploads and deletes.
+    pub status: Option<ScriptStatus>,
+    /// Set when the request was rejected.
+    pub error: Option<CommandError>,
+}
+
+impl ScriptAck {
+    pub fn new(result: Result<Option<ScriptStatus>, CommandError>) -> Self {
+        match result {
+            Ok(status) => ScriptAck { accepted: true, status, error: None },
+            Err(error) => ScriptAck { accepted: false, status: None, error: Some(error) },
+        }
+    }
+}
+
+/// Motion programs kept as Rhai or command language files, and the script running. One script runs at a time.
+pub struct Scripts {
+    dir: PathBuf,
+    robot_lock: RobotLock,
+    snapshots: SnapshotReceiver,
+    telemetry: TelemetrySender,
+    audit: SharedAudit,
+    /// The script running, or the last one run.
+    run: Mutex<Option<Arc<ScriptRun>>>,
+    next_run_id: AtomicU64,
+}
+
+pub type SharedScripts = Arc<Scripts>;
+
+impl Scripts {
+    /// Keeps scripts in the directory named by `ROBOT_SCRIPTS_DIR`, or `DEFAULT_SCRIPTS_DIR`. The directory is created on the first upload.
+    pub fn from_env(robot_lock: RobotLock, snapshots: SnapshotReceiver, telemetry: TelemetrySender, audit: SharedAudit) -> Scripts {
+        let dir = PathBuf::from(std::env::var(SCRIPTS_DIR_ENV).unwrap_or_else(|_| DEFAULT_SCRIPTS_DIR.to_string()));
+        info!("keeping scripts in {}", dir.display());
+        Scripts { dir, robot_lock, snapshots, telemetry, audit, run: Mutex::new(None), next_run_id: AtomicU64::new(1) }
+    }
+
+    /// The saved scripts, sorted by name.
+    pub fn list(&self) -> Result<Vec<ScriptInfo>, CommandError> {
+        let entries = match std::fs::read_dir(&self.dir) {
+            Ok(entries) => entries,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(err) => return Err(self.storage_error(&err)),
+        };
+        let mut scripts: Vec<ScriptInfo> = entries
+            .filter_map(|entry| {
+                let path = entry.ok()?.path();
+                let language = ScriptLanguage::ALL.into_iter().find(|language| path.extension().is_some_and(|extension| extension == language.extension()))?;
+                Some(ScriptInfo { name: path.file_stem()?.to_str()?.to_string(), language })
+            })
+            .collect();
+        scripts.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(scripts)
+    }
+
+    pub fn source(&self, name: &str) -> Result<ScriptSource, CommandError> {
+        for language in ScriptLanguage::ALL {
+            match std::fs::read_to_string(self.path(name, language)?) {
+                Ok(source) => return Ok(ScriptSource { name: name.to_string(), language, source }),
+                Err(err) if err.kind() == std::io::ErrorKind::NotFound => continue,
+                Err(err) => return Err(self.storage_error(&err)),
+            }
+        }
+        Err(CommandError::UnknownScript { name: name.to_string() })
+    }
+
+    /// Saves a script once it compiles, replacing a script of the same name in the other language.
+    pub fn upload(&self, script: &ScriptSource) -> Result<(), CommandError> {
+        let path = self.path(&script.name, script.language)?;
+        if script.source.len() > MAX_SCRIPT_BYTES {
+            return Err(CommandError::InvalidValue { field: "source", message: format!("scripts must be at most {MAX_SCRIPT_BYTES} bytes") });
+        }
+        compile(script.language, &script.source)?;
+
+        // Write to a temporary file and rename it so a running upload never leaves a truncated script.
+        let mut temporary = path.clone().into_os_string();
+        temporary.push(".tmp");
+        std::fs::create_dir_all(&self.dir).map_err(|err| self.storage_error(&err))?;
+        std::fs::write(&temporary, &script.source).map_err(|err| self.storage_error(&err))?;
+        std::fs::rename(&temporary, &path).map_err(|err| self.storage_error(&err))?;
+        for language in ScriptLanguage::ALL.into_iter().filter(|language| *language != script.language) {
+            match std::fs::remove_file(self.path(&script.name, language)?) {
+                Err(err) if err.kind() != std::io::ErrorKind::NotFound => return Err(self.storage_error(&err)),
+                _ => {}
+            }
+        }
+        info!("saved {:?} script '{}'", script.language, script.name);
+        Ok(())
+    }
+
+    pub fn delete(&self, name: &str) -> Result<(), CommandError> {
+        let mut deleted = false;
+        for language in ScriptLanguage::ALL {
+            match std::fs::remove_file(self.path(name, language)?) {
+                Ok(()) => deleted = true,
+                Err(err) if err.kind() == std::io::ErrorKind::NotFound => {}
+                Err(err) => return Err(self.storage_error(&err)),
+            }
+        }
+        if !deleted {
+            return Err(CommandError::UnknownScript { name: name.to_string() });
+        }
+        info!("deleted script '{}'", name);
+        Ok(())
+    }
+
+    /// Starts running the script saved as `name` on its own thread.
+    /// Its commands are sent as `identity` presenting `lease`, and are audited with `remote_addr`.
+    pub fn run(&self, name: &str, identity: &Identity, remote_addr: Option<SocketAddr>, lease: Option<&str>) -> Result<ScriptStatus, CommandError> {
+        let script = self.source(name)?;
+        self.run_source(&script, identity, remote_addr, lease)
+    }
+
+    /// Starts running a program file without saving it. The language is told from the extension.
+    pub fn run_file(&self, path: &Path, identity: &Identity) -> Result<ScriptStatus, CommandError> {
+        let Some(language) = ScriptLanguage::from_path(path) else {
+            return Err(CommandError::InvalidValue { field: "language", message: format!("{} is not a .rhai, .rcl, .yaml, .yml or .json file", path.display()) });
+        };
+        let source = std::fs::read_to_string(path).map_err(|err| CommandError::Storage { message: format!("{}: {}", path.display(), err) })?;
+        let name = path.file_stem().map(|stem| stem.to_string_lossy().into_owned()).unwrap_or_default();
+        self.run_source(&ScriptSource { name, language, source }, identity, None, None)
+    }
+
+    /// Starts running `script` without saving it, as `run` does.
+    pub fn run_source(&self, script: &ScriptSource, identity: &Identity, remote_addr: Option<SocketAddr>, lease: Option<&str>) -> Result<ScriptStatus, CommandError> {
+        let program = compile(script.language, &script.source)?;
+        self.start(&script.name, program, identity, remote_addr, lease)
+    }
+
+    /// Starts a pick and place as a run named `pick_and_place`, so it reports progress and is paused or aborted like a script.
+    pub fn pick_and_place(&self, task: PickAndPlace, identity: &Identity, remote_addr: Option<SocketAddr>, lease: Option<&str>) -> Result<ScriptStatus, CommandError> {
+        task.validate(self.snapshots.borrow().state.base_state)?;
+        self.start(PICK_AND_PLACE_RUN, Program::PickAndPlace(task), identity, remote_addr, lease)
+    }
+
+    fn start(&self, name: &str, program: Program, identity: &Identity, remote_addr: Option<SocketAddr>, lease: Option<&str>) -> Result<ScriptStatus, CommandError> {
+        let mut current = self.run.lock().unwrap();
+        if let Some(status) = current.as_ref().map(|run| run.status()).filter(|status| status.state.is_active()) {
+            return Err(CommandError::ScriptRunning { name: status.name });
+        }
+
+        let run_id = self.next_run_id.fetch_add(1, Ordering::Relaxed);
+        let run = Arc::new(ScriptRun::new(run_id, name, self.telemetry.clone()));
+        let context = ScriptContext {
+            run: run.clone(),
+            robot_lock: self.robot_lock.clone(),
+            snapshots: self.snapshots.clone(),
+            audit: self.audit.clone(),
+            identity: identity.clone(),
+            origin: Origin { transport: "script", client_id: Some(format!("{name} (run {run_id})")), remote_addr, identity: Some(identity.clone()) },
+            lease: lease.map(str::to_string),
+            runtime: Handle::current(),
+            started: Instant::now(),
+        };
+        info!("{} started script '{}' (run {})", identity.name, name, run_id);
+        run.publish(run.status());
+        *current = Some(run.clone());
+        if let Err(err) = std::thread::Builder::new().name(format!("script {run_id}")).spawn(move || Arc::new(context).run(program)) {
+            run.finish(ScriptState::Failed, Some(format!("could not start the script: {err}")));
+        }
+        Ok(run.status())
+    }
+
+    /// Pauses, resumes or aborts the script running.
+    pub fn control(&self, control: ScriptControl) -> Result<ScriptStatus, CommandError> {
+        let run = self.run.lock().unwrap().clone().ok_or(CommandError::NoScriptRunning)?;
+        run.control(control)
+    }
+
+    /// The status of the script running, or of the last one run.
+    pub fn status(&self) -> Option<ScriptStatus> {
+        self.run.lock().unwrap().as_ref().map(|run| run.status())
+    }
+
+    fn path(&self, name: &str, language: ScriptLanguage) -> Result<PathBuf, CommandError> {
+        validate_name(name, "script")?;
+        Ok(self.dir.join(format!("{name}.{}", language.extension())))
+    }
+
+    fn storage_error(&self, err: &dyn std::fmt::Display) -> CommandError {
+        CommandError::Storage { message: format!("{}: {}", self.dir.display(), err) }
+    }
+}
+
+/// A run of a script, shared between the thread running it and the clients controlling it.
+struct ScriptRun {
+    status: Mutex<ScriptStatus>,
+    /// Notified when the run is resumed or aborted.
+    resumed: Condvar,
+    aborted: AtomicBool,
+    telemetry: TelemetrySender,
+}
+
+impl ScriptRun {
+    fn new(run_id: u64, name: &str, telemetry: TelemetrySender) -> ScriptRun {
+        let status = ScriptStatus { run_id, name: name.to_string(), state: ScriptState::Running, started_at: Utc::now(), finished_at: None, error: None };
+        ScriptRun { status: Mutex::new(status), resumed: Condvar::new(), aborted: AtomicBool::new(false), telemetry }
+    }
+
+    fn status(&self) -> ScriptStatus {
+        self.status.lock().unwrap().clone()
+    }
+
+    fn control(&self, control: ScriptControl) -> Result<ScriptStatus, CommandError> {
+        let mut status = self.status.lock().unwrap();
+        if !status.state.is_active() {
+            return Err(CommandError::NoScriptRunning);
+        }
+        match control {
+            ScriptControl::Pause => status.state = ScriptState::Paused,
+            ScriptControl::Resume => status.state = ScriptState::Running,
+            ScriptControl::Abort => self.aborted.store(true, Ordering::SeqCst),
+        }
+        self.resumed.notify_all();
+        info!("script '{}' (run {}) {:?}", status.name, status.run_id, control);
+
+        let status = status.clone();
+        self.publish(status.clone());
+        Ok(status)
+    }
+
+    /// Waits while the run is paused. Fails once it has been aborted, which stops the script.
+    fn checkpoint(&self) -> Result<(), Interrupt> {
+        let mut status = self.status.lock().unwrap();
+        loop {
+            if self.aborted.load(Ordering::SeqCst) {
+                return Err(Interrupt::Aborted);
+            }
+            if status.state != ScriptState::Paused {
+                return Ok(());
+            }
+            status = self.resumed.wait(status).unwrap();
+        }
+    }
+
+    fn finish(&self, state: ScriptState, error: Option<String>) {
+        let status = {
+            let mut status = self.status.lock().unwrap();
+            status.state = state;
+            status.finished_at = Some(Utc::now());
+            status.error = error;
+            status.clone()
+        };
+        match &status.error {
+            Some(err) => error!("script '{}' (run {}) failed: {}", status.name, status.run_id, err),
+            None => info!("script '{}' (run {}) {:?}", status.name, status.run_id, status.state),
+        }
+        self.publish(status);
+    }
+
+    fn output(&self, line: String) {
+        let run_id = {
+            let status = self.status.lock().unwrap();
+            info!("script '{}' (run {}): {}", status.name, status.run_id, line);
+            status.run_id
+        };
+        let _ = self.telemetry.send(Telemetry::Script(ScriptEvent::Output { run_id, line }));
+    }
+
+    fn tree(&self, nodes: Vec<TreeNodeStatus>) {
+        let run_id = self.status.lock().unwrap().run_id;
+        let _ = self.telemetry.send(Telemetry::Script(ScriptEvent::Tree { run_id, nodes }));
+    }
+
+    /// Sending fails only when no client is listening.
+    fn publish(&self, status: ScriptStatus) {
+        let _ = self.telemetry.send(Telemetry::Script(ScriptEvent::Status(status)));
+    }
+}
+
+impl From<Interrupt> for Box<EvalAltResult> {
+    fn from(interrupt: Interrupt) -> Self {
+        match interrupt {
+            // Terminating cannot be caught by the script.
+            Interrupt::Aborted => EvalAltResult::ErrorTerminated(Dynamic::UNIT, Position::NONE).into(),
+            Interrupt::Failed(message) => message.into(),
+        }
+    }
+}
+
+/// A compiled script.
+enum Program {
+    Rhai(AST),
+    Rcl(Vec<Instruction>),
+    PickAndPlace(PickAndPlace),
+    BehaviorTree(BehaviorTree),
+}
+
+/// The error a run reports for an interrupt. An aborted run reports none.
+fn failure(interrupt: Interrupt) -> String {
+    match interrupt {
+        Interrupt::Failed(message) => message,
+        Interrupt::Aborted => String::new(),
+    }
+}
+
+/// What the Rhai motion API returns. Fails to stop the script.
+type RhaiResult<T> = Result<T, Box<EvalAltResult>>;
+
+/// What the functions a script calls act on.
+struct ScriptContext {
+    run: Arc<ScriptRun>,
+    robot_lock: RobotLock,
+    snapshots: SnapshotReceiver,
+    audit: SharedAudit,
+    /// The client that started the script.
+    identity: Identity,
+    origin: Origin,
+    lease: Option<String>,
+    /// Commands are executed on the server's runtime from the script's thread.
+    runtime: Handle,
+    started: Instant,
+}
+
+impl ScriptContext {
+    /// Runs the script to the end, holding the robot where it is if the script is aborted.
+    fn run(self: Arc<Self>, program: Program) {
+        let result = match program {
+            Program::Rhai(ast) => engine(self.clone()).run_ast(&ast).map_err(|err| err.to_string()),
+            Program::Rcl(instructions) => command_language::execute(self.as_ref(), &instructions).map_err(failure),
+            Program::PickAndPlace(task) => task.run(self.as_ref()).map_err(failure),
+            Program::BehaviorTree(mut tree) => tree.run(self.as_ref(), |nodes| self.run.tree(nodes)).map_err(failure),
+        };
+        let (state, error) = match result {
+            _ if self.run.aborted.load(Ordering::SeqCst) => (ScriptState::Aborted, None),
+            Ok(()) => (ScriptState::Finished, None),
+            Err(err) => (ScriptState::Failed, Some(err)),
+        };
+
+        if state == ScriptState::Aborted {
+            let state = self.snapshots.borrow().state;
+            let _ = self.send(Command::SetJointState(state.joint_state));
+            let _ = self.send(Command::SetBaseState(state.base_state));
+        }
+        self.run.finish(state, error);
+    }
+
+    /// Executes and audits a command as the client that started the script.
+    fn send(&self, command: Command) -> Result<CommandOutcome, CommandError> {
+        let name = command.name();
+        let payload = serde_json::to_value(&command).unwrap_or_default();
+        let result = self.runtime.block_on(execute_authorized(&self.robot_lock, &self.identity, self.lease.as_deref(), command));
+        self.audit.record(&self.origin, name, &payload, &result);
+        result
+    }
+
+    fn get_state(&self) -> RhaiResult<Dynamic> {
+        let snapshot = self.snapshots.borrow().clone();
+        rhai::serde::to_dynamic(serde_json::json!({
+            "joint_state": snapshot.state.joint_state,
+            "base_state": snapshot.state.base_state,
+            "coords": snapshot.coords,
+            "target_state": snapshot.target_state,
+            "settled": snapshot.settled,
+            "fault": snapshot.fault,
+        }))
+    }
+}
+
+impl Motion for ScriptContext {
+    /// Executes a command once the script may carry on, stopping the script if it is rejected.
+    fn execute(&self, command: Command) -> Result<CommandOutcome, Interrupt> {
+        self.run.checkpoint()?;
+        let name = command.name();
+        self.send(command).map_err(|error| Interrupt::Failed(format!("{name} was rejected: {error}")))
+    }
+
+    fn snapshot(&self) -> RobotSnapshot {
+        self.snapshots.borrow().clone()
+    }
+
+    /// Sleeps for `duration`, or longer if the script is paused meanwhile.
+    fn sleep(&self, duration: Duration) -> Result<(), Interrupt> {
+        let deadline = Instant::now() + duration;
+        loop {
+            self.run.checkpoint()?;
+            let now = Instant::now();
+            if now >= deadline {
+                return Ok(());
+            }
+            std::thread::sleep(POLL_PERIOD.min(deadline - now));
+        }
+    }
+
+    fn print(&self, line: String) {
+        self.run.output(line);
+    }
+
+    fn elapsed(&self) -> Duration {
+        self.started.elapsed()
+    }
+}
+
+/// An engine without access to the file system or `eval`, limited so a script cannot exhaust the server's memory or run forever.
+fn sandboxed_engine() -> Engine {
+    let mut engine = Engine::new();
+    engine.set_module_resolver(DummyModuleResolver::new());
+    engine.disable_symbol("eval");
+    engine.set_max_operations(MAX_SCRIPT_OPERATIONS);
+    engine.set_max_call_levels(64);
+    engine.set_max_expr_depths(64, 32);
+    engine.set_max_string_size(64 * 1024);
+    engine.set_max_array_size(10_000);
+    engine.set_max_map_size(10_000);
+    engine
+}
+
+/// Checks a script's syntax.
+fn compile(language: ScriptLanguage, source: &str) -> Result<Program, CommandError> {
+    match language {
+        ScriptLanguage::Rhai => sandboxed_engine().compile(source).map(Program::Rhai).map_err(|err| err.to_string()),
+        ScriptLanguage::Rcl => command_language::parse(source).map(Program::Rcl).map_err(|errors| errors.iter().map(ToString::to_string).collect::<Vec<_>>().join("; ")),
+        ScriptLanguage::BehaviorTree => BehaviorTree::parse(source).map(Program::BehaviorTree),
+    }
+    .map_err(|message| CommandError::InvalidValue { field: "source", message })
+}
+
+/// A sandboxed engine with the motion API registered, acting on `context`.
+fn engine(context: Arc<ScriptContext>) -> Engine {
+    let mut engine = sandboxed_engine();
+
+    // Pause and abort take effect between statements as well as in the motion API.
+    let run = context.run.clone();
+    engine.on_progress(move |_| run.checkpoint().err().map(|_| Dynamic::UNIT));
+    let run = context.run.clone();
+    engine.on_print(move |line| run.output(line.to_string()));
+    let run = context.run.clone();
+    engine.on_debug(move |line, _, position| run.output(format!("{line} ({position})")));
+
+    let c = context.clone();
+    engine.register_fn("move_joints", move |joints: Map| -> RhaiResult<()> { Ok(c.move_joints(fields(joints)?)?) });
+    let c = context.clone();
+    engine.register_fn("move_coords", move |coords: Map| -> RhaiResult<()> { Ok(c.move_coords(fields(coords)?)?) });
+    let c = context.clone();
+    engine.register_fn("move_linear", move |coords: Map| -> RhaiResult<()> { Ok(c.move_linear(fields(coords)?, DEFAULT_LINEAR_SPEED)?) });
+    let c = context.clone();
+    engine.register_fn("move_linear", move |coords: Map, speed: FLOAT| -> RhaiResult<()> { Ok(c.move_linear(fields(coords)?, speed)?) });
+    let c = context.clone();
+    engine.register_fn("move_linear", move |coords: Map, speed: INT| -> RhaiResult<()> { Ok(c.move_linear(fields(coords)?, speed as FLOAT)?) });
+    let c = context.clone();
+    engine.register_fn("move_base", move |base: Map| -> RhaiResult<()> { Ok(c.move_base(fields(base)?)?) });
+    let c = context.clone();
+    engine.register_fn("move_to_pose", move |name: &str| -> RhaiResult<()> { Ok(c.move_to_pose(name)?) });
+    let c = context.clone();
+    engine.register_fn("open_gripper", move |gripper_open_mm: FLOAT| -> RhaiResult<()> { Ok(c.open_gripper(gripper_open_mm)?) });
+    let c = context.clone();
+    engine.register_fn("open_gripper", move |gripper_open_mm: INT| -> RhaiResult<()> { Ok(c.open_gripper(gripper_open_mm as FLOAT)?) });
+    let c = context.clone();
+    engine.register_fn("close_gripper", move || -> RhaiResult<()> { Ok(c.open_gripper(0.0)?) });
+    let c = context.clone();
+    engine.register_fn("wait_settled", move || -> RhaiResult<bool> { Ok(c.wait_settled(None)?) });
+    let c = context.clone();
+    engine.register_fn("wait_settled", move |timeout_s: FLOAT| -> RhaiResult<bool> { Ok(c.wait_settled(Some(seconds(timeout_s)?))?) });
+    let c = context.clone();
+    engine.register_fn("wait_settled", move |timeout_s: INT| -> RhaiResult<bool> { Ok(c.wait_settled(Some(seconds(timeout_s as FLOAT)?))?) });
+    let c = context.clone();
+    engine.register_fn("sleep", move |duration_s: FLOAT| -> RhaiResult<()> { Ok(c.sleep(seconds(duration_s)?)?) });
+    let c = context.clone();
+    engine.register_fn("sleep", move |duration_s: INT| -> RhaiResult<()> { Ok(c.sleep(seconds(duration_s as FLOAT)?)?) });
+    let c = context.clone();
+    engine.register_fn("pick_and_place", move |task: Map| -> RhaiResult<()> {
+        let task: PickAndPlace = rhai::serde::from_dynamic(&Dynamic::from_map(task))?;
+        task.validate(c.snapshot().state.base_state).map_err(|error| error.to_string())?;
+        Ok(task.run(c.as_ref())?)
+    });
+    let c = context;
+    engine.register_fn("get_state", move || c.get_state());
+    engine
+}
+
+/// A Rhai object map as target fields.
+fn fields(map: Map) -> RhaiResult<Fields> {
+    rhai::serde::from_dynamic(&Dynamic::from_map(map))
+}
+
+fn seconds(seconds: FLOAT) -> RhaiResult<Duration> {
+    Duration::try_from_secs_f64(seconds).map_err(|_| format!("expected a positive number of seconds, got {seconds}").into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::robot::audit::{AuditConfig, AuditLog};
+    use crate::robot::auth::Role;
+    use crate::robot::robot_config::RobotConfig;
+    use crate::robot::robot_state::JointState;
+    use crate::robot::Robot;
+
+    use tokio::sync::RwLock;
+
+    fn scripts(dir: &Path) -> Scripts {
+        let robot = Robot::test(RobotConfig::default(), dir);
+        let snapshots = robot.subscribe_snapshots();
+        let (telemetry, _) = tokio::sync::broadcast::channel(1024);
+        let audit = Arc::new(AuditLog::start(AuditConfig { enabled: false, ..Default::default() }).unwrap());
+        Scripts { dir: dir.join("scripts"), robot_lock: Arc::new(RwLock::new(robot)), snapshots, telemetry, audit, run: Mutex::new(None), next_run_id: AtomicU64::new(1) }
+    }
+
+    fn operator() -> Identity {
+        Identity { name: "alice".to_string(), role: Role::Operator }
+    }
+
+    fn rhai(name: &str, source: &str) -> ScriptSource {
+        ScriptSource { name: name.to_string(), language: ScriptLanguage::Rhai, source: source.to_string() }
+    }
+
+    /// Waits for the run to reach `state`.
+    async fn wait_for(scripts: &Scripts, state: ScriptState) -> ScriptStatus {
+        for _ in 0..500 {
+            let status = scripts.status().unwrap();
+            if status.state == state {
+                return status;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        panic!("the script did not reach {state:?}: {:?}", scripts.status());
+    }
+
+    /// The lines the script has printed since last asked.
+    fn output(telemetry: &mut tokio::sync::broadcast::Receiver<Telemetry>) -> Vec<String> {
+        std::iter::from_fn(|| telemetry.try_recv().ok())
+            .filter_map(|telemetry| match telemetry {
+                Telemetry::Script(ScriptEvent::Output { line, .. }) => Some(line),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn script_names_are_validated() {
+        let dir = tempfile::tempdir().unwrap();
+        let scripts = scripts(dir.path());
+        for name in ["", "../escape", "nested/script", "with space", &"a".repeat(100)] {
+            let result = scripts.upload(&rhai(name, "print(1);"));
+            assert!(matches!(result, Err(CommandError::InvalidValue { field: "name", .. })), "{name:?}");
+            assert!(matches!(scripts.delete(name), Err(CommandError::InvalidValue { field: "name", .. })), "{name:?}");
+        }
+        assert!(!dir.path().join("escape.rhai").exists());
+
+        scripts.upload(&rhai("cycle-1", "print(1);")).unwrap();
+        assert_eq!(scripts.list().unwrap(), [ScriptInfo { name: "cycle-1".to_string(), language: ScriptLanguage::Rhai }]);
+        assert_eq!(scripts.source("cycle-1").unwrap().source, "print(1);");
+
+        // Saving in another language replaces the script.
+        scripts.upload(&ScriptSource { name: "cycle-1".to_string(), language: ScriptLanguage::Rcl, source: "WAIT 1".to_string() }).unwrap();
+        assert_eq!(scripts.list().unwrap(), [ScriptInfo { name: "cycle-1".to_string(), language: ScriptLanguage::Rcl }]);
+
+        scripts.delete("cycle-1").unwrap();
+        assert!(matches!(scripts.delete("cycle-1"), Err(CommandError::UnknownScript { .. })));
+        assert!(matches!(scripts.source("cycle-1"), Err(CommandError::UnknownScript { .. })));
+    }
+
+    #[test]
+    fn scripts_that_do_not_compile_are_not_saved() {
+        let dir = tempfile::tempdir().unwrap();
+        let scripts = scripts(dir.path());
+        assert!(matches!(scripts.upload(&rhai("broken", "let = 1;")), Err(CommandError::InvalidValue { field: "source", .. })));
+        assert!(matches!(scripts.upload(&rhai("large", &" ".repeat(MAX_SCRIPT_BYTES + 1))), Err(CommandError::InvalidValue { field: "source", .. })));
+        assert_eq!(scripts.list().unwrap(), []);
+    }
+
+    #[test]
+    fn eval_and_import_are_rejected() {
+        assert!(compile(ScriptLanguage::Rhai, r#"eval("print(1)");"#).is_err());
+
+        let err = sandboxed_engine().run(r#"import "motion" as motion;"#).unwrap_err();
+        assert!(matches!(*err, EvalAltResult::ErrorModuleNotFound(..)), "{err}");
+    }
+
+    #[test]
+    fn limits_are_enforced() {
+        let engine = sandboxed_engine();
+        let limited = |source: &str| *engine.run(source).unwrap_err();
+
+        assert!(matches!(limited(r#"let text = "text"; loop { text += text; }"#), EvalAltResult::ErrorDataTooLarge(..)));
+        assert!(matches!(limited("let list = []; loop { list.push(1); }"), EvalAltResult::ErrorDataTooLarge(..)));
+        assert!(matches!(limited("fn recurse(depth) { recurse(depth + 1) } recurse(0);"), EvalAltResult::ErrorStackOverflow(..)));
+
+        // Running all of `MAX_SCRIPT_OPERATIONS` takes too long in a debug build.
+        assert_eq!(engine.max_operations(), MAX_SCRIPT_OPERATIONS);
+        let mut engine = sandboxed_engine();
+        engine.set_max_operations(10_000);
+        assert!(matches!(*engine.run("loop {}").unwrap_err(), EvalAltResult::ErrorTooManyOperations(..)));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn one_script_runs_at_a_time() {
+        let dir = tempfile::tempdir().unwrap();
+        let scripts = scripts(dir.path());
+        let first = scripts.run_source(&rhai("first", "loop { sleep(0.01); }"), &operator(), None, None).unwrap();
+        assert_eq!(first.state, ScriptState::Running);
+
+        let result = scripts.run_source(&rhai("second", "print(1);"), &operator(), None, None);
+        assert!(matches!(result, Err(CommandError::ScriptRunning { name }) if name == "first"));
+
+        scripts.control(ScriptControl::Abort).unwrap();
+        wait_for(&scripts, ScriptState::Aborted).await;
+        assert!(matches!(scripts.control(ScriptControl::Pause), Err(CommandError::NoScriptRunning)));
+
+        let second = scripts.run_source(&rhai("second", "print(1);"), &operator(), None, None).unwrap();
+        assert_eq!(second.run_id, first.run_id + 1);
+        wait_for(&scripts, ScriptState::Finished).await;
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn pausing_holds_the_script_and_aborting_holds_the_robot() {
+        let dir = tempfile::tempdir().unwrap();
+        let scripts = scripts(dir.path());
+        let mut telemetry = scripts.telemetry.subscribe();
+        let source = "move_joints(#{ lift_elevation_mm: 300.0 }); let i = 0; loop { i += 1; print(i); sleep(0.01); }";
+        scripts.run_source(&rhai("counter", source), &operator(), None, None).unwrap();
+        while output(&mut telemetry).is_empty() {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(scripts.robot_lock.read().await.get_target_state().joint_state.lift_elevation_mm, 300.0);
+
+        assert_eq!(scripts.control(ScriptControl::Pause).unwrap().state, ScriptState::Paused);
+        // A statement under way when the script was paused may still finish.
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        output(&mut telemetry);
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(output(&mut telemetry), Vec::<String>::new());
+
+        assert_eq!(scripts.control(ScriptControl::Resume).unwrap().state, ScriptState::Running);
+        while output(&mut telemetry).is_empty() {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+
+        scripts.control(ScriptControl::Abort).unwrap();
+        let status = wait_for(&scripts, ScriptState::Aborted).await;
+        assert_eq!(status.error, None);
+        assert!(status.finished_at.is_some());
+        // The robot never moved, so it is held at the origin.
+        assert_eq!(scripts.robot_lock.read().await.get_target_state().joint_state, JointState::default());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn a_paused_script_can_be_aborted() {
+        let dir = tempfile::tempdir().unwrap();
+        let scripts = scripts(dir.path());
+        scripts.run_source(&rhai("waiting", "loop { sleep(0.01); }"), &operator(), None, None).unwrap();
+        scripts.control(ScriptControl::Pause).unwrap();
+
+        scripts.control(ScriptControl::Abort).unwrap();
+        wait_for(&scripts, ScriptState::Aborted).await;
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn a_rejected_command_fails_the_script() {
+        let dir = tempfile::tempdir().unwrap();
+        let scripts = scripts(dir.path());
+        let viewer = Identity { name: "bob".to_string(), role: Role::Viewer };
+        scripts.run_source(&rhai("forbidden", "open_gripper(10);"), &viewer, None, None).unwrap();
+
+        let status = wait_for(&scripts, ScriptState::Failed).await;
+        assert!(status.error.unwrap().contains("set_gripper was rejected"));
+    }
+}
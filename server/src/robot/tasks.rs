@@ -0,0 +1,255 @@
+use super::command::{Command, CommandError};
+use super::constants::{GRIPPER_WIDTH_MM, MAX_COMMAND_DISTANCE_M};
+use super::motion::{Fields, Interrupt, Motion, MIN_LINEAR_SPEED};
+use super::robot_state::Coord4DOF;
+use super::is_reachable;
+
+/// End effector speed (m/sec) of the descents and retracts when the task does not give one.
+pub const DEFAULT_PICK_AND_PLACE_SPEED: f64 = 0.1;
+
+/// Picks an object up at one end effector pose and puts it down at another.
+#[derive(serde::Serialize, serde::Deserialize, Copy, Clone, Debug, PartialEq, schemars::JsonSchema, ts_rs::TS)]
+pub struct PickAndPlace {
+    /// Where the gripper closes on the object.
+    pub pick: Coord4DOF,
+    /// Where the object is let go.
+    pub place: Coord4DOF,
+    /// Height (m) above `pick` and `place` the gripper approaches from and retracts to.
+    pub approach_height_m: f64,
+    /// Gripper opening (mm) while approaching and after letting go.
+    pub open_mm: f64,
+    /// Gripper opening (mm) that holds the object.
+    pub closed_mm: f64,
+    /// End effector speed (m/sec) of the straight descents and retracts. `DEFAULT_PICK_AND_PLACE_SPEED` when not set.
+    #[serde(default)]
+    #[ts(optional = nullable)]
+    pub speed: Option<f64>,
+}
+
+/// A step of a pick and place, in the order they run.
+#[derive(Copy, Clone, Debug)]
+enum Phase {
+    Approach,
+    DescendToPick,
+    Close,
+    RetractFromPick,
+    Transfer,
+    DescendToPlace,
+    Open,
+    RetractFromPlace,
+}
+
+const PHASES: [Phase; 8] = [Phase::Approach, Phase::DescendToPick, Phase::Close, Phase::RetractFromPick, Phase::Transfer, Phase::DescendToPlace, Phase::Open, Phase::RetractFromPlace];
+
+impl Phase {
+    fn description(&self) -> &'static str {
+        match self {
+            Phase::Approach => "open the gripper and approach the pick",
+            Phase::DescendToPick => "descend to the pick",
+            Phase::Close => "close the gripper",
+            Phase::RetractFromPick => "retract from the pick",
+            Phase::Transfer => "transfer above the place",
+            Phase::DescendToPlace => "descend to the place",
+            Phase::Open => "open the gripper",
+            Phase::RetractFromPlace => "retract from the place",
+        }
+    }
+}
+
+impl PickAndPlace {
+    /// Ensures every value is finite and within range, the gripper closes narrower than it opens,
+    /// and ik reaches the pick, the place and the poses above them with the base at `base_state`.
+    pub fn validate(&self, base_state: Coord4DOF) -> Result<(), CommandError> {
+        Command::SetCoordState(self.pick).validate()?;
+        Command::SetCoordState(self.place).validate()?;
+        let invalid = |field, message: String| Err(CommandError::InvalidValue { field, message });
+        if !self.approach_height_m.is_finite() || self.approach_height_m <= 0.0 || self.approach_height_m > MAX_COMMAND_DISTANCE_M {
+            return invalid("approach_height_m", format!("approach_height_m must be a positive number of m up to {MAX_COMMAND_DISTANCE_M}"));
+        }
+        for (field, width) in [("open_mm", self.open_mm), ("closed_mm", self.closed_mm)] {
+            if !width.is_finite() || !(0.0..=GRIPPER_WIDTH_MM).contains(&width) {
+                return invalid(field, format!("{field} must be between 0 and {GRIPPER_WIDTH_MM} mm"));
+            }
+        }
+        if self.closed_mm >= self.open_mm {
+            return invalid("closed_mm", "closed_mm must be narrower than open_mm".to_string());
+        }
+        if let Some(speed) = self.speed.filter(|speed| !speed.is_finite() || *speed < MIN_LINEAR_SPEED) {
+            return invalid("speed", format!("speed must be at least {MIN_LINEAR_SPEED} m/sec, got {speed}"));
+        }
+        if [self.pick, self.above(self.pick), self.place, self.above(self.place)].into_iter().any(|coords| !is_reachable(base_state, coords)) {
+            return Err(CommandError::Unreachable);
+        }
+        Ok(())
+    }
+
+    /// The pose `approach_height_m` above `coords`, that the gripper approaches from and retracts to.
+    fn above(&self, coords: Coord4DOF) -> Coord4DOF {
+        Coord4DOF { z: coords.z + self.approach_height_m, ..coords }
+    }
+
+    /// Runs every phase in turn, reporting each as it starts. Each phase waits for the robot to settle.
+    /// A failure is reported with the phase it stopped in.
+    pub fn run(&self, motion: &impl Motion) -> Result<(), Interrupt> {
+        for (index, phase) in PHASES.iter().enumerate() {
+            motion.print(format!("pick_and_place {}/{}: {}", index + 1, PHASES.len(), phase.description()));
+            self.run_phase(motion, *phase).map_err(|interrupt| match interrupt {
+                Interrupt::Failed(message) => Interrupt::Failed(format!("pick_and_place could not {}: {}", phase.description(), message)),
+                Interrupt::Aborted => Interrupt::Aborted,
+            })?;
+        }
+        motion.print("pick_and_place done".to_string());
+        Ok(())
+    }
+
+    fn run_phase(&self, motion: &impl Motion, phase: Phase) -> Result<(), Interrupt> {
+        let above_pick = self.above(self.pick);
+        let above_place = self.above(self.place);
+        let speed = self.speed.unwrap_or(DEFAULT_PICK_AND_PLACE_SPEED);
+        match phase {
+            Phase::Approach => {
+                motion.open_gripper(self.open_mm)?;
+                motion.move_coords(fields(above_pick)?)?;
+            }
+            Phase::DescendToPick => motion.move_linear(fields(self.pick)?, speed)?,
+            Phase::Close => motion.open_gripper(self.closed_mm)?,
+            Phase::RetractFromPick => motion.move_linear(fields(above_pick)?, speed)?,
+            Phase::Transfer => motion.move_coords(fields(above_place)?)?,
+            Phase::DescendToPlace => motion.move_linear(fields(self.place)?, speed)?,
+            Phase::Open => motion.open_gripper(self.open_mm)?,
+            Phase::RetractFromPlace => motion.move_linear(fields(above_place)?, speed)?,
+        }
+        motion.wait_settled(None).map(|_| ())
+    }
+}
+
+/// Every field of an end effector pose, to move to it exactly.
+fn fields(coords: Coord4DOF) -> Result<Fields, Interrupt> {
+    match serde_json::to_value(coords) {
+        Ok(serde_json::Value::Object(fields)) => Ok(fields),
+        Ok(value) => Err(Interrupt::Failed(format!("expected the pose as an object, got {value}"))),
+        Err(err) => Err(Interrupt::Failed(err.to_string())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::robot::motion::mock::MockMotion;
+
+    fn task() -> PickAndPlace {
+        PickAndPlace {
+            pick: Coord4DOF { x: 2.0, y: 1.0, z: 0.3, theta: 0.0 },
+            place: Coord4DOF { x: 1.0, y: 2.0, z: 0.3, theta: 90.0 },
+            approach_height_m: 0.15,
+            open_mm: 120.0,
+            closed_mm: 40.0,
+            speed: Some(0.1),
+        }
+    }
+
+    fn invalid_field(result: Result<(), CommandError>) -> Option<&'static str> {
+        match result {
+            Err(CommandError::InvalidValue { field, .. }) => Some(field),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn a_reachable_task_is_valid() {
+        assert!(task().validate(Coord4DOF::default()).is_ok());
+        assert!(PickAndPlace { speed: None, ..task() }.validate(Coord4DOF::default()).is_ok());
+    }
+
+    #[test]
+    fn invalid_values_are_rejected() {
+        let cases = [
+            (PickAndPlace { closed_mm: 120.0, ..task() }, "closed_mm"),
+            (PickAndPlace { closed_mm: 130.0, ..task() }, "closed_mm"),
+            (PickAndPlace { open_mm: GRIPPER_WIDTH_MM + 1.0, ..task() }, "open_mm"),
+            (PickAndPlace { closed_mm: -1.0, ..task() }, "closed_mm"),
+            (PickAndPlace { open_mm: f64::NAN, ..task() }, "open_mm"),
+            (PickAndPlace { approach_height_m: 0.0, ..task() }, "approach_height_m"),
+            (PickAndPlace { approach_height_m: f64::INFINITY, ..task() }, "approach_height_m"),
+            (PickAndPlace { speed: Some(0.0), ..task() }, "speed"),
+            (PickAndPlace { speed: Some(-0.1), ..task() }, "speed"),
+            (PickAndPlace { speed: Some(f64::NAN), ..task() }, "speed"),
+            (PickAndPlace { pick: Coord4DOF { x: f64::NAN, ..task().pick }, ..task() }, "x"),
+        ];
+        for (task, field) in cases {
+            assert_eq!(invalid_field(task.validate(Coord4DOF::default())), Some(field), "{task:?}");
+        }
+    }
+
+    #[test]
+    fn unreachable_poses_are_rejected() {
+        let far = Coord4DOF { x: 9.0, ..task().pick };
+        assert!(matches!(PickAndPlace { pick: far, ..task() }.validate(Coord4DOF::default()), Err(CommandError::Unreachable)));
+        assert!(matches!(PickAndPlace { place: far, ..task() }.validate(Coord4DOF::default()), Err(CommandError::Unreachable)));
+        // Reachable from the origin, but not from a base moved away.
+        assert!(matches!(task().validate(Coord4DOF { x: -7.0, ..Default::default() }), Err(CommandError::Unreachable)));
+    }
+
+    /// Names the gripper commands and the coordinate targets at one of the task's poses, in the order they were sent.
+    fn key_moves(task: &PickAndPlace, commands: &[Command]) -> Vec<&'static str> {
+        let poses = [("above pick", task.above(task.pick)), ("pick", task.pick), ("above place", task.above(task.place)), ("place", task.place)];
+        let mut moves: Vec<&'static str> = Vec::new();
+        for command in commands {
+            let name = match command {
+                Command::SetGripper(width) if *width == task.open_mm => Some("open"),
+                Command::SetGripper(width) if *width == task.closed_mm => Some("close"),
+                Command::SetCoordState(coords) => poses.iter().find(|(_, pose)| (*coords - *pose).within(1e-9, 1e-9)).map(|(name, _)| *name),
+                _ => None,
+            };
+            // The straight moves stream waypoints, so a pose can be reached more than once.
+            if let Some(name) = name.filter(|name| moves.last() != Some(name)) {
+                moves.push(name);
+            }
+        }
+        moves
+    }
+
+    #[test]
+    fn phases_run_in_order() {
+        let task = task();
+        let motion = MockMotion::new();
+        assert!(task.run(&motion).is_ok());
+
+        assert_eq!(key_moves(&task, &motion.commands.borrow()), ["open", "above pick", "pick", "close", "above pick", "above place", "place", "open", "above place"]);
+        let output = motion.output.borrow();
+        assert_eq!(output.len(), PHASES.len() + 1);
+        assert_eq!(output[0], "pick_and_place 1/8: open the gripper and approach the pick");
+        assert_eq!(output[2], "pick_and_place 3/8: close the gripper");
+        assert_eq!(output[8], "pick_and_place done");
+    }
+
+    #[test]
+    fn descents_and_retracts_are_straight() {
+        let task = task();
+        let motion = MockMotion::new();
+        assert!(task.run(&motion).is_ok());
+
+        // Every waypoint between the pick and the pose above it is directly over the pick.
+        let commands = motion.commands.borrow();
+        let descent: Vec<Coord4DOF> = commands
+            .iter()
+            .filter_map(|command| match command {
+                Command::SetCoordState(coords) if coords.z < task.above(task.pick).z - 1e-9 && (coords.x - task.pick.x).abs() < 0.5 => Some(*coords),
+                _ => None,
+            })
+            .collect();
+        assert!(descent.len() > 2);
+        assert!(descent.iter().all(|coords| (coords.x - task.pick.x).abs() < 1e-9 && (coords.y - task.pick.y).abs() < 1e-9));
+    }
+
+    #[test]
+    fn a_failure_names_the_phase() {
+        let motion = MockMotion::new();
+        motion.rejections.set(1);
+        let Err(Interrupt::Failed(message)) = task().run(&motion) else {
+            panic!("the task did not fail");
+        };
+        assert_eq!(message, "pick_and_place could not open the gripper and approach the pick: set_gripper was rejected");
+        assert_eq!(motion.output.borrow().len(), 1);
+    }
+}
@@ -0,0 +1,344 @@
+use super::control::ControlStatus;
+use super::robot_state::{Coord4DOF, LinkCoords, RobotState};
+use super::scripting::ScriptEvent;
+
+use std::collections::HashMap;
+
+use chrono::{DateTime, Utc};
+use tokio::sync::watch;
+use tokio::time::{Duration, Instant};
+
+/// Version of the `TelemetryFrame` layout. Bumped whenever a field is changed or removed.
+pub const TELEMETRY_VERSION: u32 = 1;
+
+/// Slowest `rate_hz` a stream can be subscribed at, one message every 100 seconds.
+pub const MIN_RATE_HZ: f64 = 0.01;
+
+/// Number of telemetry messages buffered for each subscriber before it starts missing messages.
+pub const TELEMETRY_CHANNEL_CAPACITY: usize = 64;
+
+/// The telemetry streams clients can subscribe to. Names match the Socket.IO events.
+#[derive(serde::Serialize, serde::Deserialize, Copy, Clone, Debug, PartialEq, Eq, Hash, schemars::JsonSchema, ts_rs::TS)]
+pub enum Stream {
+    #[serde(rename = "joint state")]
+    JointState,
+    #[serde(rename = "base coords")]
+    BaseCoords,
+    #[serde(rename = "velocity")]
+    Velocity,
+    #[serde(rename = "links")]
+    Links,
+    #[serde(rename = "fault")]
+    Fault,
+    #[serde(rename = "control")]
+    Control,
+    #[serde(rename = "telemetry")]
+    Frame,
+    #[serde(rename = "script")]
+    Script,
+}
+
+/// The robot's state, published by the controller after every tick and after every command.
+/// Consumers read it from a `SnapshotReceiver` instead of locking the robot.
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug, Default, PartialEq, schemars::JsonSchema)]
+pub struct RobotSnapshot {
+    pub timestamp: DateTime<Utc>,
+    pub state: RobotState,
+    pub coords: Coord4DOF,
+    pub links: LinkCoords,
+    pub velocity: RobotState,
+    pub target_state: RobotState,
+    pub target_coord_state: Option<Coord4DOF>,
+    pub tracking_error: RobotState,
+    pub ik_feedforward: Option<bool>,
+    pub settled: bool,
+    pub fault: Option<String>,
+    pub control: Option<ControlStatus>,
+}
+
+pub type SnapshotSender = watch::Sender<RobotSnapshot>;
+pub type SnapshotReceiver = watch::Receiver<RobotSnapshot>;
+
+/// A consistent snapshot of the robot taken in a single broadcast tick.
+#[derive(serde::Serialize, Clone, Debug, PartialEq, schemars::JsonSchema, ts_rs::TS)]
+pub struct TelemetryFrame {
+    /// Layout version, see `TELEMETRY_VERSION`.
+    pub version: u32,
+    /// Increments by one every broadcast tick so clients can detect dropped frames.
+    #[ts(type = "number")]
+    pub sequence: u64,
+    pub timestamp: DateTime<Utc>,
+    /// The current joint and base state.
+    pub state: RobotState,
+    /// The current end effector pose.
+    pub coords: Coord4DOF,
+    pub velocity: RobotState,
+    pub target_state: RobotState,
+    pub target_coord_state: Option<Coord4DOF>,
+    /// `target_state - state`, using the shortest difference for angles.
+    pub tracking_error: RobotState,
+    /// Whether ik used feedforward to reach `target_coord_state`. `None` without a coordinate target.
+    pub ik_feedforward: Option<bool>,
+    /// True once the robot has reached its targets and stopped moving.
+    pub settled: bool,
+    pub fault: Option<String>,
+    /// The client holding the control lease, if any.
+    pub control: Option<ControlStatus>,
+}
+
+impl TelemetryFrame {
+    pub fn new(snapshot: &RobotSnapshot, sequence: u64) -> Self {
+        TelemetryFrame {
+            version: TELEMETRY_VERSION,
+            sequence,
+            timestamp: snapshot.timestamp,
+            state: snapshot.state,
+            coords: snapshot.coords,
+            velocity: snapshot.velocity,
+            target_state: snapshot.target_state,
+            target_coord_state: snapshot.target_coord_state,
+            tracking_error: snapshot.tracking_error,
+            ik_feedforward: snapshot.ik_feedforward,
+            settled: snapshot.settled,
+            fault: snapshot.fault.clone(),
+            control: snapshot.control.clone(),
+        }
+    }
+}
+
+/// A telemetry message published by the broadcast loop, or by the script runner for the `script` stream.
+#[derive(serde::Serialize, Clone, Debug, PartialEq, schemars::JsonSchema, ts_rs::TS)]
+#[serde(tag = "type", content = "data")]
+pub enum Telemetry {
+    /// The current `RobotState`.
+    #[serde(rename = "joint state")]
+    JointState(RobotState),
+    /// The current end effector pose.
+    #[serde(rename = "base coords")]
+    BaseCoords(Coord4DOF),
+    /// The current joint and base velocity.
+    #[serde(rename = "velocity")]
+    Velocity(RobotState),
+    /// The pose of every joint from forward kinematics.
+    #[serde(rename = "links")]
+    Links(LinkCoords),
+    /// Sent when a fault is raised or cleared.
+    #[serde(rename = "fault")]
+    Fault(Option<String>),
+    /// Sent when control is granted, taken over, released or expires.
+    #[serde(rename = "control")]
+    Control(Option<ControlStatus>),
+    /// The complete state of the robot from one broadcast tick.
+    #[serde(rename = "telemetry")]
+    Frame(Box<TelemetryFrame>),
+    /// Output and status changes of the script running.
+    #[serde(rename = "script")]
+    Script(ScriptEvent),
+}
+
+impl Telemetry {
+    /// The stream the message belongs to.
+    pub fn stream(&self) -> Stream {
+        match self {
+            Telemetry::JointState(_) => Stream::JointState,
+            Telemetry::BaseCoords(_) => Stream::BaseCoords,
+            Telemetry::Velocity(_) => Stream::Velocity,
+            Telemetry::Links(_) => Stream::Links,
+            Telemetry::Fault(_) => Stream::Fault,
+            Telemetry::Control(_) => Stream::Control,
+            Telemetry::Frame(_) => Stream::Frame,
+            Telemetry::Script(_) => Stream::Script,
+        }
+    }
+}
+
+pub type TelemetrySender = tokio::sync::broadcast::Sender<Telemetry>;
+
+/// A stream a client is subscribed to and how it is delivered.
+#[derive(serde::Serialize, serde::Deserialize, Copy, Clone, Debug, PartialEq, schemars::JsonSchema, ts_rs::TS)]
+pub struct StreamSubscription {
+    pub stream: Stream,
+    /// Maximum messages per second, at least `MIN_RATE_HZ`. Every broadcast tick when not set.
+    #[serde(default)]
+    #[ts(optional = nullable)]
+    pub rate_hz: Option<f64>,
+    /// Only deliver a message when it differs from the last one delivered.
+    /// `telemetry` frames are compared without their `sequence` and `timestamp`.
+    #[serde(default)]
+    #[ts(as = "Option<bool>", optional)]
+    pub on_change: bool,
+}
+
+/// A request to receive a stream, either just its name or a `StreamSubscription` with delivery options.
+#[derive(serde::Deserialize, Copy, Clone, Debug, schemars::JsonSchema, ts_rs::TS)]
+#[serde(untagged)]
+pub enum StreamRequest {
+    Stream(Stream),
+    Subscription(StreamSubscription),
+}
+
+impl From<StreamRequest> for StreamSubscription {
+    fn from(request: StreamRequest) -> Self {
+        match request {
+            StreamRequest::Stream(stream) => StreamSubscription { stream, rate_hz: None, on_change: false },
+            StreamRequest::Subscription(subscription) => subscription,
+        }
+    }
+}
+
+/// The delivery state of one subscribed stream.
+#[derive(Debug)]
+struct ActiveSubscription {
+    subscription: StreamSubscription,
+    /// When the next message may be sent if the stream is rate limited.
+    next_due: Option<Instant>,
+    /// The last message delivered, kept for on change subscriptions.
+    last_sent: Option<Telemetry>,
+}
+
+/// The streams a single client is subscribed to. Decides which telemetry messages are sent to the client.
+#[derive(Default, Debug)]
+pub struct Subscriptions {
+    streams: HashMap<Stream, ActiveSubscription>,
+}
+
+impl Subscriptions {
+    /// Subscribes to each requested stream, replacing the options of streams that are already subscribed.
+    /// Nothing is changed if any request is invalid.
+    pub fn subscribe(&mut self, requests: Vec<StreamRequest>) -> Result<(), String> {
+        let subscriptions: Vec<StreamSubscription> = requests.into_iter().map(StreamSubscription::from).collect();
+
+        if let Some(rate) = subscriptions.iter().filter_map(|subscription| subscription.rate_hz).find(|rate| !rate.is_finite() || *rate < MIN_RATE_HZ) {
+            return Err(format!("rate_hz must be a number of at least {MIN_RATE_HZ}, got {rate}"));
+        }
+
+        for subscription in subscriptions {
+            self.streams.insert(subscription.stream, ActiveSubscription { subscription, next_due: None, last_sent: None });
+        }
+        Ok(())
+    }
+
+    pub fn unsubscribe(&mut self, streams: &[Stream]) {
+        for stream in streams {
+            self.streams.remove(stream);
+        }
+    }
+
+    /// The active subscriptions.
+    pub fn list(&self) -> Vec<StreamSubscription> {
+        self.streams.values().map(|active| active.subscription).collect()
+    }
+
+    /// Returns true if `message` should be sent to the client, updating the stream's rate limit and last message.
+    pub fn should_send(&mut self, message: &Telemetry) -> bool {
+        let Some(active) = self.streams.get_mut(&message.stream()) else {
+            return false;
+        };
+
+        let now = Instant::now();
+        if active.next_due.is_some_and(|due| now < due) {
+            return false;
+        }
+        if active.subscription.on_change && active.last_sent.as_ref().is_some_and(|last_sent| unchanged(last_sent, message)) {
+            return false;
+        }
+
+        if let Some(rate_hz) = active.subscription.rate_hz {
+            // Keep to the requested cadence unless the stream fell a whole period behind.
+            let period = Duration::from_secs_f64(1.0 / rate_hz);
+            active.next_due = match active.next_due {
+                Some(due) if due + period > now => Some(due + period),
+                _ => Some(now + period),
+            };
+        }
+        if active.subscription.on_change {
+            active.last_sent = Some(message.clone());
+        }
+        true
+    }
+}
+
+/// Returns true if `message` carries the same data as `last_sent`. Every frame has a new sequence and timestamp, so those are ignored.
+fn unchanged(last_sent: &Telemetry, message: &Telemetry) -> bool {
+    match (last_sent, message) {
+        (Telemetry::Frame(last_sent), Telemetry::Frame(frame)) => {
+            TelemetryFrame { sequence: frame.sequence, timestamp: frame.timestamp, ..(**last_sent).clone() } == **frame
+        }
+        _ => last_sent == message,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn subscribe(subscriptions: &mut Subscriptions, stream: Stream, rate_hz: Option<f64>, on_change: bool) -> Result<(), String> {
+        subscriptions.subscribe(vec![StreamRequest::Subscription(StreamSubscription { stream, rate_hz, on_change })])
+    }
+
+    fn frame(sequence: u64, settled: bool) -> Telemetry {
+        let snapshot = RobotSnapshot { settled, timestamp: Utc::now(), ..Default::default() };
+        Telemetry::Frame(Box::new(TelemetryFrame::new(&snapshot, sequence)))
+    }
+
+    #[test]
+    fn rejects_rates_below_the_minimum() {
+        let mut subscriptions = Subscriptions::default();
+        for rate_hz in [0.0, -1.0, 1e-300, MIN_RATE_HZ / 2.0, f64::NAN, f64::INFINITY] {
+            assert!(subscribe(&mut subscriptions, Stream::JointState, Some(rate_hz), false).is_err(), "accepted rate_hz {rate_hz}");
+        }
+        assert!(subscriptions.list().is_empty());
+        assert!(subscribe(&mut subscriptions, Stream::JointState, Some(MIN_RATE_HZ), false).is_ok());
+    }
+
+    #[test]
+    fn only_sends_subscribed_streams() {
+        let mut subscriptions = Subscriptions::default();
+        subscriptions.subscribe(vec![StreamRequest::Stream(Stream::Fault)]).unwrap();
+        assert!(subscriptions.should_send(&Telemetry::Fault(None)));
+        assert!(!subscriptions.should_send(&Telemetry::JointState(RobotState::default())));
+
+        subscriptions.unsubscribe(&[Stream::Fault]);
+        assert!(!subscriptions.should_send(&Telemetry::Fault(None)));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn rate_limits_to_the_requested_cadence() {
+        let mut subscriptions = Subscriptions::default();
+        subscribe(&mut subscriptions, Stream::JointState, Some(10.0), false).unwrap();
+        let message = Telemetry::JointState(RobotState::default());
+
+        assert!(subscriptions.should_send(&message));
+        assert!(!subscriptions.should_send(&message));
+        tokio::time::advance(Duration::from_millis(50)).await;
+        assert!(!subscriptions.should_send(&message));
+        tokio::time::advance(Duration::from_millis(50)).await;
+        assert!(subscriptions.should_send(&message));
+
+        // A stream that falls a whole period behind restarts its cadence rather than bursting to catch up.
+        tokio::time::advance(Duration::from_secs(1)).await;
+        assert!(subscriptions.should_send(&message));
+        assert!(!subscriptions.should_send(&message));
+    }
+
+    #[test]
+    fn on_change_skips_repeated_messages() {
+        let mut subscriptions = Subscriptions::default();
+        subscribe(&mut subscriptions, Stream::Fault, None, true).unwrap();
+
+        assert!(subscriptions.should_send(&Telemetry::Fault(None)));
+        assert!(!subscriptions.should_send(&Telemetry::Fault(None)));
+        assert!(subscriptions.should_send(&Telemetry::Fault(Some("lift jammed".to_string()))));
+        assert!(subscriptions.should_send(&Telemetry::Fault(None)));
+    }
+
+    #[test]
+    fn on_change_ignores_frame_sequence_and_timestamp() {
+        let mut subscriptions = Subscriptions::default();
+        subscribe(&mut subscriptions, Stream::Frame, None, true).unwrap();
+
+        assert!(subscriptions.should_send(&frame(1, false)));
+        assert!(!subscriptions.should_send(&frame(2, false)));
+        assert!(subscriptions.should_send(&frame(3, true)));
+    }
+}
@@ -0,0 +1,358 @@
+use super::audit::{Origin, SharedAudit};
+use super::auth::{control_authorized, execute_authorized, pose_authorized, replay_authorized, require_identity, script_authorized, snapshot_authorized, Identity, SharedAuth};
+use super::command::{Command, CommandAck, CommandError, CommandOutcome};
+use super::control::{ControlAck, ControlOperation, ControlStatus, LeaseGrant};
+use super::persistence::{SavedState, SnapshotAck, SnapshotOperation};
+use super::poses::{Pose, PoseAck, PoseName, PoseOperation, SavePose};
+use super::replay::{ReplayAck, ReplayControl};
+use super::scripting::{ScriptAck, ScriptControl, ScriptInfo, ScriptName, ScriptOperation, ScriptSource, ScriptStatus, SharedScripts};
+use super::tasks::PickAndPlace;
+use super::telemetry::{Stream, StreamRequest, StreamSubscription, Subscriptions, Telemetry, TelemetrySender};
+use super::RobotLock;
+
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+
+use axum::{
+    extract::{
+        ws::{Message, WebSocket, WebSocketUpgrade},
+        ConnectInfo, State,
+    },
+    middleware,
+    response::Response,
+    routing::get,
+    Extension, Router,
+};
+use tokio::sync::broadcast::error::RecvError;
+use tracing::{info, warn};
+
+/// Shared state for plain WebSocket connections.
+#[derive(Clone)]
+struct WsState {
+    robot_lock: RobotLock,
+    telemetry: TelemetrySender,
+    audit: SharedAudit,
+    scripts: SharedScripts,
+}
+
+/// A message sent by a client over the plain WebSocket.
+#[derive(serde::Deserialize, Clone, Debug, schemars::JsonSchema, ts_rs::TS)]
+#[serde(tag = "type", rename_all = "snake_case")]
+#[schemars(rename = "WsClientMessage")]
+#[ts(rename = "WsClientMessage")]
+pub(super) enum ClientMessage {
+    /// `{"type": "command", "id": 1, "command": "set_joint_state", "data": {...}}`
+    Command {
+        /// Optional request id echoed back in the ack.
+        #[serde(default)]
+        #[ts(optional = nullable)]
+        id: Option<serde_json::Value>,
+        #[serde(flatten)]
+        command: Command,
+    },
+    /// `{"type": "subscribe", "streams": ["joint state", {"stream": "base coords", "rate_hz": 5, "on_change": true}]}`
+    Subscribe { streams: Vec<StreamRequest> },
+    /// `{"type": "unsubscribe", "streams": ["joint state"]}`
+    Unsubscribe { streams: Vec<Stream> },
+    /// `{"type": "request_control", "take_over": false}`
+    RequestControl {
+        #[serde(default)]
+        #[ts(as = "Option<bool>", optional)]
+        take_over: bool,
+    },
+    /// `{"type": "renew_control"}`, renews the lease held by this connection.
+    RenewControl,
+    /// `{"type": "release_control"}`, releases the lease held by this connection.
+    ReleaseControl,
+    /// `{"type": "replay", "action": "seek", "position_s": 12.5}`
+    Replay {
+        #[serde(flatten)]
+        control: ReplayControl,
+    },
+    /// `{"type": "save_snapshot"}`, saves the robot's state to the state file.
+    SaveSnapshot,
+    /// `{"type": "load_snapshot"}`, moves the robot straight to the saved state.
+    LoadSnapshot,
+    /// `{"type": "list_poses"}`
+    ListPoses,
+    /// `{"type": "save_pose", "name": "pick_A"}` teaches the current pose, or `{"type": "save_pose", "name": "pick_A", "pose": {...}}` saves the given one.
+    SavePose {
+        #[serde(flatten)]
+        request: SavePose,
+    },
+    /// `{"type": "delete_pose", "name": "pick_A"}`
+    DeletePose {
+        #[serde(flatten)]
+        request: PoseName,
+    },
+    /// `{"type": "list_scripts"}`
+    ListScripts,
+    /// `{"type": "upload_script", "name": "palletize", "source": "move_joints(#{lift_elevation_mm: 500}); wait_settled();"}`
+    UploadScript {
+        #[serde(flatten)]
+        script: ScriptSource,
+    },
+    /// `{"type": "delete_script", "name": "palletize"}`
+    DeleteScript {
+        #[serde(flatten)]
+        script: ScriptName,
+    },
+    /// `{"type": "run_script", "name": "palletize"}`, the script's commands carry the connection's lease.
+    RunScript {
+        #[serde(flatten)]
+        script: ScriptName,
+    },
+    /// `{"type": "script", "action": "pause"}`
+    Script {
+        #[serde(flatten)]
+        control: ScriptControl,
+    },
+    /// `{"type": "pick_and_place", "pick": {...}, "place": {...}, "approach_height_m": 0.1, "open_mm": 120, "closed_mm": 40}`,
+    /// run like a script carrying the connection's lease.
+    PickAndPlace {
+        #[serde(flatten)]
+        task: PickAndPlace,
+    },
+    /// `{"type": "script_status"}`
+    ScriptStatus,
+}
+
+/// A non-telemetry message sent to a client over the plain WebSocket.
+#[derive(serde::Serialize, Clone, Debug, schemars::JsonSchema, ts_rs::TS)]
+#[serde(tag = "type", content = "data")]
+#[schemars(rename = "WsServerMessage")]
+#[ts(rename = "WsServerMessage")]
+pub(super) enum ServerMessage {
+    /// Sent in reply to every command.
+    #[serde(rename = "ack")]
+    Ack(Box<CommandAck>),
+    /// The streams the client is subscribed to after a subscribe or unsubscribe.
+    #[serde(rename = "subscriptions")]
+    Subscriptions(Vec<StreamSubscription>),
+    /// Sent when a subscribe is rejected or a message other than a command cannot be read.
+    #[serde(rename = "error")]
+    Error(String),
+    /// Sent in reply to a request, renewal or release of control.
+    #[serde(rename = "control ack")]
+    ControlAck(Box<ControlAck>),
+    /// Sent when the connection's lease expires or is taken over, with the new holder if there is one.
+    #[serde(rename = "control lost")]
+    ControlLost(Option<ControlStatus>),
+    /// Sent in reply to a replay control.
+    #[serde(rename = "replay ack")]
+    ReplayAck(Box<ReplayAck>),
+    /// Sent in reply to a save or load of the robot's state.
+    #[serde(rename = "snapshot ack")]
+    SnapshotAck(Box<SnapshotAck>),
+    /// Sent in reply to `list_poses`, every saved pose by name.
+    #[serde(rename = "poses")]
+    Poses(BTreeMap<String, Pose>),
+    /// Sent in reply to saving or deleting a pose.
+    #[serde(rename = "pose ack")]
+    PoseAck(Box<PoseAck>),
+    /// Sent in reply to `list_scripts`, the names of the saved scripts.
+    #[serde(rename = "scripts")]
+    Scripts(Vec<ScriptInfo>),
+    /// Sent in reply to uploading, deleting, running or controlling a script.
+    #[serde(rename = "script ack")]
+    ScriptAck(Box<ScriptAck>),
+    /// Sent in reply to `script_status`, the script running or the last one run.
+    #[serde(rename = "script status")]
+    ScriptStatus(Option<ScriptStatus>),
+}
+
+/// Builds the plain WebSocket endpoint that speaks the JSON protocol. The upgrade requires a valid token.
+pub fn router(robot_lock: RobotLock, telemetry: TelemetrySender, audit: SharedAudit, scripts: SharedScripts, auth: SharedAuth) -> Router {
+    Router::new()
+        .route("/ws", get(ws_handler))
+        .route_layer(middleware::from_fn_with_state(auth, require_identity))
+        .with_state(WsState { robot_lock, telemetry, audit, scripts })
+}
+
+async fn ws_handler(ws: WebSocketUpgrade, State(state): State<WsState>, Extension(identity): Extension<Identity>, ConnectInfo(remote_addr): ConnectInfo<SocketAddr>) -> Response {
+    ws.on_upgrade(move |socket| handle_socket(socket, state, identity, remote_addr))
+}
+
+/// Forwards subscribed telemetry to the client and executes the commands it sends until it disconnects.
+async fn handle_socket(mut socket: WebSocket, state: WsState, identity: Identity, remote_addr: SocketAddr) {
+    info!("websocket client connected from {} as {} ({:?})", remote_addr, identity.name, identity.role);
+    let origin = Origin { transport: "websocket", client_id: None, remote_addr: Some(remote_addr), identity: Some(identity.clone()) };
+
+    let mut telemetry = state.telemetry.subscribe();
+    let mut subscriptions = Subscriptions::default();
+    // The control lease held by this connection.
+    let mut lease: Option<LeaseGrant> = None;
+
+    loop {
+        let reply = tokio::select! {
+            message = socket.recv() => match message {
+                Some(Ok(Message::Text(text))) => handle_message(&text, &state, &origin, &identity, &mut subscriptions, &mut lease).await,
+                Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                Some(Ok(_)) => None,
+            },
+            message = telemetry.recv() => match message {
+                Ok(Telemetry::Control(status)) if lease.as_ref().is_some_and(|lease| is_lost(lease, status.as_ref())) => {
+                    lease = None;
+                    serde_json::to_string(&ServerMessage::ControlLost(status)).ok()
+                }
+                Ok(message) if subscriptions.should_send(&message) => serde_json::to_string(&message).ok(),
+                Ok(_) => None,
+                // A slow client misses messages rather than holding up the broadcast.
+                Err(RecvError::Lagged(_)) => None,
+                Err(RecvError::Closed) => break,
+            },
+        };
+
+        if let Some(reply) = reply {
+            if socket.send(Message::Text(reply)).await.is_err() {
+                break;
+            }
+        }
+    }
+
+    // Hand control back straight away rather than leaving it held until the lease expires.
+    if let Some(lease) = lease {
+        let _ = state.robot_lock.write().await.release_control(&lease.token);
+    }
+
+    info!("websocket client disconnected");
+}
+
+/// Handles a single text message from the client, returning the reply to send if there is one.
+/// Commands, including malformed ones, are audited with the message as received. Other messages that cannot be read are answered with an `error`.
+async fn handle_message(text: &str, state: &WsState, origin: &Origin, identity: &Identity, subscriptions: &mut Subscriptions, lease: &mut Option<LeaseGrant>) -> Option<String> {
+    let robot_lock = &state.robot_lock;
+    let value: serde_json::Value = match serde_json::from_str(text) {
+        Ok(value) => value,
+        Err(err) => return serde_json::to_string(&ServerMessage::Error(format!("malformed message: {err}"))).ok(),
+    };
+
+    let message: ClientMessage = match serde_json::from_value(value.clone()) {
+        Ok(message) => message,
+        // Ack a malformed command like any other so the client can match the rejection to its request id.
+        Err(err) if value.get("type").and_then(|kind| kind.as_str()) == Some("command") => {
+            let command_name = value.get("command").and_then(|name| name.as_str()).unwrap_or_default();
+            let result = Err(CommandError::Malformed { message: err.to_string() });
+            state.audit.record(origin, command_name, &value, &result);
+            return reply_ack(command_name, value.get("id").cloned(), result);
+        }
+        Err(err) => return serde_json::to_string(&ServerMessage::Error(format!("malformed message: {err}"))).ok(),
+    };
+
+    match message {
+        ClientMessage::Command { id, command } => {
+            let token = lease.as_ref().map(|lease| lease.token.as_str());
+            let name = command.name();
+            let result = execute_authorized(robot_lock, identity, token, command).await;
+            state.audit.record(origin, name, &value, &result);
+            if let Err(error) = &result {
+                warn!("rejected websocket command '{}': {}", name, error);
+            }
+            reply_ack(name, id, result)
+        }
+        ClientMessage::Subscribe { streams } => match subscriptions.subscribe(streams) {
+            Ok(()) => serde_json::to_string(&ServerMessage::Subscriptions(subscriptions.list())).ok(),
+            Err(error) => serde_json::to_string(&ServerMessage::Error(error)).ok(),
+        },
+        ClientMessage::Unsubscribe { streams } => {
+            subscriptions.unsubscribe(&streams);
+            serde_json::to_string(&ServerMessage::Subscriptions(subscriptions.list())).ok()
+        }
+        ClientMessage::RequestControl { take_over } => {
+            let holder = format!("{} (websocket)", identity.name);
+            let result = control_authorized(robot_lock, identity, ControlOperation::Request { holder: &holder, take_over }).await;
+            reply_control(result, lease)
+        }
+        ClientMessage::RenewControl => {
+            let token = lease.as_ref().map(|lease| lease.token.clone()).unwrap_or_default();
+            let result = control_authorized(robot_lock, identity, ControlOperation::Renew { token: &token }).await;
+            reply_control(result, lease)
+        }
+        ClientMessage::ReleaseControl => {
+            let token = lease.as_ref().map(|lease| lease.token.clone()).unwrap_or_default();
+            let result = control_authorized(robot_lock, identity, ControlOperation::Release { token: &token }).await;
+            if result.is_ok() {
+                *lease = None;
+            }
+            reply_control(result, lease)
+        }
+        ClientMessage::Replay { control } => {
+            let result = replay_authorized(robot_lock, identity, control).await;
+            if let Err(error) = &result {
+                warn!("rejected websocket replay control: {}", error);
+            }
+            serde_json::to_string(&ServerMessage::ReplayAck(Box::new(ReplayAck::new(result)))).ok()
+        }
+        ClientMessage::SaveSnapshot => reply_snapshot(state, origin, identity, lease, SnapshotOperation::Save).await,
+        ClientMessage::LoadSnapshot => reply_snapshot(state, origin, identity, lease, SnapshotOperation::Load).await,
+        ClientMessage::ListPoses => serde_json::to_string(&ServerMessage::Poses(robot_lock.read().await.poses().list().clone())).ok(),
+        ClientMessage::SavePose { request } => reply_pose(robot_lock, identity, PoseOperation::Save(request)).await,
+        ClientMessage::DeletePose { request } => reply_pose(robot_lock, identity, PoseOperation::Delete(request)).await,
+        ClientMessage::ListScripts => {
+            let list = state.scripts.list().unwrap_or_else(|err| {
+                warn!("could not list the scripts: {}", err);
+                Vec::new()
+            });
+            serde_json::to_string(&ServerMessage::Scripts(list)).ok()
+        }
+        ClientMessage::UploadScript { script } => reply_script(state, origin, identity, lease, ScriptOperation::Upload(script)),
+        ClientMessage::DeleteScript { script } => reply_script(state, origin, identity, lease, ScriptOperation::Delete(script)),
+        ClientMessage::RunScript { script } => reply_script(state, origin, identity, lease, ScriptOperation::Run(script)),
+        ClientMessage::Script { control } => reply_script(state, origin, identity, lease, ScriptOperation::Control(control)),
+        ClientMessage::PickAndPlace { task } => reply_script(state, origin, identity, lease, ScriptOperation::PickAndPlace(task)),
+        ClientMessage::ScriptStatus => serde_json::to_string(&ServerMessage::ScriptStatus(state.scripts.status())).ok(),
+    }
+}
+
+/// Saves or restores the robot's state and replies with the snapshot ack.
+async fn reply_snapshot(state: &WsState, origin: &Origin, identity: &Identity, lease: &Option<LeaseGrant>, operation: SnapshotOperation) -> Option<String> {
+    let token = lease.as_ref().map(|lease| lease.token.as_str());
+    let result = snapshot_authorized(&state.robot_lock, identity, token, operation).await;
+    state.audit.record(origin, operation.name(), &serde_json::Value::Null, &result.as_ref().map(SavedState::outcome).map_err(Clone::clone));
+    if let Err(error) = &result {
+        warn!("rejected websocket {}: {}", operation.name(), error);
+    }
+    serde_json::to_string(&ServerMessage::SnapshotAck(Box::new(SnapshotAck::new(result)))).ok()
+}
+
+/// Saves or deletes a pose and replies with the pose ack.
+async fn reply_pose(robot_lock: &RobotLock, identity: &Identity, operation: PoseOperation) -> Option<String> {
+    let result = pose_authorized(robot_lock, identity, operation).await;
+    if let Err(error) = &result {
+        warn!("rejected websocket pose change: {}", error);
+    }
+    serde_json::to_string(&ServerMessage::PoseAck(Box::new(PoseAck::new(result)))).ok()
+}
+
+/// Uploads, deletes, runs or controls a script and replies with the script ack. A script started here carries the connection's lease.
+fn reply_script(state: &WsState, origin: &Origin, identity: &Identity, lease: &Option<LeaseGrant>, operation: ScriptOperation) -> Option<String> {
+    let token = lease.as_ref().map(|lease| lease.token.as_str());
+    let result = script_authorized(&state.scripts, identity, origin.remote_addr, token, operation);
+    if let Err(error) = &result {
+        warn!("rejected websocket script request: {}", error);
+    }
+    serde_json::to_string(&ServerMessage::ScriptAck(Box::new(ScriptAck::new(result)))).ok()
+}
+
+/// Whether the published control status shows `lease` was taken over or expired. Older statuses still queued on the channel are ignored.
+fn is_lost(lease: &LeaseGrant, status: Option<&ControlStatus>) -> bool {
+    match status {
+        Some(status) => status.lease_id > lease.status.lease_id,
+        None => lease.status.expires_at <= chrono::Utc::now(),
+    }
+}
+
+/// Remembers a granted lease and replies with the control ack.
+fn reply_control(result: Result<Option<LeaseGrant>, CommandError>, lease: &mut Option<LeaseGrant>) -> Option<String> {
+    match &result {
+        Ok(Some(grant)) => *lease = Some(grant.clone()),
+        Ok(None) => {}
+        Err(error) => warn!("rejected websocket control request: {}", error),
+    }
+    serde_json::to_string(&ServerMessage::ControlAck(Box::new(ControlAck::new(result)))).ok()
+}
+
+fn reply_ack(command: &str, request_id: Option<serde_json::Value>, result: Result<CommandOutcome, CommandError>) -> Option<String> {
+    serde_json::to_string(&ServerMessage::Ack(Box::new(CommandAck::new(command, request_id, result)))).ok()
+}
+